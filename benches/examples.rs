@@ -0,0 +1,45 @@
+//! Instruction throughput, running each of the bundled example programs to completion.
+//! Their output goes to an unmapped port, so it is discarded.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use zeerust::cpu::opcodes::parse_stream;
+use zeerust::examples::EXAMPLES;
+use zeerust::z80::Z80;
+
+/// Run a program on a fresh machine until it halts, returning the number of instructions executed
+fn run_to_halt(binary: &[u8], blocks: bool) -> u64 {
+    let mut z80 = Z80::default();
+    z80.load(binary);
+    if blocks {
+        z80.enable_block_engine();
+    }
+    z80.run();
+    z80.instructions()
+}
+
+fn run(c: &mut Criterion) {
+    for (name, blocks) in [("run", false), ("blocks", true)] {
+        let mut group = c.benchmark_group(name);
+        for example in EXAMPLES {
+            group.throughput(Throughput::Elements(run_to_halt(example.binary, blocks)));
+            group.bench_function(example.name, |b| {
+                b.iter(|| run_to_halt(example.binary, blocks))
+            });
+        }
+        group.finish();
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for example in EXAMPLES {
+        group.throughput(Throughput::Bytes(example.binary.len() as u64));
+        group.bench_function(example.name, |b| {
+            b.iter(|| parse_stream(example.binary.to_vec()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run, decode);
+criterion_main!(benches);
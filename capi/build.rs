@@ -0,0 +1,8 @@
+use std::env;
+
+fn main() {
+    // tests/c.rs compiles a C program for the same target as the library
+    for var in &["HOST", "TARGET"] {
+        println!("cargo:rustc-env={}={}", var, env::var(var).unwrap());
+    }
+}
@@ -0,0 +1,331 @@
+//! A C API for zeerust, declared in `zeerust.h`.
+//!
+//! A `zeerust_z80` is an opaque handle, created with `zeerust_z80_new` and freed with
+//! `zeerust_z80_free`. Devices are C callbacks, each given the `data` pointer they were
+//! installed with; they're called on whichever thread is running the Z80.
+//!
+//! Every function accepts a NULL handle, and does nothing or returns an error value:
+//! false, 0 or -1 as described. Registers are passed as plain integers, so an out-of-range
+//! `zeerust_reg` is reported as an error too.
+//!
+//! `tests/c.rs` checks that the header is up to date: run it with `ZEERUST_UPDATE_HEADER` set
+//! to regenerate the header after changing this file.
+use std::os::raw::c_void;
+use std::slice;
+
+use zeerust::cpu::mem::MEMORY_SIZE;
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::io::{IoDevice, Ports};
+
+/// A Z80, with no devices installed
+pub struct Machine {
+    z80: zeerust::z80::Z80,
+}
+
+/// The registers which can be read and written with `zeerust_z80_get_reg` and `zeerust_z80_set_reg`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    IX,
+    IY,
+    SP,
+    PC,
+}
+
+/// Every `Register`, in order
+const REGISTERS: [Register; 16] = [
+    Register::A,
+    Register::F,
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::IX,
+    Register::IY,
+    Register::SP,
+    Register::PC,
+];
+
+impl Register {
+    /// The register with the given value, if there is one
+    fn from_raw(reg: u32) -> Option<Self> {
+        REGISTERS.get(reg as usize).copied()
+    }
+
+    fn reg8(self) -> Option<Reg8> {
+        match self {
+            Register::A => Some(Reg8::A),
+            Register::F => Some(Reg8::F),
+            Register::B => Some(Reg8::B),
+            Register::C => Some(Reg8::C),
+            Register::D => Some(Reg8::D),
+            Register::E => Some(Reg8::E),
+            Register::H => Some(Reg8::H),
+            Register::L => Some(Reg8::L),
+            _ => None,
+        }
+    }
+
+    fn reg16(self) -> Option<Reg16> {
+        match self {
+            Register::AF => Some(Reg16::AF),
+            Register::BC => Some(Reg16::BC),
+            Register::DE => Some(Reg16::DE),
+            Register::HL => Some(Reg16::HL),
+            Register::IX => Some(Reg16::IX),
+            Register::IY => Some(Reg16::IY),
+            Register::SP => Some(Reg16::SP),
+            _ => None,
+        }
+    }
+}
+
+/// Called for each `IN`, with the full port address. Returns the byte read.
+pub type InputCallback = extern "C" fn(data: *mut c_void, port: u16) -> u8;
+
+/// Called for each `OUT`, with the full port address and the byte written
+pub type OutputCallback = extern "C" fn(data: *mut c_void, port: u16, val: u8);
+
+/// Callbacks are called on whichever thread runs the Z80, so it's up to the caller to make sure
+/// that `data` can be used there.
+struct Callback<F> {
+    callback: F,
+    data: *mut c_void,
+}
+
+unsafe impl<F: Send> Send for Callback<F> {}
+
+impl IoDevice for Callback<InputCallback> {
+    fn read(&mut self, port: u16) -> Option<u8> {
+        Some((self.callback)(self.data, port))
+    }
+}
+
+impl IoDevice for Callback<OutputCallback> {
+    fn write(&mut self, port: u16, val: u8) -> bool {
+        (self.callback)(self.data, port, val);
+        true
+    }
+}
+
+/// Create a Z80, with all registers and memory zeroed
+#[no_mangle]
+pub extern "C" fn zeerust_z80_new() -> Box<Machine> {
+    Box::new(Machine {
+        z80: zeerust::z80::Z80::default(),
+    })
+}
+
+/// Free a Z80 created with `zeerust_z80_new`. Freeing NULL does nothing.
+#[no_mangle]
+pub extern "C" fn zeerust_z80_free(z80: Option<Box<Machine>>) {
+    drop(z80)
+}
+
+/// Copy `len` bytes into memory, starting at `addr`.
+/// Anything that would extend past the end of memory is ignored.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`, and `bytes` must point to at
+/// least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_load(
+    z80: *mut Machine,
+    addr: u16,
+    bytes: *const u8,
+    len: usize,
+) {
+    if let Some(z80) = z80.as_mut() {
+        if len > 0 {
+            z80.z80.load_at(addr, slice::from_raw_parts(bytes, len));
+        }
+    }
+}
+
+/// Execute a single instruction
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_step(z80: *mut Machine) {
+    if let Some(z80) = z80.as_mut() {
+        z80.z80.step()
+    }
+}
+
+/// Execute instructions until a HALT, or until `limit` have been executed.
+/// Returns whether the Z80 halted, or false for a NULL handle.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_run(z80: *mut Machine, limit: u64) -> bool {
+    match z80.as_mut() {
+        Some(z80) => {
+            z80.z80.run_for(limit);
+            z80.z80.is_halted()
+        }
+        None => false,
+    }
+}
+
+/// Whether the Z80 has halted, or false for a NULL handle
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_halted(z80: *const Machine) -> bool {
+    z80.as_ref().is_some_and(|z80| z80.z80.is_halted())
+}
+
+/// The number of instructions executed, or 0 for a NULL handle
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_instructions(z80: *const Machine) -> u64 {
+    z80.as_ref().map_or(0, |z80| z80.z80.instructions())
+}
+
+/// Read a register, a `zeerust_reg`. 8-bit registers are returned in the lower byte.
+/// Returns -1 for a NULL handle or an unknown register.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_get_reg(z80: *const Machine, reg: u32) -> i32 {
+    let (z80, reg) = match (z80.as_ref(), Register::from_raw(reg)) {
+        (Some(z80), Some(reg)) => (z80, reg),
+        _ => return -1,
+    };
+    let regs = &z80.z80.registers;
+    let val = match (reg.reg8(), reg.reg16()) {
+        (Some(r), _) => u16::from(regs.get_reg8(r)),
+        (_, Some(r)) => regs.get_reg16(&r),
+        // PC
+        _ => regs.get_pc(),
+    };
+    i32::from(val)
+}
+
+/// Write a register, a `zeerust_reg`. Only the lower byte of `val` is used for 8-bit registers.
+/// Returns false for a NULL handle or an unknown register.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_set_reg(z80: *mut Machine, reg: u32, val: u16) -> bool {
+    let (z80, reg) = match (z80.as_mut(), Register::from_raw(reg)) {
+        (Some(z80), Some(reg)) => (z80, reg),
+        _ => return false,
+    };
+    let regs = &mut z80.z80.registers;
+    match (reg.reg8(), reg.reg16()) {
+        (Some(r), _) => regs.set_reg8(r, val as u8),
+        (_, Some(r)) => regs.set_reg16(&r, val),
+        // PC
+        _ => regs.set_pc(val),
+    }
+    true
+}
+
+/// Copy `len` bytes of memory from `addr` into `out`,
+/// wrapping around from the top of memory to the bottom.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`, and `out` must point to at
+/// least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_read_memory(
+    z80: *const Machine,
+    addr: u16,
+    out: *mut u8,
+    len: usize,
+) {
+    let z80 = match z80.as_ref() {
+        Some(z80) if len > 0 => z80,
+        _ => return,
+    };
+    let memory = z80.z80.memory();
+    for (i, byte) in slice::from_raw_parts_mut(out, len).iter_mut().enumerate() {
+        *byte = memory[usize::from(addr.wrapping_add(i as u16))];
+    }
+}
+
+/// Copy `len` bytes from `bytes` into memory at `addr`,
+/// wrapping around from the top of memory to the bottom.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`, and `bytes` must point to at
+/// least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_write_memory(
+    z80: *mut Machine,
+    addr: u16,
+    bytes: *const u8,
+    len: usize,
+) {
+    let z80 = match z80.as_mut() {
+        Some(z80) if len > 0 => z80,
+        _ => return,
+    };
+    let (mut addr, mut bytes) = (addr, slice::from_raw_parts(bytes, len));
+    while !bytes.is_empty() {
+        let n = bytes.len().min(MEMORY_SIZE - usize::from(addr));
+        z80.z80.load_at(addr, &bytes[..n]);
+        addr = addr.wrapping_add(n as u16);
+        bytes = &bytes[n..];
+    }
+}
+
+/// Call `callback(data, port)` for every read from a port whose lower byte is `port`.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`, and `callback` must not be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_install_input(
+    z80: *mut Machine,
+    port: u8,
+    callback: InputCallback,
+    data: *mut c_void,
+) {
+    if let Some(z80) = z80.as_mut() {
+        let device = Callback { callback, data };
+        z80.z80.connect(Ports::low(port), Box::new(device));
+    }
+}
+
+/// Call `callback(data, port, val)` for every write to a port whose lower byte is `port`.
+///
+/// # Safety
+/// `z80` must be NULL or a live handle from `zeerust_z80_new`, and `callback` must not be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn zeerust_z80_install_output(
+    z80: *mut Machine,
+    port: u8,
+    callback: OutputCallback,
+    data: *mut c_void,
+) {
+    if let Some(z80) = z80.as_mut() {
+        let device = Callback { callback, data };
+        z80.z80.connect(Ports::low(port), Box::new(device));
+    }
+}
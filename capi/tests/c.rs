@@ -0,0 +1,66 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MANIFEST_DIR: &str = env!("CARGO_MANIFEST_DIR");
+
+/// Where cargo has put the library: the same directory as this test
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn header_is_up_to_date() {
+    let config = cbindgen::Config::from_root_or_default(MANIFEST_DIR);
+    let mut generated = vec![];
+    cbindgen::generate_with_config(MANIFEST_DIR, config)
+        .unwrap()
+        .write(&mut generated);
+    let generated = String::from_utf8(generated).unwrap();
+
+    let header = Path::new(MANIFEST_DIR).join("zeerust.h");
+    if env::var_os("ZEERUST_UPDATE_HEADER").is_some() {
+        fs::write(&header, &generated).unwrap();
+    }
+    assert_eq!(
+        fs::read_to_string(header).unwrap(),
+        generated,
+        "zeerust.h is out of date: rerun this test with ZEERUST_UPDATE_HEADER set"
+    );
+}
+
+#[test]
+fn c_harness() {
+    let lib = library_dir();
+    let exe = Path::new(env!("CARGO_TARGET_TMPDIR")).join("harness");
+    let compiler = cc::Build::new()
+        .cargo_metadata(false)
+        .opt_level(0)
+        .host(env!("HOST"))
+        .target(env!("TARGET"))
+        .include(MANIFEST_DIR)
+        .warnings_into_errors(true)
+        .get_compiler();
+
+    let status = compiler
+        .to_command()
+        .arg(Path::new(MANIFEST_DIR).join("tests/c/harness.c"))
+        .arg("-o")
+        .arg(&exe)
+        .arg("-L")
+        .arg(&lib)
+        .arg(format!("-Wl,-rpath,{}", lib.display()))
+        .arg("-lzeerust_capi")
+        .status()
+        .unwrap();
+    assert!(status.success(), "failed to compile the harness");
+
+    // cargo's LD_LIBRARY_PATH can point at an older copy of the library than the rpath does
+    let status = Command::new(&exe)
+        .env_remove("LD_LIBRARY_PATH")
+        .status()
+        .unwrap();
+    assert!(status.success(), "the harness failed");
+}
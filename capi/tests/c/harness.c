@@ -0,0 +1,145 @@
+/* Drives zeerust through its C API, as a C test harness would.
+ * Exits with a non-zero status, after printing the failed check, if anything is wrong. */
+#include <stdio.h>
+#include <string.h>
+
+#include "zeerust.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                          \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                  \
+            failures++;                                                      \
+        }                                                                    \
+    } while (0)
+
+struct output {
+    char buf[64];
+    size_t len;
+    uint16_t last_port;
+};
+
+static void write_byte(void *data, uint16_t port, uint8_t val) {
+    struct output *out = data;
+    if (out->len < sizeof(out->buf) - 1) {
+        out->buf[out->len++] = (char)val;
+    }
+    out->last_port = port;
+}
+
+static uint8_t read_byte(void *data, uint16_t port) {
+    const char **in = data;
+    (void)port;
+    return (uint8_t)*(*in)++;
+}
+
+/* Prints "Hello World\n" to port 0, from hello_world.asm */
+static void hello_world(void) {
+    static const uint8_t program[] = {
+        0x21, 0x10, 0x00, 0x7E, 0xC6, 0x00, 0xCA, 0x0F, 0x00, 0xD3, 0x00, 0x23, 0xC3, 0x03, 0x00,
+        0x76, 'H',  'e',  'l',  'l',  'o',  ' ',  'W',  'o',  'r',  'l',  'd',  '\n', 0x00,
+    };
+    struct output out = {0};
+    zeerust_z80 *z80 = zeerust_z80_new();
+
+    zeerust_z80_install_output(z80, 0, write_byte, &out);
+    zeerust_z80_load(z80, 0x0000, program, sizeof(program));
+    CHECK(zeerust_z80_run(z80, 1000));
+    CHECK(zeerust_z80_halted(z80));
+    CHECK(strcmp(out.buf, "Hello World\n") == 0);
+    zeerust_z80_free(z80);
+}
+
+/* Echoes two bytes from port 1 to port 2, stepping one instruction at a time */
+static void echo(void) {
+    /* IN A, (1); OUT (2), A; IN A, (1); OUT (2), A; HALT */
+    static const uint8_t program[] = {0xDB, 0x01, 0xD3, 0x02, 0xDB, 0x01, 0xD3, 0x02, 0x76};
+    const char *in = "ok";
+    struct output out = {0};
+    zeerust_z80 *z80 = zeerust_z80_new();
+
+    zeerust_z80_install_input(z80, 1, read_byte, &in);
+    zeerust_z80_install_output(z80, 2, write_byte, &out);
+    zeerust_z80_load(z80, 0x0000, program, sizeof(program));
+    zeerust_z80_step(z80);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_A) == 'o');
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_PC) == 0x0002);
+    zeerust_z80_step(z80);
+    /* OUT (n), A puts A on the upper half of the address bus */
+    CHECK(out.last_port == 0x6F02);
+    CHECK(!zeerust_z80_run(z80, 2));
+    CHECK(zeerust_z80_instructions(z80) == 4);
+    CHECK(zeerust_z80_run(z80, 10));
+    CHECK(strcmp(out.buf, "ok") == 0);
+    zeerust_z80_free(z80);
+}
+
+static void registers(void) {
+    zeerust_z80 *z80 = zeerust_z80_new();
+
+    zeerust_z80_set_reg(z80, ZEERUST_REG_HL, 0x1234);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_H) == 0x12);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_L) == 0x34);
+    zeerust_z80_set_reg(z80, ZEERUST_REG_B, 0xAB);
+    zeerust_z80_set_reg(z80, ZEERUST_REG_C, 0xCD);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_BC) == 0xABCD);
+    zeerust_z80_set_reg(z80, ZEERUST_REG_SP, 0xFFFE);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_SP) == 0xFFFE);
+
+    /* Unknown registers are reported, rather than being undefined behaviour */
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_PC + 1) == -1);
+    CHECK(!zeerust_z80_set_reg(z80, 1000, 0x1234));
+    CHECK(zeerust_z80_set_reg(z80, ZEERUST_REG_PC, 0x1234));
+    zeerust_z80_free(z80);
+}
+
+/* A NULL handle is ignored, or gives an error value */
+static void null_handles(void) {
+    uint8_t out[1] = {0xAA};
+
+    zeerust_z80_step(NULL);
+    zeerust_z80_load(NULL, 0x0000, out, sizeof(out));
+    zeerust_z80_read_memory(NULL, 0x0000, out, sizeof(out));
+    CHECK(out[0] == 0xAA);
+    CHECK(!zeerust_z80_run(NULL, 10));
+    CHECK(!zeerust_z80_halted(NULL));
+    CHECK(zeerust_z80_instructions(NULL) == 0);
+    CHECK(zeerust_z80_get_reg(NULL, ZEERUST_REG_A) == -1);
+    CHECK(!zeerust_z80_set_reg(NULL, ZEERUST_REG_A, 0));
+    zeerust_z80_free(NULL);
+}
+
+static void memory(void) {
+    static const uint8_t bytes[] = {0x01, 0x02, 0x03, 0x04};
+    uint8_t out[4] = {0};
+    zeerust_z80 *z80 = zeerust_z80_new();
+
+    /* Writes and reads wrap around the top of memory */
+    zeerust_z80_write_memory(z80, 0xFFFE, bytes, sizeof(bytes));
+    zeerust_z80_read_memory(z80, 0x0000, out, 2);
+    CHECK(out[0] == 0x03 && out[1] == 0x04);
+    zeerust_z80_read_memory(z80, 0xFFFE, out, sizeof(out));
+    CHECK(memcmp(out, bytes, sizeof(bytes)) == 0);
+
+    /* Overwriting an instruction that has already run takes effect when it runs again */
+    zeerust_z80_write_memory(z80, 0x0000, (const uint8_t[]){0x3E, 0x01}, 2); /* LD A, 1 */
+    zeerust_z80_step(z80);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_A) == 0x01);
+    zeerust_z80_write_memory(z80, 0x0001, (const uint8_t[]){0x02}, 1);
+    zeerust_z80_set_reg(z80, ZEERUST_REG_PC, 0x0000);
+    zeerust_z80_step(z80);
+    CHECK(zeerust_z80_get_reg(z80, ZEERUST_REG_A) == 0x02);
+    zeerust_z80_free(z80);
+}
+
+int main(void) {
+    hello_world();
+    echo();
+    registers();
+    memory();
+    null_handles();
+    return failures == 0 ? 0 : 1;
+}
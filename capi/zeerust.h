@@ -0,0 +1,170 @@
+#ifndef ZEERUST_H
+#define ZEERUST_H
+
+/* Generated by cbindgen from src/lib.rs: don't edit this file by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The registers which can be read and written with `zeerust_z80_get_reg` and `zeerust_z80_set_reg`
+ */
+typedef enum {
+  ZEERUST_REG_A,
+  ZEERUST_REG_F,
+  ZEERUST_REG_B,
+  ZEERUST_REG_C,
+  ZEERUST_REG_D,
+  ZEERUST_REG_E,
+  ZEERUST_REG_H,
+  ZEERUST_REG_L,
+  ZEERUST_REG_AF,
+  ZEERUST_REG_BC,
+  ZEERUST_REG_DE,
+  ZEERUST_REG_HL,
+  ZEERUST_REG_IX,
+  ZEERUST_REG_IY,
+  ZEERUST_REG_SP,
+  ZEERUST_REG_PC,
+} zeerust_reg;
+
+/**
+ * A Z80, with no devices installed
+ */
+typedef struct zeerust_z80 zeerust_z80;
+
+/**
+ * Called for each `IN`, with the full port address. Returns the byte read.
+ */
+typedef uint8_t (*zeerust_input_callback)(void *data, uint16_t port);
+
+/**
+ * Called for each `OUT`, with the full port address and the byte written
+ */
+typedef void (*zeerust_output_callback)(void *data, uint16_t port, uint8_t val);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Create a Z80, with all registers and memory zeroed
+ */
+zeerust_z80 *zeerust_z80_new(void);
+
+/**
+ * Free a Z80 created with `zeerust_z80_new`. Freeing NULL does nothing.
+ */
+void zeerust_z80_free(zeerust_z80 *z80);
+
+/**
+ * Copy `len` bytes into memory, starting at `addr`.
+ * Anything that would extend past the end of memory is ignored.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`, and `bytes` must point to at
+ * least `len` readable bytes.
+ */
+void zeerust_z80_load(zeerust_z80 *z80, uint16_t addr, const uint8_t *bytes, size_t len);
+
+/**
+ * Execute a single instruction
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+void zeerust_z80_step(zeerust_z80 *z80);
+
+/**
+ * Execute instructions until a HALT, or until `limit` have been executed.
+ * Returns whether the Z80 halted, or false for a NULL handle.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+bool zeerust_z80_run(zeerust_z80 *z80, uint64_t limit);
+
+/**
+ * Whether the Z80 has halted, or false for a NULL handle
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+bool zeerust_z80_halted(const zeerust_z80 *z80);
+
+/**
+ * The number of instructions executed, or 0 for a NULL handle
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+uint64_t zeerust_z80_instructions(const zeerust_z80 *z80);
+
+/**
+ * Read a register, a `zeerust_reg`. 8-bit registers are returned in the lower byte.
+ * Returns -1 for a NULL handle or an unknown register.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+int32_t zeerust_z80_get_reg(const zeerust_z80 *z80, uint32_t reg);
+
+/**
+ * Write a register, a `zeerust_reg`. Only the lower byte of `val` is used for 8-bit registers.
+ * Returns false for a NULL handle or an unknown register.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`.
+ */
+bool zeerust_z80_set_reg(zeerust_z80 *z80, uint32_t reg, uint16_t val);
+
+/**
+ * Copy `len` bytes of memory from `addr` into `out`,
+ * wrapping around from the top of memory to the bottom.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`, and `out` must point to at
+ * least `len` writable bytes.
+ */
+void zeerust_z80_read_memory(const zeerust_z80 *z80, uint16_t addr, uint8_t *out, size_t len);
+
+/**
+ * Copy `len` bytes from `bytes` into memory at `addr`,
+ * wrapping around from the top of memory to the bottom.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`, and `bytes` must point to at
+ * least `len` readable bytes.
+ */
+void zeerust_z80_write_memory(zeerust_z80 *z80, uint16_t addr, const uint8_t *bytes, size_t len);
+
+/**
+ * Call `callback(data, port)` for every read from a port whose lower byte is `port`.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`, and `callback` must not be NULL.
+ */
+void zeerust_z80_install_input(zeerust_z80 *z80,
+                               uint8_t port,
+                               zeerust_input_callback callback,
+                               void *data);
+
+/**
+ * Call `callback(data, port, val)` for every write to a port whose lower byte is `port`.
+ *
+ * # Safety
+ * `z80` must be NULL or a live handle from `zeerust_z80_new`, and `callback` must not be NULL.
+ */
+void zeerust_z80_install_output(zeerust_z80 *z80,
+                                uint8_t port,
+                                zeerust_output_callback callback,
+                                void *data);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ZEERUST_H */
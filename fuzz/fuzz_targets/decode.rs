@@ -0,0 +1,9 @@
+//! Every sequence of four bytes decodes to an instruction of one to four bytes
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zeerust::cpu::opcodes::opcode;
+
+fuzz_target!(|code: [u8; 4]| {
+    let (_, len) = opcode(code);
+    assert!((1..=4).contains(&len));
+});
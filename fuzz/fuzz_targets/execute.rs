@@ -0,0 +1,36 @@
+//! Any program runs for a bounded number of steps without panicking.
+//! Every port has a device attached, so that I/O goes through the device adapters.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zeerust::z80::io::{InputDevice, OutputDevice};
+use zeerust::z80::Z80;
+
+const MAX_STEPS: usize = 1_000;
+
+/// A device that reads as the port number, and discards anything written
+struct Port(u8);
+
+impl InputDevice for Port {
+    fn input(&self) -> u8 {
+        self.0
+    }
+}
+
+impl OutputDevice for Port {
+    fn output(&self, _: u8) {}
+}
+
+fuzz_target!(|program: &[u8]| {
+    let mut z80 = Z80::default();
+    for port in 0..=0xFF {
+        z80.install_input(port, Box::new(Port(port)));
+        z80.install_output(port, Box::new(Port(port)));
+    }
+    z80.load(program);
+    for _ in 0..MAX_STEPS {
+        if z80.is_halted() {
+            break;
+        }
+        z80.step();
+    }
+});
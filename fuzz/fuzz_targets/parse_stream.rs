@@ -0,0 +1,9 @@
+//! Any stream of bytes disassembles, with no more instructions than bytes
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use zeerust::cpu::opcodes::parse_stream;
+
+fuzz_target!(|data: &[u8]| {
+    let ops = parse_stream(data.to_vec());
+    assert!(ops.len() <= data.len());
+});
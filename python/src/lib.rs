@@ -0,0 +1,433 @@
+//! Python bindings for zeerust, as the `zeerust` extension module.
+//!
+//! Build it with `maturin develop`, then run the tests with `pytest`.
+//! Device callbacks run in the middle of an instruction, so they mustn't touch the `Z80` they're
+//! installed in: an exception raised by one is re-raised once the instruction has finished.
+
+// The code pyo3 generates for functions returning `PyResult` trips this
+#![allow(clippy::useless_conversion)]
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use pyo3::exceptions::{PyIndexError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PySlice};
+
+use zeerust::cpu::mem::MEMORY_SIZE;
+use zeerust::examples::{Example, EXAMPLES};
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::io::{InputDevice, OutputDevice};
+
+/// The first exception raised by a device callback since it was last checked
+type Pending = Arc<Mutex<Option<PyErr>>>;
+
+fn pending(error: &Pending, result: PyResult<()>) {
+    if let Err(e) = result {
+        error.lock().unwrap().get_or_insert(e);
+    }
+}
+
+/// An InputDevice which calls a Python function for each byte
+struct PyInput {
+    callback: PyObject,
+    error: Pending,
+}
+
+impl InputDevice for PyInput {
+    fn input(&self) -> u8 {
+        Python::with_gil(|py| {
+            let mut val = 0;
+            let result = self
+                .callback
+                .call0(py)
+                .and_then(|v| v.extract(py))
+                .map(|v| val = v);
+            pending(&self.error, result);
+            val
+        })
+    }
+}
+
+/// An OutputDevice which passes each byte to a Python function
+struct PyOutput {
+    callback: PyObject,
+    error: Pending,
+}
+
+impl OutputDevice for PyOutput {
+    fn output(&self, val: u8) {
+        Python::with_gil(|py| {
+            let result = self.callback.call1(py, (val,)).map(drop);
+            pending(&self.error, result)
+        })
+    }
+}
+
+/// A Z80 with no devices installed
+#[pyclass(name = "Z80", module = "zeerust")]
+struct Machine {
+    z80: zeerust::z80::Z80,
+    error: Pending,
+}
+
+impl Machine {
+    fn step(&mut self) -> PyResult<()> {
+        self.z80.step();
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Step until `stop` returns true, the Z80 halts, or `limit` instructions have been executed
+    fn step_while(&mut self, limit: Option<u64>, stop: impl Fn(&Self) -> bool) -> PyResult<bool> {
+        let end = limit.map_or(u64::MAX, |l| self.z80.instructions().saturating_add(l));
+        while !self.z80.is_halted() && self.z80.instructions() < end {
+            self.step()?;
+            if stop(self) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn reg8(&self, r: Reg8) -> u8 {
+        self.z80.registers.get_reg8(r)
+    }
+
+    fn reg16(&self, r: Reg16) -> u16 {
+        self.z80.registers.get_reg16(&r)
+    }
+}
+
+#[pymethods]
+impl Machine {
+    #[new]
+    fn new() -> Self {
+        Machine {
+            z80: zeerust::z80::Z80::default(),
+            error: Pending::default(),
+        }
+    }
+
+    /// Load a program into memory, starting at `addr`
+    #[pyo3(signature = (program, addr = 0))]
+    fn load(&mut self, program: &[u8], addr: u16) {
+        self.z80.load_at(addr, program);
+    }
+
+    /// Execute a single instruction
+    #[pyo3(name = "step")]
+    fn py_step(&mut self) -> PyResult<()> {
+        self.step()
+    }
+
+    /// Execute instructions until a HALT, or until `limit` have been executed
+    #[pyo3(signature = (limit = None))]
+    fn run(&mut self, limit: Option<u64>) -> PyResult<()> {
+        self.step_while(limit, |_| false).map(drop)
+    }
+
+    /// Execute instructions until the program counter reaches `pc`, returning false if the Z80
+    /// halted or `limit` instructions were executed first
+    #[pyo3(signature = (pc, limit = None))]
+    fn run_until(&mut self, pc: u16, limit: Option<u64>) -> PyResult<bool> {
+        self.step_while(limit, |m| m.z80.registers.get_pc() == pc)
+    }
+
+    /// Call `callback()` for every read from `port`. It should return a byte.
+    fn install_input(&mut self, port: u8, callback: PyObject) {
+        let error = self.error.clone();
+        let device = PyInput { callback, error };
+        self.z80.install_input(port, Box::new(device));
+    }
+
+    /// Call `callback(byte)` for every write to `port`
+    fn install_output(&mut self, port: u8, callback: PyObject) {
+        let error = self.error.clone();
+        let device = PyOutput { callback, error };
+        self.z80.install_output(port, Box::new(device));
+    }
+
+    #[getter]
+    fn halted(&self) -> bool {
+        self.z80.is_halted()
+    }
+
+    /// The number of instructions executed
+    #[getter]
+    fn instructions(&self) -> u64 {
+        self.z80.instructions()
+    }
+
+    /// All 64KiB of memory, which can be indexed and sliced like a `bytearray`
+    #[getter]
+    fn memory(slf: Bound<'_, Self>) -> Memory {
+        Memory { z80: slf.unbind() }
+    }
+
+    #[getter]
+    fn a(&self) -> u8 {
+        self.reg8(Reg8::A)
+    }
+
+    #[setter]
+    fn set_a(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::A, v)
+    }
+
+    #[getter]
+    fn f(&self) -> u8 {
+        self.reg8(Reg8::F)
+    }
+
+    #[setter]
+    fn set_f(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::F, v)
+    }
+
+    #[getter]
+    fn b(&self) -> u8 {
+        self.reg8(Reg8::B)
+    }
+
+    #[setter]
+    fn set_b(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::B, v)
+    }
+
+    #[getter]
+    fn c(&self) -> u8 {
+        self.reg8(Reg8::C)
+    }
+
+    #[setter]
+    fn set_c(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::C, v)
+    }
+
+    #[getter]
+    fn d(&self) -> u8 {
+        self.reg8(Reg8::D)
+    }
+
+    #[setter]
+    fn set_d(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::D, v)
+    }
+
+    #[getter]
+    fn e(&self) -> u8 {
+        self.reg8(Reg8::E)
+    }
+
+    #[setter]
+    fn set_e(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::E, v)
+    }
+
+    #[getter]
+    fn h(&self) -> u8 {
+        self.reg8(Reg8::H)
+    }
+
+    #[setter]
+    fn set_h(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::H, v)
+    }
+
+    #[getter]
+    fn l(&self) -> u8 {
+        self.reg8(Reg8::L)
+    }
+
+    #[setter]
+    fn set_l(&mut self, v: u8) {
+        self.z80.registers.set_reg8(Reg8::L, v)
+    }
+
+    #[getter]
+    fn af(&self) -> u16 {
+        self.reg16(Reg16::AF)
+    }
+
+    #[setter]
+    fn set_af(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::AF, v)
+    }
+
+    #[getter]
+    fn bc(&self) -> u16 {
+        self.reg16(Reg16::BC)
+    }
+
+    #[setter]
+    fn set_bc(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::BC, v)
+    }
+
+    #[getter]
+    fn de(&self) -> u16 {
+        self.reg16(Reg16::DE)
+    }
+
+    #[setter]
+    fn set_de(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::DE, v)
+    }
+
+    #[getter]
+    fn hl(&self) -> u16 {
+        self.reg16(Reg16::HL)
+    }
+
+    #[setter]
+    fn set_hl(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::HL, v)
+    }
+
+    #[getter]
+    fn ix(&self) -> u16 {
+        self.reg16(Reg16::IX)
+    }
+
+    #[setter]
+    fn set_ix(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::IX, v)
+    }
+
+    #[getter]
+    fn iy(&self) -> u16 {
+        self.reg16(Reg16::IY)
+    }
+
+    #[setter]
+    fn set_iy(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::IY, v)
+    }
+
+    #[getter]
+    fn sp(&self) -> u16 {
+        self.reg16(Reg16::SP)
+    }
+
+    #[setter]
+    fn set_sp(&mut self, v: u16) {
+        self.z80.registers.set_reg16(&Reg16::SP, v)
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.z80.registers.get_pc()
+    }
+
+    #[setter]
+    fn set_pc(&mut self, v: u16) {
+        self.z80.registers.set_pc(v)
+    }
+}
+
+/// A Z80's memory. Writes go through `load_at`, so instructions already decoded are
+/// decoded again
+#[pyclass(module = "zeerust")]
+struct Memory {
+    z80: Py<Machine>,
+}
+
+#[pymethods]
+impl Memory {
+    fn __len__(&self) -> usize {
+        MEMORY_SIZE
+    }
+
+    /// A byte, or `bytes` for a slice
+    fn __getitem__(&self, py: Python<'_>, index: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let machine = self.z80.try_borrow(py)?;
+        let memory = machine.z80.memory();
+        Ok(match index.downcast::<PySlice>() {
+            Ok(slice) => {
+                let bytes: Vec<u8> = slice_addresses(slice)?.map(|a| memory[a]).collect();
+                PyBytes::new_bound(py, &bytes).into_py(py)
+            }
+            Err(_) => memory[address(index)?].into_py(py),
+        })
+    }
+
+    /// Set a byte, or a slice to bytes of the same length
+    fn __setitem__(
+        &self,
+        py: Python<'_>,
+        index: &Bound<'_, PyAny>,
+        value: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let mut machine = self.z80.try_borrow_mut(py)?;
+        match index.downcast::<PySlice>() {
+            Ok(slice) => {
+                let addresses: Vec<usize> = slice_addresses(slice)?.collect();
+                let bytes: Vec<u8> = value.extract()?;
+                if bytes.len() != addresses.len() {
+                    return Err(PyValueError::new_err("memory slices can't be resized"));
+                }
+                for (a, b) in addresses.into_iter().zip(bytes) {
+                    machine.z80.load_at(a as u16, &[b]);
+                }
+            }
+            Err(_) => {
+                let a = address(index)?;
+                machine.z80.load_at(a as u16, &[value.extract()?]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An index into memory, counting back from the end if it's negative
+fn address(index: &Bound<'_, PyAny>) -> PyResult<usize> {
+    let i: isize = index.extract()?;
+    let i = if i < 0 { i + MEMORY_SIZE as isize } else { i };
+    usize::try_from(i)
+        .ok()
+        .filter(|&i| i < MEMORY_SIZE)
+        .ok_or_else(|| PyIndexError::new_err("memory index out of range"))
+}
+
+/// The addresses a slice of memory covers
+fn slice_addresses(slice: &Bound<'_, PySlice>) -> PyResult<impl Iterator<Item = usize>> {
+    let indices = slice.indices(MEMORY_SIZE as isize)?;
+    Ok((0..indices.slicelength).map(move |n| (indices.start + n as isize * indices.step) as usize))
+}
+
+fn example(name: &str) -> PyResult<&'static Example> {
+    EXAMPLES
+        .iter()
+        .find(|e| e.name == name)
+        .ok_or_else(|| PyKeyError::new_err(name.to_string()))
+}
+
+/// The names of the bundled examples
+#[pyfunction]
+fn examples() -> Vec<&'static str> {
+    EXAMPLES.iter().map(|e| e.name).collect()
+}
+
+/// The assembly source of a bundled example
+#[pyfunction]
+fn example_assembly(name: &str) -> PyResult<&'static str> {
+    example(name).map(|e| e.assembly)
+}
+
+/// The machine code of a bundled example
+#[pyfunction]
+fn example_binary<'py>(py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyBytes>> {
+    example(name).map(|e| PyBytes::new_bound(py, e.binary))
+}
+
+#[pymodule]
+#[pyo3(name = "zeerust")]
+fn zeerust_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Machine>()?;
+    m.add_function(wrap_pyfunction!(examples, m)?)?;
+    m.add_function(wrap_pyfunction!(example_assembly, m)?)?;
+    m.add_function(wrap_pyfunction!(example_binary, m)?)?;
+    Ok(())
+}
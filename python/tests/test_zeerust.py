@@ -0,0 +1,108 @@
+import pytest
+
+import zeerust
+
+EXPECTED_OUTPUT = {
+    "fizzbuzz": "\n".join(
+        ["01", "02", "Fizz", "04", "Buzz", "Fizz", "07", "08", "Fizz", "Buzz"]
+        + ["11", "Fizz", "13", "14", "FizzBuzz", "16", "17", "Fizz", "19", "Buzz\n"]
+    ).encode(),
+    "hello zeerust": b"ZEERUST",
+    "hello world": b"Hello World\n",
+    "countdown": b"9\n8\n7\n6\n5\n4\n3\n2\n1\n",
+}
+
+
+def machine(program):
+    """A Z80 with `program` loaded, which records its output on port 0"""
+    z80 = zeerust.Z80()
+    output = bytearray()
+    z80.install_output(0, output.append)
+    z80.load(program)
+    return z80, output
+
+
+def test_lists_examples():
+    assert sorted(zeerust.examples()) == sorted(EXPECTED_OUTPUT)
+    assert "halt" in zeerust.example_assembly("countdown")
+    with pytest.raises(KeyError):
+        zeerust.example_binary("missing")
+
+
+@pytest.mark.parametrize("name", sorted(EXPECTED_OUTPUT))
+def test_examples(name):
+    z80, output = machine(zeerust.example_binary(name))
+    z80.run()
+    assert z80.halted
+    assert bytes(output) == EXPECTED_OUTPUT[name]
+
+
+def test_run_limit():
+    z80, output = machine(zeerust.example_binary("fizzbuzz"))
+    z80.run(limit=10)
+    assert z80.instructions == 10
+    assert not z80.halted
+
+
+def test_step_and_registers():
+    # LD A, 0x2A; LD HL, 0x1234; HALT
+    z80, _ = machine(bytes([0x3E, 0x2A, 0x21, 0x34, 0x12, 0x76]))
+    z80.step()
+    assert z80.a == 0x2A
+    assert z80.pc == 0x0002
+    z80.step()
+    assert (z80.h, z80.l, z80.hl) == (0x12, 0x34, 0x1234)
+
+    z80.bc = 0xBEEF
+    assert (z80.b, z80.c) == (0xBE, 0xEF)
+    with pytest.raises(OverflowError):
+        z80.a = 0x100
+
+
+def test_run_until():
+    # INC A; INC A; INC A; HALT
+    z80, _ = machine(bytes([0x3C, 0x3C, 0x3C, 0x76]))
+    assert z80.run_until(0x0002)
+    assert (z80.pc, z80.a) == (0x0002, 2)
+    assert not z80.run_until(0x1000)
+    assert z80.halted
+
+
+def test_input_callback():
+    # IN A, (1); OUT (0), A; IN A, (1); OUT (0), A; HALT
+    z80, output = machine(bytes([0xDB, 0x01, 0xD3, 0x00] * 2 + [0x76]))
+    inputs = iter(b"hi")
+    z80.install_input(1, lambda: next(inputs))
+    z80.run()
+    assert bytes(output) == b"hi"
+
+
+def test_callback_exceptions():
+    # IN A, (1); HALT
+    z80, _ = machine(bytes([0xDB, 0x01, 0x76]))
+
+    def broken():
+        raise ValueError("no input")
+
+    z80.install_input(1, broken)
+    with pytest.raises(ValueError, match="no input"):
+        z80.run()
+    assert z80.pc == 0x0002
+
+
+def test_memory():
+    z80, _ = machine(bytes([0x3E, 0x01, 0x76]))  # LD A, 1; HALT
+    memory = z80.memory
+    assert len(memory) == 0x10000
+    assert memory[0:3] == bytes([0x3E, 0x01, 0x76])
+
+    z80.step()
+    assert z80.a == 0x01
+    # Patch the instruction that has just been executed, and execute it again
+    memory[1] = 0x05
+    z80.pc = 0x0000
+    z80.step()
+    assert z80.a == 0x05
+
+    z80.memory[0x8000:0x8002] = b"\xAB\xCD"
+    assert bytes(z80.memory[0x8000:0x8002]) == b"\xAB\xCD"
@@ -0,0 +1,716 @@
+//! A CP/M 2.2 environment, for running `.COM` programs.
+//!
+//! Programs are loaded at 0x0100, the start of the Transient Program Area.
+//! The zero page holds a jump to the BIOS warm boot routine at 0x0000, and a jump to the BDOS
+//! at 0x0005, whose address also marks the top of the TPA.
+//! No BIOS or BDOS code is run: when the program counter reaches 0x0005 the BDOS function in C
+//! is carried out on the host, and execution returns to the caller.
+//! Reaching 0x0000, calling BDOS function 0 or executing a HALT ends the program.
+//!
+//! Console functions read from and write to any `Read` and `Write`.
+//! Input isn't echoed, since a host terminal will already have done so.
+//! File functions operate on a host directory, which stands in for every drive.
+//! Host files whose names don't fit CP/M's 8.3 format are invisible.
+//!
+//! Supported BDOS functions are:
+//!
+//! * 0 (system reset), 1 (console input), 2 (console output), 6 (direct console I/O),
+//!   9 (print string), 10 (read console buffer), 11 (console status) and 12 (version number)
+//! * 13 (reset disk system), 14 (select disk), 25 (current disk) and 26 (set DMA address)
+//! * 15 (open), 16 (close), 17 and 18 (search), 19 (delete), 20 and 21 (sequential read and write),
+//!   22 (make), 23 (rename), 33 and 34 (random read and write), 35 (file size) and
+//!   36 (set random record)
+//!
+//! Other functions are logged and return 0xFF.
+//!
+//! A string printed by function 9 that has no `$` stops at the top of the TPA, rather than
+//! running on through the BDOS. Making or renaming to an ambiguous name, containing `?`,
+//! fails with 0xFF.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+
+/// Where the warm boot jump lives. Jumping here ends the program.
+pub const WBOOT: u16 = 0x0000;
+/// The entry point for BDOS calls
+pub const BDOS: u16 = 0x0005;
+/// Where programs are loaded and started
+pub const TPA: u16 = 0x0100;
+
+/// The (notional) BDOS and BIOS. Only a `RET` at the start of each is actually present.
+const BDOS_BASE: u16 = 0xFE06;
+const BIOS_BASE: u16 = 0xFF00;
+
+/// The default File Control Blocks, filled in from the command line
+const FCB1: u16 = 0x005C;
+const FCB2: u16 = 0x006C;
+/// The default DMA address, which also holds the command tail
+const DEFAULT_DMA: u16 = 0x0080;
+
+const RECORD_SIZE: usize = 128;
+/// The number of records in a logical extent
+const EXTENT_RECORDS: u32 = 128;
+/// Files are padded to a whole record with this byte, ^Z
+const EOF: u8 = 0x1A;
+
+/// A CP/M filename in FCB format: 8 bytes of name, then 3 of type, padded with spaces.
+/// A `?` in a pattern matches any character.
+type Name = [u8; 11];
+
+/// Runs CP/M programs on a `Z80`.
+/// ```no_run
+/// use zeerust::cpm::Cpm;
+/// use zeerust::z80::Z80;
+///
+/// let program = std::fs::read("zexdoc.com").unwrap();
+/// let mut z80 = Z80::default();
+/// let mut cpm = Cpm::new(std::io::stdin(), std::io::stdout(), ".");
+/// cpm.load(&mut z80, &program, &[]);
+/// cpm.run(&mut z80).unwrap();
+/// ```
+pub struct Cpm<R: Read, W: Write> {
+    input: R,
+    output: W,
+    dir: PathBuf,
+    dma: u16,
+    /// Files still to be returned by "search for next"
+    search: Vec<Name>,
+}
+
+impl<R: Read, W: Write> Cpm<R, W> {
+    /// Create a CP/M environment using the given console, and storing files in `dir`
+    pub fn new<P: AsRef<Path>>(input: R, output: W, dir: P) -> Self {
+        Self {
+            input,
+            output,
+            dir: dir.as_ref().to_path_buf(),
+            dma: DEFAULT_DMA,
+            search: vec![],
+        }
+    }
+
+    /// The console output
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Set up the zero page, load a program into the TPA and point the program counter at it.
+    /// The arguments form the command tail, and the first two are parsed into the default FCBs.
+    pub fn load(&mut self, z80: &mut Z80, program: &[u8], args: &[&str]) {
+        let [bios_lo, bios_hi] = (BIOS_BASE + 3).to_le_bytes();
+        let [bdos_lo, bdos_hi] = BDOS_BASE.to_le_bytes();
+        z80.load_at(WBOOT, &[0xC3, bios_lo, bios_hi]); // JP WBOOT
+        z80.load_at(BDOS, &[0xC3, bdos_lo, bdos_hi]); // JP BDOS
+        z80.load_at(BDOS_BASE, &[0xC9]); // RET
+        z80.load_at(BIOS_BASE + 3, &[0x76]); // HALT
+
+        for (fcb, arg) in [FCB1, FCB2].iter().zip(args.iter().chain(&["", ""])) {
+            let mut block = [0; 16];
+            block[1..12].copy_from_slice(&parse_name(arg));
+            z80.load_at(*fcb, &block);
+        }
+        let mut tail = String::new();
+        for arg in args {
+            tail.push(' ');
+            tail.push_str(&arg.to_ascii_uppercase());
+        }
+        let mut tail = tail.into_bytes();
+        tail.truncate(RECORD_SIZE - 2);
+        tail.insert(0, tail.len() as u8);
+        tail.push(0);
+        z80.load_at(DEFAULT_DMA, &tail);
+
+        z80.load_at(TPA, program);
+        z80.registers.set_pc(TPA);
+        // Returning from the program goes to the warm boot vector
+        z80.load_at(BDOS_BASE - 2, &WBOOT.to_le_bytes());
+        z80.registers.set_reg16(&Reg16::SP, BDOS_BASE - 2);
+        self.dma = DEFAULT_DMA;
+    }
+
+    /// Run the loaded program until it exits.
+    /// Errors are only returned if the console fails: file errors are reported to the program.
+    pub fn run(&mut self, z80: &mut Z80) -> io::Result<()> {
+        let result = self.run_inner(z80);
+        self.output.flush()?;
+        result
+    }
+
+    fn run_inner(&mut self, z80: &mut Z80) -> io::Result<()> {
+        while !z80.is_halted() {
+            match z80.registers.get_pc() {
+                WBOOT => break,
+                BDOS if !self.bdos(z80)? => break,
+                _ => (),
+            }
+            z80.step();
+        }
+        Ok(())
+    }
+
+    /// Carry out the BDOS function requested in C.
+    /// Returns false if the program should exit.
+    fn bdos(&mut self, z80: &mut Z80) -> io::Result<bool> {
+        let function = z80.registers.get_reg8(Reg8::C);
+        let e = z80.registers.get_reg8(Reg8::E);
+        let de = z80.registers.get_reg16(&Reg16::DE);
+        let result: u16 = match function {
+            0 => return Ok(false),
+            1 => self.read_char()?.unwrap_or(EOF).into(),
+            2 => self.write(&[e])?,
+            6 => match e {
+                0xFF => self.read_char()?.unwrap_or(0).into(),
+                // Input can't be checked without blocking, so always report it as ready
+                0xFE => 0xFF,
+                _ => self.write(&[e])?,
+            },
+            9 => {
+                let mem = z80
+                    .memory()
+                    .get(de as usize..BDOS_BASE as usize)
+                    .unwrap_or_default();
+                let end = mem.iter().position(|b| *b == b'$').unwrap_or(mem.len());
+                self.write(&mem[..end])?
+            }
+            10 => self.read_line(z80, de)?,
+            11 => 0xFF,
+            12 => 0x0022,
+            13 => {
+                self.dma = DEFAULT_DMA;
+                0
+            }
+            14 | 25 => 0,
+            26 => {
+                self.dma = de;
+                0
+            }
+            15..=23 | 33..=36 => self.file(z80, function, de),
+            _ => {
+                log::warn!("unsupported BDOS function {}", function);
+                0xFF
+            }
+        };
+        let [lo, hi] = result.to_le_bytes();
+        z80.registers.set_reg16(&Reg16::HL, result);
+        z80.registers.set_reg8(Reg8::A, lo);
+        z80.registers.set_reg8(Reg8::B, hi);
+        Ok(true)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<u16> {
+        self.output.write_all(bytes)?;
+        Ok(0)
+    }
+
+    /// Read a single byte of console input, or None at the end of input
+    fn read_char(&mut self) -> io::Result<Option<u8>> {
+        self.output.flush()?;
+        let mut buf = [0];
+        loop {
+            return match self.input.read(&mut buf) {
+                Ok(0) => Ok(None),
+                Ok(_) => Ok(Some(buf[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+        }
+    }
+
+    /// Read a line into the console buffer at `addr`.
+    /// The first byte of the buffer is its capacity; the second is set to the length read.
+    fn read_line(&mut self, z80: &mut Z80, addr: u16) -> io::Result<u16> {
+        let capacity = z80.memory()[addr as usize] as usize;
+        let mut line = vec![];
+        while let Some(c) = self.read_char()? {
+            match c {
+                b'\n' => break,
+                b'\r' => (),
+                _ if line.len() < capacity => line.push(c),
+                _ => (),
+            }
+        }
+        line.insert(0, line.len() as u8);
+        z80.load_at(addr.wrapping_add(1), &line);
+        Ok(0)
+    }
+
+    /// Carry out a file function on the FCB at `fcb`, returning the result code
+    fn file(&mut self, z80: &mut Z80, function: u8, fcb: u16) -> u16 {
+        let name = fcb_name(z80, fcb);
+        let result = match function {
+            15 => self.open(z80, fcb, &name),
+            16 => Ok(0),
+            17 => self.search_first(z80, &name),
+            18 => Ok(self.search_next(z80)),
+            19 => self.delete(&name),
+            20 => self.read_sequential(z80, fcb, &name),
+            21 => self.write_sequential(z80, fcb, &name),
+            22 => self.make(&name),
+            23 => self.rename(&name, &fcb_name(z80, fcb.wrapping_add(16))),
+            33 => self.read_record(z80, &name, random_record(z80, fcb)),
+            34 => self.write_record(z80, &name, random_record(z80, fcb)),
+            35 => self.file_size(z80, fcb, &name),
+            36 => {
+                set_random_record(z80, fcb, sequential_record(z80, fcb));
+                Ok(0)
+            }
+            _ => unreachable!("not a file function: {}", function),
+        };
+        result.unwrap_or_else(|e| {
+            log::warn!("BDOS function {} failed: {}", function, e);
+            0xFF
+        })
+    }
+
+    fn open(&mut self, z80: &mut Z80, fcb: u16, name: &Name) -> io::Result<u16> {
+        if self.find(name)?.is_empty() {
+            return Ok(0xFF);
+        }
+        // Start reading from the beginning of the requested extent
+        z80.load_at(fcb.wrapping_add(32), &[0]);
+        Ok(0)
+    }
+
+    fn search_first(&mut self, z80: &mut Z80, pattern: &Name) -> io::Result<u16> {
+        self.search = self.find(pattern)?.into_iter().map(|(n, _)| n).collect();
+        self.search.reverse();
+        Ok(self.search_next(z80))
+    }
+
+    /// Write the next matching directory entry to the first slot of the DMA buffer
+    fn search_next(&mut self, z80: &mut Z80) -> u16 {
+        match self.search.pop() {
+            Some(name) => {
+                let mut entry = [0; 32];
+                entry[1..12].copy_from_slice(&name);
+                z80.load_at(self.dma, &entry);
+                0
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn delete(&mut self, pattern: &Name) -> io::Result<u16> {
+        let files = self.find(pattern)?;
+        for (_, path) in &files {
+            fs::remove_file(path)?;
+        }
+        Ok(if files.is_empty() { 0xFF } else { 0 })
+    }
+
+    fn make(&mut self, name: &Name) -> io::Result<u16> {
+        let new = match host_name(name) {
+            Some(new) => self.dir.join(new),
+            None => return Ok(0xFF),
+        };
+        for (_, path) in self.find(name)? {
+            fs::remove_file(path)?;
+        }
+        File::create(new)?;
+        Ok(0)
+    }
+
+    fn rename(&mut self, from: &Name, to: &Name) -> io::Result<u16> {
+        let new = match host_name(to) {
+            Some(new) => self.dir.join(new),
+            None => return Ok(0xFF),
+        };
+        match self.find(from)?.first() {
+            Some((_, path)) => {
+                fs::rename(path, new)?;
+                Ok(0)
+            }
+            None => Ok(0xFF),
+        }
+    }
+
+    fn read_sequential(&mut self, z80: &mut Z80, fcb: u16, name: &Name) -> io::Result<u16> {
+        let record = sequential_record(z80, fcb);
+        let result = self.read_record(z80, name, record)?;
+        if result == 0 {
+            set_sequential_record(z80, fcb, record + 1);
+        }
+        Ok(result)
+    }
+
+    fn write_sequential(&mut self, z80: &mut Z80, fcb: u16, name: &Name) -> io::Result<u16> {
+        let record = sequential_record(z80, fcb);
+        let result = self.write_record(z80, name, record)?;
+        if result == 0 {
+            set_sequential_record(z80, fcb, record + 1);
+        }
+        Ok(result)
+    }
+
+    /// Read a record into the DMA buffer, returning 1 at the end of the file
+    fn read_record(&mut self, z80: &mut Z80, name: &Name, record: u32) -> io::Result<u16> {
+        let path = match self.find(name)?.into_iter().next() {
+            Some((_, path)) => path,
+            None => return Ok(0xFF),
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64))?;
+        let mut buf = vec![];
+        file.take(RECORD_SIZE as u64).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            return Ok(1);
+        }
+        buf.resize(RECORD_SIZE, EOF);
+        z80.load_at(self.dma, &buf);
+        Ok(0)
+    }
+
+    /// Write the DMA buffer to a record of the file
+    fn write_record(&mut self, z80: &mut Z80, name: &Name, record: u32) -> io::Result<u16> {
+        let path = match self.find(name)?.into_iter().next() {
+            Some((_, path)) => path,
+            None => return Ok(0xFF),
+        };
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(record as u64 * RECORD_SIZE as u64))?;
+        let start = self.dma as usize;
+        let mem = z80.memory();
+        match mem.get(start..start + RECORD_SIZE) {
+            Some(buf) => file.write_all(buf)?,
+            None => return Ok(0xFF),
+        }
+        Ok(0)
+    }
+
+    /// Set the random record field to the number of records in the file
+    fn file_size(&mut self, z80: &mut Z80, fcb: u16, name: &Name) -> io::Result<u16> {
+        match self.find(name)?.first() {
+            Some((_, path)) => {
+                let size = fs::metadata(path)?.len();
+                let records = size.div_ceil(RECORD_SIZE as u64);
+                set_random_record(z80, fcb, records as u32);
+                Ok(0)
+            }
+            None => Ok(0xFF),
+        }
+    }
+
+    /// The files in the directory matching a pattern, sorted by name
+    fn find(&self, pattern: &Name) -> io::Result<Vec<(Name, PathBuf)>> {
+        let mut found = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = match entry.file_name().to_str().and_then(cpm_name) {
+                Some(name) => name,
+                None => continue,
+            };
+            if pattern.iter().zip(&name).all(|(p, n)| *p == b'?' || p == n) {
+                found.push((name, entry.path()));
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+}
+
+/// Parse a command-line filename like `B:FOO.TXT` into FCB format, expanding `*` wildcards
+fn parse_name(arg: &str) -> Name {
+    let arg = arg.to_ascii_uppercase();
+    let arg = match arg.find(':') {
+        Some(i) => &arg[i + 1..],
+        None => &arg[..],
+    };
+    let (name, ext) = match arg.rfind('.') {
+        Some(i) => (&arg[..i], &arg[i + 1..]),
+        None => (arg, ""),
+    };
+    let mut fcb = [b' '; 11];
+    let (name_field, ext_field) = fcb.split_at_mut(8);
+    for (field, part) in [(name_field, name), (ext_field, ext)] {
+        for (i, c) in part.bytes().take(field.len()).enumerate() {
+            if c == b'*' {
+                field[i..].iter_mut().for_each(|b| *b = b'?');
+                break;
+            }
+            field[i] = c;
+        }
+    }
+    fcb
+}
+
+/// The name of a host file in FCB format, if it is a valid 8.3 filename
+fn cpm_name(host: &str) -> Option<Name> {
+    let (name, ext) = match host.rfind('.') {
+        Some(i) => (&host[..i], &host[i + 1..]),
+        None => (host, ""),
+    };
+    let valid = |s: &str, len| {
+        s.len() <= len
+            && s.bytes()
+                .all(|b| b.is_ascii_graphic() && !b"?*.:".contains(&b))
+    };
+    if name.is_empty() || !valid(name, 8) || !valid(ext, 3) {
+        return None;
+    }
+    let mut fcb = [b' '; 11];
+    fcb[..name.len()].copy_from_slice(name.to_ascii_uppercase().as_bytes());
+    fcb[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some(fcb)
+}
+
+/// The host filename for a new file, or `None` if the name is ambiguous or could refer to
+/// something outside the directory
+fn host_name(name: &Name) -> Option<String> {
+    if name
+        .iter()
+        .any(|&b| matches!(b, b'?' | b'/' | b'\\' | b':' | b'.') || b.is_ascii_control())
+    {
+        return None;
+    }
+    let part = |b: &[u8]| String::from_utf8_lossy(b).trim_end().to_ascii_lowercase();
+    let (name, ext) = (part(&name[..8]), part(&name[8..]));
+    Some(if ext.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, ext)
+    })
+}
+
+/// The filename in an FCB, ignoring attribute bits
+fn fcb_name(z80: &Z80, fcb: u16) -> Name {
+    let mut name = [b' '; 11];
+    for (i, n) in name.iter_mut().enumerate() {
+        *n = z80.memory()[fcb.wrapping_add(1 + i as u16) as usize] & 0x7F;
+    }
+    name
+}
+
+fn fcb_byte(z80: &Z80, fcb: u16, offset: u16) -> u8 {
+    z80.memory()[fcb.wrapping_add(offset) as usize]
+}
+
+/// The record to be read or written next, from the extent (EX, S2) and current record (CR) fields
+fn sequential_record(z80: &Z80, fcb: u16) -> u32 {
+    let extent = (fcb_byte(z80, fcb, 12) & 0x1F) as u32 | (fcb_byte(z80, fcb, 14) as u32) << 5;
+    extent * EXTENT_RECORDS + fcb_byte(z80, fcb, 32) as u32
+}
+
+fn set_sequential_record(z80: &mut Z80, fcb: u16, record: u32) {
+    let extent = record / EXTENT_RECORDS;
+    z80.load_at(fcb.wrapping_add(12), &[(extent & 0x1F) as u8]);
+    z80.load_at(fcb.wrapping_add(14), &[(extent >> 5) as u8]);
+    z80.load_at(fcb.wrapping_add(32), &[(record % EXTENT_RECORDS) as u8]);
+}
+
+/// The record number in the random record field (R0, R1)
+fn random_record(z80: &Z80, fcb: u16) -> u32 {
+    u16::from_le_bytes([fcb_byte(z80, fcb, 33), fcb_byte(z80, fcb, 34)]).into()
+}
+
+fn set_random_record(z80: &mut Z80, fcb: u16, record: u32) {
+    let [r0, r1, r2, _] = record.to_le_bytes();
+    z80.load_at(fcb.wrapping_add(33), &[r0, r1, r2]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fresh, empty directory for a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zeerust-cpm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn run(program: &[u8], input: &[u8], dir: &Path) -> (Z80, Vec<u8>) {
+        let mut z80 = Z80::default();
+        let mut cpm = Cpm::new(input, vec![], dir);
+        cpm.load(&mut z80, program, &["foo.txt", "*.com"]);
+        cpm.run(&mut z80).unwrap();
+        let output = cpm.output().clone();
+        (z80, output)
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(*b"FOO     TXT", parse_name("foo.txt"));
+        assert_eq!(*b"FOO     TXT", parse_name("a:foo.txt"));
+        assert_eq!(*b"AB??????C??", parse_name("ab*.c*"));
+        assert_eq!(*b"LONGFILEEXT", parse_name("longfilename.extension"));
+        assert_eq!(*b"           ", parse_name(""));
+
+        assert_eq!(Some(*b"ZEXDOC  COM"), cpm_name("zexdoc.com"));
+        assert_eq!(Some(*b"README     "), cpm_name("README"));
+        assert_eq!(None, cpm_name("longfilename.com"));
+        assert_eq!(None, cpm_name(".hidden"));
+        assert_eq!(None, cpm_name("a b.txt"));
+
+        assert_eq!(Some("zexdoc.com".into()), host_name(b"ZEXDOC  COM"));
+        assert_eq!(Some("readme".into()), host_name(b"README     "));
+        assert_eq!(None, host_name(b"/TMP/ZZ_ESC"));
+        assert_eq!(None, host_name(b"..      TXT"));
+        assert_eq!(None, host_name(b"C:\\FOO     "));
+        assert_eq!(None, host_name(b"FOO\0    TXT"));
+        assert_eq!(None, host_name(b"FOO?    TXT"));
+    }
+
+    #[test]
+    fn load() {
+        let mut z80 = Z80::default();
+        let mut cpm = Cpm::new(&b""[..], vec![], ".");
+        cpm.load(&mut z80, &[0x76], &["foo.txt", "b:*.com"]);
+        let mem = z80.memory();
+        assert_eq!(TPA, z80.registers.get_pc());
+        assert_eq!(0x76, mem[TPA as usize]);
+        assert_eq!([0xC3, 0x06, 0xFE], mem[0x0005..0x0008]);
+        assert_eq!(*b"FOO     TXT", mem[0x005D..0x0068]);
+        assert_eq!(*b"????????COM", mem[0x006D..0x0078]);
+        assert_eq!(b"\x10 FOO.TXT B:*.COM\x00", &mem[0x0080..0x0092]);
+    }
+
+    #[test]
+    fn console_output() {
+        let program = [
+            0x0E, 0x09, // LD C, 9
+            0x11, 0x11, 0x01, // LD DE, msg
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x0E, 0x02, // LD C, 2
+            0x1E, b'!', // LD E, '!'
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0xC9, // RET
+            0x76, // HALT
+            b'h', b'i', b'$', // msg
+        ];
+        let (z80, output) = run(&program, b"", Path::new("."));
+        assert_eq!(b"hi!", &output[..]);
+        // The program returned to the warm boot vector, rather than running into the HALT
+        assert!(!z80.is_halted());
+        assert_eq!(WBOOT, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let mut z80 = Z80::default();
+        let mut cpm = Cpm::new(&b""[..], vec![], ".");
+        cpm.load(&mut z80, &[], &[]);
+        z80.load_at(0xFE00, b"abcdef");
+        z80.registers.set_reg8(Reg8::C, 9);
+        z80.registers.set_reg16(&Reg16::DE, 0xFE00);
+        assert!(cpm.bdos(&mut z80).unwrap());
+        // The string stops at the BDOS, and doesn't include its RET or wrap round
+        assert_eq!(b"abcdef", &cpm.output()[..]);
+    }
+
+    #[test]
+    fn console_input() {
+        let program = [
+            0x0E, 0x01, // LD C, 1
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x32, 0x00, 0x02, // LD (0200h), A
+            0x0E, 0x0A, // LD C, 10
+            0x11, 0x01, 0x02, // LD DE, 0201h
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x0E, 0x00, // LD C, 0
+            0xCD, 0x05, 0x00, // CALL BDOS
+        ];
+        let mut z80 = Z80::default();
+        let mut cpm = Cpm::new(&b"xhello\r\nworld"[..], vec![], ".");
+        cpm.load(&mut z80, &program, &[]);
+        z80.load_at(0x0201, &[4]);
+        cpm.run(&mut z80).unwrap();
+        assert_eq!(b"x\x04\x04hell", &z80.memory()[0x0200..0x0207]);
+        assert_eq!(BDOS, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn files() {
+        let dir = temp_dir("files");
+        fs::write(dir.join("foo.txt"), [b'a'; 130]).unwrap();
+        fs::write(dir.join("bar.txt"), b"").unwrap();
+        fs::write(dir.join("a_very_long_name.txt"), b"").unwrap();
+
+        let program = [
+            0x0E, 0x0F, // LD C, 15
+            0x11, 0x5C, 0x00, // LD DE, FCB1
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x32, 0x00, 0x02, // LD (0200h), A
+            0x0E, 0x14, // LD C, 20
+            0x11, 0x5C, 0x00, // LD DE, FCB1
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x3A, 0xFF, 0x00, // LD A, (00FFh)
+            0x32, 0x01, 0x02, // LD (0201h), A
+            0x0E, 0x14, // LD C, 20
+            0x11, 0x5C, 0x00, // LD DE, FCB1
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x32, 0x02, 0x02, // LD (0202h), A
+            0x3A, 0x82, 0x00, // LD A, (0082h)
+            0x32, 0x03, 0x02, // LD (0203h), A
+            0x0E, 0x14, // LD C, 20
+            0x11, 0x5C, 0x00, // LD DE, FCB1
+            0xCD, 0x05, 0x00, // CALL BDOS
+            0x32, 0x04, 0x02, // LD (0204h), A
+            0xC9, // RET
+        ];
+        let (z80, _) = run(&program, b"", &dir);
+        // Opened, read a full record, then a padded partial one, then reached the end
+        assert_eq!([0x00, b'a', 0x00, EOF, 0x01], z80.memory()[0x0200..0x0205]);
+
+        let mut z80 = Z80::default();
+        let mut cpm = Cpm::new(&b""[..], vec![], &dir);
+        cpm.load(&mut z80, &[], &[]);
+        let fcb = 0x0300;
+        let set_name = |z80: &mut Z80, name: &str| {
+            z80.load_at(fcb + 1, &parse_name(name));
+        };
+
+        set_name(&mut z80, "*.txt");
+        assert_eq!(0, cpm.file(&mut z80, 17, fcb));
+        assert_eq!(*b"BAR     TXT", z80.memory()[0x0081..0x008C]);
+        assert_eq!(0, cpm.search_next(&mut z80));
+        assert_eq!(*b"FOO     TXT", z80.memory()[0x0081..0x008C]);
+        assert_eq!(0xFF, cpm.search_next(&mut z80));
+
+        set_name(&mut z80, "new.dat");
+        assert_eq!(0xFF, cpm.file(&mut z80, 15, fcb));
+        assert_eq!(0, cpm.file(&mut z80, 22, fcb));
+        z80.load_at(0x0080, &[b'z'; RECORD_SIZE]);
+        z80.load_at(fcb + 33, &[2, 0, 0]);
+        assert_eq!(0, cpm.file(&mut z80, 34, fcb));
+        assert_eq!(0, cpm.file(&mut z80, 35, fcb));
+        assert_eq!(
+            [3, 0, 0],
+            z80.memory()[fcb as usize + 33..fcb as usize + 36]
+        );
+        let written = fs::read(dir.join("new.dat")).unwrap();
+        assert_eq!(3 * RECORD_SIZE, written.len());
+        assert_eq!(b'z', written[2 * RECORD_SIZE]);
+
+        set_name(&mut z80, "foo.txt");
+        z80.load_at(fcb + 17, &parse_name("baz.txt"));
+        assert_eq!(0, cpm.file(&mut z80, 23, fcb));
+        assert!(dir.join("baz.txt").exists());
+        set_name(&mut z80, "ba?.txt");
+        assert_eq!(0, cpm.file(&mut z80, 19, fcb));
+        assert_eq!(0xFF, cpm.file(&mut z80, 19, fcb));
+        assert!(!dir.join("bar.txt").exists());
+
+        // Names that could escape the directory are refused
+        z80.load_at(fcb + 1, b"/TMP/ZZ_ESC");
+        assert_eq!(0xFF, cpm.file(&mut z80, 22, fcb));
+        set_name(&mut z80, "new.dat");
+        z80.load_at(fcb + 17, b"..      TXT");
+        assert_eq!(0xFF, cpm.file(&mut z80, 23, fcb));
+        assert!(dir.join("new.dat").exists());
+
+        // So are ambiguous names
+        z80.load_at(fcb + 17, &parse_name("new?.dat"));
+        assert_eq!(0xFF, cpm.file(&mut z80, 23, fcb));
+        assert!(dir.join("new.dat").exists());
+        assert!(!dir.join("new?.dat").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 //! The internal representation of the z80's memory.
 //! Currently just a large array.
-pub const MEMORY_SIZE: usize = 16 * 1024; // 16 kibibytes
+pub const MEMORY_SIZE: usize = 64 * 1024; // 64 kibibytes, the entire address space
 
 pub struct Memory {
     pub memory: [u8; MEMORY_SIZE],
@@ -13,3 +13,14 @@ impl Default for Memory {
         }
     }
 }
+
+impl Memory {
+    /// The `N` bytes starting at `addr`, wrapping around from the top of memory to the bottom
+    pub fn window<const N: usize>(&self, addr: u16) -> [u8; N] {
+        let mut bytes = [0; N];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.memory[usize::from(addr.wrapping_add(i as u16))];
+        }
+        bytes
+    }
+}
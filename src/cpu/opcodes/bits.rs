@@ -12,8 +12,8 @@ pub fn parse(op: u8) -> (Op, usize) {
                 0b011 => Op::RR,
                 0b100 => Op::SLA,
                 0b101 => Op::SRA,
-                // http://z80-heaven.wikidot.com/instructions-set:sll
-                0b110 => panic!("Use of undocumented instruction SLL"),
+                // Undocumented: http://z80-heaven.wikidot.com/instructions-set:sll
+                0b110 => Op::SLL,
                 0b111 => Op::SRL,
                 _ => unreachable!(),
             };
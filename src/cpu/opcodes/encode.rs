@@ -0,0 +1,391 @@
+use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8};
+use crate::prelude::*;
+
+/// An operand from the table of 8-bit registers, which most instructions encode in three bits
+#[derive(Clone, Copy, PartialEq)]
+enum Reg {
+    /// B, C, D, E, H, L, (HL) or A
+    Plain(u8),
+    /// The high (4) or low (5) half of an index register, with its prefix
+    Half(u8, u8),
+    /// (IX+d) or (IY+d), with its prefix
+    Indexed(u8, i8),
+}
+
+impl Reg {
+    fn code(self) -> u8 {
+        match self {
+            Reg::Plain(code) | Reg::Half(_, code) => code,
+            Reg::Indexed(_, _) => 0b110,
+        }
+    }
+
+    fn prefix(self) -> Option<u8> {
+        match self {
+            Reg::Plain(_) => None,
+            Reg::Half(prefix, _) | Reg::Indexed(prefix, _) => Some(prefix),
+        }
+    }
+
+    fn displacement(self) -> Option<u8> {
+        match self {
+            Reg::Indexed(_, d) => Some(d as u8),
+            _ => None,
+        }
+    }
+}
+
+fn index_prefix(reg: &Reg16) -> Option<u8> {
+    match reg {
+        Reg16::IX => Some(0xDD),
+        Reg16::IY => Some(0xFD),
+        _ => None,
+    }
+}
+
+fn reg(loc: &Location8) -> Option<Reg> {
+    let reg = match loc {
+        Location8::Reg(Reg8::B) => Reg::Plain(0b000),
+        Location8::Reg(Reg8::C) => Reg::Plain(0b001),
+        Location8::Reg(Reg8::D) => Reg::Plain(0b010),
+        Location8::Reg(Reg8::E) => Reg::Plain(0b011),
+        Location8::Reg(Reg8::H) => Reg::Plain(0b100),
+        Location8::Reg(Reg8::L) => Reg::Plain(0b101),
+        Location8::RegIndirect(Reg16::HL) => Reg::Plain(0b110),
+        Location8::Reg(Reg8::A) => Reg::Plain(0b111),
+        Location8::Reg(Reg8::IXH) => Reg::Half(0xDD, 0b100),
+        Location8::Reg(Reg8::IXL) => Reg::Half(0xDD, 0b101),
+        Location8::Reg(Reg8::IYH) => Reg::Half(0xFD, 0b100),
+        Location8::Reg(Reg8::IYL) => Reg::Half(0xFD, 0b101),
+        Location8::Indexed(r, d) => Reg::Indexed(index_prefix(r)?, *d),
+        _ => return None,
+    };
+    Some(reg)
+}
+
+/// Two operands of one instruction. They share its prefix, which changes H, L and (HL) in both
+fn reg_pair(dst: &Location8, src: &Location8) -> Option<(Reg, Reg)> {
+    let (dst, src) = (reg(dst)?, reg(src)?);
+    let compatible = |a: Reg, b: Reg| match (a, b) {
+        (Reg::Indexed(_, _), Reg::Plain(code)) => code != 0b110,
+        (Reg::Indexed(_, _), _) => false,
+        (Reg::Half(p, _), Reg::Half(q, _)) => p == q,
+        (Reg::Half(_, _), Reg::Plain(code)) => !(0b100..=0b110).contains(&code),
+        (Reg::Plain(a), Reg::Plain(b)) => a != 0b110 || b != 0b110,
+        _ => true,
+    };
+    if compatible(dst, src) && compatible(src, dst) {
+        Some((dst, src))
+    } else {
+        None
+    }
+}
+
+/// An instruction on one 8-bit register: its prefix, its opcode, the displacement, then `rest`
+fn with_reg(reg: Reg, opcode: u8, rest: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = reg.prefix().into_iter().collect();
+    bytes.push(opcode);
+    bytes.extend(reg.displacement());
+    bytes.extend_from_slice(rest);
+    bytes
+}
+
+/// The 16-bit register pairs, encoded in two bits; `last` is either SP or AF.
+/// Returns the code and the prefix the register needs
+fn pair(loc: &Location16, last: &Reg16) -> Option<(u8, Option<u8>)> {
+    match loc {
+        Location16::Reg(Reg16::BC) => Some((0b00, None)),
+        Location16::Reg(Reg16::DE) => Some((0b01, None)),
+        Location16::Reg(Reg16::HL) => Some((0b10, None)),
+        Location16::Reg(r @ Reg16::IX) | Location16::Reg(r @ Reg16::IY) => {
+            Some((0b10, index_prefix(r)))
+        }
+        Location16::Reg(r) if r == last => Some((0b11, None)),
+        _ => None,
+    }
+}
+
+/// HL, IX or IY: the prefix it needs
+fn hl(loc: &Location16) -> Option<Option<u8>> {
+    match pair(loc, &Reg16::SP)? {
+        (0b10, prefix) => Some(prefix),
+        _ => None,
+    }
+}
+
+fn prefixed(prefix: Option<u8>, rest: &[u8]) -> Vec<u8> {
+    prefix.into_iter().chain(rest.iter().copied()).collect()
+}
+
+fn condition(cond: JumpConditional) -> Option<u8> {
+    let code = match cond {
+        JumpConditional::Unconditional => return None,
+        JumpConditional::NonZero => 0b000,
+        JumpConditional::Zero => 0b001,
+        JumpConditional::NoCarry => 0b010,
+        JumpConditional::Carry => 0b011,
+        JumpConditional::ParityOdd => 0b100,
+        JumpConditional::ParityEven => 0b101,
+        JumpConditional::SignPositive => 0b110,
+        JumpConditional::SignNegative => 0b111,
+    };
+    Some(code << 3)
+}
+
+/// The CB-prefixed operations, given their top five bits
+fn bits(op: u8, loc: &Location8) -> Option<Vec<u8>> {
+    let bytes = match reg(loc)? {
+        Reg::Plain(code) => vec![0xCB, op | code],
+        Reg::Indexed(prefix, d) => vec![prefix, 0xCB, d as u8, op | 0b110],
+        Reg::Half(_, _) => return None,
+    };
+    Some(bytes)
+}
+
+/// Arithmetic and logic on the accumulator, given the operation's three bits
+fn alu(op: u8, src: &Location8) -> Option<Vec<u8>> {
+    match src {
+        Location8::Immediate(n) => Some(vec![0b1100_0110 | op << 3, *n]),
+        src => {
+            let src = reg(src)?;
+            Some(with_reg(src, 0b1000_0000 | op << 3 | src.code(), &[]))
+        }
+    }
+}
+
+fn is_acc(loc: &Location8) -> bool {
+    *loc == Location8::Reg(Reg8::A)
+}
+
+/// Encode an operation as machine code. This is the inverse of `opcode`: decoding the bytes
+/// gives back the same operation.
+/// Returns None if the Z80 has no instruction for the operation.
+pub fn encode(op: &Op) -> Option<Vec<u8>> {
+    let simple = |bytes: &[u8]| Some(bytes.to_vec());
+    let word = |n: u16| n.to_le_bytes();
+    match op {
+        Op::NOP => simple(&[0x00]),
+        Op::HALT => simple(&[0x76]),
+        Op::RLCA => simple(&[0x07]),
+        Op::RRCA => simple(&[0x0F]),
+        Op::RLA => simple(&[0x17]),
+        Op::RRA => simple(&[0x1F]),
+        Op::DAA => simple(&[0x27]),
+        Op::CPL => simple(&[0x2F]),
+        Op::SCF => simple(&[0x37]),
+        Op::CCF => simple(&[0x3F]),
+        Op::EXX => simple(&[0xD9]),
+        Op::DI => simple(&[0xF3]),
+        Op::EI => simple(&[0xFB]),
+
+        Op::NEG => simple(&[0xED, 0x44]),
+        Op::RETN => simple(&[0xED, 0x45]),
+        Op::RETI => simple(&[0xED, 0x4D]),
+        Op::RRD => simple(&[0xED, 0x67]),
+        Op::RLD => simple(&[0xED, 0x6F]),
+        Op::IM(mode) => [0x46, 0x56, 0x5E]
+            .get(usize::from(*mode))
+            .map(|op| vec![0xED, *op]),
+
+        Op::LDI => simple(&[0xED, 0xA0]),
+        Op::CPI => simple(&[0xED, 0xA1]),
+        Op::INI => simple(&[0xED, 0xA2]),
+        Op::OUTI => simple(&[0xED, 0xA3]),
+        Op::LDD => simple(&[0xED, 0xA8]),
+        Op::CPD => simple(&[0xED, 0xA9]),
+        Op::IND => simple(&[0xED, 0xAA]),
+        Op::OUTD => simple(&[0xED, 0xAB]),
+        Op::LDIR => simple(&[0xED, 0xB0]),
+        Op::CPIR => simple(&[0xED, 0xB1]),
+        Op::INIR => simple(&[0xED, 0xB2]),
+        Op::OTIR => simple(&[0xED, 0xB3]),
+        Op::LDDR => simple(&[0xED, 0xB8]),
+        Op::CPDR => simple(&[0xED, 0xB9]),
+        Op::INDR => simple(&[0xED, 0xBA]),
+        Op::OTDR => simple(&[0xED, 0xBB]),
+
+        // Jumps, calls and returns
+        Op::RST(addr) if addr & !0b0011_1000 == 0 => simple(&[0xC7 | addr]),
+        Op::DJNZ(e) => simple(&[0x10, *e as u8]),
+        Op::JR(JumpConditional::Unconditional, e) => simple(&[0x18, *e as u8]),
+        Op::JR(cond, e) => match condition(*cond)? {
+            cc if cc <= 0b0001_1000 => simple(&[0b0010_0000 | cc, *e as u8]),
+            _ => None,
+        },
+        Op::JP(cond, Location16::Immediate(nn)) => {
+            let [n1, n2] = word(*nn);
+            match condition(*cond) {
+                None => simple(&[0xC3, n1, n2]),
+                Some(cc) => simple(&[0b1100_0010 | cc, n1, n2]),
+            }
+        }
+        Op::JP(JumpConditional::Unconditional, loc) => Some(prefixed(hl(loc)?, &[0xE9])),
+        Op::CALL(cond, nn) => {
+            let [n1, n2] = word(*nn);
+            match condition(*cond) {
+                None => simple(&[0xCD, n1, n2]),
+                Some(cc) => simple(&[0b1100_0100 | cc, n1, n2]),
+            }
+        }
+        Op::RET(cond) => match condition(*cond) {
+            None => simple(&[0xC9]),
+            Some(cc) => simple(&[0b1100_0000 | cc]),
+        },
+
+        // 16-bit operations
+        Op::PUSH(loc) => {
+            let (code, prefix) = pair(loc, &Reg16::AF)?;
+            Some(prefixed(prefix, &[0b1100_0101 | code << 4]))
+        }
+        Op::POP(loc) => {
+            let (code, prefix) = pair(loc, &Reg16::AF)?;
+            Some(prefixed(prefix, &[0b1100_0001 | code << 4]))
+        }
+        Op::EX(Location16::Reg(Reg16::AF), Location16::Reg(Reg16::AFP)) => simple(&[0x08]),
+        Op::EX(Location16::Reg(Reg16::DE), Location16::Reg(Reg16::HL)) => simple(&[0xEB]),
+        Op::EX(Location16::RegIndirect(Reg16::SP), loc) => Some(prefixed(hl(loc)?, &[0xE3])),
+        Op::INC16(loc) => {
+            let (code, prefix) = pair(loc, &Reg16::SP)?;
+            Some(prefixed(prefix, &[0b0000_0011 | code << 4]))
+        }
+        Op::DEC16(loc) => {
+            let (code, prefix) = pair(loc, &Reg16::SP)?;
+            Some(prefixed(prefix, &[0b0000_1011 | code << 4]))
+        }
+        Op::ADD16(dst, src) => {
+            let prefix = hl(dst)?;
+            match pair(src, &Reg16::SP)? {
+                // Only ADD IX, IX, never ADD IX, HL
+                (0b10, p) if p != prefix => None,
+                (code, _) => Some(prefixed(prefix, &[0b0000_1001 | code << 4])),
+            }
+        }
+        Op::ADC16(Location16::Reg(Reg16::HL), src) => match pair(src, &Reg16::SP)? {
+            (code, None) => simple(&[0xED, 0b0100_1010 | code << 4]),
+            _ => None,
+        },
+        Op::SBC16(Location16::Reg(Reg16::HL), src) => match pair(src, &Reg16::SP)? {
+            (code, None) => simple(&[0xED, 0b0100_0010 | code << 4]),
+            _ => None,
+        },
+        Op::LD16(Location16::Reg(Reg16::SP), src @ Location16::Reg(_)) => {
+            Some(prefixed(hl(src)?, &[0xF9]))
+        }
+        Op::LD16(dst, Location16::Immediate(nn)) => {
+            let (code, prefix) = pair(dst, &Reg16::SP)?;
+            let [n1, n2] = word(*nn);
+            Some(prefixed(prefix, &[0b0000_0001 | code << 4, n1, n2]))
+        }
+        Op::LD16(dst, Location16::ImmediateIndirect(nn)) => {
+            let [n1, n2] = word(*nn);
+            match pair(dst, &Reg16::SP)? {
+                (0b10, prefix) => Some(prefixed(prefix, &[0x2A, n1, n2])),
+                (code, _) => simple(&[0xED, 0b0100_1011 | code << 4, n1, n2]),
+            }
+        }
+        Op::LD16(Location16::ImmediateIndirect(nn), src) => {
+            let [n1, n2] = word(*nn);
+            match pair(src, &Reg16::SP)? {
+                (0b10, prefix) => Some(prefixed(prefix, &[0x22, n1, n2])),
+                (code, _) => simple(&[0xED, 0b0100_0011 | code << 4, n1, n2]),
+            }
+        }
+
+        // 8-bit loads
+        Op::LD8(Location8::Reg(Reg8::A), src) => match src {
+            Location8::RegIndirect(Reg16::BC) => simple(&[0x0A]),
+            Location8::RegIndirect(Reg16::DE) => simple(&[0x1A]),
+            Location8::ImmediateIndirect(nn) => {
+                let [n1, n2] = word(*nn);
+                simple(&[0x3A, n1, n2])
+            }
+            Location8::Reg(Reg8::I) => simple(&[0xED, 0x57]),
+            Location8::Reg(Reg8::R) => simple(&[0xED, 0x5F]),
+            src => load8(&Location8::Reg(Reg8::A), src),
+        },
+        Op::LD8(dst, Location8::Reg(Reg8::A)) => match dst {
+            Location8::RegIndirect(Reg16::BC) => simple(&[0x02]),
+            Location8::RegIndirect(Reg16::DE) => simple(&[0x12]),
+            Location8::ImmediateIndirect(nn) => {
+                let [n1, n2] = word(*nn);
+                simple(&[0x32, n1, n2])
+            }
+            Location8::Reg(Reg8::I) => simple(&[0xED, 0x47]),
+            Location8::Reg(Reg8::R) => simple(&[0xED, 0x4F]),
+            dst => load8(dst, &Location8::Reg(Reg8::A)),
+        },
+        Op::LD8(dst, src) => load8(dst, src),
+
+        // 8-bit arithmetic and logic
+        Op::ADD8(dst, src) if is_acc(dst) => alu(0b000, src),
+        Op::ADC(dst, src) if is_acc(dst) => alu(0b001, src),
+        Op::SUB8(dst, src) if is_acc(dst) => alu(0b010, src),
+        Op::SBC(dst, src) if is_acc(dst) => alu(0b011, src),
+        Op::AND(src) => alu(0b100, src),
+        Op::XOR(src) => alu(0b101, src),
+        Op::OR(src) => alu(0b110, src),
+        Op::CP(src) => alu(0b111, src),
+        Op::INC(loc) => {
+            let r = reg(loc)?;
+            Some(with_reg(r, 0b0000_0100 | r.code() << 3, &[]))
+        }
+        Op::DEC(loc) => {
+            let r = reg(loc)?;
+            Some(with_reg(r, 0b0000_0101 | r.code() << 3, &[]))
+        }
+
+        // Bit operations
+        Op::RLC(loc) => bits(0b0000_0000, loc),
+        Op::RRC(loc) => bits(0b0000_1000, loc),
+        Op::RL(loc) => bits(0b0001_0000, loc),
+        Op::RR(loc) => bits(0b0001_1000, loc),
+        Op::SLA(loc) => bits(0b0010_0000, loc),
+        Op::SRA(loc) => bits(0b0010_1000, loc),
+        Op::SLL(loc) => bits(0b0011_0000, loc),
+        Op::SRL(loc) => bits(0b0011_1000, loc),
+        Op::BIT(b, loc) if *b < 8 => bits(0b0100_0000 | b << 3, loc),
+        Op::RES(b, loc) if *b < 8 => bits(0b1000_0000 | b << 3, loc),
+        Op::SET(b, loc) if *b < 8 => bits(0b1100_0000 | b << 3, loc),
+        // Only the DDCB and FDCB forms of the operations that write can copy their result
+        Op::COPY(op, dst) => match (encode(op)?.as_slice(), reg(&Location8::Reg(*dst))?) {
+            (&[prefix, 0xCB, d, code], Reg::Plain(r))
+                if code & 0b111 == 0b110 && code >> 6 != 0b01 =>
+            {
+                simple(&[prefix, 0xCB, d, (code & !0b111) | r])
+            }
+            _ => None,
+        },
+
+        // Input and output
+        Op::IN(dst, Location8::Immediate(n)) if is_acc(dst) => simple(&[0xDB, *n]),
+        Op::IN(Location8::Reg(Reg8::F), Location8::Reg(Reg8::C)) => simple(&[0xED, 0x70]),
+        Op::IN(dst, Location8::Reg(Reg8::C)) => match reg(dst)? {
+            Reg::Plain(code) if code != 0b110 => simple(&[0xED, 0b0100_0000 | code << 3]),
+            _ => None,
+        },
+        Op::OUT(src, Location8::Immediate(n)) if is_acc(src) => simple(&[0xD3, *n]),
+        Op::OUT(Location8::Immediate(0), Location8::Reg(Reg8::C)) => simple(&[0xED, 0x71]),
+        Op::OUT(src, Location8::Reg(Reg8::C)) => match reg(src)? {
+            Reg::Plain(code) if code != 0b110 => simple(&[0xED, 0b0100_0001 | code << 3]),
+            _ => None,
+        },
+
+        Op::Invalid(bytes) => Some(bytes.clone()),
+        _ => None,
+    }
+}
+
+fn load8(dst: &Location8, src: &Location8) -> Option<Vec<u8>> {
+    if let Location8::Immediate(n) = src {
+        let dst = reg(dst)?;
+        return Some(with_reg(dst, 0b0000_0110 | dst.code() << 3, &[*n]));
+    }
+    let (dst, src) = reg_pair(dst, src)?;
+    // Whichever operand needs the prefix also has any displacement
+    let prefixed = if dst.prefix().is_some() { dst } else { src };
+    Some(with_reg(
+        prefixed,
+        0b0100_0000 | dst.code() << 3 | src.code(),
+        &[],
+    ))
+}
@@ -0,0 +1,63 @@
+use super::util::*;
+use crate::ops::{Location16, Location8, Op, Reg16, Reg8};
+
+/// Parse an instruction prefixed with 0xED
+pub fn parse(op: u8, n1: u8, n2: u8) -> (Op, usize) {
+    let hl = Location16::Reg(Reg16::HL);
+    let opr = match op {
+        // IN (C) and OUT (C), 0 are the undocumented forms of {IN,OUT} (HL), (C).
+        // IN (C) only sets the flags, so it is represented as a load to F
+        0x70 => Op::IN(Location8::Reg(Reg8::F), Location8::Reg(Reg8::C)),
+        0x71 => Op::OUT(Location8::Immediate(0), Location8::Reg(Reg8::C)),
+        op if op & 0b1100_0111 == 0b0100_0000 => Op::IN(reg_bits(op >> 3), Location8::Reg(Reg8::C)),
+        op if op & 0b1100_0111 == 0b0100_0001 => {
+            Op::OUT(reg_bits(op >> 3), Location8::Reg(Reg8::C))
+        }
+
+        // 16-bit arithmetic
+        op if op & 0b1100_1111 == 0b0100_0010 => Op::SBC16(hl, reg16_bits(op >> 4)),
+        op if op & 0b1100_1111 == 0b0100_1010 => Op::ADC16(hl, reg16_bits(op >> 4)),
+
+        // 16-bit loads
+        op if op & 0b1100_1111 == 0b0100_0011 => {
+            return (Op::LD16(le_imm_indir(n1, n2), reg16_bits(op >> 4)), 4)
+        }
+        op if op & 0b1100_1111 == 0b0100_1011 => {
+            return (Op::LD16(reg16_bits(op >> 4), le_imm_indir(n1, n2)), 4)
+        }
+
+        // NEG, RETN and IM are repeated through the rest of their column
+        op if op & 0b1100_0111 == 0b0100_0100 => Op::NEG,
+        0x4D => Op::RETI,
+        op if op & 0b1100_0111 == 0b0100_0101 => Op::RETN,
+        op if op & 0b1100_0111 == 0b0100_0110 => Op::IM([0, 0, 1, 2][usize::from(op >> 3 & 0b11)]),
+
+        0x47 => Op::LD8(Location8::Reg(Reg8::I), Location8::Reg(Reg8::A)),
+        0x4F => Op::LD8(Location8::Reg(Reg8::R), Location8::Reg(Reg8::A)),
+        0x57 => Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::I)),
+        0x5F => Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::R)),
+        0x67 => Op::RRD,
+        0x6F => Op::RLD,
+
+        // Block transfer, search and I/O
+        0xA0 => Op::LDI,
+        0xA1 => Op::CPI,
+        0xA2 => Op::INI,
+        0xA3 => Op::OUTI,
+        0xA8 => Op::LDD,
+        0xA9 => Op::CPD,
+        0xAA => Op::IND,
+        0xAB => Op::OUTD,
+        0xB0 => Op::LDIR,
+        0xB1 => Op::CPIR,
+        0xB2 => Op::INIR,
+        0xB3 => Op::OTIR,
+        0xB8 => Op::LDDR,
+        0xB9 => Op::CPDR,
+        0xBA => Op::INDR,
+        0xBB => Op::OTDR,
+
+        op => Op::Invalid(vec![0xED, op]),
+    };
+    (opr, 2)
+}
@@ -1,5 +1,6 @@
 use super::opcode;
 use crate::ops::Op;
+use crate::prelude::*;
 
 pub fn parse_stream(stream: Vec<u8>) -> Vec<Op> {
     let mut i = 0;
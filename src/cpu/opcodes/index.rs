@@ -1,17 +1,118 @@
-use crate::cpu::opcodes::util::*;
-use crate::ops::{Location16, Op, Reg16};
+use super::util::reg_bits;
+use super::{bits, opcode};
+use crate::ops::{Location16, Location8, Op, Reg16, Reg8};
+use crate::prelude::*;
 
+/// Parse an instruction prefixed with 0xDD (for IX) or 0xFD (for IY).
+/// The prefix turns the following instruction's (HL) into (IX+d), or its H, L and HL into IXH,
+/// IXL and IX. A prefix on an instruction that doesn't use HL has no effect, so it is parsed as
+/// a NOP, and the instruction after it is parsed on its own.
 pub fn parse(reg: Reg16, op: u8, n1: u8, n2: u8) -> (Op, usize) {
     match op {
-        0x21 => (Op::LD16(Location16::Reg(reg), le_immediate(n1, n2)), 4),
-        0x2A => (Op::LD16(Location16::Reg(reg), le_imm_indir(n1, n2)), 4),
-        0x22 => (Op::LD16(le_imm_indir(n1, n2), Location16::Reg(reg)), 4),
-        0xF9 => (
-            Op::LD16(Location16::Reg(Reg16::SP), Location16::Reg(reg)),
-            2,
-        ),
-        0xE1 => (Op::POP(Location16::Reg(reg)), 2),
-        0xE5 => (Op::PUSH(Location16::Reg(reg)), 2),
-        _op => unimplemented!("{:?} {:02x}", reg, op),
+        // A prefix followed by another prefix acts as a NOP
+        0xDD | 0xED | 0xFD => return (Op::NOP, 1),
+        // EX DE, HL is never indexed
+        0xEB => return (Op::NOP, 1),
+        0xCB => return indexed_bits(reg, n1 as i8, n2),
+        _ => (),
+    }
+
+    let (plain, len) = opcode([op, n1, n2, 0]);
+    let uses_indirect = map_locations(plain.clone(), indirect_hl(Location8::Immediate(0)), |l| l);
+    if uses_indirect != plain {
+        // The displacement comes straight after the opcode, before any immediate value
+        let (plain, len) = opcode([op, n2, 0, 0]);
+        let indexed = indirect_hl(Location8::Indexed(reg, n1 as i8));
+        return (map_locations(plain, indexed, |l| l), len + 2);
+    }
+
+    let (high, low) = match reg {
+        Reg16::IY => (Reg8::IYH, Reg8::IYL),
+        _ => (Reg8::IXH, Reg8::IXL),
+    };
+    let halves = |loc| match loc {
+        Location8::Reg(Reg8::H) => Location8::Reg(high),
+        Location8::Reg(Reg8::L) => Location8::Reg(low),
+        loc => loc,
+    };
+    let whole = |loc| match loc {
+        Location16::Reg(Reg16::HL) => Location16::Reg(reg.clone()),
+        loc => loc,
+    };
+    let substituted = map_locations(plain.clone(), halves, whole);
+    if substituted != plain {
+        (substituted, len + 1)
+    } else {
+        (Op::NOP, 1)
+    }
+}
+
+/// Parse a DDCB or FDCB instruction: the prefix, 0xCB, a displacement, then the operation.
+/// Besides their documented (IX+d) forms, these have undocumented forms that also copy the
+/// result to the register the operation names. BIT only reads, so its forms are all the same.
+fn indexed_bits(reg: Reg16, d: i8, op: u8) -> (Op, usize) {
+    let (opr, _) = bits::parse((op & !0b111) | 0b110);
+    let indexed = map_locations(opr, indirect_hl(Location8::Indexed(reg, d)), |l| l);
+    match reg_bits(op) {
+        Location8::Reg(dst) if op >> 6 != 0b01 => (Op::COPY(Box::new(indexed), dst), 4),
+        _ => (indexed, 4),
+    }
+}
+
+/// A substitution for the 8-bit location (HL)
+fn indirect_hl(to: Location8) -> impl Fn(Location8) -> Location8 {
+    move |loc| match loc {
+        Location8::RegIndirect(Reg16::HL) => to.clone(),
+        loc => loc,
+    }
+}
+
+/// Replace every location an operation uses
+pub fn map_locations<F8, F16>(op: Op, f8: F8, f16: F16) -> Op
+where
+    F8: Fn(Location8) -> Location8,
+    F16: Fn(Location16) -> Location16,
+{
+    match op {
+        Op::ADC(dst, src) => Op::ADC(f8(dst), f8(src)),
+        Op::ADD8(dst, src) => Op::ADD8(f8(dst), f8(src)),
+        Op::SBC(dst, src) => Op::SBC(f8(dst), f8(src)),
+        Op::SUB8(dst, src) => Op::SUB8(f8(dst), f8(src)),
+        Op::LD8(dst, src) => Op::LD8(f8(dst), f8(src)),
+        Op::IN(dst, src) => Op::IN(f8(dst), f8(src)),
+        Op::OUT(src, dst) => Op::OUT(f8(src), f8(dst)),
+
+        Op::INC(loc) => Op::INC(f8(loc)),
+        Op::DEC(loc) => Op::DEC(f8(loc)),
+        Op::AND(loc) => Op::AND(f8(loc)),
+        Op::OR(loc) => Op::OR(f8(loc)),
+        Op::XOR(loc) => Op::XOR(f8(loc)),
+        Op::CP(loc) => Op::CP(f8(loc)),
+        Op::RLC(loc) => Op::RLC(f8(loc)),
+        Op::RL(loc) => Op::RL(f8(loc)),
+        Op::RRC(loc) => Op::RRC(f8(loc)),
+        Op::RR(loc) => Op::RR(f8(loc)),
+        Op::SLA(loc) => Op::SLA(f8(loc)),
+        Op::SLL(loc) => Op::SLL(f8(loc)),
+        Op::SRL(loc) => Op::SRL(f8(loc)),
+        Op::SRA(loc) => Op::SRA(f8(loc)),
+
+        Op::BIT(b, loc) => Op::BIT(b, f8(loc)),
+        Op::SET(b, loc) => Op::SET(b, f8(loc)),
+        Op::RES(b, loc) => Op::RES(b, f8(loc)),
+        Op::COPY(op, dst) => Op::COPY(Box::new(map_locations(*op, f8, f16)), dst),
+
+        Op::JP(cond, loc) => Op::JP(cond, f16(loc)),
+        Op::POP(loc) => Op::POP(f16(loc)),
+        Op::PUSH(loc) => Op::PUSH(f16(loc)),
+        Op::INC16(loc) => Op::INC16(f16(loc)),
+        Op::DEC16(loc) => Op::DEC16(f16(loc)),
+        Op::LD16(dst, src) => Op::LD16(f16(dst), f16(src)),
+        Op::EX(dst, src) => Op::EX(f16(dst), f16(src)),
+        Op::ADD16(dst, src) => Op::ADD16(f16(dst), f16(src)),
+        Op::ADC16(dst, src) => Op::ADC16(f16(dst), f16(src)),
+        Op::SBC16(dst, src) => Op::SBC16(f16(dst), f16(src)),
+
+        op => op,
     }
 }
@@ -4,6 +4,8 @@ use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8};
 
 mod arithmetic;
 mod bits;
+mod encode;
+mod extended;
 mod file;
 mod index;
 mod util;
@@ -11,15 +13,26 @@ mod util;
 #[cfg(test)]
 mod test;
 
+pub use encode::encode;
 pub use file::parse_stream;
+pub(crate) use index::map_locations;
 use util::*;
 
 /// Parse a series of bytes into an opcode.
 /// Opcodes can be up to four bytes, but are often less.
 /// The usize from the tuple is the number of bytes consumed.
 /// The program counter should be incremented by this much
+///
+/// Every byte sequence decodes to something: sequences that aren't instructions decode to
+/// `Op::Invalid`, which executes as a NOP.
 pub fn opcode(code: [u8; 4]) -> (Op, usize) {
     match code {
+        // Bits are all 0xCB
+        [0xCB, op, _, _] => bits::parse(op),
+        [0xED, op, n1, n2] => extended::parse(op, n1, n2),
+        [0xDD, o1, n1, n2] => index::parse(Reg16::IX, o1, n1, n2),
+        [0xFD, o1, n1, n2] => index::parse(Reg16::IY, o1, n1, n2),
+
         [0x00, _, _, _] => (Op::NOP, 1),
         [0x76, _, _, _] => (Op::HALT, 1),
 
@@ -28,33 +41,16 @@ pub fn opcode(code: [u8; 4]) -> (Op, usize) {
         [0x0F, _, _, _] => (Op::RRCA, 1),
         [0x17, _, _, _] => (Op::RLA, 1),
         [0x1F, _, _, _] => (Op::RRA, 1),
-        [0xED, 0x67, _, _] => (Op::RRD, 2),
-        [0xED, 0x6F, _, _] => (Op::RLD, 2),
-        // Bits are all 0xCB
-        [0xCB, op, _, _] => bits::parse(op),
 
         // Input/Output
         [0xDB, n, _, _] => (Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(n)), 2),
         [0xD3, n, _, _] => (Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(n)), 2),
-        [0xED, op, _, _] if op & 0b1100_0110 == 0b0100_0000 => {
-            let opr = if op & 0b1 == 0b1 { Op::OUT } else { Op::IN };
-            if let reg @ Location8::Reg(_) = reg_bits(op >> 3) {
-                (opr(reg, Location8::Reg(Reg8::C)), 2)
-            } else {
-                // {IN,OUT}((HL), (C)) is not valid
-                panic!("Unknown ExtendeD operation {:02x}", op)
-            }
-        }
-        [0xED, op, _, _] if op & 0b1100_0111 == 0b0100_0000 => {
-            if let reg @ Location8::Reg(_) = reg_bits(op >> 3) {
-                (Op::IN(reg, Location8::Reg(Reg8::C)), 2)
-            } else {
-                // IN((HL), (C)) is not valid
-                panic!("Unknown IN operation")
-            }
-        }
 
         // Jump
+        [0xE9, _, _, _] => (
+            Op::JP(JumpConditional::Unconditional, Location16::Reg(Reg16::HL)),
+            1,
+        ),
         [0xC3, n1, n2, _] => (
             Op::JP(
                 JumpConditional::Unconditional,
@@ -94,6 +90,7 @@ pub fn opcode(code: [u8; 4]) -> (Op, usize) {
 
         [0xC9, _, _, _] => (Op::RET(JumpConditional::Unconditional), 1),
         [op, _, _, _] if op & 0b1100_0111 == 0b1100_0000 => (Op::RET(jump_conditional(op >> 3)), 1),
+        [op, _, _, _] if op & 0b1100_0111 == 0b1100_0111 => (Op::RST(op & 0b0011_1000), 1),
 
         // 8-bit Load
         [op, _, _, _] if op & 0b1100_0000 == 0b0100_0000 => {
@@ -112,17 +109,11 @@ pub fn opcode(code: [u8; 4]) -> (Op, usize) {
             Op::LD16(Location16::Reg(Reg16::HL), le_imm_indir(n1, n2)),
             3,
         ),
-        [0xED, op, n1, n2] if op & 0b1100_1111 == 0b0100_1011 => {
-            (Op::LD16(reg16_bits(op >> 4), le_imm_indir(n1, n2)), 4)
-        }
 
         [0x22, n1, n2, _] => (
             Op::LD16(le_imm_indir(n1, n2), Location16::Reg(Reg16::HL)),
             3,
         ),
-        [0xED, op, n1, n2] if op & 0b1100_1111 == 0b0100_0011 => {
-            (Op::LD16(le_imm_indir(n1, n2), reg16_bits(op >> 4)), 4)
-        }
         [0xF9, _, _, _] => (
             Op::LD16(Location16::Reg(Reg16::SP), Location16::Reg(Reg16::HL)),
             1,
@@ -130,8 +121,28 @@ pub fn opcode(code: [u8; 4]) -> (Op, usize) {
 
         [op, _, _, _] if op & 0b1100_1111 == 0b1100_0101 => (Op::PUSH(reg16_bits_af(op >> 4)), 1),
         [op, _, _, _] if op & 0b1100_1111 == 0b1100_0001 => (Op::POP(reg16_bits_af(op >> 4)), 1),
-        [0xDD, o1, n1, n2] => index::parse(Reg16::IX, o1, n1, n2),
-        [0xFD, o1, n1, n2] => index::parse(Reg16::IY, o1, n1, n2),
+
+        // Exchanges
+        [0x08, _, _, _] => (
+            Op::EX(Location16::Reg(Reg16::AF), Location16::Reg(Reg16::AFP)),
+            1,
+        ),
+        [0xD9, _, _, _] => (Op::EXX, 1),
+        [0xE3, _, _, _] => (
+            Op::EX(
+                Location16::RegIndirect(Reg16::SP),
+                Location16::Reg(Reg16::HL),
+            ),
+            1,
+        ),
+        [0xEB, _, _, _] => (
+            Op::EX(Location16::Reg(Reg16::DE), Location16::Reg(Reg16::HL)),
+            1,
+        ),
+
+        // Interrupts
+        [0xF3, _, _, _] => (Op::DI, 1),
+        [0xFB, _, _, _] => (Op::EI, 1),
 
         // Indirect Loads
         [0x0A, _, _, _] => (
@@ -167,23 +178,31 @@ pub fn opcode(code: [u8; 4]) -> (Op, usize) {
 
         // Misc Math
         [0x2F, _, _, _] => (Op::CPL, 1),
-        [0xED, 0x44, _, _] => (Op::NEG, 2),
+        [0x27, _, _, _] => (Op::DAA, 1),
         [0x3F, _, _, _] => (Op::CCF, 1),
         [0x37, _, _, _] => (Op::SCF, 1),
 
-        [op, o1, _, _] if op & 0b1010_0000 == 0b1010_0000 => arithmetic::boolean(op, o1),
-
         // INC
         [a, _, _, _] if a & 0b1100_0111 == 0b0000_0100 => (Op::INC(reg_bits(a >> 3)), 1),
         // DEC
         [a, _, _, _] if a & 0b1100_0111 == 0b0000_0101 => (Op::DEC(reg_bits(a >> 3)), 1),
-        // Add and Subtract
-        [op, o1, _, _] if op & 0b1010_0000 == 0x80 => arithmetic::add_subtract(op, o1),
+        // 16-bit INC, DEC and ADD
+        [a, _, _, _] if a & 0b1100_1111 == 0b0000_0011 => (Op::INC16(reg16_bits(a >> 4)), 1),
+        [a, _, _, _] if a & 0b1100_1111 == 0b0000_1011 => (Op::DEC16(reg16_bits(a >> 4)), 1),
+        [a, _, _, _] if a & 0b1100_1111 == 0b0000_1001 => {
+            (Op::ADD16(Location16::Reg(Reg16::HL), reg16_bits(a >> 4)), 1)
+        }
 
-        // [op, _, _, ]
-        [o1, o2, o3, o4] => panic!(
-            "Unimplemented opcode [{:02x}, {:02x}, {:02x}, {:02x}]",
-            o1, o2, o3, o4
-        ),
+        // Arithmetic and logic on the accumulator, from a register or an immediate
+        [op, o1, _, _] if op & 0b1100_0000 == 0b1000_0000 || op & 0b1100_0111 == 0b1100_0110 => {
+            if op & 0b0010_0000 == 0 {
+                arithmetic::add_subtract(op, o1)
+            } else {
+                arithmetic::boolean(op, o1)
+            }
+        }
+
+        // Every single byte opcode is covered above
+        [o1, _, _, _] => unreachable!("Unhandled opcode {:02x}", o1),
     }
 }
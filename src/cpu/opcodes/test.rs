@@ -2,6 +2,7 @@ use crate::cpu::opcodes::opcode;
 #[allow(unused_imports)]
 use crate::ops::Op;
 use crate::ops::{
+    self,
     JumpConditional::*,
     Location16::{Immediate as I16, ImmediateIndirect as II16, Reg as R16},
     Location8::*,
@@ -678,9 +679,10 @@ mod bits {
     // There's an SLL, but it is undocumented
     // http://z80-heaven.wikidot.com/instructions-set:sll
     #[test]
-    #[should_panic(expected = "undocumented instruction SLL")]
     fn sll() {
-        opcode(op4!(0xCB, 0x30));
+        assert_opcode!(SLL(Reg(B)), 2, 0xCB, 0x30);
+        assert_opcode!(SLL(Reg(A)), 2, 0xCB, 0x37);
+        assert_opcode!(SLL(RegIndirect(HL)), 2, 0xCB, 0x36);
     }
 
     #[test]
@@ -739,10 +741,10 @@ fn input() {
     assert_opcode!(IN(Reg(L), Reg(C)), 2, 0xED, 0x68);
 }
 
+// IN (C) only sets flags
 #[test]
-#[should_panic(expected = "Unknown ExtendeD operation")]
 fn input_hl() {
-    opcode(op4!(0xED, 0x70));
+    assert_opcode!(IN(Reg(F), Reg(C)), 2, 0xED, 0x70);
 }
 
 #[test]
@@ -758,10 +760,10 @@ fn output() {
     assert_opcode!(OUT(Reg(L), Reg(C)), 2, 0xED, 0x69);
 }
 
+// OUT (C), 0 is undocumented
 #[test]
-#[should_panic(expected = "Unknown ExtendeD operation")]
 fn output_hl() {
-    opcode(op4!(0xED, 0x71));
+    assert_opcode!(OUT(Immediate(0), Reg(C)), 2, 0xED, 0x71);
 }
 
 #[test]
@@ -847,3 +849,258 @@ fn ret() {
     assert_opcode!(RET(SignPositive), 1, 0xF0);
     assert_opcode!(RET(SignNegative), 1, 0xF8);
 }
+
+#[test]
+fn rst() {
+    assert_opcode!(RST(0x00), 1, 0xC7);
+    assert_opcode!(RST(0x08), 1, 0xCF);
+    assert_opcode!(RST(0x30), 1, 0xF7);
+    assert_opcode!(RST(0x38), 1, 0xFF);
+}
+
+#[test]
+fn alu_immediate() {
+    assert_opcode!(ADD8(Reg(A), Immediate(0x12)), 2, 0xC6, 0x12);
+    assert_opcode!(SBC(Reg(A), Immediate(0x12)), 2, 0xDE, 0x12);
+    assert_opcode!(AND(Immediate(0x12)), 2, 0xE6, 0x12);
+    assert_opcode!(XOR(Immediate(0x12)), 2, 0xEE, 0x12);
+    assert_opcode!(OR(Immediate(0x12)), 2, 0xF6, 0x12);
+    assert_opcode!(Op::CP(Immediate(0x12)), 2, 0xFE, 0x12);
+}
+
+#[test]
+fn exchange() {
+    assert_opcode!(EX(R16(AF), R16(AFP)), 1, 0x08);
+    assert_opcode!(EX(R16(DE), R16(HL)), 1, 0xEB);
+    assert_opcode!(EX(ops::Location16::RegIndirect(SP), R16(HL)), 1, 0xE3);
+    assert_opcode!(EXX, 1, 0xD9);
+}
+
+#[test]
+fn arithmetic16() {
+    assert_opcode!(ADD16(R16(HL), R16(BC)), 1, 0x09);
+    assert_opcode!(ADD16(R16(HL), R16(SP)), 1, 0x39);
+    assert_opcode!(INC16(R16(DE)), 1, 0x13);
+    assert_opcode!(DEC16(R16(HL)), 1, 0x2B);
+    assert_opcode!(SBC16(R16(HL), R16(DE)), 2, 0xED, 0x52);
+    assert_opcode!(ADC16(R16(HL), R16(SP)), 2, 0xED, 0x7A);
+}
+
+#[test]
+fn misc() {
+    assert_opcode!(DAA, 1, 0x27);
+    assert_opcode!(JP(Unconditional, R16(HL)), 1, 0xE9);
+    assert_opcode!(DI, 1, 0xF3);
+    assert_opcode!(EI, 1, 0xFB);
+    assert_opcode!(IM(0), 2, 0xED, 0x46);
+    assert_opcode!(IM(1), 2, 0xED, 0x56);
+    assert_opcode!(IM(2), 2, 0xED, 0x5E);
+    assert_opcode!(NEG, 2, 0xED, 0x44);
+    assert_opcode!(NEG, 2, 0xED, 0x7C);
+    assert_opcode!(RETI, 2, 0xED, 0x4D);
+    assert_opcode!(RETN, 2, 0xED, 0x45);
+    assert_opcode!(LD8(Reg(I), Reg(A)), 2, 0xED, 0x47);
+    assert_opcode!(LD8(Reg(A), Reg(R)), 2, 0xED, 0x5F);
+}
+
+#[test]
+fn block() {
+    assert_opcode!(LDI, 2, 0xED, 0xA0);
+    assert_opcode!(LDIR, 2, 0xED, 0xB0);
+    assert_opcode!(CPD, 2, 0xED, 0xA9);
+    assert_opcode!(INIR, 2, 0xED, 0xB2);
+    assert_opcode!(OTDR, 2, 0xED, 0xBB);
+}
+
+#[test]
+fn invalid_extended() {
+    assert_opcode!(Invalid(vec![0xED, 0x00]), 2, 0xED, 0x00);
+    assert_opcode!(Invalid(vec![0xED, 0x77]), 2, 0xED, 0x77);
+    assert_opcode!(Invalid(vec![0xED, 0xFF]), 2, 0xED, 0xFF);
+}
+
+mod index {
+    use super::*;
+
+    #[test]
+    fn indexed() {
+        assert_opcode!(LD8(Reg(B), Indexed(IX, 5)), 3, 0xDD, 0x46, 0x05);
+        assert_opcode!(LD8(Reg(H), Indexed(IY, -1)), 3, 0xFD, 0x66, 0xFF);
+        assert_opcode!(LD8(Indexed(IX, -128), Reg(L)), 3, 0xDD, 0x75, 0x80);
+        assert_opcode!(
+            LD8(Indexed(IY, 2), Immediate(0x99)),
+            4,
+            0xFD,
+            0x36,
+            0x02,
+            0x99
+        );
+        assert_opcode!(ADD8(Reg(A), Indexed(IX, 3)), 3, 0xDD, 0x86, 0x03);
+        assert_opcode!(Op::CP(Indexed(IY, 4)), 3, 0xFD, 0xBE, 0x04);
+        assert_opcode!(INC(Indexed(IX, 0)), 3, 0xDD, 0x34, 0x00);
+    }
+
+    #[test]
+    fn halves() {
+        assert_opcode!(LD8(Reg(IXH), Reg(B)), 2, 0xDD, 0x60);
+        assert_opcode!(LD8(Reg(IYL), Reg(IYH)), 2, 0xFD, 0x6C);
+        assert_opcode!(LD8(Reg(IXL), Immediate(0x12)), 3, 0xDD, 0x2E, 0x12);
+        assert_opcode!(XOR(Reg(IYH)), 2, 0xFD, 0xAC);
+        assert_opcode!(DEC(Reg(IXL)), 2, 0xDD, 0x2D);
+    }
+
+    #[test]
+    fn whole() {
+        assert_opcode!(LD16(R16(IX), I16(0x1234)), 4, 0xDD, 0x21, 0x34, 0x12);
+        assert_opcode!(LD16(II16(0x1234), R16(IY)), 4, 0xFD, 0x22, 0x34, 0x12);
+        assert_opcode!(LD16(R16(SP), R16(IX)), 2, 0xDD, 0xF9);
+        assert_opcode!(ADD16(R16(IX), R16(IX)), 2, 0xDD, 0x29);
+        assert_opcode!(ADD16(R16(IY), R16(BC)), 2, 0xFD, 0x09);
+        assert_opcode!(INC16(R16(IY)), 2, 0xFD, 0x23);
+        assert_opcode!(JP(Unconditional, R16(IX)), 2, 0xDD, 0xE9);
+        assert_opcode!(EX(ops::Location16::RegIndirect(SP), R16(IY)), 2, 0xFD, 0xE3);
+    }
+
+    #[test]
+    fn bits() {
+        assert_opcode!(RLC(Indexed(IX, 1)), 4, 0xDD, 0xCB, 0x01, 0x06);
+        assert_opcode!(BIT(7, Indexed(IY, -2)), 4, 0xFD, 0xCB, 0xFE, 0x7E);
+        assert_opcode!(SET(0, Indexed(IX, 3)), 4, 0xDD, 0xCB, 0x03, 0xC6);
+        // Undocumented forms that also copy to a register
+        assert_opcode!(
+            COPY(Box::new(RES(1, Indexed(IX, 3))), B),
+            4,
+            0xDD,
+            0xCB,
+            0x03,
+            0x88
+        );
+        assert_opcode!(
+            COPY(Box::new(RLC(Indexed(IY, 0))), C),
+            4,
+            0xFD,
+            0xCB,
+            0x00,
+            0x01
+        );
+        assert_opcode!(
+            COPY(Box::new(SET(7, Indexed(IX, -1))), A),
+            4,
+            0xDD,
+            0xCB,
+            0xFF,
+            0xFF
+        );
+        // BIT doesn't write, so has nothing to copy
+        assert_opcode!(BIT(1, Indexed(IX, 0)), 4, 0xDD, 0xCB, 0x00, 0x49);
+    }
+
+    // A prefix on an instruction that doesn't use HL does nothing
+    #[test]
+    fn ignored() {
+        assert_opcode!(NOP, 1, 0xDD, 0x00);
+        assert_opcode!(NOP, 1, 0xDD, 0x47);
+        assert_opcode!(NOP, 1, 0xFD, 0xDD);
+        assert_opcode!(NOP, 1, 0xDD, 0xED);
+        assert_opcode!(NOP, 1, 0xDD, 0xEB);
+    }
+}
+
+// Every byte sequence decodes, to an instruction of one to four bytes
+#[test]
+fn total() {
+    for prefix in [None, Some(0xCB), Some(0xED), Some(0xDD), Some(0xFD)] {
+        for op in 0..=0xFF {
+            for tail in [0x00, 0x7F, 0x80, 0xCB, 0xDD, 0xED, 0xFF] {
+                let code = match prefix {
+                    Some(p) => [p, op, tail, tail],
+                    None => [op, tail, tail, tail],
+                };
+                let (_, len) = opcode(code);
+                assert!((1..=4).contains(&len), "{:02x?} has length {}", code, len);
+            }
+        }
+    }
+    for op in 0..=0xFF {
+        for prefix in [0xDD, 0xFD] {
+            let (_, len) = opcode([prefix, 0xCB, 0x12, op]);
+            assert_eq!(4, len);
+        }
+    }
+}
+
+mod encode {
+    use super::*;
+    use crate::cpu::opcodes::encode;
+
+    /// Decode a byte sequence, encode the result, and check the encoding decodes the same
+    fn round_trip(code: [u8; 4]) {
+        let (op, _) = opcode(code);
+        let bytes = encode(&op).unwrap_or_else(|| panic!("{:02x?} ({:?}) didn't encode", code, op));
+        let mut padded = [0; 4];
+        padded[..bytes.len()].copy_from_slice(&bytes);
+        assert_eq!((op, bytes.len()), opcode(padded), "{:02x?}", code);
+    }
+
+    // Everything the decoder produces has an encoding
+    #[test]
+    fn total() {
+        for op in 0..=0xFF {
+            for tail in [0x00, 0x7F, 0x80, 0xFF] {
+                round_trip([op, tail, tail, tail]);
+                for prefix in [0xCB, 0xED, 0xDD, 0xFD] {
+                    round_trip([prefix, op, tail, tail]);
+                }
+            }
+            round_trip([0xDD, 0xCB, 0x12, op]);
+            round_trip([0xFD, 0xCB, 0xFE, op]);
+        }
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(Some(vec![0x3E, 0x12]), encode(&LD8(Reg(A), Immediate(0x12))));
+        assert_eq!(
+            Some(vec![0xDD, 0x36, 0xFE, 0x12]),
+            encode(&LD8(Indexed(IX, -2), Immediate(0x12)))
+        );
+        assert_eq!(
+            Some(vec![0xFD, 0x66, 0x01]),
+            encode(&LD8(Reg(H), Indexed(IY, 1)))
+        );
+        assert_eq!(Some(vec![0xDD, 0x65]), encode(&LD8(Reg(IXH), Reg(IXL))));
+        assert_eq!(
+            Some(vec![0xED, 0x4B, 0x34, 0x12]),
+            encode(&LD16(R16(BC), II16(0x1234)))
+        );
+        assert_eq!(Some(vec![0xFD, 0x29]), encode(&ADD16(R16(IY), R16(IY))));
+        assert_eq!(
+            Some(vec![0xDD, 0xCB, 0x03, 0xC6]),
+            encode(&SET(0, Indexed(IX, 3)))
+        );
+        assert_eq!(
+            Some(vec![0xFD, 0xCB, 0x03, 0x3D]),
+            encode(&COPY(Box::new(SRL(Indexed(IY, 3))), L))
+        );
+    }
+
+    // Operations the Z80 has no instruction for
+    #[test]
+    fn unencodable() {
+        assert_eq!(None, encode(&ADD8(Reg(D), Immediate(10))));
+        assert_eq!(None, encode(&LD8(RegIndirect(HL), RegIndirect(HL))));
+        assert_eq!(None, encode(&LD8(Reg(IXH), Reg(L))));
+        assert_eq!(None, encode(&LD8(Reg(IXH), Reg(IYL))));
+        assert_eq!(None, encode(&LD8(Reg(IXH), Indexed(IX, 0))));
+        assert_eq!(None, encode(&ADD16(R16(IX), R16(HL))));
+        assert_eq!(None, encode(&JR(ParityEven, 0)));
+        assert_eq!(None, encode(&RLC(Reg(IXH))));
+        assert_eq!(None, encode(&BIT(8, Reg(A))));
+        assert_eq!(None, encode(&RST(0x01)));
+        assert_eq!(None, encode(&IM(3)));
+        assert_eq!(None, encode(&COPY(Box::new(RLC(Reg(B))), C)));
+        assert_eq!(None, encode(&COPY(Box::new(RLC(Indexed(IX, 0))), IXH)));
+        assert_eq!(None, encode(&COPY(Box::new(BIT(0, Indexed(IX, 0))), A)));
+        assert_eq!(None, encode(&COPY(Box::new(INC(Indexed(IX, 0))), A)));
+    }
+}
@@ -2,6 +2,7 @@
 use crate::ops::{Reg16, Reg8, StatusFlag};
 
 #[derive(Default, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Registers {
     a: u8,
     b: u8,
@@ -25,6 +26,14 @@ pub struct Registers {
     ix: u16,
     iy: u16,
     sp: u16,
+
+    i: u8,
+    r: u8,
+    /// The interrupt flip-flops
+    iff1: bool,
+    iff2: bool,
+    /// The interrupt mode
+    im: u8,
 }
 
 impl Registers {
@@ -74,6 +83,14 @@ impl Registers {
             Reg8::FP => self.fp,
             Reg8::HP => self.hp,
             Reg8::LP => self.lp,
+
+            Reg8::IXH => self.ix.to_be_bytes()[0],
+            Reg8::IXL => self.ix.to_be_bytes()[1],
+            Reg8::IYH => self.iy.to_be_bytes()[0],
+            Reg8::IYL => self.iy.to_be_bytes()[1],
+
+            Reg8::I => self.i,
+            Reg8::R => self.r,
         }
     }
 
@@ -97,12 +114,20 @@ impl Registers {
             Reg8::FP => self.fp = v,
             Reg8::HP => self.hp = v,
             Reg8::LP => self.lp = v,
+
+            Reg8::IXH => self.ix = (self.ix & 0x00FF) | (u16::from(v) << 8),
+            Reg8::IXL => self.ix = (self.ix & 0xFF00) | u16::from(v),
+            Reg8::IYH => self.iy = (self.iy & 0x00FF) | (u16::from(v) << 8),
+            Reg8::IYL => self.iy = (self.iy & 0xFF00) | u16::from(v),
+
+            Reg8::I => self.i = v,
+            Reg8::R => self.r = v,
         }
     }
 
-    /// Set a 16-bit registers. These are made of two 8-bit registers, little-endian.
+    /// Set a 16-bit registers. These are made of two 8-bit registers, the first being the high byte.
     pub fn set_reg16(&mut self, r: &Reg16, v: u16) {
-        let [r0, r1] = v.to_le_bytes();
+        let [r0, r1] = v.to_be_bytes();
         match r {
             Reg16::AF => {
                 self.a = r0;
@@ -142,7 +167,7 @@ impl Registers {
         }
     }
 
-    /// Get a 16-bit register. These are a little-endian combination of two 8-bit registers.
+    /// Get a 16-bit register. These are a combination of two 8-bit registers, the first being the high byte.
     pub fn get_reg16(&self, r: &Reg16) -> u16 {
         let (r0, r1) = match r {
             Reg16::AF => (self.a, self.f),
@@ -157,7 +182,7 @@ impl Registers {
             Reg16::IY => return self.iy,
             Reg16::SP => return self.sp,
         };
-        (u16::from(r1) << 8) + u16::from(r0)
+        (u16::from(r0) << 8) | u16::from(r1)
     }
 
     /// Get the current program counter
@@ -169,6 +194,32 @@ impl Registers {
     pub fn set_pc(&mut self, pc: u16) {
         self.pc = pc
     }
+
+    /// Get the interrupt flip-flops, IFF1 and IFF2
+    pub fn get_iff(&self) -> (bool, bool) {
+        (self.iff1, self.iff2)
+    }
+
+    /// Set the interrupt flip-flops, IFF1 and IFF2
+    pub fn set_iff(&mut self, iff1: bool, iff2: bool) {
+        self.iff1 = iff1;
+        self.iff2 = iff2;
+    }
+
+    /// Get the interrupt mode
+    pub fn get_im(&self) -> u8 {
+        self.im
+    }
+
+    /// Set the interrupt mode
+    pub fn set_im(&mut self, im: u8) {
+        self.im = im
+    }
+
+    /// Increment the lower seven bits of the refresh register, as happens on every opcode fetch
+    pub fn increment_r(&mut self, n: u8) {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(n) & 0x7F)
+    }
 }
 
 #[cfg(test)]
@@ -254,6 +305,33 @@ mod test {
         assert_eq!(0x28, regs.get_reg8(Reg8::LP));
     }
 
+    #[test]
+    fn index_halves() {
+        let mut regs = Registers::default();
+        regs.set_reg16(&Reg16::IX, 0x1234);
+        regs.set_reg16(&Reg16::IY, 0x5678);
+        assert_eq!(0x12, regs.get_reg8(Reg8::IXH));
+        assert_eq!(0x34, regs.get_reg8(Reg8::IXL));
+        assert_eq!(0x56, regs.get_reg8(Reg8::IYH));
+        assert_eq!(0x78, regs.get_reg8(Reg8::IYL));
+
+        regs.set_reg8(Reg8::IXH, 0xAB);
+        regs.set_reg8(Reg8::IYL, 0xCD);
+        assert_eq!(0xAB34, regs.get_reg16(&Reg16::IX));
+        assert_eq!(0x56CD, regs.get_reg16(&Reg16::IY));
+    }
+
+    #[test]
+    fn increment_r() {
+        let mut regs = Registers::default();
+        regs.set_reg8(Reg8::R, 0xFE);
+        regs.increment_r(1);
+        assert_eq!(0xFF, regs.get_reg8(Reg8::R));
+        // Bit 7 is left alone
+        regs.increment_r(2);
+        assert_eq!(0x81, regs.get_reg8(Reg8::R));
+    }
+
     #[test]
     fn get_set_reg16() {
         let mut regs = Registers::default();
@@ -272,6 +350,11 @@ mod test {
         assert_eq!(0xABBA, regs.get_reg16(&Reg16::IX));
         assert_eq!(0x0BB0, regs.get_reg16(&Reg16::IY));
         assert_eq!(0x0809, regs.get_reg16(&Reg16::SP));
+        // The first register of a pair is the high byte
+        assert_eq!(0x06, regs.get_reg8(Reg8::A));
+        assert_eq!(0x01, regs.get_reg8(Reg8::F));
+        assert_eq!(0x08, regs.get_reg8(Reg8::H));
+        assert_eq!(0x07, regs.get_reg8(Reg8::L));
 
         regs.set_reg16(&Reg16::AFP, 0x2621);
         regs.set_reg16(&Reg16::BCP, 0x2322);
@@ -0,0 +1,1010 @@
+//! A Debug Adapter Protocol server, so a z80 can be debugged graphically in an editor.
+//!
+//! A single program is run on a single thread, launched with these arguments:
+//! - `program`: the file to run, in any format `formats::load` recognises
+//! - `loadAddress`: where to load and start a raw binary (default 0)
+//! - `listing`: an assembler listing, so that breakpoints can be set on its lines and execution
+//!   is shown in it. Each line starting with a four-digit hexadecimal address, optionally after
+//!   a line number, is the source of the code at that address.
+//! - `symbols`: a file of `name value` lines, like `divmod: equ $0123` or `divmod = 0x0123`,
+//!   used to name stack frames and for function breakpoints
+//! - `stopOnEntry`: stop before the first instruction is executed
+//!
+//! Numbers, in `loadAddress`, symbol files and breakpoints, are hexadecimal, as they are for
+//! `--load-address`, with an optional `0x`, `$` or `#` prefix or `h` suffix.
+//!
+//! Anything the program writes to port 0 is shown as output.
+//! Stepping over and out of subroutines, and the call stack, follow `Z80::call_stack`.
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::formats;
+use crate::ops::{Reg16, Reg8, StatusFlag};
+use crate::z80::io::{ChannelOutput, Ports};
+use crate::z80::Z80;
+
+/// The only thread
+const THREAD_ID: u64 = 1;
+
+/// The `variablesReference` of the registers
+const REGISTERS: u64 = 1;
+/// The `variablesReference` of the flags
+const FLAGS: u64 = 2;
+
+/// The number of instructions executed between checks for requests from the editor
+const POLL_INTERVAL: usize = 1024;
+
+/// Serve a single debugging session, reading requests from `input` and writing responses and
+/// events to `output`. Returns once the editor disconnects.
+pub fn serve<R, W>(input: R, mut output: W) -> io::Result<()>
+where
+    R: BufRead + Send + 'static,
+    W: Write,
+{
+    let requests = spawn_reader(input);
+    let mut adapter = DebugAdapter::default();
+    loop {
+        let request = if adapter.is_running() {
+            match requests.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match requests.recv() {
+                Ok(request) => Some(request),
+                Err(_) => return Ok(()),
+            }
+        };
+        match request {
+            Some(request) => adapter.handle(&request),
+            None => adapter.resume(POLL_INTERVAL),
+        }
+        for message in adapter.take_messages() {
+            write_message(&mut output, &message)?;
+        }
+        if adapter.is_finished() {
+            return Ok(());
+        }
+    }
+}
+
+/// Read messages on another thread, so they can be checked for while the program is running
+fn spawn_reader<R: BufRead + Send + 'static>(mut input: R) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        match read_message(&mut input) {
+            Ok(Some(message)) => {
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Ignoring invalid DAP message: {}", e)
+            }
+            Ok(None) | Err(_) => return,
+        }
+    });
+    receiver
+}
+
+/// How far a resumed program should run
+#[derive(Debug, Clone, Copy)]
+struct Resume {
+    /// Stop after an instruction that leaves this many calls or fewer unreturned
+    depth: Option<usize>,
+}
+
+/// DebugAdapter translates DAP requests into operations on a `Z80`,
+/// queueing the responses and events to be sent.
+#[derive(Default)]
+pub struct DebugAdapter {
+    z80: Z80,
+    /// Bytes written to port 0
+    output: Option<Receiver<u8>>,
+    listing: Option<Listing>,
+    symbols: Symbols,
+    line_breakpoints: BTreeSet<u16>,
+    function_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+    stop_on_entry: bool,
+    running: Option<Resume>,
+    finished: bool,
+    seq: u64,
+    messages: Vec<Value>,
+}
+
+impl DebugAdapter {
+    /// Whether the program has been resumed, and `resume` should be called
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Whether the editor has disconnected
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The responses and events to send, numbered in order
+    pub fn take_messages(&mut self) -> Vec<Value> {
+        let mut messages = std::mem::take(&mut self.messages);
+        for message in &mut messages {
+            self.seq += 1;
+            message["seq"] = json!(self.seq);
+        }
+        messages
+    }
+
+    /// Handle a single request
+    pub fn handle(&mut self, request: &Value) {
+        let command = request["command"].as_str().unwrap_or_default();
+        let args = &request["arguments"];
+        // Events sent while handling a request follow its response
+        let at = self.messages.len();
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stop("entry");
+                } else {
+                    self.start(Resume { depth: None });
+                }
+                Ok(Value::Null)
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "Z80" }] })),
+            "stackTrace" => Ok(self.stack_trace(args)),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(args),
+            "readMemory" => self.read_memory(args),
+            "continue" => {
+                self.start(Resume { depth: None });
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.start(Resume {
+                    depth: Some(self.z80.call_depth()),
+                });
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                self.start(Resume {
+                    depth: Some(usize::MAX),
+                });
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                self.start(Resume {
+                    depth: self.z80.call_depth().checked_sub(1),
+                });
+                Ok(Value::Null)
+            }
+            "pause" => {
+                if self.is_running() {
+                    self.stop("pause");
+                }
+                Ok(Value::Null)
+            }
+            "terminate" => {
+                self.running = None;
+                self.event("terminated", Value::Null);
+                Ok(Value::Null)
+            }
+            "disconnect" => {
+                self.finished = true;
+                Ok(Value::Null)
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.messages.insert(at, response);
+    }
+
+    /// Execute up to `budget` instructions of a resumed program,
+    /// stopping if it halts, hits a breakpoint or finishes a step
+    pub fn resume(&mut self, budget: usize) {
+        if let Some(resume) = self.running {
+            for _ in 0..budget {
+                if let Some(reason) = self.step(resume) {
+                    self.stop(reason);
+                    break;
+                }
+            }
+            self.send_output();
+        }
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.messages.push(message);
+    }
+
+    fn send_output(&mut self) {
+        let bytes: Vec<u8> = match self.output {
+            Some(ref output) => output.try_iter().collect(),
+            None => return,
+        };
+        if !bytes.is_empty() {
+            let output = String::from_utf8_lossy(&bytes);
+            self.event("output", json!({ "category": "stdout", "output": output }));
+        }
+    }
+
+    fn start(&mut self, resume: Resume) {
+        if self.z80.is_halted() {
+            self.running = None;
+            self.event("exited", json!({ "exitCode": 0 }));
+            self.event("terminated", Value::Null);
+        } else {
+            self.running = Some(resume);
+        }
+    }
+
+    fn stop(&mut self, reason: &str) {
+        self.running = None;
+        self.send_output();
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if reason == "halt" {
+            body["description"] = json!("Halted");
+        } else if reason == "exception" {
+            body["description"] = json!("Invalid instruction");
+        }
+        self.event("stopped", body);
+    }
+
+    /// Execute a single instruction, returning why the program should stop, if it should
+    fn step(&mut self, resume: Resume) -> Option<&'static str> {
+        let z80 = &mut self.z80;
+        if panic::catch_unwind(AssertUnwindSafe(|| z80.step())).is_err() {
+            return Some("exception");
+        }
+
+        let pc = self.z80.registers.get_pc();
+        if self.z80.is_halted() {
+            Some("halt")
+        } else if self.line_breakpoints.contains(&pc) {
+            Some("breakpoint")
+        } else if self.function_breakpoints.contains(&pc) {
+            Some("function breakpoint")
+        } else if self.instruction_breakpoints.contains(&pc) {
+            Some("instruction breakpoint")
+        } else if resume
+            .depth
+            .is_some_and(|depth| self.z80.call_depth() <= depth)
+        {
+            Some("step")
+        } else {
+            None
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("No program to launch")?;
+        let load_address = match args["loadAddress"] {
+            Value::Null => Some(0),
+            Value::Number(ref n) => n.as_u64().and_then(|n| u16::try_from(n).ok()),
+            Value::String(ref s) => parse_number(s),
+            _ => None,
+        }
+        .ok_or("Invalid loadAddress")?;
+
+        let data = fs::read(program).map_err(|e| format!("{}: {}", program, e))?;
+        let mut z80 = Z80::default();
+        formats::load(&mut z80, program, load_address, &data)
+            .map_err(|e| format!("{}: {}", program, e))?;
+        let (sender, receiver) = mpsc::channel();
+        z80.connect(Ports::low(0), Box::new(ChannelOutput::new(sender)));
+
+        if let Some(path) = args["listing"].as_str() {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            self.listing = Some(Listing::parse(canonical(path), &text));
+        }
+        if let Some(path) = args["symbols"].as_str() {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            self.symbols = Symbols::parse(&text);
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.z80 = z80;
+        self.output = Some(receiver);
+        self.event("initialized", Value::Null);
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = canonical(args["source"]["path"].as_str().unwrap_or_default());
+        let listing = self.listing.as_ref().filter(|l| l.path == path);
+        if listing.is_some() {
+            self.line_breakpoints.clear();
+        }
+        let mut breakpoints = vec![];
+        for requested in array(&args["breakpoints"]) {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            // Breakpoints on lines without code move to the next line with some
+            breakpoints.push(match listing.and_then(|l| l.line_at_or_after(line)) {
+                Some((line, addr)) => {
+                    self.line_breakpoints.insert(addr);
+                    verified(addr, Some(line))
+                }
+                None => json!({ "verified": false, "line": line, "message": "No code here" }),
+            });
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, args: &Value) -> Value {
+        self.function_breakpoints.clear();
+        let mut breakpoints = vec![];
+        for requested in array(&args["breakpoints"]) {
+            let name = requested["name"].as_str().unwrap_or_default();
+            breakpoints.push(
+                match self.symbols.lookup(name).or_else(|| parse_number(name)) {
+                    Some(addr) => {
+                        self.function_breakpoints.insert(addr);
+                        verified(addr, self.line(addr))
+                    }
+                    None => json!({ "verified": false, "message": "Unknown symbol" }),
+                },
+            );
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = vec![];
+        for requested in array(&args["breakpoints"]) {
+            let addr = requested["instructionReference"]
+                .as_str()
+                .and_then(parse_number)
+                .map(|addr| addr.wrapping_add(requested["offset"].as_i64().unwrap_or(0) as u16));
+            breakpoints.push(match addr {
+                Some(addr) => {
+                    self.instruction_breakpoints.insert(addr);
+                    verified(addr, self.line(addr))
+                }
+                None => json!({ "verified": false, "message": "Invalid address" }),
+            });
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    /// The line of the listing the code at `addr` came from
+    fn line(&self, addr: u16) -> Option<usize> {
+        self.listing.as_ref()?.addresses.get(&addr).copied()
+    }
+
+    /// The current position, followed by each call that hasn't returned, innermost first
+    fn stack_trace(&self, args: &Value) -> Value {
+        let pcs: Vec<u16> = iter::once(self.z80.registers.get_pc())
+            .chain(self.z80.call_stack().iter().rev().map(|frame| frame.call))
+            .collect();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => pcs.len(),
+        };
+        let frames: Vec<Value> = pcs
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(id, &pc)| self.frame(id, pc))
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": pcs.len() })
+    }
+
+    fn frame(&self, id: usize, pc: u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": self.symbols.describe(pc),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": reference(pc),
+        });
+        if let (Some(listing), Some(line)) = (&self.listing, self.line(pc)) {
+            let name = listing.path.file_name().map(|n| n.to_string_lossy());
+            frame["source"] = json!({ "name": name, "path": listing.path });
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let regs = &self.z80.registers;
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(REGISTERS) => {
+                let reg8 = [
+                    ("A", Reg8::A),
+                    ("F", Reg8::F),
+                    ("B", Reg8::B),
+                    ("C", Reg8::C),
+                    ("D", Reg8::D),
+                    ("E", Reg8::E),
+                    ("H", Reg8::H),
+                    ("L", Reg8::L),
+                    ("I", Reg8::I),
+                    ("R", Reg8::R),
+                ];
+                let reg16 = [
+                    ("BC", Reg16::BC),
+                    ("DE", Reg16::DE),
+                    ("HL", Reg16::HL),
+                    ("IX", Reg16::IX),
+                    ("IY", Reg16::IY),
+                    ("SP", Reg16::SP),
+                    ("AF'", Reg16::AFP),
+                    ("BC'", Reg16::BCP),
+                    ("DE'", Reg16::DEP),
+                    ("HL'", Reg16::HLP),
+                ];
+                let pc = iter::once(("PC", regs.get_pc()));
+                let reg8 = reg8
+                    .iter()
+                    .map(|&(name, r)| variable(name, format!("0x{:02X}", regs.get_reg8(r))));
+                let reg16 = reg16
+                    .iter()
+                    .map(|(name, r)| (*name, regs.get_reg16(r)))
+                    .chain(pc)
+                    .map(|(name, v)| {
+                        let mut var = variable(name, reference(v));
+                        var["memoryReference"] = json!(reference(v));
+                        var
+                    });
+                reg8.chain(reg16).collect()
+            }
+            Some(FLAGS) => [
+                ("S", StatusFlag::Sign),
+                ("Z", StatusFlag::Zero),
+                ("H", StatusFlag::HalfCarry),
+                ("P/V", StatusFlag::ParityOverflow),
+                ("N", StatusFlag::AddSubtract),
+                ("C", StatusFlag::Carry),
+            ]
+            .iter()
+            .map(|(name, flag)| variable(name, u8::from(regs.get_flag(flag)).to_string()))
+            .collect(),
+            _ => return Err("Unknown variablesReference".to_string()),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let addr = args["memoryReference"]
+            .as_str()
+            .and_then(parse_number)
+            .ok_or("Invalid memoryReference")?;
+        let memory = self.z80.memory();
+        let start = i64::from(addr).saturating_add(args["offset"].as_i64().unwrap_or(0));
+        let count = args["count"].as_i64().unwrap_or(0).max(0);
+        let end = start.saturating_add(count).clamp(0, memory.len() as i64) as usize;
+        let start = start.clamp(0, memory.len() as i64) as usize;
+        let data = &memory[start..end];
+        Ok(json!({
+            "address": format!("0x{:04X}", start),
+            "data": base64(data),
+            "unreadableBytes": count as usize - data.len(),
+        }))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsFunctionBreakpoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsReadMemoryRequest": true,
+        "supportsTerminateRequest": true,
+    })
+}
+
+fn scopes() -> Value {
+    json!({ "scopes": [
+        {
+            "name": "Registers",
+            "presentationHint": "registers",
+            "variablesReference": REGISTERS,
+            "expensive": false,
+        },
+        { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+    ] })
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn verified(addr: u16, line: Option<usize>) -> Value {
+    let mut breakpoint = json!({ "verified": true, "instructionReference": reference(addr) });
+    if let Some(line) = line {
+        breakpoint["line"] = json!(line);
+    }
+    breakpoint
+}
+
+/// The way addresses are given to the editor, as memory and instruction references
+fn reference(addr: u16) -> String {
+    format!("0x{:04X}", addr)
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Paths are compared after resolving them, where possible
+fn canonical(path: &str) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| Path::new(path).to_path_buf())
+}
+
+/// Parse an address or value, in hexadecimal with an optional `0x`, `$` or `#` prefix, or `h` suffix
+fn parse_number(s: &str) -> Option<u16> {
+    let digits = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        hex
+    } else if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix('#')) {
+        hex
+    } else {
+        s.strip_suffix('h')
+            .or_else(|| s.strip_suffix('H'))
+            .unwrap_or(s)
+    };
+    u32::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|n| u16::try_from(n).ok())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3F]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// An assembler listing, mapping its lines to addresses
+#[derive(Debug, Default)]
+struct Listing {
+    path: PathBuf,
+    /// The address of each line with one, by line number (counting from 1)
+    lines: BTreeMap<usize, u16>,
+    /// The first line with each address
+    addresses: BTreeMap<u16, usize>,
+}
+
+impl Listing {
+    fn parse(path: PathBuf, text: &str) -> Self {
+        let mut listing = Listing {
+            path,
+            ..Default::default()
+        };
+        for (i, line) in text.lines().enumerate() {
+            if let Some(addr) = listing_address(line) {
+                listing.lines.insert(i + 1, addr);
+                listing.addresses.entry(addr).or_insert(i + 1);
+            }
+        }
+        listing
+    }
+
+    /// The first line at or after `line` with an address, and that address
+    fn line_at_or_after(&self, line: usize) -> Option<(usize, u16)> {
+        self.lines.range(line..).next().map(|(&l, &a)| (l, a))
+    }
+}
+
+/// The address at the start of a listing line, if it has one
+fn listing_address(line: &str) -> Option<u16> {
+    let address = |field: &str| {
+        let field = field.trim_end_matches(':');
+        if field.len() == 4 && field.bytes().all(|b| b.is_ascii_hexdigit()) {
+            u16::from_str_radix(field, 16).ok()
+        } else {
+            None
+        }
+    };
+    let mut fields = line.split_whitespace();
+    let first = fields.next()?;
+    let second = fields.next();
+    let line_number = first
+        .trim_end_matches('+')
+        .bytes()
+        .all(|b| b.is_ascii_digit());
+    match (address(first), second.and_then(address)) {
+        // A line number, which could also be read as an address, followed by an address
+        (Some(_), Some(addr)) if line_number => Some(addr),
+        (Some(addr), _) => Some(addr),
+        (None, addr) if line_number => addr,
+        _ => None,
+    }
+}
+
+/// Names for addresses
+#[derive(Debug, Default)]
+struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    /// Parse lines of `name value`, optionally with a `:` after the name and `equ` or `=` between.
+    /// Anything after a `;` is a comment.
+    fn parse(text: &str) -> Self {
+        let mut symbols = Symbols::default();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or_default();
+            let mut fields = line
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .filter(|f| !f.is_empty() && !f.eq_ignore_ascii_case("equ"));
+            if let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+                if let Some(addr) = parse_number(value) {
+                    let name = name.trim_end_matches(':').to_string();
+                    symbols.names.entry(addr).or_insert(name);
+                }
+            }
+        }
+        symbols
+    }
+
+    fn lookup(&self, name: &str) -> Option<u16> {
+        self.names
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(&addr, _)| addr)
+    }
+
+    /// The nearest symbol at or before `addr`, with the offset from it
+    fn describe(&self, addr: u16) -> String {
+        match self.names.range(..=addr).next_back() {
+            Some((&a, name)) if a == addr => name.clone(),
+            Some((&a, name)) => format!("{}+0x{:X}", name, addr - a),
+            None => reference(addr),
+        }
+    }
+}
+
+/// Read the next message, after its `Content-Length` header.
+/// Returns None at the end of the stream.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if let Some(length) = length {
+                let mut body = vec![0; length];
+                input.read_exact(&mut body)?;
+                return serde_json::from_slice(&body)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::examples::COUNTDOWN_BIN;
+    use std::io::Cursor;
+
+    /// CALL sub; LD A, 2; HALT; sub: LD A, 1; RET
+    const CALL: &[u8] = &[0xCD, 0x06, 0x00, 0x3E, 0x02, 0x76, 0x3E, 0x01, 0xC9];
+
+    const LISTING: &str = "1 0000 CD 06 00  main: call sub
+2 0003 3E 02           ld a, 2
+3 0005 76              halt
+4                ; the subroutine
+5 0006 3E 01     sub:  ld a, 1
+6 0008 C9              ret
+";
+
+    const SYMBOLS: &str = "main: equ $0000\nsub = 0x0006 ; the subroutine\n";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zeerust-dap-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Handle a request, returning its response's body and anything else sent
+    fn request(adapter: &mut DebugAdapter, command: &str, arguments: Value) -> (Value, Vec<Value>) {
+        adapter.handle(
+            &json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }),
+        );
+        while adapter.is_running() {
+            adapter.resume(POLL_INTERVAL);
+        }
+        let mut messages = adapter.take_messages().into_iter();
+        let response = messages.next().unwrap();
+        assert_eq!(response["command"], command);
+        assert_eq!(response["success"], true, "{}", response);
+        (response["body"].clone(), messages.collect())
+    }
+
+    fn events(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|m| m["event"].as_str().unwrap())
+            .collect()
+    }
+
+    fn stopped(messages: &[Value]) -> &str {
+        let stop = messages.iter().find(|m| m["event"] == "stopped").unwrap();
+        stop["body"]["reason"].as_str().unwrap()
+    }
+
+    fn launch(name: &str, program: &[u8]) -> (DebugAdapter, PathBuf) {
+        let dir = temp_dir(name);
+        fs::write(dir.join("call.bin"), program).unwrap();
+        fs::write(dir.join("call.lst"), LISTING).unwrap();
+        fs::write(dir.join("call.sym"), SYMBOLS).unwrap();
+
+        let mut adapter = DebugAdapter::default();
+        request(
+            &mut adapter,
+            "initialize",
+            json!({ "adapterID": "zeerust" }),
+        );
+        let (_, messages) = request(
+            &mut adapter,
+            "launch",
+            json!({
+                "program": dir.join("call.bin"),
+                "listing": dir.join("call.lst"),
+                "symbols": dir.join("call.sym"),
+                "stopOnEntry": true,
+            }),
+        );
+        assert_eq!(events(&messages), ["initialized"]);
+        (adapter, dir)
+    }
+
+    fn pc(adapter: &DebugAdapter) -> u16 {
+        adapter.z80.registers.get_pc()
+    }
+
+    #[test]
+    fn framing() {
+        let mut input = vec![];
+        write_message(&mut input, &json!({ "seq": 1, "command": "initialize" })).unwrap();
+        input.extend(b"Content-Length: 3\r\n\r\n{{{");
+        write_message(&mut input, &json!({ "seq": 2, "command": "disconnect" })).unwrap();
+        let mut cursor = Cursor::new(input.clone());
+        assert_eq!(read_message(&mut cursor).unwrap().unwrap()["seq"], 1);
+        assert!(read_message(&mut cursor).is_err());
+        assert_eq!(read_message(&mut cursor).unwrap().unwrap()["seq"], 2);
+        assert!(read_message(&mut cursor).unwrap().is_none());
+
+        // The invalid message is skipped, and the session ends with the disconnect
+        let mut output = vec![];
+        serve(Cursor::new(input), &mut output).unwrap();
+        let mut output = Cursor::new(output);
+        let initialize = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(initialize["seq"], 1);
+        assert_eq!(initialize["request_seq"], 1);
+        assert_eq!(initialize["body"]["supportsReadMemoryRequest"], true);
+        let disconnect = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(disconnect["seq"], 2);
+        assert_eq!(disconnect["command"], "disconnect");
+        assert!(read_message(&mut output).unwrap().is_none());
+    }
+
+    #[test]
+    fn run_to_halt() {
+        let dir = temp_dir("run");
+        fs::write(dir.join("countdown.bin"), COUNTDOWN_BIN).unwrap();
+        let mut adapter = DebugAdapter::default();
+        request(
+            &mut adapter,
+            "launch",
+            json!({ "program": dir.join("countdown.bin") }),
+        );
+        let (_, messages) = request(&mut adapter, "configurationDone", Value::Null);
+        assert_eq!(stopped(&messages), "halt");
+        let output: String = messages
+            .iter()
+            .filter(|m| m["event"] == "output")
+            .map(|m| m["body"]["output"].as_str().unwrap())
+            .collect();
+        assert_eq!(output, "9\n8\n7\n6\n5\n4\n3\n2\n1\n");
+
+        let (_, messages) = request(&mut adapter, "continue", Value::Null);
+        assert_eq!(events(&messages), ["exited", "terminated"]);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping_out() {
+        let (mut adapter, dir) = launch("breakpoints", CALL);
+        let (body, _) = request(
+            &mut adapter,
+            "setBreakpoints",
+            json!({ "source": { "path": dir.join("call.lst") }, "breakpoints": [{ "line": 4 }] }),
+        );
+        // There's no code on line 4, so the breakpoint moves to the subroutine on line 5
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["line"], 5);
+
+        let (_, messages) = request(&mut adapter, "configurationDone", Value::Null);
+        assert_eq!(stopped(&messages), "entry");
+        let (_, messages) = request(&mut adapter, "continue", Value::Null);
+        assert_eq!(stopped(&messages), "breakpoint");
+        assert_eq!(pc(&adapter), 0x0006);
+
+        let (body, _) = request(&mut adapter, "stackTrace", json!({ "threadId": THREAD_ID }));
+        let frames = body["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["name"], "sub");
+        assert_eq!(frames[0]["line"], 5);
+        assert_eq!(frames[0]["instructionPointerReference"], "0x0006");
+        assert_eq!(frames[1]["name"], "main");
+        assert_eq!(frames[1]["line"], 1);
+
+        let (_, messages) = request(&mut adapter, "stepOut", json!({ "threadId": THREAD_ID }));
+        assert_eq!(stopped(&messages), "step");
+        assert_eq!(pc(&adapter), 0x0003);
+        let (body, _) = request(&mut adapter, "stackTrace", json!({ "threadId": THREAD_ID }));
+        assert_eq!(body["totalFrames"], 1);
+        assert_eq!(body["stackFrames"][0]["name"], "main+0x3");
+    }
+
+    #[test]
+    fn stepping_over_and_in() {
+        let (mut adapter, _dir) = launch("stepping", CALL);
+        request(&mut adapter, "configurationDone", Value::Null);
+        let (_, messages) = request(&mut adapter, "next", json!({ "threadId": THREAD_ID }));
+        assert_eq!(stopped(&messages), "step");
+        assert_eq!(pc(&adapter), 0x0003);
+        assert_eq!(adapter.z80.registers.get_reg8(Reg8::A), 1);
+
+        adapter.z80.registers.set_pc(0x0000);
+        request(&mut adapter, "stepIn", json!({ "threadId": THREAD_ID }));
+        assert_eq!(pc(&adapter), 0x0006);
+        request(&mut adapter, "stepIn", json!({ "threadId": THREAD_ID }));
+        assert_eq!(pc(&adapter), 0x0008);
+
+        let (body, _) = request(
+            &mut adapter,
+            "setFunctionBreakpoints",
+            json!({ "breakpoints": [{ "name": "sub" }, { "name": "nowhere" }] }),
+        );
+        assert_eq!(body["breakpoints"][0]["verified"], true);
+        assert_eq!(body["breakpoints"][0]["line"], 5);
+        assert_eq!(body["breakpoints"][1]["verified"], false);
+        // Stepping over a call still stops at breakpoints inside it
+        request(&mut adapter, "stepIn", json!({ "threadId": THREAD_ID }));
+        assert_eq!(pc(&adapter), 0x0003);
+        adapter.z80.registers.set_pc(0x0000);
+        let (_, messages) = request(&mut adapter, "next", json!({ "threadId": THREAD_ID }));
+        assert_eq!(stopped(&messages), "function breakpoint");
+        assert_eq!(pc(&adapter), 0x0006);
+    }
+
+    #[test]
+    fn variables_and_memory() {
+        let (mut adapter, _dir) = launch("variables", CALL);
+        request(&mut adapter, "configurationDone", Value::Null);
+        request(&mut adapter, "stepIn", json!({ "threadId": THREAD_ID }));
+        request(&mut adapter, "stepIn", json!({ "threadId": THREAD_ID }));
+
+        let (body, _) = request(&mut adapter, "scopes", json!({ "frameId": 0 }));
+        assert_eq!(body["scopes"][0]["variablesReference"], REGISTERS);
+        let (body, _) = request(
+            &mut adapter,
+            "variables",
+            json!({ "variablesReference": REGISTERS }),
+        );
+        let value = |name: &str| {
+            let variables = body["variables"].as_array().unwrap();
+            let variable = variables.iter().find(|v| v["name"] == name).unwrap();
+            variable["value"].as_str().unwrap().to_string()
+        };
+        assert_eq!(value("A"), "0x01");
+        assert_eq!(value("PC"), "0x0008");
+        assert_eq!(value("SP"), "0xFFFE");
+        assert_eq!(value("HL'"), "0x0000");
+
+        adapter.z80.registers.set_flag(&StatusFlag::Carry, true);
+        let (body, _) = request(
+            &mut adapter,
+            "variables",
+            json!({ "variablesReference": FLAGS }),
+        );
+        assert_eq!(body["variables"][5]["name"], "C");
+        assert_eq!(body["variables"][5]["value"], "1");
+        assert_eq!(body["variables"][1]["value"], "0");
+
+        // The return address, pushed by the CALL
+        let (body, _) = request(
+            &mut adapter,
+            "readMemory",
+            json!({ "memoryReference": "0xFFFE", "count": 4 }),
+        );
+        assert_eq!(body["address"], "0xFFFE");
+        assert_eq!(body["data"], "AwA=");
+        assert_eq!(body["unreadableBytes"], 2);
+        let (body, _) = request(
+            &mut adapter,
+            "readMemory",
+            json!({ "memoryReference": "0x0000", "offset": 3, "count": 3 }),
+        );
+        assert_eq!(body["data"], "PgJ2");
+        let (body, _) = request(
+            &mut adapter,
+            "readMemory",
+            json!({ "memoryReference": "0xFFFF", "offset": i64::MAX, "count": i64::MAX }),
+        );
+        assert_eq!(body["data"], "");
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse_number("0x8000"), Some(0x8000));
+        assert_eq!(parse_number("$12"), Some(0x12));
+        assert_eq!(parse_number("1234h"), Some(0x1234));
+        assert_eq!(parse_number("8000"), Some(0x8000));
+        assert_eq!(parse_number("10000"), None);
+        assert_eq!(parse_number("0x10000"), None);
+
+        assert_eq!(listing_address("0100 3E 01   ld a, 1"), Some(0x0100));
+        assert_eq!(listing_address("12 0100: 3E 01"), Some(0x0100));
+        assert_eq!(listing_address("1234 0100 3E 01"), Some(0x0100));
+        assert_eq!(listing_address("ABCD 3E 01"), Some(0xABCD));
+        assert_eq!(listing_address("    ld a, 1"), None);
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+}
@@ -68,7 +68,7 @@ print0:
 	add A, 0
 	ret z
 	out (0), A
-	inc H
+	inc HL
 	jp print0
 
 fizz: db "Fizz\n",0
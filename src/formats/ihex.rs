@@ -0,0 +1,159 @@
+//! Intel HEX files.
+//!
+//! Each line is a record: `:`, then a byte count, a 16-bit address, a record type,
+//! the data and a checksum, all as pairs of hex digits.
+//! Data records are loaded at their address, plus any base set by an extended segment (02)
+//! or extended linear (04) address record. A start segment (03) or start linear (05) address
+//! record sets the program counter. Loading stops at the end of file (01) record.
+use super::{hex_bytes, text_lines, Error, Image};
+use crate::z80::Z80;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Load an Intel HEX file
+/// ```
+/// use zeerust::formats::ihex;
+/// use zeerust::z80::Z80;
+///
+/// let mut z80 = Z80::default();
+/// ihex::load(&mut z80, b":020100003E01BE\n:0400000500000100F6\n:00000001FF\n").unwrap();
+/// assert_eq!(0x3E, z80.memory()[0x0100]);
+/// assert_eq!(0x0100, z80.registers.get_pc());
+/// ```
+pub fn load(z80: &mut Z80, data: &[u8]) -> Result<(), Error> {
+    let mut image = Image::default();
+    let mut base = 0u32;
+    for (n, line) in text_lines(data)? {
+        let record = match line.strip_prefix(':') {
+            Some(hex) => hex_bytes(hex, n)?,
+            None => return Err(Error::Syntax(n)),
+        };
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(Error::Syntax(n));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(Error::Checksum(n));
+        }
+
+        let addr = u16::from_be_bytes([record[1], record[2]]);
+        let payload = &record[4..record.len() - 1];
+        match (record[3], payload) {
+            (DATA, _) => image.add(base + addr as u32, payload)?,
+            (END_OF_FILE, _) => break,
+            (EXTENDED_SEGMENT_ADDRESS, &[hi, lo]) => {
+                base = (u16::from_be_bytes([hi, lo]) as u32) << 4;
+            }
+            (EXTENDED_LINEAR_ADDRESS, &[hi, lo]) => {
+                base = (u16::from_be_bytes([hi, lo]) as u32) << 16;
+            }
+            (START_SEGMENT_ADDRESS, &[cs_hi, cs_lo, ip_hi, ip_lo]) => {
+                let cs = u16::from_be_bytes([cs_hi, cs_lo]) as u32;
+                let ip = u16::from_be_bytes([ip_hi, ip_lo]) as u32;
+                image.start = Some(start_address((cs << 4) + ip)?);
+            }
+            (START_LINEAR_ADDRESS, &[b0, b1, b2, b3]) => {
+                image.start = Some(start_address(u32::from_be_bytes([b0, b1, b2, b3]))?);
+            }
+            _ => return Err(Error::Syntax(n)),
+        }
+    }
+    image.load(z80);
+    Ok(())
+}
+
+fn start_address(addr: u32) -> Result<u16, Error> {
+    if addr > 0xFFFF {
+        Err(Error::AddressOutOfRange(addr))
+    } else {
+        Ok(addr as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load_str(s: &str) -> Result<Z80, Error> {
+        let mut z80 = Z80::default();
+        load(&mut z80, s.as_bytes())?;
+        Ok(z80)
+    }
+
+    #[test]
+    fn data_records() {
+        let z80 = load_str(
+            ":0380000021003428\n\
+             :02800300767C89\n\
+             \n\
+             :00000001FF\n\
+             :01000000AA55\n",
+        )
+        .unwrap();
+        assert_eq!(
+            &[0x21, 0x00, 0x34, 0x76, 0x7C],
+            &z80.memory()[0x8000..0x8005]
+        );
+        // Records after the end of file are ignored
+        assert_eq!(0x00, z80.memory()[0x0000]);
+        assert_eq!(0x0000, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn extended_addresses() {
+        // A segment base of 0x0100 puts offset 0x0010 at 0x1010
+        let z80 = load_str(":020000020100FB\n:01001000AA45\n:0400000301000000F8\n").unwrap();
+        assert_eq!(0xAA, z80.memory()[0x1010]);
+        assert_eq!(0x1000, z80.registers.get_pc());
+
+        assert_eq!(
+            Err(Error::AddressOutOfRange(0x10010)),
+            load_str(":020000040001F9\n:01001000AA45\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::AddressOutOfRange(0x10000)),
+            load_str(":01FFFF00AA57\n:01000000BB44\n:02FFFF00AABB9B\n").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn bad_records() {
+        assert_eq!(
+            Err(Error::Syntax(1)),
+            load_str("0100000000FF\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Syntax(2)),
+            load_str(":01000000AA55\n:0G\n").map(|_| ())
+        );
+        // Wrong byte count
+        assert_eq!(
+            Err(Error::Syntax(1)),
+            load_str(":030000003E00BF\n").map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::Checksum(1)),
+            load_str(":010000003EC0\n").map(|_| ())
+        );
+        // Unknown record type
+        assert_eq!(Err(Error::Syntax(1)), load_str(":00000006FA\n").map(|_| ()));
+    }
+
+    #[test]
+    fn errors_load_nothing() {
+        let mut z80 = Z80::default();
+        assert_eq!(
+            Err(Error::Checksum(3)),
+            load(
+                &mut z80,
+                b":020100003E01BE\n:0400000500000100F6\n:010000003EC0\n"
+            )
+        );
+        assert_eq!(0x00, z80.memory()[0x0100]);
+        assert_eq!(0x0000, z80.registers.get_pc());
+    }
+}
@@ -0,0 +1,149 @@
+//! Loaders for program and snapshot file formats.
+//! Each loader places its contents directly into a `Z80`'s memory and registers.
+//! Every loader checks the whole file first, and loads nothing if it finds an error.
+//!
+//! `sna` and `z80` load ZX Spectrum snapshots.
+//! `ihex` and `srec` load Intel HEX and Motorola S-record files, as produced by most assemblers.
+
+use std::error;
+use std::fmt;
+use std::path::Path;
+
+use crate::cpu::mem::MEMORY_SIZE;
+use crate::z80::Z80;
+
+pub mod ihex;
+pub mod sna;
+pub mod srec;
+pub mod z80;
+
+/// Errors that can occur while loading a file
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The file ended before all of its contents were read
+    Truncated,
+    /// The file is not a size this format can be
+    BadSize(usize),
+    /// The file uses a version of the format that isn't supported
+    UnsupportedVersion(usize),
+    /// A compressed block of memory did not expand to the expected size
+    BadBlock,
+    /// The given line (counting from 1) of a text format could not be parsed
+    Syntax(usize),
+    /// The checksum of the given line (counting from 1) did not match its contents
+    Checksum(usize),
+    /// A record's address is beyond the end of memory
+    AddressOutOfRange(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "file is truncated"),
+            Error::BadSize(s) => write!(f, "unexpected file size {}", s),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            Error::BadBlock => write!(f, "corrupt memory block"),
+            Error::Syntax(l) => write!(f, "syntax error on line {}", l),
+            Error::Checksum(l) => write!(f, "bad checksum on line {}", l),
+            Error::AddressOutOfRange(a) => write!(f, "address {:#X} is out of range", a),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Load a program or snapshot, choosing the format from the file extension.
+/// Files in no recognised format are loaded as raw bytes at `load_address`, which is also
+/// where execution starts.
+pub fn load(z80: &mut Z80, filename: &str, load_address: u16, data: &[u8]) -> Result<(), Error> {
+    let ext = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("sna") => sna::load(z80, data),
+        Some("z80") => z80::load(z80, data),
+        Some("hex") | Some("ihx") | Some("ihex") => ihex::load(z80, data),
+        Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+            srec::load(z80, data)
+        }
+        _ => {
+            z80.load_at(load_address, data);
+            z80.registers.set_pc(load_address);
+            Ok(())
+        }
+    }
+}
+
+/// Read a little-endian u16 from the given offset
+fn le16(data: &[u8], offset: usize) -> Result<u16, Error> {
+    match data.get(offset..offset + 2) {
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+        None => Err(Error::Truncated),
+    }
+}
+
+/// Parse a string of hexadecimal digit pairs, as used by text formats.
+/// Errors refer to the given line number.
+fn hex_bytes(s: &str, line: usize) -> Result<Vec<u8>, Error> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::Syntax(line));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| Error::Syntax(line)))
+        .collect()
+}
+
+/// The lines of a text format, numbered from 1, skipping blank lines
+fn text_lines(data: &[u8]) -> Result<impl Iterator<Item = (usize, &str)>, Error> {
+    let text = std::str::from_utf8(data).map_err(|_| Error::Syntax(1))?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty()))
+}
+
+/// The contents of a text format, collected until the whole file has been read
+#[derive(Default)]
+struct Image {
+    records: Vec<(u16, Vec<u8>)>,
+    start: Option<u16>,
+}
+
+impl Image {
+    /// Check that a block of memory fits below 64K, and add it
+    fn add(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let end = addr as usize + data.len();
+        if end > MEMORY_SIZE {
+            return Err(Error::AddressOutOfRange(end as u32 - 1));
+        }
+        self.records.push((addr as u16, data.to_vec()));
+        Ok(())
+    }
+
+    /// Write every record, in order, and set the program counter if a start address was given
+    fn load(self, z80: &mut Z80) {
+        for (addr, data) in self.records {
+            z80.load_at(addr, &data);
+        }
+        if let Some(pc) = self.start {
+            z80.registers.set_pc(pc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        assert_eq!(Ok(vec![0x01, 0xAB, 0xff]), hex_bytes("01ABff", 1));
+        assert_eq!(Ok(vec![]), hex_bytes("", 1));
+        assert_eq!(Err(Error::Syntax(3)), hex_bytes("ABC", 3));
+        assert_eq!(Err(Error::Syntax(4)), hex_bytes("0G", 4));
+        assert_eq!(Err(Error::Syntax(5)), hex_bytes("+1", 5));
+    }
+}
@@ -0,0 +1,170 @@
+//! ZX Spectrum `.sna` snapshots.
+//!
+//! A 48K snapshot is a 27-byte header followed by the 48K of RAM from 0x4000 to 0xFFFF.
+//! The program counter isn't stored in the header: it is popped from the stack.
+//!
+//! A 128K snapshot adds the program counter and the paging state after the 48K that is
+//! currently paged in, then the remaining RAM banks. Zeerust doesn't emulate memory paging,
+//! so only the banks paged into 0x4000-0xFFFF are loaded.
+//!
+//! The border colour is not emulated, and is ignored.
+use super::{le16, Error};
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+
+const HEADER_SIZE: usize = 27;
+const RAM_START: u16 = 0x4000;
+const RAM_SIZE: usize = 48 * 1024;
+const BANK_SIZE: usize = 16 * 1024;
+
+/// The size of a 48K snapshot
+pub const SIZE_48K: usize = HEADER_SIZE + RAM_SIZE;
+/// The size of a 128K snapshot with 5 further banks
+pub const SIZE_128K: usize = SIZE_48K + 4 + 5 * BANK_SIZE;
+/// The size of a 128K snapshot where the paged bank is repeated, giving 6 further banks
+pub const SIZE_128K_LONG: usize = SIZE_48K + 4 + 6 * BANK_SIZE;
+
+/// Load a 48K or 128K `.sna` snapshot.
+/// The whole file is checked before anything is loaded, so an error leaves `z80` unchanged.
+pub fn load(z80: &mut Z80, data: &[u8]) -> Result<(), Error> {
+    let is_128k = match data.len() {
+        SIZE_48K => false,
+        SIZE_128K | SIZE_128K_LONG => true,
+        size => return Err(Error::BadSize(size)),
+    };
+    let iy = le16(data, 15)?;
+    let ix = le16(data, 17)?;
+    let mut sp = le16(data, 23)?;
+    let pc = if is_128k {
+        Some(le16(data, SIZE_48K)?)
+    } else {
+        None
+    };
+
+    let regs = &mut z80.registers;
+    // Register pairs are stored little-endian, i.e. low register first
+    let pairs = [
+        (1, Reg8::LP, Reg8::HP),
+        (3, Reg8::EP, Reg8::DP),
+        (5, Reg8::CP, Reg8::BP),
+        (7, Reg8::FP, Reg8::AP),
+        (9, Reg8::L, Reg8::H),
+        (11, Reg8::E, Reg8::D),
+        (13, Reg8::C, Reg8::B),
+        (21, Reg8::F, Reg8::A),
+    ];
+    for (offset, lo, hi) in &pairs {
+        regs.set_reg8(*lo, data[*offset]);
+        regs.set_reg8(*hi, data[offset + 1]);
+    }
+    regs.set_reg8(Reg8::I, data[0]);
+    regs.set_reg8(Reg8::R, data[20]);
+    // Bit 2 holds IFF2; IFF1 is restored from it by the RETN that ends the snapshot's NMI
+    let iff = data[19] & 0b100 != 0;
+    regs.set_iff(iff, iff);
+    regs.set_im(data[25] & 0b11);
+    regs.set_reg16(&Reg16::IY, iy);
+    regs.set_reg16(&Reg16::IX, ix);
+
+    z80.load_at(RAM_START, &data[HEADER_SIZE..SIZE_48K]);
+
+    let pc = pc.unwrap_or_else(|| {
+        let mem = z80.memory();
+        let pc = u16::from_le_bytes([mem[sp as usize], mem[sp.wrapping_add(1) as usize]]);
+        sp = sp.wrapping_add(2);
+        pc
+    });
+    z80.registers.set_reg16(&Reg16::SP, sp);
+    z80.registers.set_pc(pc);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::reg::Registers;
+
+    fn header() -> Vec<u8> {
+        let mut data = vec![0; SIZE_48K];
+        data[..HEADER_SIZE].copy_from_slice(&[
+            0x3F, // I
+            0x11, 0x22, // HL'
+            0x33, 0x44, // DE'
+            0x55, 0x66, // BC'
+            0x77, 0x88, // AF'
+            0x99, 0xAA, // HL
+            0xBB, 0xCC, // DE
+            0xDD, 0xEE, // BC
+            0x3A, 0x5C, // IY
+            0x34, 0x12, // IX
+            0x04, // IFF2
+            0x00, // R
+            0x44, 0x55, // AF
+            0x00, 0x80, // SP
+            0x01, // IM
+            0x07, // border
+        ]);
+        data
+    }
+
+    #[test]
+    fn load_48k() {
+        let mut data = header();
+        // The program counter, on the stack at 0x8000
+        data[HEADER_SIZE + 0x4000] = 0xCD;
+        data[HEADER_SIZE + 0x4001] = 0xAB;
+        data[SIZE_48K - 1] = 0xFF;
+
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        let regs = &z80.registers;
+
+        assert_eq!(0x55, regs.get_reg8(Reg8::A));
+        assert_eq!(0x44, regs.get_reg8(Reg8::F));
+        assert_eq!(0xEE, regs.get_reg8(Reg8::B));
+        assert_eq!(0xDD, regs.get_reg8(Reg8::C));
+        assert_eq!(0xCC, regs.get_reg8(Reg8::D));
+        assert_eq!(0xBB, regs.get_reg8(Reg8::E));
+        assert_eq!(0xAA, regs.get_reg8(Reg8::H));
+        assert_eq!(0x99, regs.get_reg8(Reg8::L));
+        assert_eq!(0x88, regs.get_reg8(Reg8::AP));
+        assert_eq!(0x77, regs.get_reg8(Reg8::FP));
+        assert_eq!(0x22, regs.get_reg8(Reg8::HP));
+        assert_eq!(0x11, regs.get_reg8(Reg8::LP));
+        assert_eq!(0x5C3A, regs.get_reg16(&Reg16::IY));
+        assert_eq!(0x1234, regs.get_reg16(&Reg16::IX));
+        assert_eq!(0x3F, regs.get_reg8(Reg8::I));
+        assert_eq!((true, true), regs.get_iff());
+        assert_eq!(1, regs.get_im());
+        assert_eq!(0x8002, regs.get_reg16(&Reg16::SP));
+        assert_eq!(0xABCD, regs.get_pc());
+        assert_eq!(0xFF, z80.memory()[0xFFFF]);
+        assert_eq!(0x00, z80.memory()[0x3FFF]);
+    }
+
+    #[test]
+    fn load_128k() {
+        let mut data = header();
+        data.extend_from_slice(&[0x00, 0x60, 0x00, 0x00]);
+        data.resize(SIZE_128K, 0xEE);
+
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        assert_eq!(0x6000, z80.registers.get_pc());
+        assert_eq!(0x8000, z80.registers.get_reg16(&Reg16::SP));
+        assert_eq!(0x00, z80.memory()[0xFFFF]);
+    }
+
+    #[test]
+    fn bad_size() {
+        let mut z80 = Z80::default();
+        assert_eq!(Err(Error::BadSize(100)), load(&mut z80, &[0; 100]));
+
+        // A 128K snapshot missing a byte
+        let mut data = header();
+        data.resize(SIZE_128K - 1, 0xEE);
+        assert_eq!(Err(Error::BadSize(SIZE_128K - 1)), load(&mut z80, &data));
+        assert_eq!(Registers::default(), z80.registers);
+        assert_eq!(0x00, z80.memory()[RAM_START as usize]);
+    }
+}
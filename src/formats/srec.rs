@@ -0,0 +1,134 @@
+//! Motorola S-record files.
+//!
+//! Each line is a record: `S`, a type digit, then a byte count, an address, the data
+//! and a checksum, all as pairs of hex digits.
+//! S1, S2 and S3 records hold data at a 16, 24 or 32-bit address.
+//! S7, S8 and S9 records end the file, and set the program counter.
+//! Header (S0) and count (S5, S6) records are ignored.
+use super::{hex_bytes, text_lines, Error, Image};
+use crate::z80::Z80;
+
+/// Load a Motorola S-record file
+/// ```
+/// use zeerust::formats::srec;
+/// use zeerust::z80::Z80;
+///
+/// let mut z80 = Z80::default();
+/// srec::load(&mut z80, b"S10501003E01BA\nS9030100FB\n").unwrap();
+/// assert_eq!(0x3E, z80.memory()[0x0100]);
+/// assert_eq!(0x0100, z80.registers.get_pc());
+/// ```
+pub fn load(z80: &mut Z80, data: &[u8]) -> Result<(), Error> {
+    let mut image = Image::default();
+    for (n, line) in text_lines(data)? {
+        let (kind, hex) = match line.as_bytes() {
+            [b'S', kind, ..] if kind.is_ascii_digit() => (*kind, &line[2..]),
+            _ => return Err(Error::Syntax(n)),
+        };
+        let address_size = match kind {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(Error::Syntax(n)),
+        };
+
+        let record = hex_bytes(hex, n)?;
+        if record.len() < address_size + 2 || record.len() != record[0] as usize + 1 {
+            return Err(Error::Syntax(n));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+            return Err(Error::Checksum(n));
+        }
+
+        let addr = record[1..=address_size]
+            .iter()
+            .fold(0u32, |addr, b| (addr << 8) | *b as u32);
+        let payload = &record[address_size + 1..record.len() - 1];
+        match kind {
+            b'1' | b'2' | b'3' => image.add(addr, payload)?,
+            b'7' | b'8' | b'9' => {
+                if addr > 0xFFFF {
+                    return Err(Error::AddressOutOfRange(addr));
+                }
+                image.start = Some(addr as u16);
+                break;
+            }
+            _ => {}
+        }
+    }
+    image.load(z80);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load_str(s: &str) -> Result<Z80, Error> {
+        let mut z80 = Z80::default();
+        load(&mut z80, s.as_bytes())?;
+        Ok(z80)
+    }
+
+    #[test]
+    fn records() {
+        let z80 = load_str(
+            "S00600004844521B\n\
+             S106800021003424\n\
+             S20600800376FF01\n\
+             S5030002FA\n\
+             \n\
+             S8040080007B\n\
+             S1040000AA51\n",
+        )
+        .unwrap();
+        assert_eq!(
+            &[0x21, 0x00, 0x34, 0x76, 0xFF],
+            &z80.memory()[0x8000..0x8005]
+        );
+        assert_eq!(0x8000, z80.registers.get_pc());
+        // Records after the termination record are ignored
+        assert_eq!(0x00, z80.memory()[0x0000]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert_eq!(
+            Err(Error::AddressOutOfRange(0x10002)),
+            load_str("S30800010000AA00004C").map(|_| ())
+        );
+        assert_eq!(
+            Err(Error::AddressOutOfRange(0x12345)),
+            load_str("S80401234592").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn bad_records() {
+        assert_eq!(Err(Error::Syntax(1)), load_str("X1040000AA51").map(|_| ()));
+        assert_eq!(Err(Error::Syntax(1)), load_str("S4040000AA51").map(|_| ()));
+        // A multi-byte character where the type should be
+        assert_eq!(Err(Error::Syntax(1)), load_str("Sé00").map(|_| ()));
+        assert_eq!(
+            Err(Error::Syntax(2)),
+            load_str("S1040000AA51\nS1").map(|_| ())
+        );
+        // Wrong byte count
+        assert_eq!(Err(Error::Syntax(1)), load_str("S1050000AA51").map(|_| ()));
+        assert_eq!(
+            Err(Error::Checksum(1)),
+            load_str("S1040000AA52").map(|_| ())
+        );
+    }
+
+    #[test]
+    fn errors_load_nothing() {
+        let mut z80 = Z80::default();
+        assert_eq!(
+            Err(Error::AddressOutOfRange(0x12345)),
+            load(&mut z80, b"S10501003E01BA\nS80401234592\n")
+        );
+        assert_eq!(0x00, z80.memory()[0x0100]);
+        assert_eq!(0x0000, z80.registers.get_pc());
+    }
+}
@@ -0,0 +1,357 @@
+//! ZX Spectrum `.z80` snapshots, versions 1, 2 and 3.
+//!
+//! Version 1 files hold a 30-byte header followed by the 48K of RAM from 0x4000,
+//! optionally compressed. Versions 2 and 3 extend the header, and store RAM as a series of
+//! 16K pages, each optionally compressed.
+//!
+//! Compression replaces runs of five or more identical bytes (and any run of `ED`s)
+//! with `ED ED count byte`.
+//!
+//! Zeerust doesn't emulate memory paging, so for 128K machines only the banks paged into
+//! 0x4000-0xFFFF are loaded. ROM pages and the border colour are ignored.
+use super::{le16, Error};
+use crate::cpu::reg::Registers;
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+
+const V1_HEADER_SIZE: usize = 30;
+const PAGE_SIZE: usize = 16 * 1024;
+const RAM_SIZE: usize = 48 * 1024;
+const RAM_START: u16 = 0x4000;
+
+/// The lengths of the additional header in version 2 and 3 files
+const V2_HEADER_LEN: usize = 23;
+const V3_HEADER_LEN: usize = 54;
+const V3_HEADER_LEN_LONG: usize = 55;
+
+/// Memory to load, and the address it belongs at
+type Page = (u16, Vec<u8>);
+
+/// A page stored without compression has this length
+const UNCOMPRESSED: u16 = 0xFFFF;
+
+/// Load a `.z80` snapshot.
+/// The whole file is checked before anything is loaded, so an error leaves `z80` unchanged.
+pub fn load(z80: &mut Z80, data: &[u8]) -> Result<(), Error> {
+    if data.len() < V1_HEADER_SIZE {
+        return Err(Error::Truncated);
+    }
+    let (pc, pages) = match le16(data, 6)? {
+        0 => read_pages(data)?,
+        // Version 1
+        pc => (pc, vec![(RAM_START, read_ram(data)?)]),
+    };
+
+    set_registers(&mut z80.registers, data)?;
+    z80.registers.set_pc(pc);
+    for (addr, contents) in pages {
+        z80.load_at(addr, &contents);
+    }
+    Ok(())
+}
+
+/// Set every register from the version 1 header, except the program counter
+fn set_registers(regs: &mut Registers, data: &[u8]) -> Result<(), Error> {
+    regs.set_reg8(Reg8::A, data[0]);
+    regs.set_reg8(Reg8::F, data[1]);
+    // Register pairs are stored little-endian, i.e. low register first
+    let pairs = [
+        (2, Reg8::C, Reg8::B),
+        (4, Reg8::L, Reg8::H),
+        (13, Reg8::E, Reg8::D),
+        (15, Reg8::CP, Reg8::BP),
+        (17, Reg8::EP, Reg8::DP),
+        (19, Reg8::LP, Reg8::HP),
+    ];
+    for (offset, lo, hi) in &pairs {
+        regs.set_reg8(*lo, data[*offset]);
+        regs.set_reg8(*hi, data[offset + 1]);
+    }
+    regs.set_reg8(Reg8::AP, data[21]);
+    regs.set_reg8(Reg8::FP, data[22]);
+    regs.set_reg8(Reg8::I, data[10]);
+    // Bit 7 of R is stored separately, in bit 0 of the flags byte
+    regs.set_reg8(Reg8::R, (data[11] & 0x7F) | ((data[12] & 0b1) << 7));
+    regs.set_iff(data[27] != 0, data[28] != 0);
+    regs.set_im(data[29] & 0b11);
+    regs.set_reg16(&Reg16::SP, le16(data, 8)?);
+    regs.set_reg16(&Reg16::IY, le16(data, 23)?);
+    regs.set_reg16(&Reg16::IX, le16(data, 25)?);
+    Ok(())
+}
+
+/// The 48K of RAM following a version 1 header
+fn read_ram(data: &[u8]) -> Result<Vec<u8>, Error> {
+    // For compatibility, a flag byte of 255 must be treated as 1
+    let flags = if data[12] == 0xFF { 1 } else { data[12] };
+    let body = &data[V1_HEADER_SIZE..];
+    if flags & 0b0010_0000 != 0 {
+        decompress(strip_end_marker(body), RAM_SIZE)
+    } else {
+        Ok(body.get(..RAM_SIZE).ok_or(Error::Truncated)?.to_vec())
+    }
+}
+
+/// The program counter and the pages to load from a version 2 or 3 file,
+/// with the addresses they belong at
+fn read_pages(data: &[u8]) -> Result<(u16, Vec<Page>), Error> {
+    let extra = le16(data, V1_HEADER_SIZE)? as usize;
+    let is_128k = match extra {
+        V2_HEADER_LEN => data.get(34).is_some_and(|hw| *hw >= 3),
+        V3_HEADER_LEN | V3_HEADER_LEN_LONG => data.get(34).is_some_and(|hw| *hw >= 4),
+        v => return Err(Error::UnsupportedVersion(v)),
+    };
+    let pc = le16(data, 32)?;
+    let paged_bank = *data.get(35).ok_or(Error::Truncated)? & 0b111;
+
+    let mut pages = vec![];
+    let mut offset = V1_HEADER_SIZE + 2 + extra;
+    while offset < data.len() {
+        let len = le16(data, offset)?;
+        let page = *data.get(offset + 2).ok_or(Error::Truncated)?;
+        offset += 3;
+
+        let (contents, consumed) = if len == UNCOMPRESSED {
+            let raw = data
+                .get(offset..offset + PAGE_SIZE)
+                .ok_or(Error::Truncated)?;
+            (raw.to_vec(), PAGE_SIZE)
+        } else {
+            let len = len as usize;
+            let raw = data.get(offset..offset + len).ok_or(Error::Truncated)?;
+            (decompress(raw, PAGE_SIZE)?, len)
+        };
+        offset += consumed;
+
+        if let Some(addr) = page_address(page, is_128k, paged_bank) {
+            pages.push((addr, contents));
+        }
+    }
+    Ok((pc, pages))
+}
+
+/// Where a page of a version 2 or 3 file belongs in memory, if it is loaded at all
+fn page_address(page: u8, is_128k: bool, paged_bank: u8) -> Option<u16> {
+    if is_128k {
+        // Pages 3-10 hold RAM banks 0-7
+        match page.checked_sub(3)? {
+            5 => Some(0x4000),
+            2 => Some(0x8000),
+            bank if bank == paged_bank => Some(0xC000),
+            _ => None,
+        }
+    } else {
+        match page {
+            8 => Some(0x4000),
+            4 => Some(0x8000),
+            5 => Some(0xC000),
+            _ => None,
+        }
+    }
+}
+
+/// Version 1 compressed data ends with `00 ED ED 00`
+fn strip_end_marker(data: &[u8]) -> &[u8] {
+    if data.ends_with(&[0x00, 0xED, 0xED, 0x00]) {
+        &data[..data.len() - 4]
+    } else {
+        data
+    }
+}
+
+/// Expand `ED ED count byte` sequences, which must produce exactly `size` bytes
+fn decompress(data: &[u8], size: usize) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(size);
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(&[0xED, 0xED]) {
+            let run = data.get(i + 2..i + 4).ok_or(Error::BadBlock)?;
+            out.extend(std::iter::repeat_n(run[1], run[0] as usize));
+            i += 4;
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    if out.len() == size {
+        Ok(out)
+    } else {
+        Err(Error::BadBlock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v1_header(pc: u16, compressed: bool) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&[0x12, 0x34]); // A, F
+        data.extend_from_slice(&[0x56, 0x78]); // BC
+        data.extend_from_slice(&[0x9A, 0xBC]); // HL
+        data.extend_from_slice(&pc.to_le_bytes()); // PC
+        data.extend_from_slice(&[0xF0, 0xFF]); // SP
+        data.extend_from_slice(&[0x3F, 0x05]); // I, R
+        data.push(if compressed { 0x21 } else { 0x01 }); // flags, including bit 7 of R
+        data.extend_from_slice(&[0xDE, 0xF0]); // DE
+        data.extend_from_slice(&[0x01, 0x02]); // BC'
+        data.extend_from_slice(&[0x03, 0x04]); // DE'
+        data.extend_from_slice(&[0x05, 0x06]); // HL'
+        data.extend_from_slice(&[0x07, 0x08]); // A', F'
+        data.extend_from_slice(&[0x09, 0x0A]); // IY
+        data.extend_from_slice(&[0x0B, 0x0C]); // IX
+        data.extend_from_slice(&[0x01, 0x01]); // IFF1, IFF2
+        data.push(0x01); // flags
+        data
+    }
+
+    #[test]
+    fn decompress_runs() {
+        assert_eq!(
+            Ok(vec![1, 2, 2, 2, 2, 2, 2, 0xED, 3]),
+            decompress(&[1, 0xED, 0xED, 6, 2, 0xED, 3], 9)
+        );
+        assert_eq!(Err(Error::BadBlock), decompress(&[1, 2], 3));
+        assert_eq!(Err(Error::BadBlock), decompress(&[0xED, 0xED, 1], 1));
+    }
+
+    #[test]
+    fn load_v1_uncompressed() {
+        let mut data = v1_header(0x8000, false);
+        data.extend(vec![0xAA; RAM_SIZE]);
+
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        let regs = &z80.registers;
+        assert_eq!(0x12, regs.get_reg8(Reg8::A));
+        assert_eq!(0x34, regs.get_reg8(Reg8::F));
+        assert_eq!(0x78, regs.get_reg8(Reg8::B));
+        assert_eq!(0x56, regs.get_reg8(Reg8::C));
+        assert_eq!(0xF0, regs.get_reg8(Reg8::D));
+        assert_eq!(0xDE, regs.get_reg8(Reg8::E));
+        assert_eq!(0xBC, regs.get_reg8(Reg8::H));
+        assert_eq!(0x9A, regs.get_reg8(Reg8::L));
+        assert_eq!(0x02, regs.get_reg8(Reg8::BP));
+        assert_eq!(0x07, regs.get_reg8(Reg8::AP));
+        assert_eq!(0x08, regs.get_reg8(Reg8::FP));
+        assert_eq!(0x06, regs.get_reg8(Reg8::HP));
+        assert_eq!(0x0A09, regs.get_reg16(&Reg16::IY));
+        assert_eq!(0x0C0B, regs.get_reg16(&Reg16::IX));
+        assert_eq!(0x3F, regs.get_reg8(Reg8::I));
+        assert_eq!(0x85, regs.get_reg8(Reg8::R));
+        assert_eq!((true, true), regs.get_iff());
+        assert_eq!(1, regs.get_im());
+        assert_eq!(0xFFF0, regs.get_reg16(&Reg16::SP));
+        assert_eq!(0x8000, regs.get_pc());
+        assert_eq!(0x00, z80.memory()[0x3FFF]);
+        assert_eq!(0xAA, z80.memory()[0x4000]);
+        assert_eq!(0xAA, z80.memory()[0xFFFF]);
+    }
+
+    #[test]
+    fn load_v1_compressed() {
+        let mut data = v1_header(0x8000, true);
+        // 48K is 192 runs of 255 bytes, one of 191 bytes, and a final literal byte
+        for _ in 0..192 {
+            data.extend_from_slice(&[0xED, 0xED, 0xFF, 0x11]);
+        }
+        data.extend_from_slice(&[0xED, 0xED, 0xBF, 0x11, 0x22]);
+        data.extend_from_slice(&[0x00, 0xED, 0xED, 0x00]);
+
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        assert_eq!(0x11, z80.memory()[0x4000]);
+        assert_eq!(0x11, z80.memory()[0xFFFE]);
+        assert_eq!(0x22, z80.memory()[0xFFFF]);
+
+        // One byte too many
+        data.insert(data.len() - 4, 0x33);
+        assert_eq!(Err(Error::BadBlock), load(&mut z80, &data));
+    }
+
+    fn v3_file(hardware: u8, pages: &[(u8, u8)]) -> Vec<u8> {
+        let mut data = v1_header(0, false);
+        let mut extra = vec![0; V3_HEADER_LEN];
+        extra[0..2].copy_from_slice(&0x1234_u16.to_le_bytes()); // PC
+        extra[2] = hardware;
+        extra[3] = 0x07; // 128K paging: bank 7 at 0xC000
+        data.extend_from_slice(&(V3_HEADER_LEN as u16).to_le_bytes());
+        data.extend(extra);
+        for (page, fill) in pages {
+            // Compress each page as 64 runs of 0xFF and one of 0x40
+            let mut block = vec![];
+            for _ in 0..64 {
+                block.extend_from_slice(&[0xED, 0xED, 0xFF, *fill]);
+            }
+            block.extend_from_slice(&[0xED, 0xED, 0x40, *fill]);
+            data.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            data.push(*page);
+            data.extend(block);
+        }
+        data
+    }
+
+    #[test]
+    fn load_v3_48k() {
+        let data = v3_file(0, &[(4, 0x44), (5, 0x55), (8, 0x88), (0, 0x01)]);
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        assert_eq!(0x1234, z80.registers.get_pc());
+        assert_eq!(0x00, z80.memory()[0x0000]);
+        assert_eq!(0x88, z80.memory()[0x4000]);
+        assert_eq!(0x44, z80.memory()[0x8000]);
+        assert_eq!(0x55, z80.memory()[0xFFFF]);
+    }
+
+    #[test]
+    fn load_v3_128k() {
+        let data = v3_file(4, &[(3, 0x30), (5, 0x52), (8, 0x55), (10, 0x57)]);
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        assert_eq!(0x55, z80.memory()[0x4000]);
+        assert_eq!(0x52, z80.memory()[0x8000]);
+        assert_eq!(0x57, z80.memory()[0xC000]);
+    }
+
+    #[test]
+    fn load_v2_uncompressed_page() {
+        let mut data = v1_header(0, false);
+        data.extend_from_slice(&(V2_HEADER_LEN as u16).to_le_bytes());
+        data.extend(vec![0; V2_HEADER_LEN]);
+        data.extend_from_slice(&[0xFF, 0xFF, 8]);
+        data.extend(vec![0x99; PAGE_SIZE]);
+
+        let mut z80 = Z80::default();
+        load(&mut z80, &data).unwrap();
+        assert_eq!(0x99, z80.memory()[0x4000]);
+        assert_eq!(0x99, z80.memory()[0x7FFF]);
+        assert_eq!(0x00, z80.memory()[0x8000]);
+
+        data.truncate(data.len() - 1);
+        assert_eq!(Err(Error::Truncated), load(&mut z80, &data));
+    }
+
+    #[test]
+    fn errors_leave_the_z80_unchanged() {
+        // A good 48K page followed by a truncated one
+        let mut data = v3_file(0, &[(8, 0x88)]);
+        data.extend_from_slice(&[0xFF, 0xFF, 4, 0x44]);
+        let mut z80 = Z80::default();
+        assert_eq!(Err(Error::Truncated), load(&mut z80, &data));
+        assert_eq!(Registers::default(), z80.registers);
+        assert_eq!(0x00, z80.memory()[0x4000]);
+
+        let mut data = v1_header(0x1234, true);
+        data.extend_from_slice(&[0xED, 0xED, 0x01]);
+        assert_eq!(Err(Error::BadBlock), load(&mut z80, &data));
+        assert_eq!(Registers::default(), z80.registers);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = v1_header(0, false);
+        data.extend_from_slice(&[0x10, 0x00]);
+        let mut z80 = Z80::default();
+        assert_eq!(Err(Error::UnsupportedVersion(16)), load(&mut z80, &data));
+    }
+}
@@ -0,0 +1,538 @@
+//! A GDB Remote Serial Protocol stub, so a z80 can be debugged with `gdb-multiarch` or an IDE.
+//!
+//! Only a single connection on localhost is served.
+//! Registers are presented in the order used by GDB's own z80 target:
+//! AF, BC, DE, HL, SP, PC, IX, IY, AF', BC', DE', HL', IR.
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+
+/// SIGINT, reported when the debugger interrupts a running target
+const SIGINT: u8 = 2;
+/// SIGTRAP, reported after a single step or when a breakpoint is hit
+const SIGTRAP: u8 = 5;
+
+/// The number of instructions executed between checks for an interrupt from the debugger
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="data_ptr"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="ix" bitsize="16" type="data_ptr"/>
+    <reg name="iy" bitsize="16" type="data_ptr"/>
+    <reg name="af'" bitsize="16" type="int"/>
+    <reg name="bc'" bitsize="16" type="int"/>
+    <reg name="de'" bitsize="16" type="int"/>
+    <reg name="hl'" bitsize="16" type="int"/>
+    <reg name="ir" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+/// The number of registers presented to GDB
+const REGISTER_COUNT: usize = 13;
+
+/// Listen on localhost at the given port, and serve a single debugger connection.
+/// Returns once the debugger detaches, kills the target or disconnects.
+pub fn listen(z80: &mut Z80, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, addr) = listener.accept()?;
+    log::info!("GDB connected from {}", addr);
+    GdbStub::new(z80).serve(stream)
+}
+
+/// The result of handling a single packet
+#[derive(Debug, PartialEq)]
+enum Reply {
+    /// Send this packet, and wait for the next one
+    Packet(String),
+    /// Send this packet (if any), then close the connection
+    Close(Option<String>),
+}
+
+/// What was read from the connection
+#[derive(Debug, PartialEq)]
+enum Received {
+    /// A packet, without the framing or checksum
+    Packet(String),
+    /// A packet whose checksum didn't match, to be sent again
+    Corrupt,
+    Closed,
+}
+
+/// GdbStub translates GDB packets into operations on a `Z80`.
+pub struct GdbStub<'a> {
+    z80: &'a mut Z80,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(z80: &'a mut Z80) -> Self {
+        Self {
+            z80,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Serve packets from the given connection until it is closed
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let packet = match read_packet(&mut stream)? {
+                Received::Packet(packet) => packet,
+                Received::Corrupt => {
+                    stream.write_all(b"-")?;
+                    continue;
+                }
+                Received::Closed => return Ok(()),
+            };
+            stream.write_all(b"+")?;
+
+            let mut poll = stream.try_clone()?;
+            let reply = self.handle(&packet, &mut || interrupted(&mut poll));
+            match reply {
+                Reply::Packet(p) => write_packet(&mut stream, &p)?,
+                Reply::Close(p) => {
+                    if let Some(p) = p {
+                        write_packet(&mut stream, &p)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Handle a single packet (without the framing or checksum).
+    /// `interrupted` is polled periodically while the target is running.
+    fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Reply {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+        let reply = match cmd {
+            "?" => stop_reply(SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.resume(true, interrupted),
+            "c" => self.resume(false, interrupted),
+            "Z" => self.breakpoint(args, true),
+            "z" => self.breakpoint(args, false),
+            "H" => "OK".to_string(),
+            "q" => self.query(args),
+            "D" => return Reply::Close(Some("OK".to_string())),
+            "k" => return Reply::Close(None),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_addr_len(range) {
+                Some((offset, len)) => xfer_chunk(TARGET_XML, offset as usize, len),
+                None => error(EINVAL),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn get_register(&self, n: usize) -> Option<u16> {
+        let regs = &self.z80.registers;
+        if let Some((hi, lo)) = register_pair(n) {
+            return Some(u16::from_be_bytes([regs.get_reg8(hi), regs.get_reg8(lo)]));
+        }
+        Some(match n {
+            4 => regs.get_reg16(&Reg16::SP),
+            5 => regs.get_pc(),
+            6 => regs.get_reg16(&Reg16::IX),
+            7 => regs.get_reg16(&Reg16::IY),
+            _ => return None,
+        })
+    }
+
+    fn set_register(&mut self, n: usize, v: u16) -> bool {
+        let regs = &mut self.z80.registers;
+        if let Some((hi, lo)) = register_pair(n) {
+            let [h, l] = v.to_be_bytes();
+            regs.set_reg8(hi, h);
+            regs.set_reg8(lo, l);
+            return true;
+        }
+        match n {
+            4 => regs.set_reg16(&Reg16::SP, v),
+            5 => regs.set_pc(v),
+            6 => regs.set_reg16(&Reg16::IX, v),
+            7 => regs.set_reg16(&Reg16::IY, v),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT)
+            .filter_map(|n| self.get_register(n))
+            .map(|v| encode_hex(&v.to_le_bytes()))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = match decode_hex(args) {
+            Some(ref b) if b.len() == REGISTER_COUNT * 2 => b.clone(),
+            _ => return error(EINVAL),
+        };
+        for (n, v) in bytes.chunks(2).enumerate() {
+            self.set_register(n, u16::from_le_bytes([v[0], v[1]]));
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|n| self.get_register(n))
+        {
+            Some(v) => encode_hex(&v.to_le_bytes()),
+            None => error(EINVAL),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+        let v = parts.next().and_then(decode_hex);
+        match (n, v) {
+            (Some(n), Some(ref v)) if v.len() == 2 => {
+                if self.set_register(n, u16::from_le_bytes([v[0], v[1]])) {
+                    "OK".to_string()
+                } else {
+                    error(EINVAL)
+                }
+            }
+            _ => error(EINVAL),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(al) => al,
+            None => return error(EINVAL),
+        };
+        let addr = addr as usize;
+        match addr
+            .checked_add(len)
+            .and_then(|end| self.z80.memory().get(addr..end))
+        {
+            Some(bytes) => encode_hex(bytes),
+            None => error(EFAULT),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let al = parts.next().and_then(parse_addr_len);
+        let data = parts.next().and_then(decode_hex);
+        let ((addr, len), data) = match (al, data) {
+            (Some(al), Some(data)) if al.1 == data.len() => (al, data),
+            _ => return error(EINVAL),
+        };
+        if usize::from(addr) + len > self.z80.memory().len() {
+            return error(EFAULT);
+        }
+        self.z80.load_at(addr, &data);
+        "OK".to_string()
+    }
+
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        // Z0 is a software breakpoint, Z1 a hardware breakpoint.
+        // Both are implemented by checking the program counter after every step.
+        let mut parts = args.splitn(3, ',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            // Watchpoints are not supported
+            _ => String::new(),
+        }
+    }
+
+    /// Run the target until it halts, hits a breakpoint or is interrupted.
+    /// If `single` is set, only a single instruction is executed.
+    fn resume(&mut self, single: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let mut executed: usize = 0;
+        loop {
+            if self.z80.is_halted() {
+                return "W00".to_string();
+            }
+            self.z80.step();
+            if single || self.breakpoints.contains(&self.z80.registers.get_pc()) {
+                return stop_reply(SIGTRAP);
+            }
+
+            executed += 1;
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) && interrupted() {
+                return stop_reply(SIGINT);
+            }
+        }
+    }
+}
+
+/// The 8-bit registers that make up a GDB register, high byte first
+fn register_pair(n: usize) -> Option<(Reg8, Reg8)> {
+    Some(match n {
+        0 => (Reg8::A, Reg8::F),
+        1 => (Reg8::B, Reg8::C),
+        2 => (Reg8::D, Reg8::E),
+        3 => (Reg8::H, Reg8::L),
+        8 => (Reg8::AP, Reg8::FP),
+        9 => (Reg8::BP, Reg8::CP),
+        10 => (Reg8::DP, Reg8::EP),
+        11 => (Reg8::HP, Reg8::LP),
+        12 => (Reg8::I, Reg8::R),
+        _ => return None,
+    })
+}
+
+/// Invalid argument
+const EINVAL: u8 = 22;
+/// Bad address
+const EFAULT: u8 = 14;
+
+fn error(errno: u8) -> String {
+    format!("E{:02x}", errno)
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+/// Return a portion of a qXfer object, prefixed by 'm' if there is more to read, or 'l' if not.
+fn xfer_chunk(object: &str, offset: usize, len: usize) -> String {
+    let bytes = object.as_bytes();
+    let start = offset.min(bytes.len());
+    let end = offset.saturating_add(len).min(bytes.len());
+    let prefix = if end < bytes.len() { 'm' } else { 'l' };
+    format!("{}{}", prefix, String::from_utf8_lossy(&bytes[start..end]))
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Read the next `$packet#xx` from the stream, skipping acknowledgements.
+/// The checksum covers the packet as sent, before escapes are removed.
+fn read_packet<R: Read>(stream: &mut R) -> io::Result<Received> {
+    let mut byte = [0];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(Received::Closed);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+
+    let mut raw = vec![];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(Received::Closed);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        raw.push(byte[0]);
+    }
+
+    let mut sum = [0; 2];
+    stream.read_exact(&mut sum)?;
+    let expected = std::str::from_utf8(&sum)
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok());
+    if expected != Some(checksum(&raw)) {
+        log::warn!("GDB packet checksum mismatch");
+        return Ok(Received::Corrupt);
+    }
+
+    let mut data = vec![];
+    let mut bytes = raw.into_iter();
+    while let Some(b) = bytes.next() {
+        data.push(match b {
+            b'}' => bytes.next().unwrap_or(0) ^ 0x20,
+            b => b,
+        });
+    }
+    Ok(Received::Packet(
+        String::from_utf8_lossy(&data).into_owned(),
+    ))
+}
+
+fn write_packet<W: Write>(stream: &mut W, data: &str) -> io::Result<()> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for b in data.bytes() {
+        match b {
+            b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', b ^ 0x20]),
+            b => escaped.push(b),
+        }
+    }
+    write!(stream, "$")?;
+    stream.write_all(&escaped)?;
+    write!(stream, "#{:02x}", checksum(&escaped))?;
+    stream.flush()
+}
+
+/// Check whether GDB has sent an interrupt (0x03) without blocking.
+fn interrupted(stream: &mut TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0];
+    let result = matches!(stream.read(&mut byte), Ok(1) if byte[0] == 0x03);
+    let _ = stream.set_nonblocking(false);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn handle(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet, &mut || false) {
+            Reply::Packet(p) => p,
+            Reply::Close(p) => panic!("unexpected close: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut z80 = Z80::default();
+        z80.registers.set_reg8(Reg8::A, 0x12);
+        z80.registers.set_reg8(Reg8::F, 0x34);
+        z80.registers.set_pc(0x0102);
+        let mut stub = GdbStub::new(&mut z80);
+
+        let regs = handle(&mut stub, "g");
+        assert_eq!(REGISTER_COUNT * 4, regs.len());
+        assert_eq!("3412", &regs[0..4]);
+        assert_eq!("0201", &regs[20..24]);
+        assert_eq!("0201", handle(&mut stub, "p5"));
+
+        assert_eq!("OK", handle(&mut stub, "P3=cdab"));
+        assert_eq!("OK", handle(&mut stub, &format!("G{}", regs)));
+        assert_eq!(regs, handle(&mut stub, "g"));
+        assert_eq!("E16", handle(&mut stub, "p20"));
+    }
+
+    #[test]
+    fn memory() {
+        let mut z80 = Z80::default();
+        z80.load_at(0x10, &[0xAB]);
+        let mut stub = GdbStub::new(&mut z80);
+
+        assert_eq!("00ab00", handle(&mut stub, "mf,3"));
+        assert_eq!("OK", handle(&mut stub, "M10,2:cafe"));
+        assert_eq!("cafe", handle(&mut stub, "m10,2"));
+        assert_eq!("E0e", handle(&mut stub, "mffff,2"));
+        assert_eq!("E0e", handle(&mut stub, "m10,ffffffffffffffff"));
+        assert_eq!("E16", handle(&mut stub, "M10,2:ca"));
+    }
+
+    #[test]
+    fn step_and_breakpoints() {
+        let mut z80 = Z80::default();
+        // LD A, 1; LD A, 2; LD A, 3; HALT
+        z80.load(&[0x3E, 0x01, 0x3E, 0x02, 0x3E, 0x03, 0x76]);
+        let mut stub = GdbStub::new(&mut z80);
+
+        assert_eq!("S05", handle(&mut stub, "s"));
+        assert_eq!("0200", handle(&mut stub, "p5"));
+
+        assert_eq!("OK", handle(&mut stub, "Z0,4,1"));
+        assert_eq!("S05", handle(&mut stub, "c"));
+        assert_eq!("0400", handle(&mut stub, "p5"));
+
+        assert_eq!("OK", handle(&mut stub, "z0,4,1"));
+        assert_eq!("W00", handle(&mut stub, "c"));
+        assert_eq!("W00", handle(&mut stub, "s"));
+        assert_eq!(0x03, stub.z80.registers.get_reg8(Reg8::A));
+    }
+
+    #[test]
+    fn target_xml() {
+        let mut z80 = Z80::default();
+        let mut stub = GdbStub::new(&mut z80);
+
+        let head = handle(&mut stub, "qXfer:features:read:target.xml:0,10");
+        assert_eq!("m<?xml version=\"1", head);
+        let all = handle(&mut stub, "qXfer:features:read:target.xml:0,1000");
+        assert_eq!(format!("l{}", TARGET_XML), all);
+    }
+
+    #[test]
+    fn framing() {
+        let mut input: &[u8] = b"+$m0,4#fd$M0,1:}]#ee$m0,4#00";
+        let mut next = || read_packet(&mut input).unwrap();
+        assert_eq!(Received::Packet("m0,4".to_string()), next());
+        assert_eq!(Received::Packet("M0,1:}".to_string()), next());
+        assert_eq!(Received::Corrupt, next());
+        assert_eq!(Received::Closed, next());
+
+        let mut output = vec![];
+        write_packet(&mut output, "OK").unwrap();
+        assert_eq!(b"$OK#9a".to_vec(), output);
+    }
+
+    #[test]
+    fn detach() {
+        let mut z80 = Z80::default();
+        let mut stub = GdbStub::new(&mut z80);
+        assert_eq!(
+            Reply::Close(Some("OK".to_string())),
+            stub.handle("D", &mut || false)
+        );
+        assert_eq!(Reply::Close(None), stub.handle("k", &mut || false));
+    }
+}
@@ -1,11 +1,30 @@
 //! An emulator for an idealised z80 CPU.
+//!
+//! With the default `std` feature disabled, only the CPU core (`cpu`, `ops` and `z80`) and the
+//! bundled `examples` are built, for `no_std` targets with an allocator.
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate enum_display_derive;
 
+#[cfg(feature = "std")]
+pub mod cpm;
 pub mod cpu;
+#[cfg(feature = "dap")]
+pub mod dap;
 pub mod ops;
+#[cfg(feature = "std")]
+pub mod trace_diff;
 #[macro_use]
 mod assert;
 pub mod examples;
+#[cfg(feature = "std")]
+pub mod formats;
+#[cfg(feature = "std")]
+pub mod gdbstub;
+mod prelude;
 pub mod z80;
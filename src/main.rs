@@ -2,12 +2,28 @@ extern crate zeerust;
 
 use std::env;
 use std::fs::File;
-use std::io::{stdout, Read, Result, Write};
+use std::io::{stdin, stdout, BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
 
 extern crate stderrlog;
 
+use zeerust::cpm::Cpm;
+use zeerust::dap;
+use zeerust::formats;
+use zeerust::gdbstub;
+use zeerust::trace_diff;
 use zeerust::z80;
 use zeerust::z80::io;
+use zeerust::z80::trace;
+
+const USAGE: &str =
+    "usage: zeerust [--gdb <port>] [--trace <file.jsonl|file.bin>] [--load-address <hex>] <file>
+       zeerust [--trace <file.jsonl|file.bin>] --cpm <file.com> [<arguments>...]
+       zeerust trace-diff <file> <reference trace>
+       zeerust dap";
+
+/// The number of matching steps shown before a divergence in trace-diff mode
+const TRACE_DIFF_CONTEXT: usize = 5;
 
 struct StdoutOutput {}
 
@@ -17,12 +33,117 @@ impl io::OutputDevice for StdoutOutput {
     }
 }
 
+/// Command line options
+#[derive(Default)]
+struct Args {
+    filename: String,
+    gdb_port: Option<u16>,
+    trace: Option<String>,
+    /// Where to load a raw binary, and start running it
+    load_address: u16,
+    /// Compare execution against this reference trace
+    trace_diff: Option<String>,
+    /// Run a CP/M program, using the current directory as its disk
+    cpm: bool,
+    /// Arguments for the CP/M program
+    cpm_args: Vec<String>,
+    /// Serve the Debug Adapter Protocol on stdin and stdout, instead of running a file
+    dap: bool,
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    std::process::exit(1);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args::default();
+    let mut filename = None;
+    let mut argv = env::args().skip(1).peekable();
+    if argv.peek().map(String::as_str) == Some("trace-diff") {
+        argv.next();
+        args.filename = argv.next().unwrap_or_else(|| usage("Missing file to run"));
+        args.trace_diff = Some(
+            argv.next()
+                .unwrap_or_else(|| usage("Missing reference trace")),
+        );
+        if let Some(arg) = argv.next() {
+            usage(&format!("Unexpected argument {}", arg));
+        }
+        return args;
+    }
+    if argv.peek().map(String::as_str) == Some("dap") {
+        argv.next();
+        if let Some(arg) = argv.next() {
+            usage(&format!("Unexpected argument {}", arg));
+        }
+        args.dap = true;
+        return args;
+    }
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--gdb" => {
+                let port = argv
+                    .next()
+                    .unwrap_or_else(|| usage("Missing port for --gdb"));
+                let port = port
+                    .parse()
+                    .unwrap_or_else(|_| usage(&format!("Invalid port {}", port)));
+                args.gdb_port = Some(port);
+            }
+            "--trace" => {
+                let file = argv
+                    .next()
+                    .unwrap_or_else(|| usage("Missing file for --trace"));
+                args.trace = Some(file);
+            }
+            "--load-address" => {
+                let addr = argv
+                    .next()
+                    .unwrap_or_else(|| usage("Missing address for --load-address"));
+                let trimmed = addr.trim_start_matches("0x").trim_start_matches("0X");
+                args.load_address = u16::from_str_radix(trimmed, 16)
+                    .unwrap_or_else(|_| usage(&format!("Invalid address {}", addr)));
+            }
+            "--cpm" => args.cpm = true,
+            _ if filename.is_none() => filename = Some(arg),
+            _ if args.cpm => args.cpm_args.push(arg),
+            _ => usage(&format!("Unexpected argument {}", arg)),
+        }
+    }
+    if args.cpm && args.gdb_port.is_some() {
+        usage("--cpm can't be used with --gdb");
+    }
+    args.filename = filename.unwrap_or_else(|| usage("Missing file to run"));
+    args
+}
+
+/// Load a program or snapshot, reporting errors with the file name
+fn load(z80: &mut z80::Z80, filename: &str, load_address: u16, buf: &[u8]) -> Result<()> {
+    formats::load(z80, filename, load_address, buf)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", filename, e)))
+}
+
+/// Write a JSON Lines trace if the file ends in .jsonl, otherwise a binary one
+fn tracer(filename: &str) -> Result<Box<dyn trace::Tracer>> {
+    let file = BufWriter::new(File::create(filename)?);
+    let json = Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext == "jsonl" || ext == "json");
+    Ok(if json {
+        Box::new(trace::JsonLinesTracer::new(file))
+    } else {
+        Box::new(trace::BinaryTracer::new(file))
+    })
+}
+
 fn main() -> Result<()> {
-    let filename = env::args().nth(1).unwrap_or_else(|| {
-        eprintln!("Missing file to run");
-        std::process::exit(1);
-    });
-    let mut file = File::open(filename)?;
+    let args = parse_args();
+    if args.dap {
+        // The program is given by the editor, and stdout carries the protocol
+        return dap::serve(BufReader::new(stdin()), stdout());
+    }
+    let mut file = File::open(&args.filename)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf)?;
 
@@ -35,7 +156,39 @@ fn main() -> Result<()> {
 
     let mut z80 = z80::Z80::default();
     z80.install_output(0x00, Box::new(StdoutOutput {}));
-    z80.load(buf.as_slice());
-    z80.run();
+    let mut cpm = if args.cpm {
+        let mut cpm = Cpm::new(stdin(), stdout(), ".");
+        let cpm_args: Vec<&str> = args.cpm_args.iter().map(String::as_str).collect();
+        cpm.load(&mut z80, &buf, &cpm_args);
+        Some(cpm)
+    } else {
+        load(&mut z80, &args.filename, args.load_address, &buf)?;
+        None
+    };
+    if let Some(ref reference) = args.trace_diff {
+        let reference = BufReader::new(File::open(reference)?);
+        match trace_diff::diff(&mut z80, reference, TRACE_DIFF_CONTEXT)? {
+            Ok(steps) => eprintln!("{} steps matched the reference", steps),
+            Err(divergence) => {
+                eprintln!("{}", divergence);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(ref filename) = args.trace {
+        z80.set_tracer(tracer(filename)?);
+    }
+    if let Some(ref mut cpm) = cpm {
+        cpm.run(&mut z80)?;
+    } else if let Some(port) = args.gdb_port {
+        eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+        gdbstub::listen(&mut z80, port)?
+    } else {
+        z80.run();
+    }
+    if let Some(mut tracer) = z80.take_tracer() {
+        tracer.flush()?;
+    }
     Ok(())
 }
@@ -1,7 +1,9 @@
 //! This module provides the symbolic representation of all z80 instructions
 //! You can construct these yourself, or you can parse binaries using `zeerust::cpu::opcodes`.
 
-use std::fmt::Display;
+use core::fmt;
+
+use crate::prelude::*;
 
 /// Op represents a single operation.
 /// This representation (and backing implementation) is more expressive than
@@ -50,7 +52,7 @@ pub enum Op {
     /// HALT execution (until woken)
     HALT, // End execution (until woken)
 
-    /// BCD nonsense. Not implemented
+    /// Decimal Adjust Accumulator, correcting it after BCD addition or subtraction
     DAA,
 
     /// Rotate Accumulator Left, set Carry
@@ -72,6 +74,8 @@ pub enum Op {
 
     /// Shift Left
     SLA(Location8),
+    /// Shift Left, setting bit 0. Undocumented
+    SLL(Location8),
     /// Shift Right
     SRL(Location8),
     /// Shift Right, preserving 7th bit
@@ -88,6 +92,9 @@ pub enum Op {
     SET(u8, Location8),
     /// RESet b bit in location
     RES(u8, Location8),
+    /// A rotate, shift, SET or RES on (IX+d) or (IY+d) that also COPYs the result to a register.
+    /// Undocumented
+    COPY(Box<Op>, Reg8),
 
     /// INput from a peripheral
     IN(Location8, Location8),
@@ -113,40 +120,74 @@ pub enum Op {
     LD8(Location8, Location8),
     /// LoaD the given address (16-bit)
     LD16(Location16, Location16),
-    // TODO
-    // CPD,
-    // CPDR,
-    // CPI,
-    // CPIR,
-    // DI,
-    // EI,
-    // EX,
-    // EXX,
-    // IM,
-    // IN,
-    // IND,
-    // INDR,
-    // INI,
-    // INIR,
-    // LDD,
-    // LDDR,
-    // LDI,
-    // OTDR,
-    // OTIR,
-    // OUTD,
-    // OUTI,
-    // RETI,
-    // RETN,
-    // RST,
-    // SLA,
-    // SLL,
-    // SL1,
-    // SRA,
-    // SRL,
+    /// EXchange the values of two locations
+    EX(Location16, Location16),
+    /// EXchange BC, DE and HL with their shadow registers
+    EXX,
+
+    /// ADD (16-bit)
+    ADD16(Location16, Location16),
+    /// ADd including Carry (16-bit)
+    ADC16(Location16, Location16),
+    /// SuBtract including borrow (16-bit)
+    SBC16(Location16, Location16),
+    /// INCrement (16-bit). Flags are unaffected
+    INC16(Location16),
+    /// DECrement (16-bit). Flags are unaffected
+    DEC16(Location16),
+
+    /// Disable Interrupts
+    DI,
+    /// Enable Interrupts
+    EI,
+    /// set Interrupt Mode 0, 1 or 2
+    IM(u8),
+    /// ReSTart: call the given address in the zero page
+    RST(u8),
+    /// RETurn from Interrupt
+    RETI,
+    /// RETurn from Non-maskable interrupt
+    RETN,
+
+    /// LoaD (DE) from (HL), then Increment both and decrement BC
+    LDI,
+    /// LDI, Repeated until BC is zero
+    LDIR,
+    /// LoaD (DE) from (HL), then Decrement all of HL, DE and BC
+    LDD,
+    /// LDD, Repeated until BC is zero
+    LDDR,
+    /// ComPare A with (HL), then Increment HL and decrement BC
+    CPI,
+    /// CPI, Repeated until BC is zero or a match is found
+    CPIR,
+    /// ComPare A with (HL), then Decrement HL and BC
+    CPD,
+    /// CPD, Repeated until BC is zero or a match is found
+    CPDR,
+    /// INput from port (C) to (HL), then Increment HL and decrement B
+    INI,
+    /// INI, Repeated until B is zero
+    INIR,
+    /// INput from port (C) to (HL), then Decrement HL and B
+    IND,
+    /// IND, Repeated until B is zero
+    INDR,
+    /// OUTput (HL) to port (C), then Increment HL and decrement B
+    OUTI,
+    /// OUTI, Repeated until B is zero
+    OTIR,
+    /// OUTput (HL) to port (C), then Decrement HL and B
+    OUTD,
+    /// OUTD, Repeated until B is zero
+    OTDR,
+
+    /// A byte sequence that isn't a defined instruction. Executes as a NOP
+    Invalid(Vec<u8>),
 }
 
 /// 8 bit registers
-#[derive(Debug, PartialEq, Clone, Copy, Display)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Reg8 {
     A,
     F,
@@ -172,10 +213,24 @@ pub enum Reg8 {
     HP,
     /// L'
     LP,
+
+    /// The high byte of IX. Undocumented
+    IXH,
+    /// The low byte of IX. Undocumented
+    IXL,
+    /// The high byte of IY. Undocumented
+    IYH,
+    /// The low byte of IY. Undocumented
+    IYL,
+
+    /// Interrupt vector
+    I,
+    /// memory Refresh counter
+    R,
 }
 
 /// 16-bit registers
-#[derive(Debug, PartialEq, Clone, Display)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Reg16 {
     AF,
     BC,
@@ -196,6 +251,19 @@ pub enum Reg16 {
     SP,
 }
 
+// Registers display as their variant names, such as `AP` for A'
+impl fmt::Display for Reg8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Reg16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
 /// Anywhere an 8-bit value could could come from or be stored to
 #[derive(Debug, PartialEq, Clone)]
 pub enum Location8 {
@@ -207,6 +275,8 @@ pub enum Location8 {
     ImmediateIndirect(u16),
     /// A literal number
     Immediate(u8),
+    /// A location in memory, pointed to by an index register plus a displacement
+    Indexed(Reg16, i8),
 }
 
 /// Anywhere a 16-bit value could could come from or be stored to
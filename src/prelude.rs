@@ -0,0 +1,3 @@
+//! The parts of the standard prelude that come from `alloc`, so that the core builds without `std`
+pub use alloc::boxed::Box;
+pub use alloc::vec::Vec;
@@ -0,0 +1,290 @@
+//! Compare execution against a register trace produced by another emulator.
+//!
+//! The reference is a text file with one line per instruction, giving the register state
+//! *before* that instruction executes. Each line holds `NAME:HEX` or `NAME=HEX` pairs,
+//! for any of PC, AF, BC, DE, HL and SP, in any order and case. Other tokens are ignored,
+//! as are blank lines and lines starting with `#` or `;`. For example:
+//! ```text
+//! PC:0000 AF:0000 BC:0000 DE:0000 HL:0000 SP:0000
+//! PC:0002 AF:5A00 BC:0000 DE:0000 HL:0000 SP:0000
+//! ```
+//! Only the registers present on a line are compared.
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::io::{self, BufRead};
+
+use crate::ops::{Reg16, Reg8};
+use crate::z80::Z80;
+
+/// A register that can appear in a reference trace
+#[derive(Debug, PartialEq, Clone, Copy, Display)]
+pub enum Field {
+    PC,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+impl Field {
+    const ALL: [Field; 6] = [
+        Field::PC,
+        Field::AF,
+        Field::BC,
+        Field::DE,
+        Field::HL,
+        Field::SP,
+    ];
+
+    fn parse(name: &str) -> Option<Field> {
+        Self::ALL
+            .iter()
+            .find(|f| f.to_string().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    /// The value of this register in the emulator.
+    /// Pairs are assembled from their 8-bit halves, high byte first.
+    fn get(self, z80: &Z80) -> u16 {
+        let regs = &z80.registers;
+        let pair = |hi, lo| u16::from_be_bytes([regs.get_reg8(hi), regs.get_reg8(lo)]);
+        match self {
+            Field::PC => regs.get_pc(),
+            Field::AF => pair(Reg8::A, Reg8::F),
+            Field::BC => pair(Reg8::B, Reg8::C),
+            Field::DE => pair(Reg8::D, Reg8::E),
+            Field::HL => pair(Reg8::H, Reg8::L),
+            Field::SP => regs.get_reg16(&Reg16::SP),
+        }
+    }
+}
+
+/// Parse a single line of a reference trace.
+/// Returns None if the line contains no registers.
+pub fn parse_line(line: &str) -> Option<Vec<(Field, u16)>> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let fields: Vec<_> = line
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter_map(|token| {
+            let mut parts = token.splitn(2, [':', '=']);
+            let field = Field::parse(parts.next()?)?;
+            let value = u16::from_str_radix(parts.next()?, 16).ok()?;
+            Some((field, value))
+        })
+        .collect();
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields)
+    }
+}
+
+/// Format the emulator's state in the same format as a reference trace
+fn format_state(z80: &Z80) -> String {
+    Field::ALL
+        .iter()
+        .map(|f| format!("{}:{:04X}", f, f.get(z80)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A register that differed from the reference
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub field: Field,
+    pub expected: u16,
+    pub actual: u16,
+}
+
+/// Why the emulator and the reference disagree
+#[derive(Debug, PartialEq)]
+pub enum Reason {
+    /// One or more registers differed
+    Registers(Vec<Mismatch>),
+    /// The emulator halted, but the reference continued
+    Halted,
+}
+
+/// The first point where the emulator disagreed with the reference
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// The number of instructions that were executed before the divergence
+    pub step: usize,
+    /// The (1-based) line of the reference trace
+    pub line: usize,
+    pub reason: Reason,
+    /// The preceding reference lines, alongside the emulator's state at the time
+    pub context: Vec<(String, String)>,
+    /// The reference line that didn't match
+    pub expected: String,
+    /// The emulator's state when the divergence was detected
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Divergence after {} steps (reference line {}):",
+            self.step, self.line
+        )?;
+        match &self.reason {
+            Reason::Registers(mismatches) => {
+                for m in mismatches {
+                    writeln!(
+                        f,
+                        "  {}: expected {:04X}, got {:04X}",
+                        m.field, m.expected, m.actual
+                    )?;
+                }
+            }
+            Reason::Halted => writeln!(f, "  emulator halted, but the reference continues")?,
+        }
+        writeln!(f, "Context:")?;
+        for (expected, actual) in &self.context {
+            writeln!(f, "    reference: {}", expected)?;
+            writeln!(f, "    zeerust:   {}", actual)?;
+        }
+        writeln!(f, "  > reference: {}", self.expected)?;
+        write!(f, "  > zeerust:   {}", self.actual)
+    }
+}
+
+/// Step the emulator once for every line of the reference, stopping at the first divergence.
+/// Up to `context` preceding lines are kept to explain the divergence.
+/// Returns the number of steps that matched if there was no divergence.
+pub fn diff<R: BufRead>(
+    z80: &mut Z80,
+    reference: R,
+    context: usize,
+) -> io::Result<Result<usize, Divergence>> {
+    let mut history = VecDeque::with_capacity(context + 1);
+    let mut step = 0;
+
+    for (i, line) in reference.lines().enumerate() {
+        let line = line?;
+        let expected = match parse_line(&line) {
+            Some(fields) => fields,
+            None => continue,
+        };
+
+        let reason = if step > 0 && z80.is_halted() {
+            Some(Reason::Halted)
+        } else {
+            let mismatches: Vec<_> = expected
+                .iter()
+                .filter(|(field, value)| field.get(z80) != *value)
+                .map(|(field, value)| Mismatch {
+                    field: *field,
+                    expected: *value,
+                    actual: field.get(z80),
+                })
+                .collect();
+            if mismatches.is_empty() {
+                None
+            } else {
+                Some(Reason::Registers(mismatches))
+            }
+        };
+
+        let actual = format_state(z80);
+        if let Some(reason) = reason {
+            return Ok(Err(Divergence {
+                step,
+                line: i + 1,
+                reason,
+                context: history.into_iter().collect(),
+                expected: line.trim().to_string(),
+                actual,
+            }));
+        }
+
+        history.push_back((line.trim().to_string(), actual));
+        if history.len() > context {
+            history.pop_front();
+        }
+
+        z80.step();
+        step += 1;
+    }
+    Ok(Ok(step))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // LD A, 5Ah; LD B, 1; DEC B; HALT
+    const PROGRAM: &[u8] = &[0x3E, 0x5A, 0x06, 0x01, 0x05, 0x76];
+
+    fn z80() -> Z80 {
+        let mut z80 = Z80::default();
+        z80.load(PROGRAM);
+        z80
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Some(vec![
+                (Field::PC, 0x0100),
+                (Field::AF, 0xFFD7),
+                (Field::SP, 0)
+            ]),
+            parse_line("PC:0100 af=FFD7, ix:1234 sp:0000 cycles:12")
+        );
+        assert_eq!(None, parse_line(""));
+        assert_eq!(None, parse_line("# PC:0000"));
+        assert_eq!(None, parse_line("hello world"));
+    }
+
+    #[test]
+    fn matches() {
+        let reference = "PC:0000 AF:0000 BC:0000 SP:0000\n\
+                         \n\
+                         PC:0002 AF:5A00\n\
+                         PC:0004 AF:5A00 BC:0100\n\
+                         PC:0005 BC:0000\n";
+        assert_eq!(Ok(4), diff(&mut z80(), reference.as_bytes(), 2).unwrap());
+    }
+
+    #[test]
+    fn diverges() {
+        let reference = "PC:0000 AF:0000\n\
+                         PC:0002 AF:5A00\n\
+                         PC:0004 AF:5B00 BC:0100\n\
+                         PC:0005\n";
+        let divergence = diff(&mut z80(), reference.as_bytes(), 1)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(2, divergence.step);
+        assert_eq!(3, divergence.line);
+        assert_eq!(
+            Reason::Registers(vec![Mismatch {
+                field: Field::AF,
+                expected: 0x5B00,
+                actual: 0x5A00
+            }]),
+            divergence.reason
+        );
+        assert_eq!(1, divergence.context.len());
+        assert_eq!("PC:0002 AF:5A00", divergence.context[0].0);
+        assert!(divergence
+            .to_string()
+            .contains("AF: expected 5B00, got 5A00"));
+    }
+
+    #[test]
+    fn halted() {
+        let reference = "PC:0000\nPC:0002\nPC:0004\nPC:0005\nPC:0006\nPC:0007\n";
+        let divergence = diff(&mut z80(), reference.as_bytes(), 3)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(Reason::Halted, divergence.reason);
+        assert_eq!(4, divergence.step);
+    }
+}
@@ -0,0 +1,298 @@
+//! An optional execution engine that compiles straight-line code ahead of time.
+//!
+//! When enabled with `Z80::enable_block_engine`, `run` and `run_for` decode a basic block at a
+//! time: the instructions from the program counter up to and including the next one that can jump.
+//! Each instruction is compiled to a `Micro`, which has its operands resolved in advance for the
+//! most common forms, and falls back to the interpreter for the rest. The block then runs without
+//! decoding or looking anything up, until control leaves it. Each block remembers where control
+//! went when it last left, so loops don't look their blocks up either.
+//!
+//! Writing to memory that any block was compiled from discards every block, so self-modifying
+//! code behaves as it does in the interpreter. `step` runs a single instruction, so it always
+//! goes through the interpreter, as do runs that are traced or recorded in history.
+use alloc::collections::BTreeMap;
+
+use super::cache::WINDOW;
+use super::Z80;
+use crate::cpu::mem::{Memory, MEMORY_SIZE};
+use crate::cpu::opcodes;
+use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8};
+use crate::prelude::*;
+
+/// The most instructions compiled into a single block
+const MAX_BLOCK: usize = 64;
+
+/// A compiled instruction
+#[derive(Clone)]
+enum Micro {
+    /// LD r, r'
+    Copy(Reg8, Reg8),
+    /// LD r, n
+    Set(Reg8, u8),
+    /// LD r, (rr)
+    Load(Reg8, Reg16),
+    /// LD (rr), r
+    Store(Reg16, Reg8),
+    /// LD rr, nn
+    Set16(Reg16, u16),
+    /// INC rr or DEC rr
+    Step16(Reg16, u16),
+    /// JP nn
+    Jump(u16),
+    /// Anything else, run by the interpreter
+    Interpret(Op),
+}
+
+impl Micro {
+    fn compile(op: Op) -> Self {
+        match op {
+            // LD A, I and LD A, R also set flags
+            Op::LD8(Location8::Reg(dst), Location8::Reg(src))
+                if src != Reg8::I && src != Reg8::R =>
+            {
+                Micro::Copy(dst, src)
+            }
+            Op::LD8(Location8::Reg(dst), Location8::Immediate(n)) => Micro::Set(dst, n),
+            Op::LD8(Location8::Reg(dst), Location8::RegIndirect(rr)) => Micro::Load(dst, rr),
+            Op::LD8(Location8::RegIndirect(rr), Location8::Reg(src)) => Micro::Store(rr, src),
+            Op::LD16(Location16::Reg(rr), Location16::Immediate(nn)) => Micro::Set16(rr, nn),
+            Op::INC16(Location16::Reg(rr)) => Micro::Step16(rr, 1),
+            Op::DEC16(Location16::Reg(rr)) => Micro::Step16(rr, 0xFFFF),
+            Op::JP(JumpConditional::Unconditional, Location16::Immediate(nn)) => Micro::Jump(nn),
+            op => Micro::Interpret(op),
+        }
+    }
+}
+
+/// Whether control can leave an instruction other than by falling through to the next one
+fn ends_block(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::JP(..)
+            | Op::JR(..)
+            | Op::DJNZ(_)
+            | Op::CALL(..)
+            | Op::RET(_)
+            | Op::RETI
+            | Op::RETN
+            | Op::RST(_)
+            | Op::HALT
+            | Op::LDIR
+            | Op::LDDR
+            | Op::CPIR
+            | Op::CPDR
+            | Op::INIR
+            | Op::INDR
+            | Op::OTIR
+            | Op::OTDR
+    )
+}
+
+#[derive(Clone)]
+struct Instruction {
+    micro: Micro,
+    len: u16,
+    /// How much R is incremented by: prefixed instructions take two opcode fetches
+    refresh: u8,
+}
+
+/// The most successors remembered for each block
+const MAX_EXITS: usize = 2;
+
+struct Block {
+    instructions: Vec<Instruction>,
+    /// Blocks that control has passed to from this one, by their start address
+    exits: Vec<(u16, usize)>,
+}
+
+pub(super) struct BlockEngine {
+    blocks: Vec<Block>,
+    /// Blocks by the address of their first instruction
+    starts: BTreeMap<u16, usize>,
+    /// One bit for each byte of memory, set if any instruction was decoded from it
+    code: Vec<u64>,
+    /// The block that control just left
+    exited: Option<usize>,
+}
+
+impl Default for BlockEngine {
+    fn default() -> Self {
+        Self {
+            blocks: vec![],
+            starts: BTreeMap::new(),
+            code: vec![0; MEMORY_SIZE / 64],
+            exited: None,
+        }
+    }
+}
+
+impl BlockEngine {
+    fn is_code(&self, addr: u16) -> bool {
+        self.code[usize::from(addr) / 64] & (1 << (addr % 64)) != 0
+    }
+
+    fn mark_code(&mut self, addr: u16) {
+        self.code[usize::from(addr) / 64] |= 1 << (addr % 64);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.blocks.clear();
+        self.starts.clear();
+        self.code.iter_mut().for_each(|c| *c = 0);
+        self.exited = None;
+    }
+
+    /// Discard every block if any of the `len` bytes from `addr` were compiled from
+    pub(super) fn invalidate_range(&mut self, addr: u16, len: usize) {
+        if (0..len).any(|i| self.is_code(addr.wrapping_add(i as u16))) {
+            self.clear();
+        }
+    }
+
+    /// Compile the block starting at `start`, returning its index
+    fn compile(&mut self, memory: &Memory, start: u16) -> usize {
+        let mut instructions = vec![];
+        let mut addr = start;
+        loop {
+            let bytes = memory.window(addr);
+            let (op, len) = opcodes::opcode(bytes);
+            let prefixed = len > 1 && matches!(bytes[0], 0xCB | 0xED | 0xDD | 0xFD);
+            for i in 0..WINDOW {
+                self.mark_code(addr.wrapping_add(i));
+            }
+            let last = ends_block(&op);
+            instructions.push(Instruction {
+                micro: Micro::compile(op),
+                len: len as u16,
+                refresh: if prefixed { 2 } else { 1 },
+            });
+            addr = addr.wrapping_add(len as u16);
+            // Blocks don't wrap around from the top of memory to the bottom
+            if last || addr < start || instructions.len() == MAX_BLOCK {
+                break;
+            }
+        }
+        self.blocks.push(Block {
+            instructions,
+            exits: vec![],
+        });
+        let block = self.blocks.len() - 1;
+        self.starts.insert(start, block);
+        block
+    }
+
+    /// The block starting at `pc`, compiling it if needed. Following the same jump as last time
+    /// avoids looking it up.
+    fn enter(&mut self, memory: &Memory, pc: u16) -> usize {
+        let from = self.exited.take();
+        if let Some(from) = from {
+            if let Some(&(_, block)) = self.blocks[from].exits.iter().find(|(a, _)| *a == pc) {
+                return block;
+            }
+        }
+        let block = match self.starts.get(&pc) {
+            Some(&block) => block,
+            None => self.compile(memory, pc),
+        };
+        if let Some(from) = from {
+            let exits = &mut self.blocks[from].exits;
+            if exits.len() < MAX_EXITS {
+                exits.push((pc, block));
+            }
+        }
+        block
+    }
+}
+
+impl Z80 {
+    /// Run `run` and `run_for` through the block engine, which is faster than the interpreter for
+    /// long-running loops, at the cost of compiling code before it first runs.
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x01, 0x3C, 0x76]); // LD A, 1; INC A; HALT
+    /// z80.enable_block_engine();
+    /// z80.run();
+    /// assert_eq!(0x02, z80.registers.get_reg8(zeerust::ops::Reg8::A));
+    /// ```
+    pub fn enable_block_engine(&mut self) {
+        if self.blocks.is_none() {
+            self.blocks = Some(Box::default());
+        }
+    }
+
+    /// Go back to interpreting every instruction, discarding any compiled blocks
+    pub fn disable_block_engine(&mut self) {
+        self.blocks = None;
+    }
+
+    /// Whether the next instructions can be run by the block engine
+    pub(super) fn uses_block_engine(&self) -> bool {
+        self.blocks.is_some() && self.history.is_none() && !self.is_tracing()
+    }
+
+    /// Execute the block at the program counter until control leaves it, or until `end`
+    /// instructions have been executed in total
+    pub(super) fn run_block(&mut self, end: u64) {
+        let pc = self.registers.get_pc();
+        let engine = self.blocks.as_mut().expect("block engine enabled");
+        let block = engine.enter(&self.memory, pc);
+        let mut index = 0;
+        while self.exec_compiled(block, index) && self.instructions < end {
+            index += 1;
+        }
+        if let Some(engine) = self.blocks.as_mut() {
+            if !engine.blocks.is_empty() {
+                engine.exited = Some(block);
+            }
+        }
+    }
+
+    /// Execute a compiled instruction, returning whether the next one in its block follows it
+    fn exec_compiled(&mut self, block: usize, index: usize) -> bool {
+        let engine = self.blocks.as_ref().expect("block engine enabled");
+        let instruction = engine.blocks[block].instructions[index].clone();
+
+        let pc = self.registers.get_pc();
+        self.registers.increment_r(instruction.refresh);
+        let next = pc.wrapping_add(instruction.len);
+        let next_pc = self.exec_micro(instruction.micro).unwrap_or(next);
+        self.registers.set_pc(next_pc);
+        self.instructions += 1;
+
+        // A write to compiled code will have discarded every block, and none are compiled
+        // until the next one is entered
+        let compiled = self
+            .blocks
+            .as_ref()
+            .and_then(|engine| engine.blocks.get(block))
+            .map_or(0, |b| b.instructions.len());
+        next_pc == next && index + 1 < compiled
+    }
+
+    fn exec_micro(&mut self, micro: Micro) -> Option<u16> {
+        match micro {
+            Micro::Copy(dst, src) => {
+                let val = self.registers.get_reg8(src);
+                self.registers.set_reg8(dst, val);
+            }
+            Micro::Set(dst, n) => self.registers.set_reg8(dst, n),
+            Micro::Load(dst, rr) => {
+                let val = self.read_mem(self.registers.get_reg16(&rr));
+                self.registers.set_reg8(dst, val);
+            }
+            Micro::Store(rr, src) => {
+                self.write_mem(self.registers.get_reg16(&rr), self.registers.get_reg8(src))
+            }
+            Micro::Set16(rr, nn) => self.registers.set_reg16(&rr, nn),
+            Micro::Step16(rr, step) => {
+                let val = self.registers.get_reg16(&rr).wrapping_add(step);
+                self.registers.set_reg16(&rr, val);
+            }
+            Micro::Jump(nn) => return Some(nn),
+            Micro::Interpret(op) => return self.exec_with_offset(op),
+        }
+        None
+    }
+}
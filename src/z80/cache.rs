@@ -0,0 +1,103 @@
+//! A cache of decoded instructions.
+//!
+//! `Z80::step` looks instructions up here before decoding them, so a loop is only decoded once.
+//! The cache is direct-mapped: each address can only be held in one slot, shared with every
+//! address `SLOTS` bytes apart. Writing to any byte an instruction was decoded from removes it,
+//! so self-modifying code is decoded afresh.
+//!
+//! That includes bytes after the instruction itself: a lone DD or FD prefix only decodes as one
+//! because of the byte following it. So a write removes any instruction starting in the
+//! `WINDOW` bytes up to and including it.
+use super::Z80;
+use crate::cpu::opcodes;
+use crate::ops::Op;
+use crate::prelude::*;
+
+const SLOTS: usize = 1024;
+
+/// The number of bytes the decoder looks at
+pub(super) const WINDOW: u16 = 4;
+
+#[derive(Clone)]
+struct Entry {
+    addr: u16,
+    len: usize,
+    op: Op,
+}
+
+#[derive(Default)]
+pub(super) struct DecodeCache {
+    /// Empty until the first instruction is cached, so idle machines don't allocate.
+    /// Entries are boxed so that the empty slots are cheap to allocate.
+    slots: Vec<Option<Box<Entry>>>,
+}
+
+impl DecodeCache {
+    fn slot(addr: u16) -> usize {
+        usize::from(addr) % SLOTS
+    }
+
+    fn get(&self, addr: u16) -> Option<(Op, usize)> {
+        match self.slots.get(Self::slot(addr)) {
+            Some(Some(e)) if e.addr == addr => Some((e.op.clone(), e.len)),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, addr: u16, op: &Op, len: usize) {
+        if self.slots.is_empty() {
+            self.slots = vec![None; SLOTS];
+        }
+        self.slots[Self::slot(addr)] = Some(Box::new(Entry {
+            addr,
+            len,
+            op: op.clone(),
+        }));
+    }
+
+    /// Remove any instruction decoded from the byte at `addr`
+    fn invalidate(&mut self, addr: u16) {
+        if self.slots.is_empty() {
+            return;
+        }
+        for offset in 0..WINDOW {
+            let start = addr.wrapping_sub(offset);
+            let slot = &mut self.slots[Self::slot(start)];
+            if matches!(slot, Some(e) if e.addr == start) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Remove any instruction decoded from a byte in the `len` bytes from `addr`
+    fn invalidate_range(&mut self, addr: u16, len: usize) {
+        if len >= SLOTS {
+            self.slots.clear();
+        } else {
+            for i in 0..len {
+                self.invalidate(addr.wrapping_add(i as u16));
+            }
+        }
+    }
+}
+
+impl Z80 {
+    /// The instruction at `pc` and its length, from the cache if possible
+    pub(super) fn decode(&mut self, pc: u16) -> (Op, usize) {
+        if let Some(decoded) = self.decoded.get(pc) {
+            return decoded;
+        }
+        // Instructions wrap around from the top of memory to the bottom
+        let (op, len) = opcodes::opcode(self.memory.window(pc));
+        self.decoded.insert(pc, &op, len);
+        (op, len)
+    }
+
+    /// Forget any decoded or compiled instruction that depends on the `len` bytes from `addr`
+    pub(super) fn invalidate_code(&mut self, addr: u16, len: usize) {
+        self.decoded.invalidate_range(addr, len);
+        if let Some(blocks) = self.blocks.as_mut() {
+            blocks.invalidate_range(addr, len);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Call-depth tracking, for stepping over and out of subroutines.
+//!
+//! Every `CALL` and `RST` executed pushes a frame onto the call stack. A `RET`, `RETI` or `RETN`
+//! pops every frame whose return address is at or below the stack slot it returns through, so
+//! subroutines that discard their return address, or return through one pushed by hand, are
+//! still followed. Code that moves the stack pointer elsewhere isn't.
+use super::Z80;
+use crate::ops::Reg16;
+
+/// A subroutine call that hasn't returned yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// The address of the `CALL` or `RST` instruction
+    pub call: u16,
+    /// The start of the subroutine
+    pub target: u16,
+    /// Where the subroutine will return to
+    pub return_address: u16,
+    /// Where the return address is stored on the stack
+    pub sp: u16,
+}
+
+impl Z80 {
+    /// The calls that haven't returned yet, outermost first
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0xCD, 0x04, 0x00, 0x76, 0xC9]); // CALL 4; HALT; RET
+    /// z80.step();
+    /// assert_eq!(0x0003, z80.call_stack()[0].return_address);
+    /// z80.step();
+    /// assert!(z80.call_stack().is_empty());
+    /// ```
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.calls
+    }
+
+    /// The number of calls that haven't returned yet
+    pub fn call_depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Execute a single instruction, but if it's a `CALL` or `RST`, keep going until the
+    /// subroutine returns, a HALT is encountered, or `limit` instructions have been executed.
+    /// Returns true if the subroutine returned, or there wasn't one.
+    pub fn step_over(&mut self, limit: u64) -> bool {
+        if limit == 0 {
+            return false;
+        }
+        let depth = self.calls.len();
+        self.step();
+        self.return_to(depth, limit - 1)
+    }
+
+    /// Execute instructions until the current subroutine returns, a HALT is encountered, or
+    /// `limit` instructions have been executed.
+    /// Returns true if the subroutine returned, and false, without executing anything, if
+    /// there's no subroutine to return from.
+    pub fn step_out(&mut self, limit: u64) -> bool {
+        match self.calls.len().checked_sub(1) {
+            Some(depth) => self.return_to(depth, limit),
+            None => false,
+        }
+    }
+
+    /// Step until no more than `depth` calls are in progress, as `run_for` would.
+    /// Returns false if a HALT or the limit was reached first.
+    fn return_to(&mut self, depth: usize, limit: u64) -> bool {
+        let end = self.instructions.saturating_add(limit);
+        while self.calls.len() > depth {
+            if self.is_halted || self.instructions >= end {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+
+    /// Note a call, just after its return address has been pushed
+    pub(super) fn enter_call(&mut self, call: u16, target: u16, return_address: u16) {
+        let sp = self.registers.get_reg16(&Reg16::SP);
+        // Anything at or below the new return address has been abandoned
+        self.leave_calls(sp);
+        self.calls.push(Frame {
+            call,
+            target,
+            return_address,
+            sp,
+        });
+    }
+
+    /// Note a return through the return address at `sp`
+    pub(super) fn leave_calls(&mut self, sp: u16) {
+        while let Some(&frame) = self.calls.last() {
+            if frame.sp > sp {
+                break;
+            }
+            self.calls.pop();
+            self.record_return(frame);
+        }
+    }
+
+    /// Forget every call, when the stack no longer applies
+    #[cfg(feature = "std")]
+    pub(super) fn clear_calls(&mut self) {
+        self.calls.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::examples::FIZZBUZZ_BIN;
+    use crate::ops::Reg8;
+
+    // CALL outer; HALT; outer: CALL inner; LD A, 1; RET; inner: LD A, 2; RET
+    const NESTED: &[u8] = &[
+        0xCD, 0x04, 0x00, 0x76, 0xCD, 0x0B, 0x00, 0x3E, 0x01, 0xC9, 0x00, 0x3E, 0x02, 0xC9,
+    ];
+
+    fn load(program: &[u8]) -> Z80 {
+        let mut z80 = Z80::default();
+        z80.load(program);
+        z80
+    }
+
+    fn return_addresses(z80: &Z80) -> Vec<u16> {
+        z80.call_stack().iter().map(|f| f.return_address).collect()
+    }
+
+    #[test]
+    fn nested_calls() {
+        let mut z80 = load(NESTED);
+        z80.step();
+        z80.step();
+        assert_eq!(2, z80.call_depth());
+        assert_eq!(vec![0x0003, 0x0007], return_addresses(&z80));
+        let inner = z80.call_stack()[1];
+        assert_eq!(0x0004, inner.call);
+        assert_eq!(0x000B, inner.target);
+        assert_eq!(0xFFFC, inner.sp);
+
+        z80.step();
+        z80.step();
+        assert_eq!(vec![0x0003], return_addresses(&z80));
+        z80.run();
+        assert_eq!(0, z80.call_depth());
+    }
+
+    #[test]
+    fn step_over() {
+        let mut z80 = load(NESTED);
+        z80.step_over(u64::MAX);
+        assert_eq!(0x0003, z80.registers.get_pc());
+        assert_eq!(1, z80.registers.get_reg8(Reg8::A));
+        assert_eq!(0, z80.call_depth());
+
+        // Anything else is a single step
+        z80.registers.set_pc(0x0007);
+        z80.step_over(u64::MAX);
+        assert_eq!(0x0009, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn limits() {
+        // CALL 4; HALT; loop: JR loop
+        let mut z80 = load(&[0xCD, 0x04, 0x00, 0x76, 0x18, 0xFE]);
+        assert!(!z80.step_over(0));
+        assert_eq!(0, z80.instructions());
+        assert!(!z80.step_over(10));
+        assert_eq!(10, z80.instructions());
+        assert_eq!(1, z80.call_depth());
+        assert!(!z80.step_out(5));
+        assert_eq!(15, z80.instructions());
+
+        // A HALT inside the subroutine stops it from returning
+        z80.load_at(0x0004, &[0x76]);
+        assert!(!z80.step_out(u64::MAX));
+        assert!(z80.is_halted());
+        assert_eq!(1, z80.call_depth());
+    }
+
+    #[test]
+    fn step_out() {
+        let mut z80 = load(NESTED);
+        assert!(!z80.step_out(u64::MAX));
+        assert_eq!(0x0000, z80.registers.get_pc());
+
+        z80.step();
+        z80.step();
+        assert!(z80.step_out(u64::MAX));
+        assert_eq!(0x0007, z80.registers.get_pc());
+        assert_eq!(2, z80.registers.get_reg8(Reg8::A));
+        assert!(z80.step_out(u64::MAX));
+        assert_eq!(0x0003, z80.registers.get_pc());
+        assert_eq!(1, z80.registers.get_reg8(Reg8::A));
+    }
+
+    #[test]
+    fn restarts_and_conditional_calls() {
+        // RST 10h; CALL NZ, 10h; XOR A; CALL NZ, 10h; HALT; .org 10h: RET
+        let mut z80 = load(&[0xD7, 0xC4, 0x10, 0x00, 0xAF, 0xC4, 0x10, 0x00, 0x76]);
+        z80.load_at(0x0010, &[0xC9]);
+        z80.step();
+        assert_eq!(vec![0x0001], return_addresses(&z80));
+        z80.step();
+        assert_eq!(0, z80.call_depth());
+
+        // The flags are still clear, so the first CALL NZ is taken
+        z80.step_over(u64::MAX);
+        assert_eq!(0x0004, z80.registers.get_pc());
+        assert_eq!(0, z80.call_depth());
+        z80.step();
+        z80.step();
+        assert_eq!(0x0008, z80.registers.get_pc());
+        assert_eq!(0, z80.call_depth());
+    }
+
+    #[test]
+    fn discarded_return_addresses() {
+        // CALL 4; HALT; POP HL; CALL 9; HALT; RET
+        let mut z80 = load(&[0xCD, 0x04, 0x00, 0x76, 0xE1, 0xCD, 0x09, 0x00, 0x76, 0xC9]);
+        z80.run_for(3);
+        // The first call's return address was popped, and overwritten by the second's
+        assert_eq!(vec![0x0008], return_addresses(&z80));
+        z80.step();
+        assert_eq!(0, z80.call_depth());
+
+        // CALL 4; HALT; PUSH HL; RET; RET
+        let mut z80 = load(&[0xCD, 0x04, 0x00, 0x76, 0xE5, 0xC9, 0xC9]);
+        z80.registers.set_reg16(&Reg16::HL, 0x0006);
+        z80.run_for(3);
+        // Returning through the address pushed by hand leaves the call in progress
+        assert_eq!(0x0006, z80.registers.get_pc());
+        assert_eq!(1, z80.call_depth());
+        z80.step();
+        assert_eq!(0x0003, z80.registers.get_pc());
+        assert_eq!(0, z80.call_depth());
+    }
+
+    #[test]
+    fn block_engine() {
+        let mut z80 = load(NESTED);
+        z80.enable_block_engine();
+        z80.run_for(2);
+        assert_eq!(2, z80.call_depth());
+        z80.run();
+        assert_eq!(0, z80.call_depth());
+    }
+
+    #[test]
+    fn step_back() {
+        let mut z80 = load(NESTED);
+        z80.enable_history(100);
+        z80.run_for(4);
+        assert_eq!(vec![0x0003], return_addresses(&z80));
+        z80.step_back();
+        assert_eq!(vec![0x0003, 0x0007], return_addresses(&z80));
+        z80.step_back();
+        z80.step_back();
+        assert_eq!(vec![0x0003], return_addresses(&z80));
+        z80.step_back();
+        assert_eq!(0, z80.call_depth());
+    }
+
+    #[test]
+    fn fizzbuzz() {
+        // printFizzBuzz calls printNum, which calls divmod and printDigit
+        let mut z80 = load(FIZZBUZZ_BIN);
+        let mut deepest = 0;
+        while !z80.is_halted() {
+            z80.step();
+            deepest = deepest.max(z80.call_depth());
+            if z80.call_depth() == 3 {
+                let expected = z80.call_stack()[2].return_address;
+                assert!(z80.step_out(u64::MAX));
+                assert_eq!(expected, z80.registers.get_pc());
+                assert_eq!(2, z80.call_depth());
+            }
+        }
+        assert_eq!(3, deepest);
+        assert_eq!(0, z80.call_depth());
+    }
+}
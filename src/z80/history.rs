@@ -0,0 +1,253 @@
+//! Reverse execution.
+//!
+//! When history is enabled, every call to `Z80::step` records enough to undo it:
+//! the registers, halt flag and call stack depth beforehand, the previous value of every byte of
+//! memory written, and any calls that returned.
+//! Only the most recent steps are kept, up to a fixed capacity.
+//! Input and output can't be undone: stepping back over `IN` or `OUT` won't un-read or un-write a byte.
+use alloc::collections::VecDeque;
+
+use super::calls::Frame;
+use super::Z80;
+use crate::cpu::reg::Registers;
+use crate::prelude::*;
+
+/// The state needed to reverse a single step
+struct Undo {
+    registers: Registers,
+    is_halted: bool,
+    /// Addresses written during the step, and the value they held beforehand, in write order
+    writes: Vec<(u16, u8)>,
+    call_depth: usize,
+    /// Calls that returned during the step, innermost first
+    returns: Vec<Frame>,
+}
+
+pub(super) struct History {
+    capacity: usize,
+    steps: VecDeque<Undo>,
+    /// Writes made so far by the step in progress
+    writes: Vec<(u16, u8)>,
+    /// Calls returned from so far by the step in progress
+    returns: Vec<Frame>,
+}
+
+impl History {
+    /// Forget every recorded step
+    #[cfg(feature = "std")]
+    pub(super) fn clear(&mut self) {
+        self.steps.clear();
+        self.writes.clear();
+        self.returns.clear();
+    }
+}
+
+impl Z80 {
+    /// Start recording history, keeping at most `steps` steps.
+    /// Any history already recorded is discarded.
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x01, 0x3E, 0x02]); // LD A, 1; LD A, 2
+    /// z80.enable_history(1000);
+    /// z80.step();
+    /// z80.step();
+    /// assert!(z80.step_back());
+    /// assert_eq!(0x0002, z80.registers.get_pc());
+    /// ```
+    pub fn enable_history(&mut self, steps: usize) {
+        self.history = Some(History {
+            capacity: steps,
+            steps: VecDeque::new(),
+            writes: vec![],
+            returns: vec![],
+        });
+    }
+
+    /// Stop recording history, and discard what has been recorded
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// The number of steps that can currently be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.steps.len())
+    }
+
+    /// Undo the most recent step.
+    /// Returns false if there is no history to undo.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|h| h.steps.pop_back()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        for (addr, val) in undo.writes.into_iter().rev() {
+            self.memory.memory[addr as usize] = val;
+            self.invalidate_code(addr, 1);
+        }
+        // Drop any call the step made, and put back those that returned
+        let depth = undo.call_depth - undo.returns.len();
+        self.calls.truncate(depth);
+        self.calls.extend(undo.returns.into_iter().rev());
+        self.registers = undo.registers;
+        self.is_halted = undo.is_halted;
+        self.instructions -= 1;
+        true
+    }
+
+    /// Step backwards until the most recent instruction that wrote to `addr` has been undone,
+    /// leaving the program counter pointing at that instruction.
+    /// If no recorded step wrote to `addr`, nothing is undone and false is returned.
+    pub fn run_back_to_write(&mut self, addr: u16) -> bool {
+        let steps = match self.history.as_ref() {
+            Some(h) => h
+                .steps
+                .iter()
+                .rev()
+                .position(|undo| undo.writes.iter().any(|(a, _)| *a == addr)),
+            None => None,
+        };
+        match steps {
+            Some(n) => {
+                for _ in 0..=n {
+                    self.step_back();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Note the value about to be overwritten at `addr`, if history is enabled.
+    pub(super) fn record_write(&mut self, addr: u16) {
+        if let Some(h) = self.history.as_mut() {
+            h.writes.push((addr, self.memory.memory[addr as usize]));
+        }
+    }
+
+    /// Note a call that has returned, if history is enabled.
+    pub(super) fn record_return(&mut self, frame: Frame) {
+        if let Some(h) = self.history.as_mut() {
+            h.returns.push(frame);
+        }
+    }
+
+    /// The state to restore if the step about to be executed is undone
+    pub(super) fn history_checkpoint(&self) -> Option<(Registers, bool, usize)> {
+        self.history
+            .as_ref()
+            .map(|_| (self.registers.clone(), self.is_halted, self.calls.len()))
+    }
+
+    /// Record a completed step, discarding the oldest if history is full
+    pub(super) fn commit_history(
+        &mut self,
+        (registers, is_halted, call_depth): (Registers, bool, usize),
+    ) {
+        if let Some(h) = self.history.as_mut() {
+            let writes = core::mem::take(&mut h.writes);
+            let returns = core::mem::take(&mut h.returns);
+            if h.capacity == 0 {
+                return;
+            }
+            if h.steps.len() == h.capacity {
+                h.steps.pop_front();
+            }
+            h.steps.push_back(Undo {
+                registers,
+                is_halted,
+                writes,
+                call_depth,
+                returns,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ops::{Reg16, Reg8};
+
+    // LD HL, 1000h; LD (HL), 1; LD A, 2; LD (HL), A; INC (HL); HALT
+    const PROGRAM: &[u8] = &[0x21, 0x00, 0x10, 0x36, 0x01, 0x3E, 0x02, 0x77, 0x34, 0x76];
+
+    fn load() -> Z80 {
+        let mut z80 = Z80::default();
+        z80.load(PROGRAM);
+        z80
+    }
+
+    #[test]
+    fn step_back() {
+        let mut z80 = load();
+        z80.enable_history(100);
+        z80.run();
+        let addr = z80.registers.get_reg16(&Reg16::HL) as usize;
+        assert_eq!(3, z80.memory.memory[addr]);
+        assert!(z80.is_halted());
+        assert_eq!(6, z80.history_len());
+
+        assert!(z80.step_back());
+        assert!(!z80.is_halted());
+        assert_eq!(9, z80.registers.get_pc());
+
+        assert!(z80.step_back());
+        assert_eq!(2, z80.memory.memory[addr]);
+
+        while z80.step_back() {}
+        assert_eq!(0, z80.memory.memory[addr]);
+        assert_eq!(load().registers, z80.registers);
+
+        z80.run();
+        assert_eq!(3, z80.memory.memory[addr]);
+    }
+
+    #[test]
+    fn run_back_to_write() {
+        let mut z80 = load();
+        z80.enable_history(100);
+        z80.run();
+        let addr = z80.registers.get_reg16(&Reg16::HL);
+
+        assert!(z80.run_back_to_write(addr));
+        assert_eq!(0x08, z80.registers.get_pc());
+        assert_eq!(2, z80.memory.memory[addr as usize]);
+
+        assert!(z80.run_back_to_write(addr));
+        assert_eq!(0x07, z80.registers.get_pc());
+        assert_eq!(0x02, z80.registers.get_reg8(Reg8::A));
+
+        assert!(z80.run_back_to_write(addr));
+        assert_eq!(0x03, z80.registers.get_pc());
+
+        assert!(!z80.run_back_to_write(addr));
+        assert_eq!(0x03, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut z80 = load();
+        z80.enable_history(2);
+        z80.run();
+        assert_eq!(2, z80.history_len());
+        assert!(z80.step_back());
+        assert!(z80.step_back());
+        assert!(!z80.step_back());
+        assert_eq!(0x08, z80.registers.get_pc());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut z80 = load();
+        z80.step();
+        assert_eq!(0, z80.history_len());
+        assert!(!z80.step_back());
+        assert_eq!(0x03, z80.registers.get_pc());
+
+        z80.enable_history(0);
+        z80.step();
+        assert!(!z80.step_back());
+    }
+}
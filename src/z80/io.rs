@@ -1,22 +1,185 @@
 //! Methods associated with the IN and OUT instructions of the z80
-use std::cell::RefCell;
-use std::rc::Rc;
+//!
+//! Devices sit on an I/O bus, which sees the full 16-bit port address:
+//! `IN A, (n)` and `OUT (n), A` put A on the upper half, and the other forms put B there.
+//! Each device answers a set of `Ports`, which can ignore some of the address lines,
+//! just as real hardware often only decodes a few of them.
+//!
+//! Devices must be `Send`, so that a `Z80` can be moved to another thread.
+//! `ChannelInput` and `ChannelOutput` connect it to the rest of the program with channels.
+//! They, and the `BufInput` and `BufOutput` used in tests, need the `std` feature.
+#[cfg(feature = "std")]
+use std::sync::mpsc::{Receiver, Sender};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+use crate::prelude::*;
 
 use super::Z80;
 
 /// An InputDevice can be read from, one byte at a time
-pub trait InputDevice {
+pub trait InputDevice: Send {
     /// Read a single byte
     fn input(&self) -> u8;
+
+    /// Read a single byte, or return None if there's nothing to read,
+    /// so the port reads as unmapped
+    fn try_input(&self) -> Option<u8> {
+        Some(self.input())
+    }
 }
 
 /// An OutputDevice can be written to, one byte at a time
-pub trait OutputDevice {
+pub trait OutputDevice: Send {
     /// Write a single byte
     fn output(&self, val: u8);
 }
 
+/// A device on the I/O bus, which is given the full address of the port being accessed.
+/// It can handle reads, writes, or both.
+pub trait IoDevice: Send {
+    /// Read a byte from `port`, or return None to leave the read to other devices
+    fn read(&mut self, _port: u16) -> Option<u8> {
+        None
+    }
+
+    /// Write a byte to `port`, returning false to leave the write to other devices
+    fn write(&mut self, _port: u16, _val: u8) -> bool {
+        false
+    }
+}
+
+/// The port addresses a device answers to: those which equal `matches` in every bit set in `mask`.
+/// For example, the ZX Spectrum's ULA answers every even port:
+/// ```
+/// use zeerust::z80::io::Ports;
+///
+/// let ula = Ports { mask: 0x0001, matches: 0x0000 };
+/// assert!(ula.contains(0x7FFE));
+/// assert!(!ula.contains(0x00FF));
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Ports {
+    pub mask: u16,
+    pub matches: u16,
+}
+
+impl Ports {
+    /// A single port, decoded on every address line
+    pub fn exact(port: u16) -> Self {
+        Self {
+            mask: 0xFFFF,
+            matches: port,
+        }
+    }
+
+    /// Every port with the given lower byte, whatever is on the upper half of the address bus
+    pub fn low(port: u8) -> Self {
+        Self {
+            mask: 0x00FF,
+            matches: u16::from(port),
+        }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        port & self.mask == self.matches & self.mask
+    }
+}
+
+/// The devices attached to a Z80, and what is read from ports none of them answer
+pub(super) struct Bus {
+    devices: Vec<(Ports, Box<dyn IoDevice>)>,
+    unmapped: u8,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self {
+            devices: vec![],
+            // Nothing drives the data bus, so it floats high
+            unmapped: 0xFF,
+        }
+    }
+}
+
+impl Bus {
+    /// The devices answering `port`, most recently connected first
+    fn devices(&mut self, port: u16) -> impl Iterator<Item = &mut Box<dyn IoDevice>> {
+        self.devices
+            .iter_mut()
+            .rev()
+            .filter(move |(ports, _)| ports.contains(port))
+            .map(|(_, device)| device)
+    }
+
+    pub(super) fn read(&mut self, port: u16) -> u8 {
+        let unmapped = self.unmapped;
+        self.devices(port)
+            .find_map(|d| d.read(port))
+            .unwrap_or(unmapped)
+    }
+
+    /// Writes no device accepts are discarded
+    pub(super) fn write(&mut self, port: u16, val: u8) {
+        let _ = self.devices(port).any(|d| d.write(port, val));
+    }
+}
+
+/// Adapts an InputDevice to the bus
+struct Input(Box<dyn InputDevice>);
+
+impl IoDevice for Input {
+    fn read(&mut self, _port: u16) -> Option<u8> {
+        self.0.try_input()
+    }
+}
+
+/// Adapts an OutputDevice to the bus
+struct Output(Box<dyn OutputDevice>);
+
+impl IoDevice for Output {
+    fn write(&mut self, _port: u16, val: u8) -> bool {
+        self.0.output(val);
+        true
+    }
+}
+
 impl Z80 {
+    /// Attach a device to the I/O bus, answering the given ports.
+    /// Where several devices answer the same port, the most recently connected one that handles
+    /// the read or write is used. For example:
+    /// ```
+    /// use zeerust::z80;
+    /// use zeerust::z80::io::{IoDevice, Ports};
+    ///
+    /// /// Reads back the last byte written to it
+    /// struct Latch(u8);
+    ///
+    /// impl IoDevice for Latch {
+    ///     fn read(&mut self, _port: u16) -> Option<u8> {
+    ///         Some(self.0)
+    ///     }
+    ///
+    ///     fn write(&mut self, _port: u16, val: u8) -> bool {
+    ///         self.0 = val;
+    ///         true
+    ///     }
+    /// }
+    ///
+    /// let mut z80 = z80::Z80::default();
+    /// z80.connect(Ports { mask: 0x00F0, matches: 0x0010 }, Box::new(Latch(0)));
+    ///```
+    /// This will then be usable with `IN` and `OUT` on ports 0x10 to 0x1F.
+    pub fn connect(&mut self, ports: Ports, device: Box<dyn IoDevice>) {
+        self.bus.devices.push((ports, device));
+    }
+
+    /// Set the value read from ports that no device answers. This is 0xFF by default,
+    /// as the data bus floats high when nothing drives it.
+    pub fn set_unmapped_input(&mut self, val: u8) {
+        self.bus.unmapped = val;
+    }
+
     /// Install an input device at the given index. For example:
     /// ```
     /// use zeerust::z80;
@@ -25,9 +188,10 @@ impl Z80 {
     /// let inp = z80::io::BufInput::new(vec!(b'Z'));
     /// z80.install_input(0, Box::new(inp.clone()));
     ///```
-    /// This will then be usable with `IN (0), <register>`.
-    pub fn install_input(&mut self, index: u8, device: Box<InputDevice>) {
-        self.input_devices.insert(index, device);
+    /// This will then be usable with `IN (0), <register>`,
+    /// whatever is on the upper half of the address bus.
+    pub fn install_input(&mut self, index: u8, device: Box<dyn InputDevice>) {
+        self.connect(Ports::low(index), Box::new(Input(device)));
     }
 
     /// Install an output device at the given index. For example:
@@ -38,50 +202,170 @@ impl Z80 {
     /// let out = z80::io::BufOutput::default();
     /// z80.install_output(0, Box::new(out.clone()));
     ///```
-    /// This will then be usable with `OUT (0), <register>`.
-    pub fn install_output(&mut self, index: u8, device: Box<OutputDevice>) {
-        self.output_devices.insert(index, device);
+    /// This will then be usable with `OUT (0), <register>`,
+    /// whatever is on the upper half of the address bus.
+    pub fn install_output(&mut self, index: u8, device: Box<dyn OutputDevice>) {
+        self.connect(Ports::low(index), Box::new(Output(device)));
     }
 }
 
 /// BufInput is a simple InputDevice than produces input when requested, from back to front.
-/// Useful in tests.
-#[derive(Default, PartialEq, Clone)]
+/// Useful in tests. Once it's empty, its port reads as unmapped.
+#[derive(Default, Clone)]
+#[cfg(feature = "std")]
 pub struct BufInput {
-    input: Rc<RefCell<Vec<u8>>>,
+    input: Arc<Mutex<Vec<u8>>>,
 }
 
+#[cfg(feature = "std")]
 impl InputDevice for BufInput {
     /// Read the right-most byte from the internal buffer
+    ///
+    /// # Panics
+    /// Panics if the buffer is empty
     fn input(&self) -> u8 {
-        self.input.borrow_mut().pop().unwrap()
+        self.try_input().expect("BufInput is empty")
+    }
+
+    fn try_input(&self) -> Option<u8> {
+        self.input.lock().unwrap().pop()
     }
 }
 
+#[cfg(feature = "std")]
+impl IoDevice for BufInput {
+    fn read(&mut self, _port: u16) -> Option<u8> {
+        self.input.lock().unwrap().pop()
+    }
+}
+
+#[cfg(feature = "std")]
 impl BufInput {
     pub fn new(v: Vec<u8>) -> Self {
         Self {
-            input: Rc::new(RefCell::new(v)),
+            input: Arc::new(Mutex::new(v)),
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl PartialEq for BufInput {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.input, &other.input)
+            || *self.input.lock().unwrap() == *other.input.lock().unwrap()
+    }
+}
+
 /// BufOutput is a simple Output device that receives output and appends it to an internal vector.
-#[derive(Default, PartialEq, Clone)]
+#[derive(Default, Clone)]
+#[cfg(feature = "std")]
 pub struct BufOutput {
-    output: Rc<RefCell<Vec<u8>>>,
+    output: Arc<Mutex<Vec<u8>>>,
 }
 
+#[cfg(feature = "std")]
 impl BufOutput {
     /// All of the outputs recieved from the processor, most recent last.
     pub fn result(&self) -> Vec<u8> {
-        self.output.borrow_mut().to_vec()
+        self.output.lock().unwrap().to_vec()
     }
 }
 
+#[cfg(feature = "std")]
 impl OutputDevice for BufOutput {
     /// Write a byte to the end of the internal buffer
     fn output(&self, val: u8) {
-        self.output.borrow_mut().push(val)
+        self.output.lock().unwrap().push(val)
+    }
+}
+
+#[cfg(feature = "std")]
+impl IoDevice for BufOutput {
+    fn write(&mut self, _port: u16, val: u8) -> bool {
+        self.output(val);
+        true
+    }
+}
+
+#[cfg(feature = "std")]
+impl PartialEq for BufOutput {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.output, &other.output)
+            || *self.output.lock().unwrap() == *other.output.lock().unwrap()
+    }
+}
+
+/// Reads bytes sent from another thread. For example, to type into a Z80 running in the background:
+/// ```
+/// use std::sync::mpsc;
+/// use zeerust::z80::io::{ChannelInput, Ports};
+/// use zeerust::z80::Z80;
+///
+/// let (keys, receiver) = mpsc::channel();
+/// let mut z80 = Z80::default();
+/// z80.connect(Ports::low(0), Box::new(ChannelInput::new(receiver)));
+/// z80.load(&[0xDB, 0x00, 0x76]); // IN A, (0); HALT
+///
+/// let cpu = std::thread::spawn(move || {
+///     z80.run();
+///     z80
+/// });
+/// keys.send(b'Z').unwrap();
+/// let z80 = cpu.join().unwrap();
+/// assert_eq!(b'Z', z80.registers.get_reg8(zeerust::ops::Reg8::A));
+/// ```
+#[cfg(feature = "std")]
+pub struct ChannelInput {
+    receiver: Receiver<u8>,
+    blocking: bool,
+}
+
+#[cfg(feature = "std")]
+impl ChannelInput {
+    /// Reads wait until a byte is sent. Once the sender has gone, they read as unmapped ports.
+    pub fn new(receiver: Receiver<u8>) -> Self {
+        Self {
+            receiver,
+            blocking: true,
+        }
+    }
+
+    /// Reads never wait: if no byte has been sent, they read as unmapped ports.
+    pub fn polling(receiver: Receiver<u8>) -> Self {
+        Self {
+            receiver,
+            blocking: false,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl IoDevice for ChannelInput {
+    fn read(&mut self, _port: u16) -> Option<u8> {
+        if self.blocking {
+            self.receiver.recv().ok()
+        } else {
+            self.receiver.try_recv().ok()
+        }
+    }
+}
+
+/// Sends bytes written to it to another thread. Once the receiver has gone, writes are discarded.
+#[cfg(feature = "std")]
+pub struct ChannelOutput {
+    sender: Sender<u8>,
+}
+
+#[cfg(feature = "std")]
+impl ChannelOutput {
+    pub fn new(sender: Sender<u8>) -> Self {
+        Self { sender }
+    }
+}
+
+#[cfg(feature = "std")]
+impl IoDevice for ChannelOutput {
+    fn write(&mut self, _port: u16, val: u8) -> bool {
+        self.sender.send(val).is_ok()
     }
 }
@@ -1,42 +1,67 @@
 //! This is where the emulator itself lives.
 //! All other modules simply provide support for this one.
-use std::collections::HashMap;
+use core::cell::RefCell;
 
 use crate::cpu;
 use crate::ops;
+use crate::prelude::*;
 
+mod blocks;
+mod cache;
+pub mod calls;
+mod history;
 pub mod io;
 mod run;
+#[cfg(feature = "std")]
+pub mod snapshot;
+pub mod trace;
 #[cfg(test)]
 mod tests;
 
 /// The core emulation type.
 /// Create one with ::default().
-/// This will initialize everything to zero, except the stack pointer, which is set to MAX_MEMORY.
-/// (currently 16 kibibytes).
-/// By default, no input or output devices are attached.
-/// Use install_input and install_output to connect them.
+/// This will initialize everything to zero, including the stack pointer:
+/// the stack grows downwards, so the first value pushed will be stored at the very top of memory.
+/// By default, no input or output devices are attached, and every port reads as 0xFF.
+/// Use connect, or install_input and install_output, to attach them.
 pub struct Z80 {
     pub registers: cpu::reg::Registers,
-    pub memory: cpu::mem::Memory,
+    /// Read with `memory`, and write with `load_at`, which keeps the decode cache up to date
+    memory: cpu::mem::Memory,
 
     is_halted: bool,
+    /// The number of instructions executed by step
+    instructions: u64,
+    decoded: cache::DecodeCache,
+    blocks: Option<Box<blocks::BlockEngine>>,
 
-    input_devices: HashMap<u8, Box<io::InputDevice>>,
-    output_devices: HashMap<u8, Box<io::OutputDevice>>,
+    bus: io::Bus,
+
+    tracer: Option<Box<dyn trace::Tracer>>,
+    accesses: RefCell<Vec<trace::Access>>,
+
+    history: Option<history::History>,
+    /// The calls that haven't returned yet, outermost first
+    calls: Vec<calls::Frame>,
 }
 
 impl Default for Z80 {
     fn default() -> Self {
-        let mut registers = cpu::reg::Registers::default();
-        registers.set_reg16(&ops::Reg16::SP, cpu::mem::MEMORY_SIZE as u16);
         Self {
-            registers,
+            registers: cpu::reg::Registers::default(),
             memory: cpu::mem::Memory::default(),
 
             is_halted: false,
-            input_devices: HashMap::new(),
-            output_devices: HashMap::new(),
+            instructions: 0,
+            decoded: cache::DecodeCache::default(),
+            blocks: None,
+            bus: io::Bus::default(),
+
+            tracer: None,
+            accesses: RefCell::new(vec![]),
+
+            history: None,
+            calls: vec![],
         }
     }
 }
@@ -54,31 +79,50 @@ impl Z80 {
 
     fn exec_with_offset(&mut self, op: ops::Op) -> Option<u16> {
         match op {
+            // LD A, I and LD A, R also set flags
+            ops::Op::LD8(
+                ops::Location8::Reg(ops::Reg8::A),
+                src @ ops::Location8::Reg(ops::Reg8::I),
+            )
+            | ops::Op::LD8(
+                ops::Location8::Reg(ops::Reg8::A),
+                src @ ops::Location8::Reg(ops::Reg8::R),
+            ) => self.load_interrupt_reg(&src),
             ops::Op::LD8(dst, src) => self.set_loc8(&dst, self.get_loc8(&src)),
             ops::Op::LD16(dst, src) => self.set_loc16(&dst, self.get_loc16(&src)),
             ops::Op::PUSH(src) => self.push(&src),
             ops::Op::POP(dst) => self.pop(&dst),
+            ops::Op::EX(a, b) => self.exchange(&a, &b),
+            ops::Op::EXX => self.exchange_shadows(),
 
             ops::Op::ADD8(dst, src) => self.add(&dst, &src, false),
             ops::Op::ADC(dst, src) => self.add(&dst, &src, true),
-            ops::Op::INC(dst) => self.add(&dst, &Self::ONE_IMM, false),
+            ops::Op::INC(dst) => self.preserving_carry(|z80| z80.add(&dst, &Self::ONE_IMM, false)),
 
             ops::Op::SUB8(dst, src) => self.subtract(&dst, &src, false, true),
             ops::Op::SBC(dst, src) => self.subtract(&dst, &src, true, true),
-            ops::Op::DEC(dst) => self.subtract(&dst, &Self::ONE_IMM, false, true),
+            ops::Op::DEC(dst) => {
+                self.preserving_carry(|z80| z80.subtract(&dst, &Self::ONE_IMM, false, true))
+            }
             ops::Op::CP(src) => self.subtract(&Self::ACC, &src, false, false),
 
-            ops::Op::AND(src) => self.bool_op(&src, |d, s| d & s),
-            ops::Op::OR(src) => self.bool_op(&src, |d, s| d | s),
-            ops::Op::XOR(src) => self.bool_op(&src, |d, s| d ^ s),
+            ops::Op::ADD16(dst, src) => self.add16(&dst, &src),
+            ops::Op::ADC16(dst, src) => self.add_carry16(&dst, &src, false),
+            ops::Op::SBC16(dst, src) => self.add_carry16(&dst, &src, true),
+            ops::Op::INC16(loc) => self.set_loc16(&loc, self.get_loc16(&loc).wrapping_add(1)),
+            ops::Op::DEC16(loc) => self.set_loc16(&loc, self.get_loc16(&loc).wrapping_sub(1)),
 
-            ops::Op::DAA => unimplemented!(),
+            ops::Op::AND(src) => self.bool_op(&src, true, |d, s| d & s),
+            ops::Op::OR(src) => self.bool_op(&src, false, |d, s| d | s),
+            ops::Op::XOR(src) => self.bool_op(&src, false, |d, s| d ^ s),
+
+            ops::Op::DAA => self.decimal_adjust(),
             ops::Op::CPL => self.complement(),
             ops::Op::NEG => self.negate(),
             ops::Op::CCF => self.toggle_carry(),
             ops::Op::SCF => self.set_carry(),
 
-            ops::Op::NOP => (),
+            ops::Op::NOP | ops::Op::Invalid(_) => (),
             ops::Op::HALT => self.is_halted = true,
 
             ops::Op::RLCA => self.rotate_left(&Self::ACC, false),
@@ -91,7 +135,8 @@ impl Z80 {
             ops::Op::RR(reg) => self.rotate_right_thru_acc(&reg, true),
 
             ops::Op::SRL(loc) => self.shift_right(&loc, false),
-            ops::Op::SLA(loc) => self.shift_left(&loc),
+            ops::Op::SLA(loc) => self.shift_left(&loc, false),
+            ops::Op::SLL(loc) => self.shift_left(&loc, true),
             ops::Op::SRA(loc) => self.shift_right(&loc, true),
 
             ops::Op::RLD => self.rotate_nibble_left(),
@@ -100,6 +145,7 @@ impl Z80 {
             ops::Op::BIT(b, loc) => self.get_bit(b, &loc),
             ops::Op::SET(b, loc) => self.set_bit(b, &loc),
             ops::Op::RES(b, loc) => self.reset_bit(b, &loc),
+            ops::Op::COPY(op, dst) => self.copy_result(*op, dst),
 
             ops::Op::IN(dst, src_port) => self.read_in(&src_port, &dst),
             ops::Op::OUT(src, dst_port) => self.write_out(&dst_port, &src),
@@ -109,10 +155,62 @@ impl Z80 {
             ops::Op::DJNZ(offset) => return self.decrement_jump(offset),
             ops::Op::CALL(cond, addr) => return self.call(cond, addr),
             ops::Op::RET(cond) => return self.return_(cond),
+            ops::Op::RST(addr) => return self.restart(addr),
+            // RETI also restores IFF1
+            ops::Op::RETI | ops::Op::RETN => {
+                let (_, iff2) = self.registers.get_iff();
+                self.registers.set_iff(iff2, iff2);
+                return self.return_(ops::JumpConditional::Unconditional);
+            }
+
+            ops::Op::DI => self.registers.set_iff(false, false),
+            ops::Op::EI => self.registers.set_iff(true, true),
+            ops::Op::IM(mode) => self.registers.set_im(mode),
+
+            ops::Op::LDI => return self.block_load(1, false),
+            ops::Op::LDD => return self.block_load(-1, false),
+            ops::Op::LDIR => return self.block_load(1, true),
+            ops::Op::LDDR => return self.block_load(-1, true),
+            ops::Op::CPI => return self.block_compare(1, false),
+            ops::Op::CPD => return self.block_compare(-1, false),
+            ops::Op::CPIR => return self.block_compare(1, true),
+            ops::Op::CPDR => return self.block_compare(-1, true),
+            ops::Op::INI => return self.block_in(1, false),
+            ops::Op::IND => return self.block_in(-1, false),
+            ops::Op::INIR => return self.block_in(1, true),
+            ops::Op::INDR => return self.block_in(-1, true),
+            ops::Op::OUTI => return self.block_out(1, false),
+            ops::Op::OUTD => return self.block_out(-1, false),
+            ops::Op::OTIR => return self.block_out(1, true),
+            ops::Op::OTDR => return self.block_out(-1, true),
         };
         None
     }
 
+    /// Run an operation on a register in place of its memory operand: load the operand into the
+    /// register, operate, then store the result back. Memory is read and written once, as it is
+    /// by the operation alone
+    fn copy_result(&mut self, op: ops::Op, dst: ops::Reg8) {
+        let loc = match &op {
+            ops::Op::RLC(loc)
+            | ops::Op::RL(loc)
+            | ops::Op::RRC(loc)
+            | ops::Op::RR(loc)
+            | ops::Op::SLA(loc)
+            | ops::Op::SLL(loc)
+            | ops::Op::SRL(loc)
+            | ops::Op::SRA(loc)
+            | ops::Op::SET(_, loc)
+            | ops::Op::RES(_, loc) => loc.clone(),
+            _ => return self.exec(op),
+        };
+        let reg = ops::Location8::Reg(dst);
+        self.set_loc8(&reg, self.get_loc8(&loc));
+        let on_reg = |l| if l == loc { reg.clone() } else { l };
+        self.exec(cpu::opcodes::map_locations(op, on_reg, |l| l));
+        self.set_loc8(&loc, self.get_loc8(&reg));
+    }
+
     fn is_borrow(min: u8, sub: u8, bit: u8) -> bool {
         let mask = (1 << (bit + 1)) - 1;
         (min & mask) < (sub & mask)
@@ -126,67 +224,82 @@ impl Z80 {
         store_result: bool,
     ) {
         let v1 = self.get_loc8(dst);
-        let mut v2 = self.get_loc8(src);
-
-        if include_carry && self.registers.get_flag(&ops::StatusFlag::Carry) {
-            // FIXME: what if _this_ overflows? Behaviour seems undefined
-            v2 += 1
-        }
+        let v2 = self.get_loc8(src);
+        let borrow = u8::from(include_carry && self.registers.get_flag(&ops::StatusFlag::Carry));
 
-        let (sum, ov) = v1.overflowing_sub(v2);
+        let result = v1.wrapping_sub(v2).wrapping_sub(borrow);
         if store_result {
-            self.set_loc8(&dst, sum);
+            self.set_loc8(dst, result);
         }
 
-        // Seven bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::Carry, Self::is_borrow(v1, v2, 6));
+        // Borrow from bit 8
+        self.registers.set_flag(
+            &ops::StatusFlag::Carry,
+            u16::from(v1) < u16::from(v2) + u16::from(borrow),
+        );
         // Subtracting
         self.registers.set_flag(&ops::StatusFlag::AddSubtract, true);
-        // Eight bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::ParityOverflow, ov);
-        // Third bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::HalfCarry, Self::is_borrow(v1, v2, 2));
+        // Signed overflow: the operands' signs differ, and the result's differs from the first
+        self.registers.set_flag(
+            &ops::StatusFlag::ParityOverflow,
+            (v1 ^ v2) & (v1 ^ result) & 0x80 != 0,
+        );
+        // Borrow from bit 4
+        self.registers.set_flag(
+            &ops::StatusFlag::HalfCarry,
+            (v1 & 0x0F) < (v2 & 0x0F) + borrow,
+        );
         // Result is zero
-        self.registers.set_flag(&ops::StatusFlag::Zero, sum == 0);
+        self.registers.set_flag(&ops::StatusFlag::Zero, result == 0);
         // 8th bit is 1
         self.registers
-            .set_flag(&ops::StatusFlag::Sign, (sum & 0b1000_0000) != 0);
+            .set_flag(&ops::StatusFlag::Sign, (result & 0b1000_0000) != 0);
     }
 
     fn add(&mut self, dst: &ops::Location8, src: &ops::Location8, include_carry: bool) {
         let v1 = self.get_loc8(dst);
-        let mut v2 = self.get_loc8(src);
-
-        if include_carry && self.registers.get_flag(&ops::StatusFlag::Carry) {
-            // FIXME: what if _this_ overflows? Behaviour seems undefined
-            v2 += 1
-        }
-
-        let (sum, ov) = v1.overflowing_add(v2);
-        self.set_loc8(&dst, sum);
-        // Seven bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::Carry, (v1 & v2 & 0b0100_0000) != 0);
+        let v2 = self.get_loc8(src);
+        let carry = u8::from(include_carry && self.registers.get_flag(&ops::StatusFlag::Carry));
+
+        let result = v1.wrapping_add(v2).wrapping_add(carry);
+        self.set_loc8(dst, result);
+        // Carry from bit 7
+        self.registers.set_flag(
+            &ops::StatusFlag::Carry,
+            u16::from(v1) + u16::from(v2) + u16::from(carry) > 0xFF,
+        );
         // Adding
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
-        // Eight bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::ParityOverflow, ov);
-        // Third bit carry
-        self.registers
-            .set_flag(&ops::StatusFlag::HalfCarry, (v1 & v2 & 0b00100) != 0);
+        // Signed overflow: the operands' signs match, and the result's doesn't
+        self.registers.set_flag(
+            &ops::StatusFlag::ParityOverflow,
+            !(v1 ^ v2) & (v1 ^ result) & 0x80 != 0,
+        );
+        // Carry from bit 3
+        self.registers.set_flag(
+            &ops::StatusFlag::HalfCarry,
+            (v1 & 0x0F) + (v2 & 0x0F) + carry > 0x0F,
+        );
         // Sum is zero
-        self.registers.set_flag(&ops::StatusFlag::Zero, sum == 0);
+        self.registers.set_flag(&ops::StatusFlag::Zero, result == 0);
         // 8th bit is 1
         self.registers
-            .set_flag(&ops::StatusFlag::Sign, (sum & 0b1000_0000) != 0);
+            .set_flag(&ops::StatusFlag::Sign, (result & 0b1000_0000) != 0);
     }
 
-    fn bool_op<F>(&mut self, src: &ops::Location8, f: F)
+    /// Run an operation that sets the flags, but leave the carry flag as it was
+    fn preserving_carry<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let carry = self.registers.get_flag(&ops::StatusFlag::Carry);
+        f(self);
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry);
+    }
+
+    /// AND, OR and XOR. Only AND sets the half carry flag
+    fn bool_op<F>(&mut self, src: &ops::Location8, half_carry: bool, f: F)
     where
         F: Fn(u8, u8) -> u8,
     {
@@ -201,9 +314,92 @@ impl Z80 {
         // Adding
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
-        // Third bit carry
-        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::HalfCarry, half_carry);
+
+        self.parity_flags(result);
+    }
+
+    fn add16(&mut self, dst: &ops::Location16, src: &ops::Location16) {
+        let v1 = self.get_loc16(dst);
+        let v2 = self.get_loc16(src);
+        let (sum, carry) = v1.overflowing_add(v2);
+        self.set_loc16(dst, sum);
+
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, false);
+        // Carry from bit 11
+        self.registers.set_flag(
+            &ops::StatusFlag::HalfCarry,
+            (v1 & 0x0FFF) + (v2 & 0x0FFF) > 0x0FFF,
+        );
+    }
+
+    /// ADC and SBC on 16-bit registers, which (unlike ADD) set every flag
+    fn add_carry16(&mut self, dst: &ops::Location16, src: &ops::Location16, subtract: bool) {
+        let v1 = self.get_loc16(dst);
+        let v2 = self.get_loc16(src);
+        let carry = u16::from(self.registers.get_flag(&ops::StatusFlag::Carry));
+
+        let (result, carry_out, half, overflow) = if subtract {
+            let result = v1.wrapping_sub(v2).wrapping_sub(carry);
+            (
+                result,
+                u32::from(v1) < u32::from(v2) + u32::from(carry),
+                (v1 & 0x0FFF) < (v2 & 0x0FFF) + carry,
+                ((v1 ^ v2) & (v1 ^ result) & 0x8000) != 0,
+            )
+        } else {
+            let result = v1.wrapping_add(v2).wrapping_add(carry);
+            (
+                result,
+                u32::from(v1) + u32::from(v2) + u32::from(carry) > 0xFFFF,
+                (v1 & 0x0FFF) + (v2 & 0x0FFF) + carry > 0x0FFF,
+                (!(v1 ^ v2) & (v1 ^ result) & 0x8000) != 0,
+            )
+        };
+        self.set_loc16(dst, result);
+
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry_out);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, subtract);
+        self.registers
+            .set_flag(&ops::StatusFlag::ParityOverflow, overflow);
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, half);
+        self.registers.set_flag(&ops::StatusFlag::Zero, result == 0);
+        self.registers
+            .set_flag(&ops::StatusFlag::Sign, (result & 0x8000) != 0);
+    }
+
+    fn decimal_adjust(&mut self) {
+        let a = self.registers.get_reg8(ops::Reg8::A);
+        let subtract = self.registers.get_flag(&ops::StatusFlag::AddSubtract);
+        let half = self.registers.get_flag(&ops::StatusFlag::HalfCarry);
+        let mut carry = self.registers.get_flag(&ops::StatusFlag::Carry);
+
+        let mut correction = 0;
+        if half || (a & 0x0F) > 9 {
+            correction |= 0x06;
+        }
+        if carry || a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+        let result = if subtract {
+            a.wrapping_sub(correction)
+        } else {
+            a.wrapping_add(correction)
+        };
+        let half = if subtract {
+            half && (a & 0x0F) < 6
+        } else {
+            (a & 0x0F) > 9
+        };
+        self.registers.set_reg8(ops::Reg8::A, result);
 
+        self.registers.set_flag(&ops::StatusFlag::Carry, carry);
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, half);
         self.parity_flags(result);
     }
 
@@ -239,6 +435,8 @@ impl Z80 {
     fn toggle_carry(&mut self) {
         let carry = self.registers.get_flag(&ops::StatusFlag::Carry);
         self.registers.set_flag(&ops::StatusFlag::Carry, !carry);
+        // The previous carry
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, carry);
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
     }
@@ -255,7 +453,7 @@ impl Z80 {
         let result = val.rotate_left(1);
         self.set_loc8(loc, result);
         self.registers
-            .set_flag(&ops::StatusFlag::Carry, result & 0x80 != 0);
+            .set_flag(&ops::StatusFlag::Carry, val & 0x80 != 0);
         self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
@@ -290,7 +488,7 @@ impl Z80 {
         let result = val.rotate_right(1);
         self.set_loc8(loc, result);
         self.registers
-            .set_flag(&ops::StatusFlag::Carry, result & 0b1 != 0);
+            .set_flag(&ops::StatusFlag::Carry, val & 0b1 != 0);
         self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
@@ -337,10 +535,14 @@ impl Z80 {
         self.parity_flags(result);
     }
 
-    fn shift_left(&mut self, loc: &ops::Location8) {
+    fn shift_left(&mut self, loc: &ops::Location8, set_low_bit: bool) {
         let val = self.get_loc8(loc);
         let carry = (val & 0x80) != 0;
-        let result = val << 1;
+        let mut result = val << 1;
+
+        if set_low_bit {
+            result |= 0b1;
+        }
 
         self.set_loc8(loc, result);
 
@@ -386,8 +588,13 @@ impl Z80 {
     fn get_bit(&mut self, bit: u8, loc: &ops::Location8) {
         assert!(bit < 8);
         let val = self.get_loc8(loc);
+        let set = val & (1 << bit);
+        // Parity follows zero, and sign shows bit 7 when it is the one tested
+        self.registers.set_flag(&ops::StatusFlag::Zero, set == 0);
         self.registers
-            .set_flag(&ops::StatusFlag::Zero, val & (1 << bit) == 0);
+            .set_flag(&ops::StatusFlag::ParityOverflow, set == 0);
+        self.registers
+            .set_flag(&ops::StatusFlag::Sign, set & 0b1000_0000 != 0);
         self.registers.set_flag(&ops::StatusFlag::HalfCarry, true);
         self.registers
             .set_flag(&ops::StatusFlag::AddSubtract, false);
@@ -405,26 +612,147 @@ impl Z80 {
         self.set_loc8(loc, val & !(1 << bit));
     }
 
-    fn read_in(&mut self, peripheral: &ops::Location8, loc: &ops::Location8) {
-        let peripheral = self.get_loc8(peripheral);
-        let result = match self.input_devices.get_mut(&peripheral) {
-            None => panic!("no peripheral installed in 0x{:02x}", peripheral),
-            Some(d) => d.input(),
+    /// The full address of a port: `(C)` puts B on the upper half of the address bus,
+    /// and an immediate port puts A there
+    fn port_address(&self, port: &ops::Location8) -> u16 {
+        let high = match port {
+            ops::Location8::Reg(ops::Reg8::C) => ops::Reg8::B,
+            _ => ops::Reg8::A,
         };
-        self.set_loc8(loc, result);
+        u16::from_be_bytes([self.registers.get_reg8(high), self.get_loc8(port)])
+    }
+
+    fn read_in(&mut self, port: &ops::Location8, loc: &ops::Location8) {
+        let port = self.port_address(port);
+        let result = self.bus.read(port);
+        self.record_access(trace::Access::PortIn { port, val: result });
+        if *loc == ops::Location8::Reg(ops::Reg8::F) {
+            // IN (C) discards the value, only setting the flags
+            self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+            self.registers
+                .set_flag(&ops::StatusFlag::AddSubtract, false);
+            self.parity_flags(result);
+        } else {
+            self.set_loc8(loc, result);
+        }
+    }
+
+    /// LD A, I and LD A, R: the parity flag shows whether interrupts are enabled
+    fn load_interrupt_reg(&mut self, src: &ops::Location8) {
+        let val = self.get_loc8(src);
+        self.set_loc8(&Self::ACC, val);
+
+        let (_, iff2) = self.registers.get_iff();
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::ParityOverflow, iff2);
+        self.registers.set_flag(&ops::StatusFlag::Zero, val == 0);
+        self.registers
+            .set_flag(&ops::StatusFlag::Sign, (val & 0b1000_0000) != 0);
+    }
+
+    fn exchange(&mut self, a: &ops::Location16, b: &ops::Location16) {
+        let v1 = self.get_loc16(a);
+        let v2 = self.get_loc16(b);
+        self.set_loc16(a, v2);
+        self.set_loc16(b, v1);
+    }
+
+    fn exchange_shadows(&mut self) {
+        use ops::Reg8::*;
+        for (reg, shadow) in [(B, BP), (C, CP), (D, DP), (E, EP), (H, HP), (L, LP)] {
+            let v1 = self.registers.get_reg8(reg);
+            let v2 = self.registers.get_reg8(shadow);
+            self.registers.set_reg8(reg, v2);
+            self.registers.set_reg8(shadow, v1);
+        }
+    }
+
+    /// Step a 16-bit register forwards or backwards
+    fn advance(&mut self, reg: &ops::Reg16, step: i8) {
+        let val = self.registers.get_reg16(reg).wrapping_add(step as u16);
+        self.registers.set_reg16(reg, val);
+    }
+
+    /// Decrement BC, returning whether it is now non-zero
+    fn decrement_bc(&mut self) -> bool {
+        self.advance(&ops::Reg16::BC, -1);
+        self.registers.get_reg16(&ops::Reg16::BC) != 0
+    }
+
+    /// Decrement B, returning whether it is now non-zero
+    fn decrement_b(&mut self) -> bool {
+        let b = self.registers.get_reg8(ops::Reg8::B).wrapping_sub(1);
+        self.registers.set_reg8(ops::Reg8::B, b);
+        self.registers.set_flag(&ops::StatusFlag::Zero, b == 0);
+        self.registers.set_flag(&ops::StatusFlag::AddSubtract, true);
+        b != 0
     }
 
-    fn write_out(&mut self, peripheral: &ops::Location8, loc: &ops::Location8) {
-        let peripheral = self.get_loc8(peripheral);
+    /// The next PC for a block instruction: itself, if it repeats and isn't finished
+    fn repeat(&self, again: bool) -> Option<u16> {
+        if again {
+            Some(self.registers.get_pc())
+        } else {
+            None
+        }
+    }
+
+    /// LDI or LDD, or LDIR or LDDR if `repeat` is set
+    fn block_load(&mut self, step: i8, repeat: bool) -> Option<u16> {
+        let val = self.get_loc8(&Self::HL_INDIRECT);
+        self.set_loc8(&ops::Location8::RegIndirect(ops::Reg16::DE), val);
+        self.advance(&ops::Reg16::HL, step);
+        self.advance(&ops::Reg16::DE, step);
+        let remaining = self.decrement_bc();
+
+        self.registers.set_flag(&ops::StatusFlag::HalfCarry, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::AddSubtract, false);
+        self.registers
+            .set_flag(&ops::StatusFlag::ParityOverflow, remaining);
+        self.repeat(repeat && remaining)
+    }
+
+    /// CPI or CPD, or CPIR or CPDR if `repeat` is set
+    fn block_compare(&mut self, step: i8, repeat: bool) -> Option<u16> {
+        self.preserving_carry(|z80| z80.subtract(&Self::ACC, &Self::HL_INDIRECT, false, false));
+        self.advance(&ops::Reg16::HL, step);
+        let remaining = self.decrement_bc();
+
+        self.registers
+            .set_flag(&ops::StatusFlag::ParityOverflow, remaining);
+        let found = self.registers.get_flag(&ops::StatusFlag::Zero);
+        self.repeat(repeat && remaining && !found)
+    }
+
+    /// INI or IND, or INIR or INDR if `repeat` is set
+    fn block_in(&mut self, step: i8, repeat: bool) -> Option<u16> {
+        self.read_in(&ops::Location8::Reg(ops::Reg8::C), &Self::HL_INDIRECT);
+        self.advance(&ops::Reg16::HL, step);
+        let remaining = self.decrement_b();
+        self.repeat(repeat && remaining)
+    }
+
+    /// OUTI or OUTD, or OTIR or OTDR if `repeat` is set
+    fn block_out(&mut self, step: i8, repeat: bool) -> Option<u16> {
+        let remaining = self.decrement_b();
+        self.write_out(&ops::Location8::Reg(ops::Reg8::C), &Self::HL_INDIRECT);
+        self.advance(&ops::Reg16::HL, step);
+        self.repeat(repeat && remaining)
+    }
+
+    fn write_out(&mut self, port: &ops::Location8, loc: &ops::Location8) {
+        let port = self.port_address(port);
         let val = self.get_loc8(loc);
-        match self.output_devices.get_mut(&peripheral) {
-            None => panic!("no peripheral installed in 0x{:02x}", peripheral),
-            Some(d) => d.output(val),
-        };
+        self.bus.write(port, val);
+        self.record_access(trace::Access::PortOut { port, val });
     }
 
     fn parity_flags(&mut self, val: u8) {
-        let parity = val.count_zeros() % 2 == 0;
+        let parity = val.count_zeros().is_multiple_of(2);
 
         self.registers
             .set_flag(&ops::StatusFlag::ParityOverflow, parity);
@@ -433,15 +761,28 @@ impl Z80 {
             .set_flag(&ops::StatusFlag::Sign, (val & 0b1000_0000) != 0);
     }
 
+    /// Read a byte of memory on behalf of an instruction
+    fn read_mem(&self, addr: u16) -> u8 {
+        let val = self.memory.memory[addr as usize];
+        self.record_access(trace::Access::MemRead { addr, val });
+        val
+    }
+
+    /// Write a byte of memory on behalf of an instruction
+    fn write_mem(&mut self, addr: u16, val: u8) {
+        self.record_write(addr);
+        self.invalidate_code(addr, 1);
+        self.memory.memory[addr as usize] = val;
+        self.record_access(trace::Access::MemWrite { addr, val });
+    }
+
     fn get_loc8(&self, loc: &ops::Location8) -> u8 {
         match loc {
             ops::Location8::Immediate(v) => *v,
             ops::Location8::Reg(reg) => self.registers.get_reg8(*reg),
-            ops::Location8::RegIndirect(reg) => {
-                let addr = self.registers.get_reg16(&reg);
-                self.memory.memory[addr as usize]
-            }
-            ops::Location8::ImmediateIndirect(addr) => self.memory.memory[*addr as usize],
+            ops::Location8::RegIndirect(reg) => self.read_mem(self.registers.get_reg16(reg)),
+            ops::Location8::ImmediateIndirect(addr) => self.read_mem(*addr),
+            ops::Location8::Indexed(reg, d) => self.read_mem(self.indexed_addr(reg, *d)),
         }
     }
 
@@ -449,16 +790,16 @@ impl Z80 {
         match loc {
             ops::Location8::Immediate(_) => panic!("Attempting to set immediate value!"),
             ops::Location8::Reg(reg) => self.registers.set_reg8(*reg, val),
-            ops::Location8::ImmediateIndirect(addr) => {
-                self.memory.memory[*addr as usize] = val;
-            }
-            ops::Location8::RegIndirect(reg) => {
-                let addr = self.registers.get_reg16(reg);
-                self.memory.memory[addr as usize] = val;
-            }
+            ops::Location8::ImmediateIndirect(addr) => self.write_mem(*addr, val),
+            ops::Location8::RegIndirect(reg) => self.write_mem(self.registers.get_reg16(reg), val),
+            ops::Location8::Indexed(reg, d) => self.write_mem(self.indexed_addr(reg, *d), val),
         }
     }
 
+    fn indexed_addr(&self, reg: &ops::Reg16, d: i8) -> u16 {
+        self.registers.get_reg16(reg).wrapping_add(d as u16)
+    }
+
     fn get_loc16(&self, loc: &ops::Location16) -> u16 {
         match loc {
             ops::Location16::Reg(reg) => self.registers.get_reg16(reg),
@@ -466,10 +807,9 @@ impl Z80 {
                 &ops::Location16::ImmediateIndirect(self.registers.get_reg16(reg)),
             ),
             ops::Location16::Immediate(n) => *n,
-            ops::Location16::ImmediateIndirect(n) => u16::from_le_bytes([
-                self.memory.memory[*n as usize],
-                self.memory.memory[(*n + 1) as usize],
-            ]),
+            ops::Location16::ImmediateIndirect(n) => {
+                u16::from_le_bytes([self.read_mem(*n), self.read_mem(n.wrapping_add(1))])
+            }
         }
     }
 
@@ -483,8 +823,8 @@ impl Z80 {
             ),
             ops::Location16::ImmediateIndirect(n) => {
                 let [n1, n2] = v.to_le_bytes();
-                self.memory.memory[*n as usize] = n1;
-                self.memory.memory[(*n + 1) as usize] = n2;
+                self.write_mem(*n, n1);
+                self.write_mem(n.wrapping_add(1), n2);
             }
         }
     }
@@ -548,7 +888,7 @@ impl Z80 {
     fn push_val(&mut self, val: u16) {
         self.registers.set_reg16(
             &ops::Reg16::SP,
-            self.registers.get_reg16(&ops::Reg16::SP) - 2,
+            self.registers.get_reg16(&ops::Reg16::SP).wrapping_sub(2),
         );
         self.set_loc16(&ops::Location16::RegIndirect(ops::Reg16::SP), val);
     }
@@ -561,7 +901,7 @@ impl Z80 {
         let n = self.get_loc16(&ops::Location16::RegIndirect(ops::Reg16::SP));
         self.registers.set_reg16(
             &ops::Reg16::SP,
-            self.registers.get_reg16(&ops::Reg16::SP) + 2,
+            self.registers.get_reg16(&ops::Reg16::SP).wrapping_add(2),
         );
         n
     }
@@ -573,15 +913,27 @@ impl Z80 {
 
     fn call(&mut self, cond: ops::JumpConditional, loc: u16) -> Option<u16> {
         if self.eval_cond(cond) {
-            self.push_val(self.registers.get_pc() + 3); // All CALL instructions are 3 bytes
+            let pc = self.registers.get_pc();
+            let return_address = pc.wrapping_add(3); // All CALL instructions are 3 bytes
+            self.push_val(return_address);
+            self.enter_call(pc, loc, return_address);
             Some(loc)
         } else {
             None
         }
     }
 
+    fn restart(&mut self, addr: u8) -> Option<u16> {
+        let pc = self.registers.get_pc();
+        let return_address = pc.wrapping_add(1); // RST is a single byte
+        self.push_val(return_address);
+        self.enter_call(pc, u16::from(addr), return_address);
+        Some(u16::from(addr))
+    }
+
     fn return_(&mut self, cond: ops::JumpConditional) -> Option<u16> {
         if self.eval_cond(cond) {
+            self.leave_calls(self.registers.get_reg16(&ops::Reg16::SP));
             Some(self.pop_val())
         } else {
             None
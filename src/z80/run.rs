@@ -1,51 +1,93 @@
-extern crate log;
+#[cfg(feature = "trace")]
 use log::debug;
 
 use super::Z80;
 use crate::cpu::opcodes;
-use crate::ops::{Op, Reg16, Reg8};
+use crate::ops::Op;
+#[cfg(feature = "trace")]
+use crate::ops::{Reg16, Reg8};
 
 impl Z80 {
     /// Load a function into memory.
     /// This is done by mapping the provided bytes into memory, starting at 0x0000
-    /// You only have 16 kibibytes to work with, so be careful!
+    /// Memory is 64 kibibytes; anything beyond that is ignored.
     pub fn load(&mut self, program: &[u8]) {
-        for (i, b) in program.iter().enumerate() {
-            self.memory.memory[i] = *b
-        }
+        self.load_at(0x0000, program)
+    }
+
+    /// Load bytes into memory, starting at `addr`.
+    /// Anything that would extend past the end of memory is ignored.
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load_at(0x0100, &[0x3E, 0x01]); // LD A, 1
+    /// assert_eq!(0x3E, z80.memory()[0x0100]);
+    /// ```
+    pub fn load_at(&mut self, addr: u16, bytes: &[u8]) {
+        let memory = &mut self.memory.memory[addr as usize..];
+        let len = memory.len().min(bytes.len());
+        memory[..len].copy_from_slice(&bytes[..len]);
+        self.invalidate_code(addr, len);
+    }
+
+    /// All of memory. Use `load_at` to write to it
+    pub fn memory(&self) -> &[u8] {
+        &self.memory.memory
     }
 
     /// Parse the CPU instruction at the given location.
     /// If the location exists in memory, return the opcode and opcode size in bytes
     /// Otherwise, return none.
-    ///
-    /// # Panics
-    /// Panics if no valid opcode is found and the specified location
+    /// Bytes that aren't a valid instruction are returned as `Op::Invalid`.
     pub fn parse_opcode(&self, location: usize) -> Option<(Op, usize)> {
-        let mem = self.memory.memory;
-        let byte = match mem.get(location) {
-            Some(byte) => *byte,
-            None => return None,
-        };
-
-        let opcode_horizon = [
-            byte,
-            mem.get(location + 1).map_or(0x00, |i| *i),
-            mem.get(location + 2).map_or(0x00, |i| *i),
-            mem.get(location + 3).map_or(0x00, |i| *i),
-        ];
-        Some(opcodes::opcode(opcode_horizon))
+        if location >= self.memory.memory.len() {
+            return None;
+        }
+        // Instructions wrap around from the top of memory to the bottom
+        Some(opcodes::opcode(self.memory.window(location as u16)))
     }
 
     /// Execute a single instruction.
     /// The program counter will be updated to the new position, ready to call step again
-    ///
-    /// # Panics
-    /// Panics if program counter is beyond the end of CPU memory
     pub fn step(&mut self) {
         let pc = self.registers.get_pc();
-        let (opc, consumed) = self.parse_opcode(pc as usize).expect("out of memory range");
-        debug!("Running {:?}", opc);
+        let (opc, consumed) = self.decode(pc);
+        #[cfg(feature = "trace")]
+        self.log_step(&opc);
+        // Prefixed instructions take two opcode fetches, each incrementing R
+        let prefixed =
+            consumed > 1 && matches!(self.memory.memory[pc as usize], 0xCB | 0xED | 0xDD | 0xFD);
+        let checkpoint = self.history_checkpoint();
+        let traced = if self.is_tracing() {
+            // Capture the instruction now, in case it overwrites itself
+            let bytes: [u8; 4] = self.memory.window(pc);
+            Some((
+                opc.clone(),
+                bytes[..consumed].to_vec(),
+                self.registers.clone(),
+            ))
+        } else {
+            None
+        };
+        self.registers.increment_r(if prefixed { 2 } else { 1 });
+        let next_pc = self
+            .exec_with_offset(opc)
+            .unwrap_or_else(|| pc.wrapping_add(consumed as u16));
+        self.registers.set_pc(next_pc);
+        self.instructions += 1;
+        if let Some(checkpoint) = checkpoint {
+            self.commit_history(checkpoint);
+        }
+        if let Some((opc, bytes, before)) = traced {
+            self.record_step(pc, bytes, opc, before);
+        }
+    }
+
+    /// Log an instruction about to be executed, with the registers it starts from
+    #[cfg(feature = "trace")]
+    fn log_step(&self, op: &Op) {
+        debug!("Running {:?}", op);
         debug!(
             "A: {:02x}, B: {:02x}, C: {:02x}, D: {:02x}, HL: {:04x}, F: {:08b}, PC: {:02x}",
             self.registers.get_reg8(Reg8::A),
@@ -56,18 +98,34 @@ impl Z80 {
             self.registers.get_reg8(Reg8::F),
             self.registers.get_pc(),
         );
-        let pc = self
-            .exec_with_offset(opc) //dbg!(opc))
-            .unwrap_or(pc + consumed as u16);
-        self.registers.set_pc(pc as u16)
+    }
+
+    /// Whether a HALT instruction has been executed
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    /// The number of instructions executed by `step` (or `run` and `run_for`)
+    pub fn instructions(&self) -> u64 {
+        self.instructions
     }
 
     /// Start executing.
-    /// The program counter is set to 0x0000, and instructions are executed until a HALT is encountered.
-    /// If the program does not contain a HALT, the emulator will simply continue until it runs out of memory.
+    /// Instructions are executed from the current program counter until a HALT is encountered.
+    /// If the program never executes a HALT, this never returns; `run_for` sets a limit.
     pub fn run(&mut self) {
-        while !self.is_halted {
-            self.step()
+        self.run_for(u64::MAX)
+    }
+
+    /// Execute `n` instructions, or fewer if a HALT is encountered first
+    pub fn run_for(&mut self, n: u64) {
+        let end = self.instructions.saturating_add(n);
+        while !self.is_halted && self.instructions < end {
+            if self.uses_block_engine() {
+                self.run_block(end)
+            } else {
+                self.step()
+            }
         }
     }
 }
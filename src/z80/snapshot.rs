@@ -0,0 +1,330 @@
+//! Saving and restoring the complete state of the machine.
+//!
+//! A `Snapshot` holds the registers, all of memory, the halt flag and the number of
+//! instructions executed. Installed devices, tracers, history and the call stack are not included.
+//!
+//! Snapshots can be stored in a versioned binary format (all multi-byte values little-endian):
+//!
+//! * the header `ZSNP`, then the format version (u16, currently 2)
+//! * the registers: A F B C D E H L A' F' B' C' D' E' H' L' (u8 each), then IX IY SP PC (u16 each)
+//! * the interrupt state: I R IFF1 IFF2 IM (u8 each). Version 1 snapshots don't have these
+//! * the halt flag (u8, 0 or 1)
+//! * the number of instructions executed (u64)
+//! * the size of memory (u32), followed by its contents
+//!
+//! With the `serde` feature enabled, `Snapshot` also implements `Serialize` and `Deserialize`.
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::Z80;
+use crate::cpu::mem::MEMORY_SIZE;
+use crate::cpu::reg::Registers;
+use crate::ops::{Reg16, Reg8};
+
+/// The 8-bit registers, in the order they are stored
+const REG8: [Reg8; 16] = [
+    Reg8::A,
+    Reg8::F,
+    Reg8::B,
+    Reg8::C,
+    Reg8::D,
+    Reg8::E,
+    Reg8::H,
+    Reg8::L,
+    Reg8::AP,
+    Reg8::FP,
+    Reg8::BP,
+    Reg8::CP,
+    Reg8::DP,
+    Reg8::EP,
+    Reg8::HP,
+    Reg8::LP,
+];
+
+/// The 16-bit registers (other than PC), in the order they are stored
+const REG16: [Reg16; 3] = [Reg16::IX, Reg16::IY, Reg16::SP];
+
+/// The complete state of a `Z80`, as produced by `Z80::snapshot`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot {
+    pub registers: Registers,
+    pub memory: Vec<u8>,
+    pub is_halted: bool,
+    /// The number of instructions executed by `step`
+    pub instructions: u64,
+}
+
+/// Errors that can occur while reading a snapshot
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying reader failed, or the snapshot was truncated
+    Io(io::Error),
+    /// The data did not start with the snapshot header
+    BadMagic,
+    /// The snapshot was written by a newer version of zeerust
+    UnsupportedVersion(u16),
+    /// The snapshot's memory is not the size of the z80's memory
+    BadMemorySize(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "error reading snapshot: {}", e),
+            Error::BadMagic => write!(f, "not a zeerust snapshot"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            Error::BadMemorySize(s) => write!(f, "snapshot has {} bytes of memory", s),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Snapshot {
+    pub const MAGIC: &'static [u8] = b"ZSNP";
+    pub const VERSION: u16 = 2;
+
+    /// Write the snapshot in the binary format described in the module documentation
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(Self::MAGIC)?;
+        w.write_all(&Self::VERSION.to_le_bytes())?;
+        for r in &REG8 {
+            w.write_all(&[self.registers.get_reg8(*r)])?;
+        }
+        for r in &REG16 {
+            w.write_all(&self.registers.get_reg16(r).to_le_bytes())?;
+        }
+        w.write_all(&self.registers.get_pc().to_le_bytes())?;
+        let (iff1, iff2) = self.registers.get_iff();
+        w.write_all(&[
+            self.registers.get_reg8(Reg8::I),
+            self.registers.get_reg8(Reg8::R),
+            iff1 as u8,
+            iff2 as u8,
+            self.registers.get_im(),
+        ])?;
+        w.write_all(&[self.is_halted as u8])?;
+        w.write_all(&self.instructions.to_le_bytes())?;
+        w.write_all(&(self.memory.len() as u32).to_le_bytes())?;
+        w.write_all(&self.memory)
+    }
+
+    /// Read a snapshot written by `write_to`
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if magic != Self::MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = u16::from_le_bytes(read_array(r)?);
+        if version == 0 || version > Self::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut registers = Registers::default();
+        for reg in &REG8 {
+            let [v] = read_array(r)?;
+            registers.set_reg8(*reg, v);
+        }
+        for reg in &REG16 {
+            registers.set_reg16(reg, u16::from_le_bytes(read_array(r)?));
+        }
+        registers.set_pc(u16::from_le_bytes(read_array(r)?));
+        if version >= 2 {
+            let [i, refresh, iff1, iff2, im] = read_array(r)?;
+            registers.set_reg8(Reg8::I, i);
+            registers.set_reg8(Reg8::R, refresh);
+            registers.set_iff(iff1 != 0, iff2 != 0);
+            registers.set_im(im);
+        }
+
+        let [halted] = read_array(r)?;
+        let instructions = u64::from_le_bytes(read_array(r)?);
+        let size = u32::from_le_bytes(read_array(r)?);
+        if size as usize != MEMORY_SIZE {
+            return Err(Error::BadMemorySize(size));
+        }
+        let mut memory = vec![0; MEMORY_SIZE];
+        r.read_exact(&mut memory)?;
+
+        Ok(Self {
+            registers,
+            memory,
+            is_halted: halted != 0,
+            instructions,
+        })
+    }
+
+    /// The snapshot in its binary format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.write_to(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    /// Parse a snapshot from its binary format
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, Error> {
+        Self::read_from(&mut bytes)
+    }
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+impl Z80 {
+    /// Capture the current state of the machine.
+    /// ```
+    /// use zeerust::z80::Z80;
+    ///
+    /// let mut z80 = Z80::default();
+    /// z80.load(&[0x3E, 0x01, 0x3E, 0x02]); // LD A, 1; LD A, 2
+    /// z80.step();
+    /// let snapshot = z80.snapshot();
+    /// z80.step();
+    ///
+    /// z80.restore(&snapshot).unwrap();
+    /// assert_eq!(0x0002, z80.registers.get_pc());
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            memory: self.memory.memory.to_vec(),
+            is_halted: self.is_halted,
+            instructions: self.instructions,
+        }
+    }
+
+    /// Return the machine to a previously captured state.
+    /// Recorded history and the call stack are discarded, since they no longer apply.
+    ///
+    /// # Errors
+    /// Fails without changing anything if the snapshot's memory is the wrong size.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        if snapshot.memory.len() != MEMORY_SIZE {
+            return Err(Error::BadMemorySize(snapshot.memory.len() as u32));
+        }
+        self.load(&snapshot.memory);
+        self.registers = snapshot.registers.clone();
+        self.is_halted = snapshot.is_halted;
+        self.instructions = snapshot.instructions;
+        if let Some(h) = self.history.as_mut() {
+            h.clear();
+        }
+        self.clear_calls();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn z80() -> Z80 {
+        let mut z80 = Z80::default();
+        // LD HL, 1000h; LD (HL), 5Ah; LD A, 12h; HALT
+        z80.load(&[0x21, 0x00, 0x10, 0x36, 0x5A, 0x3E, 0x12, 0x76]);
+        z80.registers.set_reg8(Reg8::CP, 0x34);
+        z80.registers.set_reg16(&Reg16::IY, 0xBEEF);
+        z80
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut z80 = z80();
+        z80.step();
+        let snapshot = z80.snapshot();
+        assert_eq!(1, snapshot.instructions);
+
+        z80.run();
+        assert!(z80.is_halted());
+        assert_eq!(4, z80.instructions());
+
+        z80.restore(&snapshot).unwrap();
+        assert!(!z80.is_halted());
+        assert_eq!(1, z80.instructions());
+        assert_eq!(0x03, z80.registers.get_pc());
+        assert_eq!(0, z80.memory.memory[0x1000]);
+        assert_eq!(snapshot, z80.snapshot());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut z80 = z80();
+        z80.run();
+        let snapshot = z80.snapshot();
+        let bytes = snapshot.to_bytes();
+
+        assert_eq!(b"ZSNP\x02\x00", &bytes[..6]);
+        assert_eq!(6 + 16 + 8 + 5 + 1 + 8 + 4 + MEMORY_SIZE, bytes.len());
+        assert_eq!(snapshot, Snapshot::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn read_version_1() {
+        let mut z80 = z80();
+        z80.run();
+        let mut snapshot = z80.snapshot();
+        snapshot.registers.set_reg8(Reg8::R, 0);
+
+        // Version 1 is version 2 without the interrupt state
+        let mut bytes = snapshot.to_bytes();
+        bytes[4] = 1;
+        bytes.drain(6 + 16 + 8..6 + 16 + 8 + 5);
+        assert_eq!(snapshot, Snapshot::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn bad_snapshots() {
+        let bytes = z80().snapshot().to_bytes();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(Snapshot::from_bytes(&bad), Err(Error::BadMagic)));
+
+        let mut bad = bytes.clone();
+        bad[4] = 99;
+        assert!(matches!(
+            Snapshot::from_bytes(&bad),
+            Err(Error::UnsupportedVersion(99))
+        ));
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..100]),
+            Err(Error::Io(_))
+        ));
+
+        let mut snapshot = z80().snapshot();
+        snapshot.memory.truncate(10);
+        assert!(matches!(
+            z80().restore(&snapshot),
+            Err(Error::BadMemorySize(10))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let snapshot = z80().snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(snapshot, serde_json::from_str(&json).unwrap());
+    }
+}
@@ -5,8 +5,8 @@ use crate::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8, Status
 fn get_loc8() {
     let mut z80 = Z80::default();
     z80.registers.set_reg8(Reg8::A, 0xC5);
-    z80.registers.set_reg8(Reg8::H, 0xAA);
-    z80.registers.set_reg8(Reg8::L, 0x0F);
+    z80.registers.set_reg8(Reg8::H, 0x0F);
+    z80.registers.set_reg8(Reg8::L, 0xAA);
     z80.memory.memory[0x0FAA] = 0xD1;
     z80.memory.memory[0x0DCC] = 0x75;
 
@@ -19,8 +19,8 @@ fn get_loc8() {
 #[test]
 fn get_loc16() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x0D);
+    z80.registers.set_reg8(Reg8::H, 0x0D);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
 
     assert_hex!(0x0DCC, z80.get_loc16(&Location16::Reg(Reg16::HL)));
     assert_hex!(0xF0C5, z80.get_loc16(&Location16::Immediate(0xF0C5)));
@@ -34,12 +34,47 @@ fn get_loc16() {
 }
 
 #[test]
-#[should_panic]
-fn get_loc8_segfault() {
+fn load_at() {
+    let mut z80 = Z80::default();
+    z80.load_at(0x8000, &[0x01, 0x02]);
+    assert_hex!(0x00, z80.memory.memory[0x7FFF]);
+    assert_hex!(0x01, z80.memory.memory[0x8000]);
+    assert_hex!(0x02, z80.memory.memory[0x8001]);
+    // Loading doesn't wrap around past the end of memory
+    z80.load_at(0xFFFF, &[0x03, 0x04]);
+    assert_hex!(0x03, z80.memory.memory[0xFFFF]);
+    assert_hex!(0x00, z80.memory.memory[0x0000]);
+}
+
+#[test]
+fn get_loc_top_of_memory() {
     let mut z80 = Z80::default();
+    z80.memory.memory[0xFFFF] = 0xAB;
+    z80.memory.memory[0x0000] = 0xCD;
     z80.registers.set_reg8(Reg8::H, 0xFF);
     z80.registers.set_reg8(Reg8::L, 0xFF);
-    z80.get_loc8(&Location8::RegIndirect(Reg16::HL));
+    assert_hex!(0xAB, z80.get_loc8(&Location8::RegIndirect(Reg16::HL)));
+    // 16-bit reads wrap around to the bottom of memory
+    assert_hex!(
+        0xCDAB,
+        z80.get_loc16(&Location16::ImmediateIndirect(0xFFFF))
+    );
+}
+
+#[test]
+fn parse_opcode_top_of_memory() {
+    let mut z80 = Z80::default();
+    // LD HL, 0x1234, split across the top and bottom of memory
+    z80.load_at(0xFFFE, &[0x21, 0x34]);
+    z80.load(&[0x12]);
+    assert_eq!(
+        Some((
+            Op::LD16(Location16::Reg(Reg16::HL), Location16::Immediate(0x1234)),
+            3
+        )),
+        z80.parse_opcode(0xFFFE)
+    );
+    assert_eq!(None, z80.parse_opcode(0x10000));
 }
 
 #[test]
@@ -55,8 +90,8 @@ fn set_loc8() {
     z80.set_loc8(&Location8::Reg(Reg8::A), 0xDD);
     assert_hex!(0xDD, z80.registers.get_reg8(Reg8::A));
 
-    z80.registers.set_reg8(Reg8::H, 0x11);
-    z80.registers.set_reg8(Reg8::L, 0x0A);
+    z80.registers.set_reg8(Reg8::H, 0x0A);
+    z80.registers.set_reg8(Reg8::L, 0x11);
 
     z80.set_loc8(&Location8::RegIndirect(Reg16::HL), 0xEE);
     assert_hex!(0xEE, z80.memory.memory[0x0A11]);
@@ -119,6 +154,21 @@ fn push_op() {
     assert_hex!(0x1005, z80.registers.get_reg16(&Reg16::SP));
 }
 
+#[test]
+fn push_default_sp() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::BC, 0x1234);
+    z80.exec(Op::PUSH(Location16::Reg(Reg16::BC)));
+    assert_hex!(0xFFFE, z80.registers.get_reg16(&Reg16::SP));
+    assert_hex!(
+        0x1234,
+        z80.get_loc16(&Location16::ImmediateIndirect(0xFFFE))
+    );
+    z80.exec(Op::POP(Location16::Reg(Reg16::DE)));
+    assert_hex!(0x0000, z80.registers.get_reg16(&Reg16::SP));
+    assert_hex!(0x1234, z80.registers.get_reg16(&Reg16::DE));
+}
+
 #[test]
 fn pop_op() {
     let mut z80 = Z80::default();
@@ -143,10 +193,10 @@ fn add8_op() {
         z80.registers,
         Sign = true,
         Zero = false,
-        HalfCarry = true,
-        ParityOverflow = false,
+        HalfCarry = false,
+        ParityOverflow = true,
         AddSubtract = false,
-        Carry = true,
+        Carry = false,
     );
 
     z80.registers.set_reg8(Reg8::A, 0xFF);
@@ -159,18 +209,18 @@ fn add8_op() {
         z80.registers,
         Sign = false,
         Zero = true,
-        HalfCarry = false,
-        ParityOverflow = true,
+        HalfCarry = true,
+        ParityOverflow = false,
         AddSubtract = false,
-        Carry = false,
+        Carry = true,
     );
 }
 
 #[test]
 fn inc_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.memory.memory[0x20CC] = 0xFF;
 
     z80.exec(Op::INC(Location8::RegIndirect(Reg16::HL)));
@@ -180,8 +230,8 @@ fn inc_op() {
         z80.registers,
         Sign = false,
         Zero = true,
-        HalfCarry = false,
-        ParityOverflow = true,
+        HalfCarry = true,
+        ParityOverflow = false,
         AddSubtract = false,
         Carry = false,
     );
@@ -198,10 +248,10 @@ fn adc8_op() {
         z80.registers,
         Sign = true,
         Zero = false,
-        HalfCarry = true,
-        ParityOverflow = false,
+        HalfCarry = false,
+        ParityOverflow = true,
         AddSubtract = false,
-        Carry = true,
+        Carry = false,
     );
 }
 
@@ -219,9 +269,9 @@ fn sub8_op() {
         Sign = false,
         Zero = false,
         HalfCarry = true,
-        ParityOverflow = false,
+        ParityOverflow = true,
         AddSubtract = true,
-        Carry = true,
+        Carry = false,
     );
 
     z80.registers.set_reg8(Reg8::A, 0x0A); // 10
@@ -229,13 +279,13 @@ fn sub8_op() {
         Location8::Reg(Reg8::A),
         Location8::Immediate(0x0B), // 11
     ));
-    assert_bin!((0xFF) as u8, z80.registers.get_reg8(Reg8::A)); // -1
+    assert_bin!(0xFF_u8, z80.registers.get_reg8(Reg8::A)); // -1
     assert_flags!(
         z80.registers,
         Sign = true,
         Zero = false,
         HalfCarry = true,
-        ParityOverflow = true,
+        ParityOverflow = false,
         AddSubtract = true,
         Carry = true,
     );
@@ -252,9 +302,9 @@ fn cp_op() {
         Sign = false,
         Zero = false,
         HalfCarry = true,
-        ParityOverflow = false,
+        ParityOverflow = true,
         AddSubtract = true,
-        Carry = true,
+        Carry = false,
     );
 }
 
@@ -276,6 +326,20 @@ fn sbc8_op() {
     );
 }
 
+// Found by fuzzing: adding the carry to 0xFF overflowed
+#[test]
+fn carry_into_ff() {
+    let mut z80 = Z80::default();
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.registers.set_reg8(Reg8::A, 0x12);
+    z80.exec(Op::SBC(Location8::Reg(Reg8::A), Location8::Immediate(0xFF)));
+    assert_hex!(0x12, z80.registers.get_reg8(Reg8::A));
+
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.exec(Op::ADC(Location8::Reg(Reg8::A), Location8::Immediate(0xFF)));
+    assert_hex!(0x12, z80.registers.get_reg8(Reg8::A));
+}
+
 #[test]
 fn dec_op() {
     let mut z80 = Z80::default();
@@ -293,6 +357,21 @@ fn dec_op() {
     );
 }
 
+#[test]
+fn inc_dec_keep_carry() {
+    let mut z80 = Z80::default();
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.registers.set_reg8(Reg8::A, 0x0F);
+    z80.exec(Op::INC(Location8::Reg(Reg8::A)));
+    assert_flags!(z80.registers, HalfCarry = true, Carry = true,);
+
+    z80.registers.set_flag(&StatusFlag::Carry, false);
+    z80.registers.set_reg8(Reg8::A, 0x00);
+    z80.exec(Op::DEC(Location8::Reg(Reg8::A)));
+    assert_hex!(0xFF, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(z80.registers, HalfCarry = true, Carry = false,);
+}
+
 #[test]
 fn and_op() {
     let mut z80 = Z80::default();
@@ -303,7 +382,7 @@ fn and_op() {
         z80.registers,
         Sign = false,
         Zero = true,
-        HalfCarry = false,
+        HalfCarry = true,
         ParityOverflow = true,
         AddSubtract = false,
         Carry = false,
@@ -345,10 +424,34 @@ fn xor_op() {
 }
 
 #[test]
-#[should_panic]
 fn daa_op() {
     let mut z80 = Z80::default();
+    // 0x15 + 0x27 = 0x3C, which is 42 in BCD once adjusted
+    z80.registers.set_reg8(Reg8::A, 0x3C);
     z80.exec(Op::DAA);
+    assert_hex!(0x42, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(z80.registers, Carry = false, HalfCarry = true, Zero = false,);
+
+    // 0x99 + 0x01 = 0x9A, which is 100 once adjusted
+    z80.registers.set_reg8(Reg8::A, 0x9A);
+    z80.registers.set_flag(&StatusFlag::HalfCarry, false);
+    z80.exec(Op::DAA);
+    assert_hex!(0x00, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(
+        z80.registers,
+        Carry = true,
+        Zero = true,
+        ParityOverflow = true,
+    );
+
+    // 0x42 - 0x15 = 0x2D, with a half borrow, which is 27 once adjusted
+    z80.registers.set_reg8(Reg8::A, 0x2D);
+    z80.registers.set_flag(&StatusFlag::AddSubtract, true);
+    z80.registers.set_flag(&StatusFlag::HalfCarry, true);
+    z80.registers.set_flag(&StatusFlag::Carry, false);
+    z80.exec(Op::DAA);
+    assert_hex!(0x27, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(z80.registers, Carry = false, AddSubtract = true,);
 }
 
 #[test]
@@ -437,9 +540,20 @@ fn ccf_op() {
     z80.registers.set_flag(&StatusFlag::AddSubtract, true);
     z80.registers.set_flag(&StatusFlag::Carry, false);
     z80.exec(Op::CCF);
-    assert_flags!(z80.registers, Carry = true, AddSubtract = false,);
+    assert_flags!(
+        z80.registers,
+        Carry = true,
+        AddSubtract = false,
+        HalfCarry = false,
+    );
+    // The half carry takes the previous carry
     z80.exec(Op::CCF);
-    assert_flags!(z80.registers, Carry = false, AddSubtract = false,);
+    assert_flags!(
+        z80.registers,
+        Carry = false,
+        AddSubtract = false,
+        HalfCarry = true,
+    );
 }
 
 #[test]
@@ -467,7 +581,7 @@ fn rlca_op() {
         z80.registers,
         HalfCarry = false,
         AddSubtract = false,
-        Carry = true,
+        Carry = false,
     );
 }
 
@@ -507,7 +621,7 @@ fn rrca_op() {
         z80.registers,
         HalfCarry = false,
         AddSubtract = false,
-        Carry = false,
+        Carry = true,
     );
 }
 
@@ -665,8 +779,8 @@ fn sra_op() {
 #[test]
 fn rld_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b0111_1010);
     z80.memory.memory[0x20CC] = 0b0011_0001;
 
@@ -684,8 +798,8 @@ fn rld_op() {
     );
 
     // Zero accumulator
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b0000_1010);
     z80.memory.memory[0x20CC] = 0b0000_1110;
 
@@ -706,8 +820,8 @@ fn rld_op() {
 #[test]
 fn rrd_op() {
     let mut z80 = Z80::default();
-    z80.registers.set_reg8(Reg8::H, 0xCC);
-    z80.registers.set_reg8(Reg8::L, 0x20);
+    z80.registers.set_reg8(Reg8::H, 0x20);
+    z80.registers.set_reg8(Reg8::L, 0xCC);
     z80.registers.set_reg8(Reg8::A, 0b1000_0100);
     z80.memory.memory[0x20CC] = 0b0010_0000;
 
@@ -736,6 +850,8 @@ fn bit_op() {
         assert_flags!(
             z80.registers,
             Zero = *expect,
+            ParityOverflow = *expect,
+            Sign = i == 7,
             HalfCarry = true,
             AddSubtract = false,
         );
@@ -819,10 +935,36 @@ fn in_op() {
 }
 
 #[test]
-#[should_panic(expected = "no peripheral installed in 0x00")]
 fn in_no_device_installed() {
     let mut z80 = Z80::default();
     z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0xFF, z80.registers.get_reg8(Reg8::A));
+
+    z80.set_unmapped_input(0x00);
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0x00, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn in_past_end_of_buffer() {
+    let mut z80 = Z80::default();
+    z80.set_unmapped_input(0x42);
+    z80.install_input(0x00, Box::new(super::io::BufInput::new(vec![0x11])));
+    z80.connect(
+        super::io::Ports::exact(0x0001),
+        Box::new(super::io::BufInput::new(vec![0x22])),
+    );
+
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0x11, z80.registers.get_reg8(Reg8::A));
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0x42, z80.registers.get_reg8(Reg8::A));
+
+    z80.registers.set_reg16(&Reg16::BC, 0x0001);
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Reg(Reg8::C)));
+    assert_hex!(0x22, z80.registers.get_reg8(Reg8::A));
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Reg(Reg8::C)));
+    assert_hex!(0x42, z80.registers.get_reg8(Reg8::A));
 }
 
 #[test]
@@ -852,10 +994,141 @@ fn out_op() {
 }
 
 #[test]
-#[should_panic(expected = "no peripheral installed in 0x00")]
 fn out_no_device_installed() {
     let mut z80 = Z80::default();
+    let buf = super::io::BufOutput::default();
+    z80.install_output(0x01, Box::new(buf.clone()));
     z80.exec(Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert!(buf.result().is_empty());
+}
+
+/// Records the full address of every port read or written
+#[derive(Default, Clone)]
+struct PortLog(std::sync::Arc<std::sync::Mutex<Vec<u16>>>);
+
+impl super::io::IoDevice for PortLog {
+    fn read(&mut self, port: u16) -> Option<u8> {
+        self.0.lock().unwrap().push(port);
+        Some(port as u8)
+    }
+
+    fn write(&mut self, port: u16, _val: u8) -> bool {
+        self.0.lock().unwrap().push(port);
+        true
+    }
+}
+
+#[test]
+fn port_addresses() {
+    let mut z80 = Z80::default();
+    let log = PortLog::default();
+    z80.connect(
+        super::io::Ports {
+            mask: 0,
+            matches: 0,
+        },
+        Box::new(log.clone()),
+    );
+    z80.registers.set_reg16(&Reg16::BC, 0x1234);
+    z80.registers.set_reg16(&Reg16::HL, 0x1000);
+
+    z80.registers.set_reg8(Reg8::A, 0xAB);
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0xCD)));
+    z80.exec(Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0xFE)));
+    z80.exec(Op::IN(Location8::Reg(Reg8::D), Location8::Reg(Reg8::C)));
+    z80.exec(Op::OUT(Location8::Reg(Reg8::D), Location8::Reg(Reg8::C)));
+    z80.exec(Op::INI);
+    // B is decremented before OUTI puts it on the address bus
+    z80.exec(Op::OUTI);
+    assert_eq!(
+        vec![0xABCD, 0xCDFE, 0x1234, 0x1234, 0x1234, 0x1034],
+        *log.0.lock().unwrap()
+    );
+}
+
+#[test]
+fn partial_decoding() {
+    let mut z80 = Z80::default();
+    let even = PortLog::default();
+    let high = super::io::BufOutput::default();
+    z80.connect(
+        super::io::Ports {
+            mask: 0x0001,
+            matches: 0x0000,
+        },
+        Box::new(even.clone()),
+    );
+    z80.connect(super::io::Ports::exact(0x7FFE), Box::new(high.clone()));
+
+    z80.registers.set_reg16(&Reg16::BC, 0x7FFE);
+    z80.registers.set_reg8(Reg8::D, 0x42);
+    z80.exec(Op::IN(Location8::Reg(Reg8::E), Location8::Reg(Reg8::C)));
+    assert_hex!(0xFE, z80.registers.get_reg8(Reg8::E));
+    z80.exec(Op::OUT(Location8::Reg(Reg8::D), Location8::Reg(Reg8::C)));
+    assert_eq!(vec![0x42], high.result());
+
+    z80.registers.set_reg16(&Reg16::BC, 0xBFFE);
+    z80.exec(Op::OUT(Location8::Reg(Reg8::D), Location8::Reg(Reg8::C)));
+    assert_eq!(vec![0x42], high.result());
+    assert_eq!(vec![0x7FFE, 0xBFFE], *even.0.lock().unwrap());
+
+    z80.registers.set_reg16(&Reg16::BC, 0xBFFF);
+    z80.exec(Op::IN(Location8::Reg(Reg8::E), Location8::Reg(Reg8::C)));
+    assert_hex!(0xFF, z80.registers.get_reg8(Reg8::E));
+}
+
+#[test]
+fn z80_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Z80>();
+}
+
+#[test]
+fn channel_devices() {
+    use super::io::{ChannelInput, ChannelOutput, Ports};
+    use std::sync::mpsc;
+
+    let (input, receiver) = mpsc::channel();
+    let (sender, output) = mpsc::channel();
+    let mut z80 = Z80::default();
+    z80.connect(Ports::low(0x00), Box::new(ChannelInput::new(receiver)));
+    z80.connect(Ports::low(0x01), Box::new(ChannelOutput::new(sender)));
+    // Echo bytes until a zero: IN A, (0); OR A; JR Z, +4; OUT (1), A; JR -9; HALT
+    z80.load(&[0xDB, 0x00, 0xB7, 0x28, 0x04, 0xD3, 0x01, 0x18, 0xF7, 0x76]);
+
+    let cpu = std::thread::spawn(move || {
+        z80.run();
+        z80
+    });
+    for &b in b"Zee" {
+        input.send(b).unwrap();
+        assert_eq!(b, output.recv().unwrap());
+    }
+    input.send(0).unwrap();
+    let z80 = cpu.join().unwrap();
+    assert!(z80.is_halted());
+    assert!(output.try_recv().is_err());
+}
+
+#[test]
+fn polling_channel_input() {
+    use super::io::{ChannelInput, ChannelOutput, Ports};
+    use std::sync::mpsc;
+
+    let (input, receiver) = mpsc::channel();
+    let mut z80 = Z80::default();
+    z80.connect(Ports::low(0x00), Box::new(ChannelInput::polling(receiver)));
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0xFF, z80.registers.get_reg8(Reg8::A));
+    input.send(0x42).unwrap();
+    z80.exec(Op::IN(Location8::Reg(Reg8::A), Location8::Immediate(0x00)));
+    assert_hex!(0x42, z80.registers.get_reg8(Reg8::A));
+
+    // Writes are discarded once nothing is receiving them
+    let (sender, output) = mpsc::channel();
+    drop(output);
+    z80.connect(Ports::low(0x01), Box::new(ChannelOutput::new(sender)));
+    z80.exec(Op::OUT(Location8::Reg(Reg8::A), Location8::Immediate(0x01)));
 }
 
 #[test]
@@ -1043,3 +1316,432 @@ fn call_cond_carry() {
 
     // Not testing the other states, well covered by the JP tests
 }
+
+#[test]
+fn indexed() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::IX, 0x1000);
+    z80.memory.memory[0x1005] = 0x12;
+    z80.memory.memory[0x0FFE] = 0x34;
+
+    assert_hex!(0x12, z80.get_loc8(&Location8::Indexed(Reg16::IX, 5)));
+    assert_hex!(0x34, z80.get_loc8(&Location8::Indexed(Reg16::IX, -2)));
+    z80.set_loc8(&Location8::Indexed(Reg16::IX, 127), 0x56);
+    assert_hex!(0x56, z80.memory.memory[0x107F]);
+}
+
+#[test]
+fn copy_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::IY, 0x1000);
+    z80.memory.memory[0x0FFF] = 0b1001_0001;
+    let rlc = Op::RLC(Location8::Indexed(Reg16::IY, -1));
+    z80.exec(Op::COPY(Box::new(rlc), Reg8::D));
+    assert_bin!(0b0010_0011, z80.memory.memory[0x0FFF]);
+    assert_bin!(0b0010_0011, z80.registers.get_reg8(Reg8::D));
+    assert_flags!(z80.registers, Carry = true,);
+
+    let res = Op::RES(0, Location8::Indexed(Reg16::IY, -1));
+    z80.exec(Op::COPY(Box::new(res), Reg8::A));
+    assert_bin!(0b0010_0010, z80.memory.memory[0x0FFF]);
+    assert_bin!(0b0010_0010, z80.registers.get_reg8(Reg8::A));
+    assert_bin!(0b0010_0011, z80.registers.get_reg8(Reg8::D));
+}
+
+#[test]
+fn sll_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg8(Reg8::B, 0b1001_0001);
+    z80.exec(Op::SLL(Location8::Reg(Reg8::B)));
+    assert_bin!(0b0010_0011, z80.registers.get_reg8(Reg8::B));
+    assert_flags!(z80.registers, Carry = true,);
+}
+
+#[test]
+fn invalid_op() {
+    let mut z80 = Z80::default();
+    z80.load(&[0xED, 0x00]);
+    z80.step();
+    assert_eq!(0x0002, z80.registers.get_pc());
+}
+
+#[test]
+fn ex_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::DE, 0x1234);
+    z80.registers.set_reg16(&Reg16::HL, 0x5678);
+    z80.exec(Op::EX(
+        Location16::Reg(Reg16::DE),
+        Location16::Reg(Reg16::HL),
+    ));
+    assert_hex!(0x5678, z80.registers.get_reg16(&Reg16::DE));
+    assert_hex!(0x1234, z80.registers.get_reg16(&Reg16::HL));
+
+    z80.registers.set_reg16(&Reg16::SP, 0x2000);
+    z80.memory.memory[0x2000] = 0xCD;
+    z80.memory.memory[0x2001] = 0xAB;
+    z80.exec(Op::EX(
+        Location16::RegIndirect(Reg16::SP),
+        Location16::Reg(Reg16::HL),
+    ));
+    assert_hex!(0xABCD, z80.registers.get_reg16(&Reg16::HL));
+    assert_hex!(0x34, z80.memory.memory[0x2000]);
+    assert_hex!(0x12, z80.memory.memory[0x2001]);
+}
+
+#[test]
+fn exx_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg8(Reg8::B, 0x01);
+    z80.registers.set_reg8(Reg8::LP, 0x02);
+    z80.registers.set_reg8(Reg8::A, 0x03);
+    z80.exec(Op::EXX);
+    assert_hex!(0x01, z80.registers.get_reg8(Reg8::BP));
+    assert_hex!(0x02, z80.registers.get_reg8(Reg8::L));
+    assert_hex!(0x00, z80.registers.get_reg8(Reg8::B));
+    // A and F aren't exchanged
+    assert_hex!(0x03, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn add16_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::HL, 0x0FFF);
+    z80.registers.set_reg16(&Reg16::BC, 0x0001);
+    z80.exec(Op::ADD16(
+        Location16::Reg(Reg16::HL),
+        Location16::Reg(Reg16::BC),
+    ));
+    assert_hex!(0x1000, z80.registers.get_reg16(&Reg16::HL));
+    assert_flags!(z80.registers, HalfCarry = true, Carry = false,);
+
+    z80.registers.set_reg16(&Reg16::IX, 0xF000);
+    z80.exec(Op::ADD16(
+        Location16::Reg(Reg16::IX),
+        Location16::Reg(Reg16::IX),
+    ));
+    assert_hex!(0xE000, z80.registers.get_reg16(&Reg16::IX));
+    assert_flags!(z80.registers, HalfCarry = false, Carry = true,);
+}
+
+#[test]
+fn adc16_sbc16_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::HL, 0x7FFF);
+    z80.registers.set_reg16(&Reg16::DE, 0x0000);
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.exec(Op::ADC16(
+        Location16::Reg(Reg16::HL),
+        Location16::Reg(Reg16::DE),
+    ));
+    assert_hex!(0x8000, z80.registers.get_reg16(&Reg16::HL));
+    assert_flags!(
+        z80.registers,
+        Sign = true,
+        Zero = false,
+        ParityOverflow = true,
+        HalfCarry = true,
+        AddSubtract = false,
+        Carry = false,
+    );
+
+    z80.registers.set_reg16(&Reg16::DE, 0x8000);
+    z80.exec(Op::SBC16(
+        Location16::Reg(Reg16::HL),
+        Location16::Reg(Reg16::DE),
+    ));
+    assert_hex!(0x0000, z80.registers.get_reg16(&Reg16::HL));
+    assert_flags!(
+        z80.registers,
+        Zero = true,
+        ParityOverflow = false,
+        AddSubtract = true,
+        Carry = false,
+    );
+}
+
+#[test]
+fn inc16_dec16_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_reg16(&Reg16::SP, 0xFFFF);
+    z80.exec(Op::INC16(Location16::Reg(Reg16::SP)));
+    assert_hex!(0x0000, z80.registers.get_reg16(&Reg16::SP));
+    z80.exec(Op::DEC16(Location16::Reg(Reg16::SP)));
+    assert_hex!(0xFFFF, z80.registers.get_reg16(&Reg16::SP));
+    // Flags are unaffected
+    assert_eq!(0, z80.registers.get_reg8(Reg8::F));
+}
+
+#[test]
+fn interrupt_ops() {
+    let mut z80 = Z80::default();
+    z80.exec(Op::EI);
+    assert_eq!((true, true), z80.registers.get_iff());
+    z80.exec(Op::DI);
+    assert_eq!((false, false), z80.registers.get_iff());
+    z80.exec(Op::IM(2));
+    assert_eq!(2, z80.registers.get_im());
+
+    z80.registers.set_reg8(Reg8::I, 0x80);
+    z80.registers.set_iff(false, true);
+    z80.exec(Op::LD8(Location8::Reg(Reg8::A), Location8::Reg(Reg8::I)));
+    assert_hex!(0x80, z80.registers.get_reg8(Reg8::A));
+    assert_flags!(
+        z80.registers,
+        Sign = true,
+        Zero = false,
+        ParityOverflow = true,
+    );
+
+    z80.registers.set_reg16(&Reg16::SP, 0x2000);
+    z80.memory.memory[0x2000] = 0x34;
+    z80.memory.memory[0x2001] = 0x12;
+    assert_eq!(Some(0x1234), z80.exec_with_offset(Op::RETN));
+    assert_eq!((true, true), z80.registers.get_iff());
+
+    z80.registers.set_reg16(&Reg16::SP, 0x2000);
+    z80.registers.set_iff(false, true);
+    assert_eq!(Some(0x1234), z80.exec_with_offset(Op::RETI));
+    assert_eq!((true, true), z80.registers.get_iff());
+}
+
+#[test]
+fn rst_op() {
+    let mut z80 = Z80::default();
+    z80.registers.set_pc(0x1234);
+    z80.registers.set_reg16(&Reg16::SP, 0x2000);
+    assert_eq!(Some(0x0038), z80.exec_with_offset(Op::RST(0x38)));
+    assert_hex!(0x35, z80.memory.memory[0x1FFE]);
+    assert_hex!(0x12, z80.memory.memory[0x1FFF]);
+}
+
+#[test]
+fn ldir_op() {
+    let mut z80 = Z80::default();
+    // LD HL, 1000h; LD DE, 2000h; LD BC, 3; LDIR; HALT
+    z80.load(&[
+        0x21, 0x00, 0x10, 0x11, 0x00, 0x20, 0x01, 0x03, 0x00, 0xED, 0xB0, 0x76,
+    ]);
+    z80.load_at(0x1000, &[0xAA, 0xBB, 0xCC, 0xDD]);
+    z80.run();
+    assert_eq!(
+        &[0xAA, 0xBB, 0xCC, 0x00],
+        &z80.memory.memory[0x2000..0x2004]
+    );
+    assert_hex!(0x1003, z80.registers.get_reg16(&Reg16::HL));
+    assert_hex!(0x2003, z80.registers.get_reg16(&Reg16::DE));
+    assert_hex!(0x0000, z80.registers.get_reg16(&Reg16::BC));
+    assert_flags!(z80.registers, ParityOverflow = false,);
+    // Three loads, and the instructions around them
+    assert_eq!(7, z80.instructions());
+}
+
+#[test]
+fn cpir_op() {
+    let mut z80 = Z80::default();
+    z80.load_at(0x1000, &[0x01, 0x02, 0x03, 0x04]);
+    z80.registers.set_reg8(Reg8::A, 0x03);
+    z80.registers.set_reg16(&Reg16::HL, 0x1000);
+    z80.registers.set_reg16(&Reg16::BC, 0x0010);
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+
+    while z80.exec_with_offset(Op::CPIR).is_some() {}
+    assert_hex!(0x1003, z80.registers.get_reg16(&Reg16::HL));
+    assert_hex!(0x000D, z80.registers.get_reg16(&Reg16::BC));
+    assert_flags!(
+        z80.registers,
+        Zero = true,
+        ParityOverflow = true,
+        AddSubtract = true,
+        Carry = true,
+    );
+}
+
+#[test]
+fn block_io_ops() {
+    let mut z80 = Z80::default();
+    let input = super::io::BufInput::new(vec![0x22, 0x11]);
+    let output = super::io::BufOutput::default();
+    z80.install_input(0x07, Box::new(input));
+    z80.install_output(0x07, Box::new(output.clone()));
+    z80.registers.set_reg8(Reg8::C, 0x07);
+
+    z80.registers.set_reg8(Reg8::B, 0x02);
+    z80.registers.set_reg16(&Reg16::HL, 0x1000);
+    while z80.exec_with_offset(Op::INIR).is_some() {}
+    assert_eq!(&[0x11, 0x22], &z80.memory.memory[0x1000..0x1002]);
+    assert_hex!(0x00, z80.registers.get_reg8(Reg8::B));
+    assert_flags!(z80.registers, Zero = true,);
+
+    assert_hex!(0x1002, z80.registers.get_reg16(&Reg16::HL));
+
+    z80.registers.set_reg8(Reg8::B, 0x02);
+    z80.registers.set_reg16(&Reg16::HL, 0x1001);
+    z80.exec(Op::OUTD);
+    z80.exec(Op::OUTD);
+    assert_eq!(vec![0x22, 0x11], output.result());
+    assert_hex!(0x0FFF, z80.registers.get_reg16(&Reg16::HL));
+}
+
+#[test]
+fn in_flags_only() {
+    let mut z80 = Z80::default();
+    z80.install_input(0x01, Box::new(super::io::BufInput::new(vec![0x00])));
+    z80.registers.set_reg8(Reg8::C, 0x01);
+    z80.registers.set_flag(&StatusFlag::Carry, true);
+    z80.exec(Op::IN(Location8::Reg(Reg8::F), Location8::Reg(Reg8::C)));
+    assert_flags!(
+        z80.registers,
+        Zero = true,
+        ParityOverflow = true,
+        Carry = true,
+    );
+}
+
+#[test]
+fn wrap_around_memory() {
+    let mut z80 = Z80::default();
+    // LD A, 42h, with its operand at the bottom of memory
+    z80.memory.memory[0xFFFF] = 0x3E;
+    z80.memory.memory[0x0000] = 0x42;
+    z80.registers.set_pc(0xFFFF);
+    z80.step();
+    assert_hex!(0x42, z80.registers.get_reg8(Reg8::A));
+    assert_hex!(0x0001, z80.registers.get_pc());
+}
+
+#[test]
+fn refresh_before_execute() {
+    let mut z80 = Z80::default();
+    // LD A, R sees both of its opcode fetches
+    z80.load(&[0xED, 0x5F]);
+    z80.registers.set_reg8(Reg8::R, 0x10);
+    z80.step();
+    assert_hex!(0x12, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn refresh_register() {
+    let mut z80 = Z80::default();
+    // NOP; LD IX, 0; SET 0, (IX+0); HALT
+    z80.load(&[0x00, 0xDD, 0x21, 0x00, 0x00, 0xDD, 0xCB, 0x00, 0xC6, 0x76]);
+    z80.registers.set_reg8(Reg8::R, 0xFF);
+    z80.run();
+    // Bit 7 is kept; prefixed instructions count twice
+    assert_hex!(0x85, z80.registers.get_reg8(Reg8::R));
+}
+
+#[test]
+fn self_modifying_code() {
+    let mut z80 = Z80::default();
+    // LD A, 1; INC A; LD (0x0001), A; JP 0x0000
+    z80.load(&[0x3E, 0x01, 0x3C, 0x32, 0x01, 0x00, 0xC3, 0x00, 0x00]);
+    z80.run_for(8);
+    // The second time round, LD A loads the value stored into it the first time
+    assert_hex!(0x03, z80.registers.get_reg8(Reg8::A));
+    assert_hex!(0x03, z80.memory.memory[0x0001]);
+}
+
+#[test]
+fn decode_cache_invalidation() {
+    let mut z80 = Z80::default();
+    z80.load(&[0x3E, 0x01]); // LD A, 1
+    z80.step();
+
+    z80.registers.set_pc(0);
+    z80.load_at(0x0001, &[0x02]);
+    z80.step();
+    assert_hex!(0x02, z80.registers.get_reg8(Reg8::A));
+
+    // An instruction wrapping around the top of memory is invalidated by a write to the bottom
+    z80.load_at(0xFFFF, &[0x3E]);
+    z80.registers.set_pc(0xFFFF);
+    z80.step();
+    z80.registers.set_pc(0xFFFF);
+    z80.exec(Op::LD8(
+        Location8::ImmediateIndirect(0x0000),
+        Location8::Immediate(0x04),
+    ));
+    z80.step();
+    assert_hex!(0x04, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn decode_cache_lone_prefix() {
+    for blocks in [false, true] {
+        let mut z80 = Z80::default();
+        if blocks {
+            z80.enable_block_engine();
+        }
+        // A DD prefix followed by NOP is ignored
+        z80.load(&[0xDD, 0x00]);
+        z80.run_for(1);
+        assert_hex!(0x0001, z80.registers.get_pc());
+
+        // Followed by CB, it becomes SET 0, (IX+0)
+        z80.load_at(0x0001, &[0xCB, 0x00, 0xC6]);
+        z80.registers.set_pc(0);
+        z80.registers.set_reg16(&Reg16::IX, 0x1000);
+        z80.run_for(1);
+        assert_hex!(0x01, z80.memory.memory[0x1000]);
+    }
+}
+
+#[test]
+fn run_for() {
+    for blocks in [false, true] {
+        let mut z80 = Z80::default();
+        if blocks {
+            z80.enable_block_engine();
+        }
+        // INC A; INC A; INC A; HALT
+        z80.load(&[0x3C, 0x3C, 0x3C, 0x76]);
+        z80.run_for(2);
+        assert_eq!(2, z80.instructions());
+        assert_hex!(0x0002, z80.registers.get_pc());
+
+        z80.run_for(10);
+        assert!(z80.is_halted());
+        assert_eq!(4, z80.instructions());
+        assert_hex!(0x03, z80.registers.get_reg8(Reg8::A));
+    }
+}
+
+#[test]
+fn block_engine_self_modifying_code() {
+    let mut z80 = Z80::default();
+    z80.enable_block_engine();
+    // LD A, 1; INC A; LD (0x0001), A; JP 0x0000
+    z80.load(&[0x3E, 0x01, 0x3C, 0x32, 0x01, 0x00, 0xC3, 0x00, 0x00]);
+    for _ in 0..8 {
+        z80.step();
+    }
+    assert_hex!(0x03, z80.registers.get_reg8(Reg8::A));
+
+    // Overwriting the rest of the block being run: the HALT becomes INC A
+    // LD HL, 0x0007; LD (HL), 0x3C; NOP; NOP; HALT
+    let mut z80 = Z80::default();
+    z80.enable_block_engine();
+    z80.load(&[0x21, 0x07, 0x00, 0x36, 0x3C, 0x00, 0x00, 0x76]);
+    z80.run_for(5);
+    assert!(!z80.is_halted());
+    assert_hex!(0x01, z80.registers.get_reg8(Reg8::A));
+}
+
+#[test]
+fn block_engine_examples() {
+    for example in crate::examples::EXAMPLES {
+        let run = |blocks: bool| {
+            let mut z80 = Z80::default();
+            let out = super::io::BufOutput::default();
+            z80.install_output(0x00, Box::new(out.clone()));
+            if blocks {
+                z80.enable_block_engine();
+            }
+            z80.load(example.binary);
+            z80.run();
+            (z80.registers, z80.instructions, out.result())
+        };
+        assert_eq!(run(false), run(true), "{}", example.name);
+    }
+}
@@ -0,0 +1,495 @@
+//! Structured recording of every instruction executed by `Z80::step`.
+//!
+//! Install a `Tracer` with `Z80::set_tracer`. Two are provided:
+//! `JsonLinesTracer`, which writes one JSON object per instruction, and
+//! `BinaryTracer`, which writes a compact fixed-layout log. These need the `std` feature.
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+use super::Z80;
+use crate::cpu::reg::Registers;
+use crate::ops::Op;
+#[cfg(feature = "std")]
+use crate::ops::{Reg16, Reg8};
+use crate::prelude::*;
+
+/// A memory or I/O access made while executing an instruction.
+/// Opcode fetches are not included; see `TraceRecord::bytes` instead.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    /// A byte was read from memory
+    MemRead { addr: u16, val: u8 },
+    /// A byte was written to memory
+    MemWrite { addr: u16, val: u8 },
+    /// A byte was read from a port, given by its full 16-bit address
+    PortIn { port: u16, val: u8 },
+    /// A byte was written to a port, given by its full 16-bit address
+    PortOut { port: u16, val: u8 },
+}
+
+#[cfg(feature = "std")]
+impl Access {
+    fn kind(&self) -> &'static str {
+        match self {
+            Access::MemRead { .. } => "read",
+            Access::MemWrite { .. } => "write",
+            Access::PortIn { .. } => "in",
+            Access::PortOut { .. } => "out",
+        }
+    }
+
+    /// The memory address or port number accessed
+    fn addr(&self) -> u16 {
+        match *self {
+            Access::MemRead { addr, .. } | Access::MemWrite { addr, .. } => addr,
+            Access::PortIn { port, .. } | Access::PortOut { port, .. } => port,
+        }
+    }
+
+    fn val(&self) -> u8 {
+        match *self {
+            Access::MemRead { val, .. }
+            | Access::MemWrite { val, .. }
+            | Access::PortIn { val, .. }
+            | Access::PortOut { val, .. } => val,
+        }
+    }
+}
+
+/// Everything that happened during a single call to `Z80::step`
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    /// The address the instruction was fetched from
+    pub pc: u16,
+    /// The bytes making up the instruction
+    pub bytes: Vec<u8>,
+    /// The decoded instruction
+    pub op: Op,
+    /// Register state before the instruction executed
+    pub before: Registers,
+    /// Register state after the instruction executed, including the new program counter
+    pub after: Registers,
+    /// Memory and I/O accesses, in the order they happened
+    pub accesses: Vec<Access>,
+}
+
+/// A Tracer receives a record of every instruction executed.
+pub trait Tracer: Send {
+    /// Record a single instruction
+    fn trace(&mut self, record: &TraceRecord);
+
+    /// Flush any buffered output, reporting the first error encountered while tracing
+    #[cfg(feature = "std")]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The number of values in `register_values` that fit in a byte
+#[cfg(feature = "std")]
+const BYTE_REGISTERS: usize = 21;
+
+/// The names and values of every register, and the interrupt state, in a fixed order:
+/// the byte-sized values, then the 16-bit registers
+#[cfg(feature = "std")]
+fn register_values(regs: &Registers) -> [(&'static str, u16); BYTE_REGISTERS + 4] {
+    let r8 = |r| u16::from(regs.get_reg8(r));
+    let (iff1, iff2) = regs.get_iff();
+    [
+        ("a", r8(Reg8::A)),
+        ("f", r8(Reg8::F)),
+        ("b", r8(Reg8::B)),
+        ("c", r8(Reg8::C)),
+        ("d", r8(Reg8::D)),
+        ("e", r8(Reg8::E)),
+        ("h", r8(Reg8::H)),
+        ("l", r8(Reg8::L)),
+        ("a_", r8(Reg8::AP)),
+        ("f_", r8(Reg8::FP)),
+        ("b_", r8(Reg8::BP)),
+        ("c_", r8(Reg8::CP)),
+        ("d_", r8(Reg8::DP)),
+        ("e_", r8(Reg8::EP)),
+        ("h_", r8(Reg8::HP)),
+        ("l_", r8(Reg8::LP)),
+        ("i", r8(Reg8::I)),
+        ("r", r8(Reg8::R)),
+        ("iff1", iff1.into()),
+        ("iff2", iff2.into()),
+        ("im", regs.get_im().into()),
+        ("ix", regs.get_reg16(&Reg16::IX)),
+        ("iy", regs.get_reg16(&Reg16::IY)),
+        ("sp", regs.get_reg16(&Reg16::SP)),
+        ("pc", regs.get_pc()),
+    ]
+}
+
+/// JsonLinesTracer writes each instruction as a single line of JSON, for example:
+/// ```text
+/// {"pc":0,"bytes":[62,90],"op":"LD8(Reg(A), Immediate(90))","before":{"a":0,...},"after":{...},"accesses":[]}
+/// ```
+/// Accesses look like `{"kind":"write","addr":4096,"val":90}`,
+/// where kind is one of `read`, `write`, `in` or `out`.
+#[cfg(feature = "std")]
+pub struct JsonLinesTracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let w = &mut self.writer;
+        write!(w, "{{\"pc\":{},\"bytes\":[", record.pc)?;
+        for (i, b) in record.bytes.iter().enumerate() {
+            write!(w, "{}{}", if i == 0 { "" } else { "," }, b)?;
+        }
+        write!(
+            w,
+            "],\"op\":\"{}\"",
+            json_escape(&format!("{:?}", record.op))
+        )?;
+        for (name, regs) in &[("before", &record.before), ("after", &record.after)] {
+            write!(w, ",\"{}\":{{", name)?;
+            for (i, (reg, v)) in register_values(regs).iter().enumerate() {
+                write!(w, "{}\"{}\":{}", if i == 0 { "" } else { "," }, reg, v)?;
+            }
+            write!(w, "}}")?;
+        }
+        write!(w, ",\"accesses\":[")?;
+        for (i, a) in record.accesses.iter().enumerate() {
+            write!(
+                w,
+                "{}{{\"kind\":\"{}\",\"addr\":{},\"val\":{}}}",
+                if i == 0 { "" } else { "," },
+                a.kind(),
+                a.addr(),
+                a.val()
+            )?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write + Send> Tracer for JsonLinesTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = self.write_record(record).err();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// BinaryTracer writes a compact log, starting with the header `ZTRC` and a version byte (2).
+/// Each instruction is then recorded as (all multi-byte values little-endian):
+///
+/// * the program counter (u16)
+/// * the number of opcode bytes (u8), followed by the bytes themselves
+/// * the registers before execution: A F B C D E H L A' F' B' C' D' E' H' L' I R (u8 each),
+///   IFF1 IFF2 (u8 each, 0 or 1) and the interrupt mode (u8), then IX IY SP PC (u16 each)
+/// * the registers after execution, in the same layout
+/// * the number of accesses (u16), each of which is a kind (u8: 0 read, 1 write, 2 in, 3 out),
+///   an address or port (u16) and a value (u8)
+///
+/// The decoded `Op` is not recorded, as it can be recovered from the opcode bytes.
+#[cfg(feature = "std")]
+pub struct BinaryTracer<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> BinaryTracer<W> {
+    pub const MAGIC: &'static [u8] = b"ZTRC";
+    pub const VERSION: u8 = 2;
+
+    pub fn new(writer: W) -> Self {
+        let mut tracer = Self {
+            writer,
+            error: None,
+        };
+        tracer.error = tracer.write_header().err();
+        tracer
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.writer.write_all(Self::MAGIC)?;
+        self.writer.write_all(&[Self::VERSION])
+    }
+
+    fn write_registers(&mut self, regs: &Registers) -> io::Result<()> {
+        let values = register_values(regs);
+        let (r8, r16) = values.split_at(BYTE_REGISTERS);
+        for (_, v) in r8 {
+            self.writer.write_all(&[*v as u8])?;
+        }
+        for (_, v) in r16 {
+            self.writer.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        self.writer.write_all(&record.pc.to_le_bytes())?;
+        self.writer.write_all(&[record.bytes.len() as u8])?;
+        self.writer.write_all(&record.bytes)?;
+        self.write_registers(&record.before)?;
+        self.write_registers(&record.after)?;
+        self.writer
+            .write_all(&(record.accesses.len() as u16).to_le_bytes())?;
+        for a in &record.accesses {
+            let kind = match a {
+                Access::MemRead { .. } => 0,
+                Access::MemWrite { .. } => 1,
+                Access::PortIn { .. } => 2,
+                Access::PortOut { .. } => 3,
+            };
+            let [lo, hi] = a.addr().to_le_bytes();
+            self.writer.write_all(&[kind, lo, hi, a.val()])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write + Send> Tracer for BinaryTracer<W> {
+    fn trace(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = self.write_record(record).err();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+impl Z80 {
+    /// Record every instruction executed by `step` (and therefore `run`) with the given tracer,
+    /// replacing any tracer previously installed.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Remove the installed tracer, if any, so it can be flushed.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    /// Whether a tracer is installed
+    pub(super) fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    pub(super) fn record_access(&self, access: Access) {
+        if self.is_tracing() {
+            self.accesses.borrow_mut().push(access);
+        }
+    }
+
+    /// Pass a completed instruction to the tracer
+    pub(super) fn record_step(&mut self, pc: u16, bytes: Vec<u8>, op: Op, before: Registers) {
+        let record = TraceRecord {
+            pc,
+            bytes,
+            op,
+            before,
+            after: self.registers.clone(),
+            accesses: self.accesses.replace(vec![]),
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.trace(&record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default, Clone)]
+    struct Recorder {
+        records: Arc<Mutex<Vec<TraceRecord>>>,
+    }
+
+    impl Tracer for Recorder {
+        fn trace(&mut self, record: &TraceRecord) {
+            self.records.lock().unwrap().push(record.clone())
+        }
+    }
+
+    // LD HL, 0x1000; LD (HL), 0x5A; LD A, (HL); OUT (0), A; HALT
+    const PROGRAM: &[u8] = &[0x21, 0x00, 0x10, 0x36, 0x5A, 0x7E, 0xD3, 0x00, 0x76];
+
+    #[test]
+    fn records_steps() {
+        let recorder = Recorder::default();
+        let mut z80 = Z80::default();
+        z80.install_output(0, Box::new(crate::z80::io::BufOutput::default()));
+        z80.set_tracer(Box::new(recorder.clone()));
+        z80.load(PROGRAM);
+        z80.run();
+
+        let records = recorder.records.lock().unwrap();
+        assert_eq!(5, records.len());
+
+        assert_eq!(0, records[0].pc);
+        assert_eq!(vec![0x21, 0x00, 0x10], records[0].bytes);
+        assert_eq!(0, records[0].before.get_reg16(&Reg16::HL));
+        assert_eq!(0x1000, records[0].after.get_reg16(&Reg16::HL));
+        assert_eq!(3, records[0].after.get_pc());
+
+        let addr = records[1].after.get_reg16(&Reg16::HL);
+        assert_eq!(
+            vec![Access::MemWrite { addr, val: 0x5A }],
+            records[1].accesses
+        );
+        assert_eq!(
+            vec![Access::MemRead { addr, val: 0x5A }],
+            records[2].accesses
+        );
+        assert_eq!(
+            vec![Access::PortOut {
+                port: 0x5A00,
+                val: 0x5A
+            }],
+            records[3].accesses
+        );
+        assert_eq!(Op::HALT, records[4].op);
+    }
+
+    #[test]
+    fn records_bytes_before_execution() {
+        let recorder = Recorder::default();
+        let mut z80 = Z80::default();
+        z80.set_tracer(Box::new(recorder.clone()));
+        // LD A, 0x76; LD (2), A; HALT
+        z80.load(&[0x3E, 0x76, 0x32, 0x02, 0x00, 0x76]);
+        z80.run();
+
+        let records = recorder.records.lock().unwrap();
+        assert_eq!(3, records.len());
+        assert_eq!(0x76, z80.memory()[2]);
+        assert_eq!(vec![0x32, 0x02, 0x00], records[1].bytes);
+    }
+
+    #[test]
+    fn wrapping_instructions() {
+        let recorder = Recorder::default();
+        let mut z80 = Z80::default();
+        z80.set_tracer(Box::new(recorder.clone()));
+        // LD A, 0x5A, straddling the top of memory
+        z80.load_at(0xFFFF, &[0x3E]);
+        z80.load_at(0x0000, &[0x5A]);
+        z80.registers.set_pc(0xFFFF);
+        z80.step();
+
+        let records = recorder.records.lock().unwrap();
+        assert_eq!(vec![0x3E, 0x5A], records[0].bytes);
+    }
+
+    #[test]
+    fn untraced_accesses_are_discarded() {
+        let mut z80 = Z80::default();
+        z80.load(PROGRAM);
+        z80.step();
+        z80.step();
+        assert!(z80.accesses.borrow().is_empty());
+    }
+
+    fn record() -> TraceRecord {
+        let mut after = Registers::default();
+        after.set_reg8(Reg8::A, 0x5A);
+        after.set_reg8(Reg8::R, 0x01);
+        after.set_iff(true, true);
+        after.set_im(2);
+        after.set_pc(2);
+        TraceRecord {
+            pc: 0,
+            bytes: vec![0x3E, 0x5A],
+            op: Op::LD8(
+                crate::ops::Location8::Reg(Reg8::A),
+                crate::ops::Location8::Immediate(0x5A),
+            ),
+            before: Registers::default(),
+            after,
+            accesses: vec![Access::PortIn { port: 1, val: 2 }],
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut out = vec![];
+        {
+            let mut tracer = JsonLinesTracer::new(&mut out);
+            tracer.trace(&record());
+            tracer.trace(&record());
+            tracer.flush().unwrap();
+        }
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with(
+            r#"{"pc":0,"bytes":[62,90],"op":"LD8(Reg(A), Immediate(90))","before":{"a":0,"f":0,"#
+        ));
+        assert!(lines[0].contains(r#""after":{"a":90,"#));
+        assert!(lines[0].contains(r#""i":0,"r":1,"iff1":1,"iff2":1,"im":2,"ix":0,"#));
+        assert!(lines[0].contains(r#""sp":0,"pc":2}"#));
+        assert!(lines[0].ends_with(r#""accesses":[{"kind":"in","addr":1,"val":2}]}"#));
+    }
+
+    #[test]
+    fn binary() {
+        let mut out = vec![];
+        {
+            let mut tracer = BinaryTracer::new(&mut out);
+            tracer.trace(&record());
+            tracer.flush().unwrap();
+        }
+        assert_eq!(b"ZTRC\x02", &out[..5]);
+        // pc, length and opcode
+        assert_eq!(&[0x00, 0x00, 0x02, 0x3E, 0x5A], &out[5..10]);
+        // 29 bytes of registers each, before and after
+        assert_eq!(0x5A, out[10 + 29]);
+        // I, R, IFF1, IFF2 and IM
+        assert_eq!(
+            &[0x00, 0x01, 0x01, 0x01, 0x02],
+            &out[10 + 29 + 16..10 + 29 + 21]
+        );
+        assert_eq!(&[0x02, 0x00], &out[10 + 29 + 27..10 + 29 + 29]);
+        // one access
+        assert_eq!(&[0x01, 0x00, 0x02, 0x01, 0x00, 0x02], &out[10 + 58..]);
+    }
+}
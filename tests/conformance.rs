@@ -0,0 +1,109 @@
+//! Conformance tests, running the standard Z80 instruction exercisers under CP/M.
+//!
+//! The exercisers aren't distributed with zeerust. Put `prelim.com`, `zexdoc.com` and
+//! `zexall.com` in `tests/exercisers/`, or in the directory named by `ZEERUST_EXERCISERS`.
+//!
+//! Zeerust doesn't model the undocumented flag bits 3 and 5, so `zexall` is expected to report
+//! errors, and `zexdoc` to pass.
+//!
+//! These tests are slow, so they are ignored by default. Running them without the exercisers
+//! fails, rather than passing without testing anything. Run them with
+//!
+//! ```text
+//! cargo test --release --test conformance -- --ignored --nocapture
+//! ```
+extern crate zeerust;
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use zeerust::cpm::Cpm;
+use zeerust::z80::Z80;
+
+/// Console output, collected for checking and echoed to stderr to show progress
+#[derive(Default)]
+struct Console {
+    output: Vec<u8>,
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        io::stderr().write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+fn exerciser_dir() -> PathBuf {
+    match env::var_os("ZEERUST_EXERCISERS") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/exercisers"),
+    }
+}
+
+/// Run an exerciser, returning its console output
+fn run(name: &str) -> String {
+    let path = exerciser_dir().join(name);
+    let program = fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "can't read {}: {}; put the exercisers in tests/exercisers/ or set ZEERUST_EXERCISERS",
+            path.display(),
+            e
+        )
+    });
+
+    let mut z80 = Z80::default();
+    let mut cpm = Cpm::new(io::empty(), Console::default(), exerciser_dir());
+    cpm.load(&mut z80, &program, &[]);
+    cpm.run(&mut z80).unwrap();
+    String::from_utf8_lossy(&cpm.output().output).into_owned()
+}
+
+/// Check the output of one of the ZEX exercisers, which report each failing group with "ERROR"
+fn assert_zex_passed(output: &str) {
+    let failures: Vec<&str> = output.lines().filter(|l| l.contains("ERROR")).collect();
+    assert!(
+        failures.is_empty(),
+        "{} groups failed:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+    assert!(
+        output.contains("Tests complete"),
+        "exerciser did not finish"
+    );
+}
+
+#[test]
+#[ignore]
+fn prelim() {
+    let output = run("prelim.com");
+    assert!(
+        output.contains("Preliminary tests complete"),
+        "preliminary tests failed: {}",
+        output
+    );
+}
+
+/// Documented instruction behaviour
+#[test]
+#[ignore]
+fn zexdoc() {
+    assert_zex_passed(&run("zexdoc.com"));
+}
+
+/// All instruction behaviour, including the undocumented flag bits 3 and 5.
+/// Zeerust doesn't model those bits, so this is a known failure: the groups that check them
+/// report errors. It fails if zexall passes, or doesn't run
+#[test]
+#[ignore]
+#[should_panic(expected = "groups failed")]
+fn zexall() {
+    assert_zex_passed(&run("zexall.com"));
+}
@@ -0,0 +1,342 @@
+//! Differential tests: random programs run on zeerust and on an independent model of the Z80
+//! (in `oracle/`), comparing every register, flag and byte of memory after each instruction.
+//!
+//! Programs run until they reach an instruction the oracle doesn't model (I/O, HALT), or for
+//! `MAX_STEPS` instructions. Failing programs are shrunk by proptest; set `PROPTEST_CASES` to
+//! run more of them.
+//!
+//! The block engine is checked the same way, against zeerust's own interpreter.
+extern crate proptest;
+extern crate zeerust;
+
+mod oracle;
+
+use oracle::Oracle;
+use proptest::prelude::*;
+use zeerust::cpu::mem::MEMORY_SIZE;
+use zeerust::cpu::opcodes::opcode;
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::Z80;
+
+const MAX_STEPS: usize = 100;
+
+const MAIN: [Reg8; 8] = [
+    Reg8::B,
+    Reg8::C,
+    Reg8::D,
+    Reg8::E,
+    Reg8::H,
+    Reg8::L,
+    Reg8::F,
+    Reg8::A,
+];
+const SHADOW: [Reg8; 8] = [
+    Reg8::BP,
+    Reg8::CP,
+    Reg8::DP,
+    Reg8::EP,
+    Reg8::HP,
+    Reg8::LP,
+    Reg8::FP,
+    Reg8::AP,
+];
+
+/// Everything but memory, in the oracle's layout
+#[derive(Debug, PartialEq)]
+struct State {
+    main: [u8; 8],
+    shadow: [u8; 8],
+    ix: u16,
+    iy: u16,
+    sp: u16,
+    pc: u16,
+    i: u8,
+    r: u8,
+    iff: (bool, bool),
+    im: u8,
+}
+
+impl State {
+    fn of_z80(z80: &Z80) -> Self {
+        let regs = &z80.registers;
+        State {
+            main: MAIN.map(|r| regs.get_reg8(r)),
+            shadow: SHADOW.map(|r| regs.get_reg8(r)),
+            ix: regs.get_reg16(&Reg16::IX),
+            iy: regs.get_reg16(&Reg16::IY),
+            sp: regs.get_reg16(&Reg16::SP),
+            pc: regs.get_pc(),
+            i: regs.get_reg8(Reg8::I),
+            r: regs.get_reg8(Reg8::R),
+            iff: regs.get_iff(),
+            im: regs.get_im(),
+        }
+    }
+
+    fn of_oracle(oracle: &Oracle) -> Self {
+        State {
+            main: oracle.main,
+            shadow: oracle.shadow,
+            ix: oracle.ix,
+            iy: oracle.iy,
+            sp: oracle.sp,
+            pc: oracle.pc,
+            i: oracle.i,
+            r: oracle.r,
+            iff: (oracle.iff1, oracle.iff2),
+            im: oracle.im,
+        }
+    }
+
+    fn load(&self, z80: &mut Z80, oracle: &mut Oracle) {
+        for (i, (reg, shadow)) in MAIN.iter().zip(&SHADOW).enumerate() {
+            z80.registers.set_reg8(*reg, self.main[i]);
+            z80.registers.set_reg8(*shadow, self.shadow[i]);
+        }
+        z80.registers.set_reg16(&Reg16::IX, self.ix);
+        z80.registers.set_reg16(&Reg16::IY, self.iy);
+        z80.registers.set_reg16(&Reg16::SP, self.sp);
+        z80.registers.set_pc(self.pc);
+        z80.registers.set_reg8(Reg8::I, self.i);
+        z80.registers.set_reg8(Reg8::R, self.r);
+        z80.registers.set_iff(self.iff.0, self.iff.1);
+        z80.registers.set_im(self.im);
+
+        oracle.main = self.main;
+        oracle.shadow = self.shadow;
+        oracle.ix = self.ix;
+        oracle.iy = self.iy;
+        oracle.sp = self.sp;
+        oracle.pc = self.pc;
+        oracle.i = self.i;
+        oracle.r = self.r;
+        oracle.iff1 = self.iff.0;
+        oracle.iff2 = self.iff.1;
+        oracle.im = self.im;
+    }
+}
+
+/// The registers a program starts with. It is loaded at 0, where it starts running
+fn initial_state() -> impl Strategy<Value = State> {
+    (
+        any::<([u8; 8], [u8; 8])>(),
+        any::<(u16, u16, u16)>(),
+        any::<(u8, u8, bool, bool)>(),
+        0..=2_u8,
+    )
+        .prop_map(
+            |((main, shadow), (ix, iy, sp), (i, r, iff1, iff2), im)| State {
+                main,
+                shadow,
+                ix,
+                iy,
+                sp,
+                pc: 0,
+                i,
+                r,
+                iff: (iff1, iff2),
+                im,
+            },
+        )
+}
+
+/// Fill memory with pseudo-random bytes, so that loads find something other than zero
+fn fill(memory: &mut [u8], seed: u64) {
+    // xorshift64
+    let mut x = seed | 1;
+    for byte in memory {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *byte = x as u8;
+    }
+}
+
+/// An instruction, from its opcode bytes followed by random operands
+fn instruction(head: impl Strategy<Value = Vec<u8>>) -> impl Strategy<Value = Vec<u8>> {
+    (head, any::<[u8; 3]>()).prop_map(|(mut bytes, tail)| {
+        bytes.extend_from_slice(&tail);
+        let (_, len) = opcode([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        bytes.truncate(len);
+        bytes
+    })
+}
+
+/// Unprefixed opcodes, except HALT and I/O
+fn unprefixed() -> impl Strategy<Value = u8> {
+    any::<u8>().prop_filter("prefix, HALT or I/O", |op| {
+        !matches!(op, 0x76 | 0xCB | 0xD3 | 0xDB | 0xDD | 0xED | 0xFD)
+    })
+}
+
+/// ED-prefixed opcodes, except I/O
+fn extended() -> impl Strategy<Value = u8> {
+    prop_oneof![
+        (0x40..0x80_u8).prop_filter("I/O", |op| op & 0b110 != 0),
+        prop::sample::select(vec![0xA0, 0xA1, 0xA8, 0xA9, 0xB0, 0xB1, 0xB8, 0xB9]),
+        // These do nothing
+        prop::sample::select(vec![0x00, 0x3F, 0x80, 0xA4, 0xC0, 0xFF]),
+    ]
+}
+
+/// DD- and FD-prefixed instructions
+fn indexed() -> impl Strategy<Value = Vec<u8>> {
+    let prefix = prop::sample::select(vec![0xDD, 0xFD]);
+    prop_oneof![
+        3 => (prefix.clone(), unprefixed()).prop_map(|(p, op)| vec![p, op]),
+        // The forms of DDCB and FDCB that don't also copy to a register
+        1 => (prefix, any::<u8>(), any::<u8>()).prop_map(|(p, d, op)| {
+            let op = if op >> 6 == 1 { op } else { (op & !7) | 6 };
+            vec![p, 0xCB, d, op]
+        }),
+    ]
+}
+
+fn program(instruction: impl Strategy<Value = Vec<u8>>) -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(instruction, 1..16).prop_map(|is| is.concat())
+}
+
+/// The first address at which two memories differ
+fn first_difference(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    if expected == actual {
+        None
+    } else {
+        (0..expected.len()).find(|&a| expected[a] != actual[a])
+    }
+}
+
+/// Run a program on both CPUs, failing at the first difference
+fn compare(program: &[u8], state: &State, seed: u64) -> Result<(), TestCaseError> {
+    let mut z80 = Z80::default();
+    let mut oracle = Oracle::default();
+    fill(&mut oracle.memory, seed);
+    z80.load(&oracle.memory);
+    z80.load(program);
+    oracle.memory[..program.len()].copy_from_slice(program);
+    state.load(&mut z80, &mut oracle);
+
+    for step in 0..MAX_STEPS {
+        let pc = oracle.pc;
+        if !oracle.step() {
+            break;
+        }
+        let op = z80.parse_opcode(pc.into());
+        z80.step();
+        prop_assert_eq!(
+            State::of_oracle(&oracle),
+            State::of_z80(&z80),
+            "after step {}, {:?} at {:04X}",
+            step,
+            op,
+            pc
+        );
+        if let Some(addr) = first_difference(&oracle.memory, z80.memory()) {
+            prop_assert!(
+                false,
+                "after step {}, {:?} at {:04X}: memory at {:04X} is {:02X}, expected {:02X}",
+                step,
+                op,
+                pc,
+                addr,
+                z80.memory()[addr],
+                oracle.memory[addr]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run a program with the interpreter and with the block engine, `chunks` instructions at a time,
+/// failing at the first difference
+fn compare_engines(
+    program: &[u8],
+    state: &State,
+    seed: u64,
+    chunks: &[u64],
+) -> Result<(), TestCaseError> {
+    let mut interpreted = Z80::default();
+    let mut compiled = Z80::default();
+    compiled.enable_block_engine();
+    let mut memory = vec![0; MEMORY_SIZE];
+    fill(&mut memory, seed);
+    for z80 in [&mut interpreted, &mut compiled] {
+        z80.load(&memory);
+        z80.load(program);
+        state.load(z80, &mut Oracle::default());
+    }
+
+    for &chunk in chunks {
+        let pc = interpreted.registers.get_pc();
+        interpreted.run_for(chunk);
+        compiled.run_for(chunk);
+        prop_assert_eq!(
+            State::of_z80(&interpreted),
+            State::of_z80(&compiled),
+            "after {} instructions from {:04X}",
+            chunk,
+            pc
+        );
+        prop_assert_eq!(interpreted.instructions(), compiled.instructions());
+        prop_assert_eq!(interpreted.is_halted(), compiled.is_halted());
+        if let Some(addr) = first_difference(interpreted.memory(), compiled.memory()) {
+            prop_assert!(
+                false,
+                "after {} instructions from {:04X}: memory at {:04X} is {:02X}, expected {:02X}",
+                chunk,
+                pc,
+                addr,
+                compiled.memory()[addr],
+                interpreted.memory()[addr]
+            );
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn unprefixed_ops(
+        program in program(instruction(unprefixed().prop_map(|op| vec![op]))),
+        state in initial_state(),
+        seed in any::<u64>(),
+    ) {
+        compare(&program, &state, seed)?;
+    }
+
+    #[test]
+    fn bit_ops(
+        program in program(instruction(any::<u8>().prop_map(|op| vec![0xCB, op]))),
+        state in initial_state(),
+        seed in any::<u64>(),
+    ) {
+        compare(&program, &state, seed)?;
+    }
+
+    #[test]
+    fn extended_ops(
+        program in program(instruction(extended().prop_map(|op| vec![0xED, op]))),
+        state in initial_state(),
+        seed in any::<u64>(),
+    ) {
+        compare(&program, &state, seed)?;
+    }
+
+    #[test]
+    fn indexed_ops(
+        program in program(instruction(indexed())),
+        state in initial_state(),
+        seed in any::<u64>(),
+    ) {
+        compare(&program, &state, seed)?;
+    }
+
+    #[test]
+    fn block_engine(
+        program in program(instruction(any::<[u8; 2]>().prop_map(|b| b.to_vec()))),
+        state in initial_state(),
+        seed in any::<u64>(),
+        chunks in proptest::collection::vec(1..16u64, 1..=MAX_STEPS),
+    ) {
+        compare_engines(&program, &state, seed, &chunks)?;
+    }
+}
@@ -0,0 +1,804 @@
+//! An independent model of the Z80, used as an oracle for differential testing.
+//!
+//! It shares no code with zeerust. Instructions are decoded from the fields of the opcode (x, y,
+//! z, p and q, as laid out in "Decoding Z80 Opcodes") and their operands looked up in tables.
+//! Flags are computed from the carry bits of each operation rather than by comparing operands.
+//!
+//! Zeerust doesn't model the undocumented flag bits 3 and 5, so instructions leave them as they
+//! were, except those that load F whole. Interrupts, HALT and I/O aren't modelled either:
+//! `step` refuses to run those instructions.
+
+pub const CARRY: u8 = 0x01;
+pub const SUBTRACT: u8 = 0x02;
+pub const PARITY: u8 = 0x04;
+pub const HALF: u8 = 0x10;
+pub const ZERO: u8 = 0x40;
+pub const SIGN: u8 = 0x80;
+/// Every flag an instruction can set
+const ALL: u8 = SIGN | ZERO | HALF | PARITY | SUBTRACT | CARRY;
+
+// Indexes into the register file. The rest match the `r` table
+const B: usize = 0;
+const C: usize = 1;
+const D: usize = 2;
+const E: usize = 3;
+const H: usize = 4;
+const L: usize = 5;
+const F: usize = 6;
+const A: usize = 7;
+
+/// The conditions, as (flag, whether it must be set), indexed by y
+const CONDITIONS: [(u8, bool); 8] = [
+    (ZERO, false),
+    (ZERO, true),
+    (CARRY, false),
+    (CARRY, true),
+    (PARITY, false),
+    (PARITY, true),
+    (SIGN, false),
+    (SIGN, true),
+];
+
+/// The interrupt mode set by ED x=1 z=6, indexed by y
+const INTERRUPT_MODES: [u8; 8] = [0, 0, 1, 2, 0, 0, 1, 2];
+
+/// Which register stands in for HL
+#[derive(Clone, Copy, PartialEq)]
+enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+/// An 8-bit operand, resolved once per instruction
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(usize),
+    /// The high or low half of IX or IY
+    Half(Index, bool),
+    Mem(u16),
+}
+
+/// Split an opcode into its x, y, z, p and q fields
+fn fields(op: u8) -> (u8, u8, u8, u8, u8) {
+    let y = (op >> 3) & 7;
+    (op >> 6, y, op & 7, y >> 1, y & 1)
+}
+
+/// Whether an unprefixed instruction uses H, L, HL or (HL), so that a DD or FD prefix changes it
+fn uses_hl(op: u8) -> bool {
+    let (x, y, z, p, q) = fields(op);
+    let hl_reg = |r| (4..=6).contains(&r);
+    match x {
+        0 => match z {
+            1 => p == 2 || q == 1,
+            2 | 3 => p == 2,
+            4..=6 => hl_reg(y),
+            _ => false,
+        },
+        1 => op != 0x76 && (hl_reg(y) || hl_reg(z)),
+        2 => hl_reg(z),
+        _ => matches!(op, 0xE1 | 0xE3 | 0xE5 | 0xE9 | 0xF9),
+    }
+}
+
+/// The result of an 8-bit addition, and the flags it sets
+fn add8(a: u8, b: u8, carry: u8) -> (u8, u8) {
+    let sum = u16::from(a) + u16::from(b) + u16::from(carry);
+    let r = sum as u8;
+    let overflow = (a ^ r) & (b ^ r) & 0x80;
+    let flags = sign_zero(r) | ((a ^ b ^ r) & HALF) | (overflow >> 5) | (sum >> 8) as u8;
+    (r, flags)
+}
+
+/// The result of an 8-bit subtraction, and the flags it sets
+fn sub8(a: u8, b: u8, borrow: u8) -> (u8, u8) {
+    let diff = u16::from(a)
+        .wrapping_sub(u16::from(b))
+        .wrapping_sub(u16::from(borrow));
+    let r = diff as u8;
+    let overflow = (a ^ b) & (a ^ r) & 0x80;
+    let flags = sign_zero(r)
+        | ((a ^ b ^ r) & HALF)
+        | (overflow >> 5)
+        | SUBTRACT
+        | ((diff >> 8) as u8 & CARRY);
+    (r, flags)
+}
+
+fn sign_zero(v: u8) -> u8 {
+    (v & SIGN) | if v == 0 { ZERO } else { 0 }
+}
+
+/// Rotate or shift a byte as CB x=0 does, returning the result and the carry out
+fn rotate(y: u8, v: u8, carry: u8) -> (u8, u8) {
+    match y {
+        0 => (v.rotate_left(1), v >> 7),
+        1 => (v.rotate_right(1), v & 1),
+        2 => ((v << 1) | carry, v >> 7),
+        3 => ((v >> 1) | (carry << 7), v & 1),
+        4 => (v << 1, v >> 7),
+        5 => ((v >> 1) | (v & 0x80), v & 1),
+        6 => ((v << 1) | 1, v >> 7),
+        _ => (v >> 1, v & 1),
+    }
+}
+
+#[derive(Clone)]
+pub struct Oracle {
+    /// B, C, D, E, H, L, F and A
+    pub main: [u8; 8],
+    /// The shadow registers, in the same order
+    pub shadow: [u8; 8],
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub im: u8,
+    pub memory: Vec<u8>,
+    /// Sign, zero and parity flags of every byte
+    szp: [u8; 256],
+}
+
+impl Default for Oracle {
+    fn default() -> Self {
+        let mut szp = [0; 256];
+        for (v, flags) in szp.iter_mut().enumerate() {
+            let v = v as u8;
+            *flags = sign_zero(v)
+                | if v.count_ones().is_multiple_of(2) {
+                    PARITY
+                } else {
+                    0
+                };
+        }
+        Oracle {
+            main: [0; 8],
+            shadow: [0; 8],
+            ix: 0,
+            iy: 0,
+            sp: 0,
+            pc: 0,
+            i: 0,
+            r: 0,
+            iff1: false,
+            iff2: false,
+            im: 0,
+            memory: vec![0; 0x10000],
+            szp,
+        }
+    }
+}
+
+impl Oracle {
+    /// Run one instruction. Returns false, leaving the state as it was, if the instruction isn't
+    /// one the oracle models.
+    pub fn step(&mut self) -> bool {
+        let before = self.clone();
+        let ran = self.execute();
+        if !ran {
+            *self = before;
+        }
+        ran
+    }
+
+    fn execute(&mut self) -> bool {
+        match self.fetch_opcode() {
+            0xCB => {
+                let op = self.fetch_opcode();
+                let operand = self.operand(op & 7, Index::HL);
+                self.bits(op, operand);
+                true
+            }
+            0xED => {
+                let op = self.fetch_opcode();
+                self.extended(op)
+            }
+            0xDD => self.indexed(Index::IX),
+            0xFD => self.indexed(Index::IY),
+            op => self.base(op, Index::HL),
+        }
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let v = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch();
+        let hi = self.fetch();
+        u16::from_le_bytes([lo, hi])
+    }
+
+    /// Fetch an opcode byte, which refreshes memory: the low seven bits of R count up
+    fn fetch_opcode(&mut self) -> u8 {
+        self.r = (self.r & 0x80) | (self.r.wrapping_add(1) & 0x7F);
+        self.fetch()
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        self.memory[usize::from(addr)]
+    }
+
+    fn write(&mut self, addr: u16, v: u8) {
+        self.memory[usize::from(addr)] = v;
+    }
+
+    fn read16(&self, addr: u16) -> u16 {
+        u16::from_le_bytes([self.read(addr), self.read(addr.wrapping_add(1))])
+    }
+
+    fn write16(&mut self, addr: u16, v: u16) {
+        let [lo, hi] = v.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
+    fn push(&mut self, v: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        self.write16(self.sp, v);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = self.read16(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    fn pair(&self, hi: usize, lo: usize) -> u16 {
+        u16::from_be_bytes([self.main[hi], self.main[lo]])
+    }
+
+    fn set_pair(&mut self, hi: usize, lo: usize, v: u16) {
+        let [h, l] = v.to_be_bytes();
+        self.main[hi] = h;
+        self.main[lo] = l;
+    }
+
+    /// The `rp` table: BC, DE, HL and SP
+    fn rp(&self, p: u8, idx: Index) -> u16 {
+        match (p, idx) {
+            (0, _) => self.pair(B, C),
+            (1, _) => self.pair(D, E),
+            (2, Index::HL) => self.pair(H, L),
+            (2, Index::IX) => self.ix,
+            (2, Index::IY) => self.iy,
+            _ => self.sp,
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, idx: Index, v: u16) {
+        match (p, idx) {
+            (0, _) => self.set_pair(B, C, v),
+            (1, _) => self.set_pair(D, E, v),
+            (2, Index::HL) => self.set_pair(H, L, v),
+            (2, Index::IX) => self.ix = v,
+            (2, Index::IY) => self.iy = v,
+            _ => self.sp = v,
+        }
+    }
+
+    /// The `rp2` table: BC, DE, HL and AF
+    fn rp2(&self, p: u8, idx: Index) -> u16 {
+        match p {
+            3 => self.pair(A, F),
+            p => self.rp(p, idx),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, idx: Index, v: u16) {
+        match p {
+            3 => self.set_pair(A, F, v),
+            p => self.set_rp(p, idx, v),
+        }
+    }
+
+    /// The address of (HL), or (IX+d) or (IY+d), fetching the displacement
+    fn indirect(&mut self, idx: Index) -> u16 {
+        match idx {
+            Index::HL => self.pair(H, L),
+            Index::IX => self.ix.wrapping_add(self.fetch() as i8 as u16),
+            Index::IY => self.iy.wrapping_add(self.fetch() as i8 as u16),
+        }
+    }
+
+    /// Resolve an entry of the `r` table
+    fn operand(&mut self, r: u8, idx: Index) -> Operand {
+        match r {
+            6 => Operand::Mem(self.indirect(idx)),
+            4 | 5 if idx != Index::HL => Operand::Half(idx, r == 4),
+            r => Operand::Reg(usize::from(r)),
+        }
+    }
+
+    fn get(&self, operand: Operand) -> u8 {
+        match operand {
+            Operand::Reg(r) => self.main[r],
+            Operand::Half(idx, high) => {
+                let [hi, lo] = self.rp(2, idx).to_be_bytes();
+                if high {
+                    hi
+                } else {
+                    lo
+                }
+            }
+            Operand::Mem(addr) => self.read(addr),
+        }
+    }
+
+    fn set(&mut self, operand: Operand, v: u8) {
+        match operand {
+            Operand::Reg(r) => self.main[r] = v,
+            Operand::Half(idx, high) => {
+                let [mut hi, mut lo] = self.rp(2, idx).to_be_bytes();
+                if high {
+                    hi = v;
+                } else {
+                    lo = v;
+                }
+                self.set_rp(2, idx, u16::from_be_bytes([hi, lo]));
+            }
+            Operand::Mem(addr) => self.write(addr, v),
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        self.main[F]
+    }
+
+    /// Set the flags in `mask` to those in `flags`, leaving the rest
+    fn set_flags(&mut self, mask: u8, flags: u8) {
+        self.main[F] = (self.main[F] & !mask) | (flags & mask);
+    }
+
+    fn condition(&self, y: u8) -> bool {
+        let (flag, set) = CONDITIONS[usize::from(y)];
+        (self.flags() & flag != 0) == set
+    }
+
+    fn jump_relative(&mut self, d: u8) {
+        self.pc = self.pc.wrapping_add(d as i8 as u16);
+    }
+
+    /// An unprefixed instruction, with HL replaced by `idx`
+    fn base(&mut self, op: u8, idx: Index) -> bool {
+        let (x, y, z, p, q) = fields(op);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => (),
+                1 => {
+                    std::mem::swap(&mut self.main[A], &mut self.shadow[A]);
+                    std::mem::swap(&mut self.main[F], &mut self.shadow[F]);
+                }
+                2 => {
+                    let d = self.fetch();
+                    self.main[B] = self.main[B].wrapping_sub(1);
+                    if self.main[B] != 0 {
+                        self.jump_relative(d);
+                    }
+                }
+                3 => {
+                    let d = self.fetch();
+                    self.jump_relative(d);
+                }
+                y => {
+                    let d = self.fetch();
+                    if self.condition(y - 4) {
+                        self.jump_relative(d);
+                    }
+                }
+            },
+            (0, 1) if q == 0 => {
+                let nn = self.fetch16();
+                self.set_rp(p, idx, nn);
+            }
+            (0, 1) => {
+                let v = self.add16(self.rp(2, idx), self.rp(p, idx));
+                self.set_rp(2, idx, v);
+            }
+            (0, 2) => match (q, p) {
+                (0, 0) => self.write(self.pair(B, C), self.main[A]),
+                (0, 1) => self.write(self.pair(D, E), self.main[A]),
+                (0, 2) => {
+                    let nn = self.fetch16();
+                    self.write16(nn, self.rp(2, idx));
+                }
+                (0, _) => {
+                    let nn = self.fetch16();
+                    self.write(nn, self.main[A]);
+                }
+                (_, 0) => self.main[A] = self.read(self.pair(B, C)),
+                (_, 1) => self.main[A] = self.read(self.pair(D, E)),
+                (_, 2) => {
+                    let nn = self.fetch16();
+                    let v = self.read16(nn);
+                    self.set_rp(2, idx, v);
+                }
+                _ => {
+                    let nn = self.fetch16();
+                    self.main[A] = self.read(nn);
+                }
+            },
+            (0, 3) => {
+                let v = self.rp(p, idx);
+                let v = if q == 0 {
+                    v.wrapping_add(1)
+                } else {
+                    v.wrapping_sub(1)
+                };
+                self.set_rp(p, idx, v);
+            }
+            (0, 4) | (0, 5) => {
+                let operand = self.operand(y, idx);
+                let v = self.get(operand);
+                let (r, flags) = if z == 4 { add8(v, 1, 0) } else { sub8(v, 1, 0) };
+                self.set(operand, r);
+                self.set_flags(ALL & !CARRY, flags);
+            }
+            (0, 6) => {
+                let operand = self.operand(y, idx);
+                let n = self.fetch();
+                self.set(operand, n);
+            }
+            (0, _) => self.accumulator(y),
+
+            // LD (HL), (HL) is HALT
+            (1, 6) if y == 6 => return false,
+            (1, _) => {
+                // H and L are never replaced alongside (IX+d)
+                let dst = self.operand(y, if z == 6 { Index::HL } else { idx });
+                let src = self.operand(z, if y == 6 { Index::HL } else { idx });
+                self.set(dst, self.get(src));
+            }
+            (2, _) => {
+                let operand = self.operand(z, idx);
+                let v = self.get(operand);
+                self.alu(y, v);
+            }
+
+            (_, 0) => {
+                if self.condition(y) {
+                    self.pc = self.pop();
+                }
+            }
+            (_, 1) => match (q, p) {
+                (0, p) => {
+                    let v = self.pop();
+                    self.set_rp2(p, idx, v);
+                }
+                (_, 0) => self.pc = self.pop(),
+                (_, 1) => {
+                    for r in [B, C, D, E, H, L] {
+                        std::mem::swap(&mut self.main[r], &mut self.shadow[r]);
+                    }
+                }
+                (_, 2) => self.pc = self.rp(2, idx),
+                _ => self.sp = self.rp(2, idx),
+            },
+            (_, 2) => {
+                let nn = self.fetch16();
+                if self.condition(y) {
+                    self.pc = nn;
+                }
+            }
+            (_, 3) => match y {
+                0 => self.pc = self.fetch16(),
+                4 => {
+                    let v = self.read16(self.sp);
+                    self.write16(self.sp, self.rp(2, idx));
+                    self.set_rp(2, idx, v);
+                }
+                5 => {
+                    let (de, hl) = (self.pair(D, E), self.pair(H, L));
+                    self.set_pair(D, E, hl);
+                    self.set_pair(H, L, de);
+                }
+                6 => {
+                    self.iff1 = false;
+                    self.iff2 = false;
+                }
+                7 => {
+                    self.iff1 = true;
+                    self.iff2 = true;
+                }
+                // I/O, and the CB prefix
+                _ => return false,
+            },
+            (_, 4) => {
+                let nn = self.fetch16();
+                if self.condition(y) {
+                    self.push(self.pc);
+                    self.pc = nn;
+                }
+            }
+            (_, 5) => match (q, p) {
+                (0, p) => self.push(self.rp2(p, idx)),
+                (_, 0) => {
+                    let nn = self.fetch16();
+                    self.push(self.pc);
+                    self.pc = nn;
+                }
+                // The DD, ED and FD prefixes
+                _ => return false,
+            },
+            (_, 6) => {
+                let n = self.fetch();
+                self.alu(y, n);
+            }
+            _ => {
+                self.push(self.pc);
+                self.pc = u16::from(y) * 8;
+            }
+        }
+        true
+    }
+
+    /// The `alu` table, operating on A
+    fn alu(&mut self, y: u8, v: u8) {
+        let a = self.main[A];
+        let carry = self.flags() & CARRY;
+        let (r, flags) = match y {
+            0 => add8(a, v, 0),
+            1 => add8(a, v, carry),
+            2 | 7 => sub8(a, v, 0),
+            3 => sub8(a, v, carry),
+            4 => (a & v, self.szp[usize::from(a & v)] | HALF),
+            5 => (a ^ v, self.szp[usize::from(a ^ v)]),
+            _ => (a | v, self.szp[usize::from(a | v)]),
+        };
+        // CP only sets the flags
+        if y != 7 {
+            self.main[A] = r;
+        }
+        self.set_flags(ALL, flags);
+    }
+
+    /// x=0, z=7: rotations of A and the other single-byte operations on A and the flags
+    fn accumulator(&mut self, y: u8) {
+        let a = self.main[A];
+        let carry = self.flags() & CARRY;
+        match y {
+            0..=3 => {
+                let (r, carry) = rotate(y, a, carry);
+                self.main[A] = r;
+                self.set_flags(HALF | SUBTRACT | CARRY, carry);
+            }
+            4 => self.daa(),
+            5 => {
+                self.main[A] = !a;
+                self.set_flags(HALF | SUBTRACT, HALF | SUBTRACT);
+            }
+            6 => self.set_flags(HALF | SUBTRACT | CARRY, CARRY),
+            // The old carry moves to the half carry
+            _ => self.set_flags(HALF | SUBTRACT | CARRY, (carry << 4) | (carry ^ CARRY)),
+        }
+    }
+
+    /// DAA, following the tables in Sean Young's "The Undocumented Z80 Documented"
+    fn daa(&mut self) {
+        let a = self.main[A];
+        let flags = self.flags();
+        let (hi, lo) = (a >> 4, a & 0x0F);
+        let carry = flags & CARRY != 0;
+        let half = flags & HALF != 0;
+        let subtract = flags & SUBTRACT != 0;
+
+        let diff = match (carry, hi, half, lo) {
+            (false, 0..=9, false, 0..=9) => 0x00,
+            (false, 0..=9, true, 0..=9) => 0x06,
+            (false, 0..=8, _, 0xA..=0xF) => 0x06,
+            (false, 0xA..=0xF, false, 0..=9) => 0x60,
+            (true, _, false, 0..=9) => 0x60,
+            (true, _, true, 0..=9) => 0x66,
+            (true, _, _, 0xA..=0xF) => 0x66,
+            (false, 9..=0xF, _, 0xA..=0xF) => 0x66,
+            (false, 0xA..=0xF, true, 0..=9) => 0x66,
+            _ => unreachable!("{:02X} is not a byte", a),
+        };
+        let carry_out = match (carry, hi, lo) {
+            (false, 0..=9, 0..=9) | (false, 0..=8, 0xA..=0xF) => 0,
+            _ => CARRY,
+        };
+        let half_out = match (subtract, half, lo) {
+            (false, _, 0xA..=0xF) | (true, true, 0..=5) => HALF,
+            _ => 0,
+        };
+        let r = if subtract {
+            a.wrapping_sub(diff)
+        } else {
+            a.wrapping_add(diff)
+        };
+        self.main[A] = r;
+        self.set_flags(
+            SIGN | ZERO | HALF | PARITY | CARRY,
+            self.szp[usize::from(r)] | half_out | carry_out,
+        );
+    }
+
+    fn add16(&mut self, a: u16, b: u16) -> u16 {
+        let sum = u32::from(a) + u32::from(b);
+        let r = sum as u16;
+        let half = ((a ^ b ^ r) >> 8) as u8 & HALF;
+        self.set_flags(HALF | SUBTRACT | CARRY, half | (sum >> 16) as u8);
+        r
+    }
+
+    /// ADC HL, rp and SBC HL, rp
+    fn carry16(&mut self, a: u16, b: u16, subtract: bool) -> u16 {
+        let carry = u32::from(self.flags() & CARRY);
+        let (wide, overflow) = if subtract {
+            let wide = u32::from(a).wrapping_sub(u32::from(b)).wrapping_sub(carry);
+            (wide, (a ^ b) & (a ^ wide as u16))
+        } else {
+            let wide = u32::from(a) + u32::from(b) + carry;
+            (wide, (a ^ wide as u16) & (b ^ wide as u16))
+        };
+        let r = wide as u16;
+        let flags = ((r >> 8) as u8 & SIGN)
+            | if r == 0 { ZERO } else { 0 }
+            | ((a ^ b ^ r) >> 8) as u8 & HALF
+            | ((overflow & 0x8000) >> 13) as u8
+            | if subtract { SUBTRACT } else { 0 }
+            | (wide >> 16) as u8 & CARRY;
+        self.set_flags(ALL, flags);
+        r
+    }
+
+    /// A CB-prefixed instruction, on an operand already resolved
+    fn bits(&mut self, op: u8, operand: Operand) {
+        let (x, y, _, _, _) = fields(op);
+        let v = self.get(operand);
+        let bit = 1 << y;
+        match x {
+            0 => {
+                let (r, carry) = rotate(y, v, self.flags() & CARRY);
+                self.set(operand, r);
+                self.set_flags(ALL, self.szp[usize::from(r)] | carry);
+            }
+            1 => {
+                let zero = if v & bit == 0 { ZERO | PARITY } else { 0 };
+                self.set_flags(
+                    SIGN | ZERO | HALF | PARITY | SUBTRACT,
+                    (v & bit & SIGN) | zero | HALF,
+                );
+            }
+            2 => self.set(operand, v & !bit),
+            _ => self.set(operand, v | bit),
+        }
+    }
+
+    /// A DD- or FD-prefixed instruction. The prefix has already been fetched
+    fn indexed(&mut self, idx: Index) -> bool {
+        match self.read(self.pc) {
+            0xCB => {
+                self.fetch_opcode();
+                let addr = self.indirect(idx);
+                let op = self.fetch();
+                self.bits(op, Operand::Mem(addr));
+                // Besides BIT, those not on (IX+d) also copy the result to a register
+                if op & 7 != 6 && op >> 6 != 1 {
+                    self.set(Operand::Reg(usize::from(op & 7)), self.read(addr));
+                }
+                true
+            }
+            op if uses_hl(op) => {
+                self.fetch_opcode();
+                self.base(op, idx)
+            }
+            // The prefix does nothing, and the next instruction runs on its own
+            _ => true,
+        }
+    }
+
+    /// An ED-prefixed instruction
+    fn extended(&mut self, op: u8) -> bool {
+        let (x, y, z, p, q) = fields(op);
+        match (x, z) {
+            (1, 0) | (1, 1) => return false,
+            (1, 2) => {
+                let v = self.carry16(self.pair(H, L), self.rp(p, Index::HL), q == 0);
+                self.set_pair(H, L, v);
+            }
+            (1, 3) => {
+                let nn = self.fetch16();
+                if q == 0 {
+                    self.write16(nn, self.rp(p, Index::HL));
+                } else {
+                    let v = self.read16(nn);
+                    self.set_rp(p, Index::HL, v);
+                }
+            }
+            (1, 4) => {
+                let (r, flags) = sub8(0, self.main[A], 0);
+                self.main[A] = r;
+                self.set_flags(ALL, flags);
+            }
+            // RETN and RETI
+            (1, 5) => {
+                self.iff1 = self.iff2;
+                self.pc = self.pop();
+            }
+            (1, 6) => self.im = INTERRUPT_MODES[usize::from(y)],
+            (1, _) => match y {
+                0 => self.i = self.main[A],
+                1 => self.r = self.main[A],
+                2 | 3 => {
+                    let v = if y == 2 { self.i } else { self.r };
+                    self.main[A] = v;
+                    let iff2 = if self.iff2 { PARITY } else { 0 };
+                    self.set_flags(SIGN | ZERO | HALF | PARITY | SUBTRACT, sign_zero(v) | iff2);
+                }
+                4 | 5 => {
+                    let addr = self.pair(H, L);
+                    let (a, m) = (self.main[A], self.read(addr));
+                    let (a, m) = if y == 4 {
+                        ((a & 0xF0) | (m & 0x0F), (a << 4) | (m >> 4))
+                    } else {
+                        ((a & 0xF0) | (m >> 4), (m << 4) | (a & 0x0F))
+                    };
+                    self.main[A] = a;
+                    self.write(addr, m);
+                    self.set_flags(
+                        SIGN | ZERO | HALF | PARITY | SUBTRACT,
+                        self.szp[usize::from(a)],
+                    );
+                }
+                _ => (),
+            },
+            (2, 0..=3) if y >= 4 => {
+                let step = if y & 1 == 0 { 1 } else { 0xFFFF };
+                let repeat = y >= 6;
+                match z {
+                    0 => self.block_load(step, repeat),
+                    1 => self.block_compare(step, repeat),
+                    _ => return false,
+                }
+            }
+            // Everything else acts as a NOP
+            _ => (),
+        }
+        true
+    }
+
+    /// Move HL by `step`, and decrement BC, returning the parity flag: whether BC is non-zero
+    fn block_advance(&mut self, step: u16) -> u8 {
+        let hl = self.pair(H, L).wrapping_add(step);
+        self.set_pair(H, L, hl);
+        let bc = self.pair(B, C).wrapping_sub(1);
+        self.set_pair(B, C, bc);
+        if bc != 0 {
+            PARITY
+        } else {
+            0
+        }
+    }
+
+    fn block_load(&mut self, step: u16, repeat: bool) {
+        let de = self.pair(D, E);
+        self.write(de, self.read(self.pair(H, L)));
+        self.set_pair(D, E, de.wrapping_add(step));
+        let remaining = self.block_advance(step);
+        self.set_flags(HALF | SUBTRACT | PARITY, remaining);
+        if repeat && remaining != 0 {
+            self.pc = self.pc.wrapping_sub(2);
+        }
+    }
+
+    fn block_compare(&mut self, step: u16, repeat: bool) {
+        let (r, flags) = sub8(self.main[A], self.read(self.pair(H, L)), 0);
+        let remaining = self.block_advance(step);
+        self.set_flags(
+            SIGN | ZERO | HALF | PARITY | SUBTRACT,
+            (flags & !PARITY) | remaining,
+        );
+        if repeat && remaining != 0 && r != 0 {
+            self.pc = self.pc.wrapping_sub(2);
+        }
+    }
+}
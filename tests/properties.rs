@@ -0,0 +1,106 @@
+//! Properties of the ops model, the register file and instruction execution, checked on random
+//! inputs from the strategies in `strategies/`.
+extern crate proptest;
+extern crate zeerust;
+
+mod strategies;
+
+use proptest::prelude::*;
+use strategies::{op, pair, registers, REG16};
+use zeerust::cpu::opcodes::{encode, opcode};
+use zeerust::ops::{Location16, Location8, Op, Reg16, Reg8, StatusFlag};
+use zeerust::z80::Z80;
+
+/// Decode an encoded instruction, padding it to the four bytes the decoder looks at
+fn decode(bytes: &[u8]) -> (Op, usize) {
+    let mut code = [0; 4];
+    code[..bytes.len()].copy_from_slice(bytes);
+    opcode(code)
+}
+
+proptest! {
+    #[test]
+    fn encode_then_decode(op in op()) {
+        if let Some(bytes) = encode(&op) {
+            prop_assert!((1..=4).contains(&bytes.len()), "{:02X?}", bytes);
+            prop_assert_eq!((op, bytes.len()), decode(&bytes));
+        }
+    }
+
+    // Instructions with several encodings needn't encode to the bytes they came from
+    #[test]
+    fn decode_then_encode(code in any::<[u8; 4]>()) {
+        let (op, len) = opcode(code);
+        let bytes = encode(&op);
+        prop_assert!(bytes.is_some(), "{:?} didn't encode", op);
+        prop_assert_eq!((op, len), decode(&bytes.unwrap()));
+    }
+
+    #[test]
+    fn set_reg16_sets_halves(
+        mut registers in registers(),
+        (reg, high, low) in pair(),
+        v in any::<u16>(),
+    ) {
+        let before = registers.clone();
+        registers.set_reg16(&reg, v);
+        let [h, l] = v.to_be_bytes();
+        prop_assert_eq!(h, registers.get_reg8(high));
+        prop_assert_eq!(l, registers.get_reg8(low));
+        prop_assert_eq!(v, registers.get_reg16(&reg));
+        for other in REG16.iter().filter(|r| **r != reg) {
+            prop_assert_eq!(before.get_reg16(other), registers.get_reg16(other));
+        }
+    }
+
+    #[test]
+    fn set_reg8_sets_pair(
+        mut registers in registers(),
+        (reg, high, low) in pair(),
+        h in any::<u8>(),
+        l in any::<u8>(),
+    ) {
+        registers.set_reg8(high, h);
+        registers.set_reg8(low, l);
+        prop_assert_eq!(u16::from_be_bytes([h, l]), registers.get_reg16(&reg));
+    }
+
+    #[test]
+    fn push_then_pop(
+        registers in registers(),
+        from in prop::sample::select(vec![Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::IX, Reg16::IY]),
+        to in prop::sample::select(vec![Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::IX, Reg16::IY]),
+    ) {
+        let mut z80 = Z80::default();
+        z80.registers = registers.clone();
+        z80.exec(Op::PUSH(Location16::Reg(from.clone())));
+        z80.exec(Op::POP(Location16::Reg(to.clone())));
+
+        prop_assert_eq!(registers.get_reg16(&from), z80.registers.get_reg16(&to));
+        prop_assert_eq!(registers.get_reg16(&Reg16::SP), z80.registers.get_reg16(&Reg16::SP));
+        if from == to {
+            prop_assert_eq!(registers, z80.registers);
+        }
+    }
+
+    #[test]
+    fn add_then_sub(
+        registers in registers(),
+        src in prop_oneof![
+            any::<u8>().prop_map(Location8::Immediate),
+            prop::sample::select(vec![Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L])
+                .prop_map(Location8::Reg),
+        ],
+    ) {
+        let mut z80 = Z80::default();
+        z80.registers = registers.clone();
+        let acc = Location8::Reg(Reg8::A);
+        z80.exec(Op::ADD8(acc.clone(), src.clone()));
+        let carry = z80.registers.get_flag(&StatusFlag::Carry);
+        z80.exec(Op::SUB8(acc, src));
+
+        prop_assert_eq!(registers.get_reg8(Reg8::A), z80.registers.get_reg8(Reg8::A));
+        // The subtraction borrows exactly when the addition carried
+        prop_assert_eq!(carry, z80.registers.get_flag(&StatusFlag::Carry));
+    }
+}
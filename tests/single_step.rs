@@ -0,0 +1,263 @@
+//! Single instruction tests, in the JSON format of the SingleStepTests suite.
+//!
+//! Each file holds an array of cases. A case gives the registers and RAM before one instruction,
+//! the state expected after it, the bus activity in each cycle and any port accesses:
+//!
+//! ```text
+//! { "name": "3e 0000",
+//!   "initial": { "pc": 8192, "sp": 0, "a": 0, ..., "af_": 0, ..., "ram": [[8192, 62], [8193, 90]] },
+//!   "final": { "pc": 8194, "a": 90, ... },
+//!   "cycles": [[8192, 62, "r-m-"], ...],
+//!   "ports": [[4660, 18, "r"]] }
+//! ```
+//!
+//! Zeerust doesn't model cycles, the EI delay or the internal WZ, P and Q registers, so those
+//! are ignored. Undocumented flag bits 3 and 5 are
+//! ignored too. Everything else is compared: registers, the final RAM listed, and port writes.
+//!
+//! A small hand-written sample, in `tests/single_step/`, is run with the normal tests. To run a whole suite, point
+//! `ZEERUST_SINGLE_STEP` at a directory of test files (such as the suite's `v1` directory):
+//!
+//! ```text
+//! ZEERUST_SINGLE_STEP=path/to/z80/v1 cargo test --release --test single_step -- --ignored
+//! ```
+extern crate serde_json;
+extern crate zeerust;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use serde_json::Value;
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::io::{BufInput, BufOutput, Ports};
+use zeerust::z80::Z80;
+
+/// Flags other than the undocumented bits 3 and 5
+const DOCUMENTED_FLAGS: u8 = 0b1101_0111;
+
+const REG8: [(&str, Reg8); 10] = [
+    ("a", Reg8::A),
+    ("f", Reg8::F),
+    ("b", Reg8::B),
+    ("c", Reg8::C),
+    ("d", Reg8::D),
+    ("e", Reg8::E),
+    ("h", Reg8::H),
+    ("l", Reg8::L),
+    ("i", Reg8::I),
+    ("r", Reg8::R),
+];
+
+/// The shadow register pairs, as (high, low) halves
+const SHADOW: [(&str, Reg8, Reg8); 4] = [
+    ("af_", Reg8::AP, Reg8::FP),
+    ("bc_", Reg8::BP, Reg8::CP),
+    ("de_", Reg8::DP, Reg8::EP),
+    ("hl_", Reg8::HP, Reg8::LP),
+];
+
+const REG16: [(&str, Reg16); 3] = [("ix", Reg16::IX), ("iy", Reg16::IY), ("sp", Reg16::SP)];
+
+fn field(state: &Value, name: &str) -> u16 {
+    state[name]
+        .as_u64()
+        .unwrap_or_else(|| panic!("missing or invalid field {}", name)) as u16
+}
+
+fn ram(state: &Value) -> impl Iterator<Item = (u16, u8)> + '_ {
+    state["ram"]
+        .as_array()
+        .expect("missing ram")
+        .iter()
+        .map(|entry| {
+            (
+                entry[0].as_u64().unwrap() as u16,
+                entry[1].as_u64().unwrap() as u8,
+            )
+        })
+}
+
+fn set_state(z80: &mut Z80, state: &Value) {
+    for (name, reg) in &REG8 {
+        z80.registers.set_reg8(*reg, field(state, name) as u8);
+    }
+    for (name, hi, lo) in &SHADOW {
+        let [h, l] = field(state, name).to_be_bytes();
+        z80.registers.set_reg8(*hi, h);
+        z80.registers.set_reg8(*lo, l);
+    }
+    for (name, reg) in &REG16 {
+        z80.registers.set_reg16(reg, field(state, name));
+    }
+    z80.registers.set_pc(field(state, "pc"));
+    z80.registers
+        .set_iff(field(state, "iff1") != 0, field(state, "iff2") != 0);
+    z80.registers.set_im(field(state, "im") as u8);
+    for (addr, val) in ram(state) {
+        z80.load_at(addr, &[val]);
+    }
+}
+
+/// Describe every way the z80 differs from the expected state
+fn compare_state(z80: &Z80, state: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    let mut compare = |name: &str, expected: u16, actual: u16| {
+        if expected != actual {
+            errors.push(format!(
+                "{}: expected {:#X}, got {:#X}",
+                name, expected, actual
+            ));
+        }
+    };
+    for (name, reg) in &REG8 {
+        let mut expected = field(state, name) as u8;
+        let mut actual = z80.registers.get_reg8(*reg);
+        if *reg == Reg8::F {
+            expected &= DOCUMENTED_FLAGS;
+            actual &= DOCUMENTED_FLAGS;
+        }
+        compare(name, expected.into(), actual.into());
+    }
+    for (name, hi, lo) in &SHADOW {
+        let actual = u16::from_be_bytes([z80.registers.get_reg8(*hi), z80.registers.get_reg8(*lo)]);
+        compare(name, field(state, name), actual);
+    }
+    for (name, reg) in &REG16 {
+        compare(name, field(state, name), z80.registers.get_reg16(reg));
+    }
+    compare("pc", field(state, "pc"), z80.registers.get_pc());
+    let (iff1, iff2) = z80.registers.get_iff();
+    compare("iff1", field(state, "iff1"), iff1.into());
+    compare("iff2", field(state, "iff2"), iff2.into());
+    compare("im", field(state, "im"), z80.registers.get_im().into());
+    for (addr, val) in ram(state) {
+        let name = format!("({:04X})", addr);
+        compare(&name, val.into(), z80.memory()[addr as usize].into());
+    }
+    errors
+}
+
+/// Run a single case, returning a description of any failure
+fn run_case(case: &Value) -> Result<(), String> {
+    let mut z80 = Z80::default();
+    set_state(&mut z80, &case["initial"]);
+
+    let mut reads: BTreeMap<u16, Vec<u8>> = BTreeMap::new();
+    let mut writes: BTreeMap<u16, Vec<u8>> = BTreeMap::new();
+    for port in case["ports"].as_array().into_iter().flatten() {
+        let addr = port[0].as_u64().unwrap() as u16;
+        let val = port[1].as_u64().unwrap() as u8;
+        match port[2].as_str() {
+            Some("r") => reads.entry(addr).or_default().push(val),
+            _ => writes.entry(addr).or_default().push(val),
+        }
+    }
+    for (port, mut vals) in reads {
+        // BufInput produces its input from back to front
+        vals.reverse();
+        z80.connect(Ports::exact(port), Box::new(BufInput::new(vals)));
+    }
+    let outputs: Vec<(u16, Vec<u8>, BufOutput)> = writes
+        .into_iter()
+        .map(|(port, vals)| {
+            let out = BufOutput::default();
+            z80.connect(Ports::exact(port), Box::new(out.clone()));
+            (port, vals, out)
+        })
+        .collect();
+
+    panic::catch_unwind(AssertUnwindSafe(|| z80.step())).map_err(|e| {
+        match e.downcast_ref::<String>() {
+            Some(message) => format!("panicked: {}", message),
+            None => "panicked".to_string(),
+        }
+    })?;
+
+    let mut errors = compare_state(&z80, &case["final"]);
+    for (port, expected, out) in outputs {
+        if out.result() != expected {
+            errors.push(format!(
+                "port {:04X}: expected {:02X?}, got {:02X?}",
+                port,
+                expected,
+                out.result()
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+/// Run every case in a file, returning the number that passed and the failures
+fn run_file(path: &Path) -> (usize, Vec<String>) {
+    let json = fs::read_to_string(path).unwrap();
+    let cases: Vec<Value> = serde_json::from_str(&json).unwrap();
+    let mut passed = 0;
+    let mut failures = vec![];
+    for case in &cases {
+        match run_case(case) {
+            Ok(()) => passed += 1,
+            Err(e) => failures.push(format!("{}: {}", case["name"], e)),
+        }
+    }
+    (passed, failures)
+}
+
+#[test]
+fn sample() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/single_step/sample.json");
+    let (passed, failures) = run_file(&path);
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+    assert!(passed > 0);
+}
+
+#[test]
+#[ignore]
+fn full_suite() {
+    let dir = match env::var_os("ZEERUST_SINGLE_STEP") {
+        Some(dir) => dir,
+        None => {
+            eprintln!("skipping: ZEERUST_SINGLE_STEP is not set");
+            return;
+        }
+    };
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+
+    // Missing peripherals panic; only their summary is interesting
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let mut failed_files = vec![];
+    for path in &files {
+        let (passed, failures) = run_file(path);
+        if let Some(first) = failures.first() {
+            let name = path.file_name().unwrap().to_string_lossy();
+            failed_files.push(format!(
+                "{}: {} of {} failed, first {}",
+                name,
+                failures.len(),
+                passed + failures.len(),
+                first
+            ));
+        }
+    }
+    panic::set_hook(hook);
+
+    assert!(
+        failed_files.is_empty(),
+        "{} of {} files had failures:\n{}",
+        failed_files.len(),
+        files.len(),
+        failed_files.join("\n")
+    );
+}
@@ -0,0 +1,242 @@
+//! Proptest strategies for zeerust's model of the Z80: operations, their locations, and
+//! register files.
+use proptest::prelude::*;
+use proptest::sample::select;
+use zeerust::cpu::reg::Registers;
+use zeerust::ops::{JumpConditional, Location16, Location8, Op, Reg16, Reg8};
+
+pub const REG8: [Reg8; 22] = [
+    Reg8::A,
+    Reg8::F,
+    Reg8::B,
+    Reg8::C,
+    Reg8::D,
+    Reg8::E,
+    Reg8::H,
+    Reg8::L,
+    Reg8::AP,
+    Reg8::FP,
+    Reg8::BP,
+    Reg8::CP,
+    Reg8::DP,
+    Reg8::EP,
+    Reg8::HP,
+    Reg8::LP,
+    Reg8::IXH,
+    Reg8::IXL,
+    Reg8::IYH,
+    Reg8::IYL,
+    Reg8::I,
+    Reg8::R,
+];
+
+pub const REG16: [Reg16; 11] = [
+    Reg16::AF,
+    Reg16::BC,
+    Reg16::DE,
+    Reg16::HL,
+    Reg16::AFP,
+    Reg16::BCP,
+    Reg16::DEP,
+    Reg16::HLP,
+    Reg16::IX,
+    Reg16::IY,
+    Reg16::SP,
+];
+
+/// The 16-bit registers made of two 8-bit registers, with their high and low halves
+pub const PAIRS: [(Reg16, Reg8, Reg8); 10] = [
+    (Reg16::AF, Reg8::A, Reg8::F),
+    (Reg16::BC, Reg8::B, Reg8::C),
+    (Reg16::DE, Reg8::D, Reg8::E),
+    (Reg16::HL, Reg8::H, Reg8::L),
+    (Reg16::AFP, Reg8::AP, Reg8::FP),
+    (Reg16::BCP, Reg8::BP, Reg8::CP),
+    (Reg16::DEP, Reg8::DP, Reg8::EP),
+    (Reg16::HLP, Reg8::HP, Reg8::LP),
+    (Reg16::IX, Reg8::IXH, Reg8::IXL),
+    (Reg16::IY, Reg8::IYH, Reg8::IYL),
+];
+
+pub fn reg8() -> impl Strategy<Value = Reg8> {
+    select(REG8.to_vec())
+}
+
+pub fn reg16() -> impl Strategy<Value = Reg16> {
+    select(REG16.to_vec())
+}
+
+pub fn pair() -> impl Strategy<Value = (Reg16, Reg8, Reg8)> {
+    select(PAIRS.to_vec())
+}
+
+fn index_reg() -> impl Strategy<Value = Reg16> {
+    select(vec![Reg16::IX, Reg16::IY])
+}
+
+pub fn condition() -> impl Strategy<Value = JumpConditional> {
+    select(vec![
+        JumpConditional::Unconditional,
+        JumpConditional::NonZero,
+        JumpConditional::Zero,
+        JumpConditional::NoCarry,
+        JumpConditional::Carry,
+        JumpConditional::ParityOdd,
+        JumpConditional::ParityEven,
+        JumpConditional::SignPositive,
+        JumpConditional::SignNegative,
+    ])
+}
+
+/// The locations most instructions can use as an 8-bit operand:
+/// B, C, D, E, H, L, (HL), A, the halves of IX and IY, and (IX+d) and (IY+d)
+pub fn operand8() -> impl Strategy<Value = Location8> {
+    prop_oneof![
+        3 => select(vec![
+            Location8::Reg(Reg8::B),
+            Location8::Reg(Reg8::C),
+            Location8::Reg(Reg8::D),
+            Location8::Reg(Reg8::E),
+            Location8::Reg(Reg8::H),
+            Location8::Reg(Reg8::L),
+            Location8::RegIndirect(Reg16::HL),
+            Location8::Reg(Reg8::A),
+        ]),
+        1 => select(vec![Reg8::IXH, Reg8::IXL, Reg8::IYH, Reg8::IYL]).prop_map(Location8::Reg),
+        1 => (index_reg(), any::<i8>()).prop_map(|(r, d)| Location8::Indexed(r, d)),
+    ]
+}
+
+/// Any 8-bit location, weighted towards those instructions can use
+pub fn location8() -> impl Strategy<Value = Location8> {
+    prop_oneof![
+        5 => operand8(),
+        1 => reg8().prop_map(Location8::Reg),
+        1 => reg16().prop_map(Location8::RegIndirect),
+        1 => any::<u16>().prop_map(Location8::ImmediateIndirect),
+        2 => any::<u8>().prop_map(Location8::Immediate),
+    ]
+}
+
+pub fn location16() -> impl Strategy<Value = Location16> {
+    prop_oneof![
+        3 => reg16().prop_map(Location16::Reg),
+        1 => reg16().prop_map(Location16::RegIndirect),
+        1 => any::<u16>().prop_map(Location16::ImmediateIndirect),
+        1 => any::<u16>().prop_map(Location16::Immediate),
+    ]
+}
+
+/// Any operation, other than `Op::Invalid`, which only stands for bytes the decoder rejects.
+/// Many have no Z80 instruction, such as `ADD8(Reg(D), Immediate(10))`
+pub fn op() -> impl Strategy<Value = Op> {
+    let none = vec![
+        Op::CPL,
+        Op::NEG,
+        Op::CCF,
+        Op::SCF,
+        Op::NOP,
+        Op::HALT,
+        Op::DAA,
+        Op::RLCA,
+        Op::RLA,
+        Op::RRCA,
+        Op::RRA,
+        Op::RLD,
+        Op::RRD,
+        Op::EXX,
+        Op::DI,
+        Op::EI,
+        Op::RETI,
+        Op::RETN,
+        Op::LDI,
+        Op::LDIR,
+        Op::LDD,
+        Op::LDDR,
+        Op::CPI,
+        Op::CPIR,
+        Op::CPD,
+        Op::CPDR,
+        Op::INI,
+        Op::INIR,
+        Op::IND,
+        Op::INDR,
+        Op::OUTI,
+        Op::OTIR,
+        Op::OUTD,
+        Op::OTDR,
+    ];
+    let unary8: Vec<fn(Location8) -> Op> = vec![
+        Op::INC,
+        Op::DEC,
+        Op::AND,
+        Op::OR,
+        Op::XOR,
+        Op::CP,
+        Op::RLC,
+        Op::RL,
+        Op::RRC,
+        Op::RR,
+        Op::SLA,
+        Op::SLL,
+        Op::SRL,
+        Op::SRA,
+    ];
+    let binary8: Vec<fn(Location8, Location8) -> Op> = vec![
+        Op::ADC,
+        Op::ADD8,
+        Op::SBC,
+        Op::SUB8,
+        Op::LD8,
+        Op::IN,
+        Op::OUT,
+    ];
+    let bits: Vec<fn(u8, Location8) -> Op> = vec![Op::BIT, Op::SET, Op::RES];
+    let unary16: Vec<fn(Location16) -> Op> = vec![Op::POP, Op::PUSH, Op::INC16, Op::DEC16];
+    let binary16: Vec<fn(Location16, Location16) -> Op> =
+        vec![Op::LD16, Op::EX, Op::ADD16, Op::ADC16, Op::SBC16];
+
+    // The operations COPY wraps, on the operands they encode with
+    let copied = prop_oneof![
+        (select(unary8.clone()), operand8()).prop_map(|(op, a)| op(a)),
+        (select(bits.clone()), 0..8_u8, operand8()).prop_map(|(op, b, a)| op(b, a)),
+    ];
+
+    prop_oneof![
+        select(none),
+        (select(unary8), location8()).prop_map(|(op, a)| op(a)),
+        (select(binary8), location8(), location8()).prop_map(|(op, a, b)| op(a, b)),
+        (select(bits), 0..8_u8, location8()).prop_map(|(op, b, a)| op(b, a)),
+        (select(unary16), location16()).prop_map(|(op, a)| op(a)),
+        (select(binary16), location16(), location16()).prop_map(|(op, a, b)| op(a, b)),
+        (condition(), location16()).prop_map(|(c, a)| Op::JP(c, a)),
+        (condition(), any::<i8>()).prop_map(|(c, e)| Op::JR(c, e)),
+        any::<i8>().prop_map(Op::DJNZ),
+        (condition(), any::<u16>()).prop_map(|(c, a)| Op::CALL(c, a)),
+        condition().prop_map(Op::RET),
+        (0..8_u8).prop_map(|n| Op::RST(n * 8)),
+        (0..3_u8).prop_map(Op::IM),
+        (copied, reg8()).prop_map(|(op, r)| Op::COPY(Box::new(op), r)),
+    ]
+}
+
+/// A register file with every register set at random
+pub fn registers() -> impl Strategy<Value = Registers> {
+    (
+        any::<[u8; 22]>(),
+        any::<(u16, u16)>(),
+        any::<(bool, bool)>(),
+        0..=2_u8,
+    )
+        .prop_map(|(values, (pc, sp), (iff1, iff2), im)| {
+            let mut registers = Registers::default();
+            for (reg, v) in REG8.iter().zip(values) {
+                registers.set_reg8(*reg, v);
+            }
+            registers.set_reg16(&Reg16::SP, sp);
+            registers.set_pc(pc);
+            registers.set_iff(iff1, iff2);
+            registers.set_im(im);
+            registers
+        })
+}
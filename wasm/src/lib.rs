@@ -0,0 +1,147 @@
+//! WebAssembly bindings for zeerust, used by the playground in `www/`.
+//!
+//! A `Machine` is a Z80 with an output device on port 0, as the bundled examples expect.
+use wasm_bindgen::prelude::*;
+
+use zeerust::examples::EXAMPLES;
+use zeerust::ops::{Reg16, Reg8};
+use zeerust::z80::io::BufOutput;
+use zeerust::z80::Z80;
+
+/// The names of the bundled examples
+#[wasm_bindgen]
+pub fn examples() -> Vec<String> {
+    EXAMPLES.iter().map(|e| e.name.to_string()).collect()
+}
+
+/// The assembly source of a bundled example
+#[wasm_bindgen(js_name = exampleAssembly)]
+pub fn example_assembly(name: &str) -> Option<String> {
+    EXAMPLES
+        .iter()
+        .find(|e| e.name == name)
+        .map(|e| e.assembly.to_string())
+}
+
+/// The machine code of a bundled example
+#[wasm_bindgen(js_name = exampleBinary)]
+pub fn example_binary(name: &str) -> Option<Vec<u8>> {
+    EXAMPLES
+        .iter()
+        .find(|e| e.name == name)
+        .map(|e| e.binary.to_vec())
+}
+
+/// The main registers, as returned by `Machine::registers`
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub ix: u16,
+    pub iy: u16,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+#[wasm_bindgen]
+pub struct Machine {
+    z80: Z80,
+    output: BufOutput,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        let mut z80 = Z80::default();
+        let output = BufOutput::default();
+        z80.install_output(0, Box::new(output.clone()));
+        Self { z80, output }
+    }
+}
+
+#[wasm_bindgen]
+impl Machine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Machine {
+        Self::default()
+    }
+
+    /// Reset the machine, then load a program at 0x0000
+    pub fn load(&mut self, program: &[u8]) {
+        *self = Self::default();
+        self.z80.load(program);
+    }
+
+    /// Execute a single instruction
+    pub fn step(&mut self) {
+        self.z80.step();
+    }
+
+    /// Execute instructions until a HALT, or until `limit` have been executed,
+    /// returning whether the machine halted
+    pub fn run(&mut self, limit: u32) -> bool {
+        self.z80.run_for(u64::from(limit));
+        self.z80.is_halted()
+    }
+
+    pub fn halted(&self) -> bool {
+        self.z80.is_halted()
+    }
+
+    /// The number of instructions executed since the program was loaded
+    pub fn instructions(&self) -> f64 {
+        self.z80.instructions() as f64
+    }
+
+    pub fn registers(&self) -> Registers {
+        let regs = &self.z80.registers;
+        Registers {
+            a: regs.get_reg8(Reg8::A),
+            f: regs.get_reg8(Reg8::F),
+            b: regs.get_reg8(Reg8::B),
+            c: regs.get_reg8(Reg8::C),
+            d: regs.get_reg8(Reg8::D),
+            e: regs.get_reg8(Reg8::E),
+            h: regs.get_reg8(Reg8::H),
+            l: regs.get_reg8(Reg8::L),
+            ix: regs.get_reg16(&Reg16::IX),
+            iy: regs.get_reg16(&Reg16::IY),
+            sp: regs.get_reg16(&Reg16::SP),
+            pc: regs.get_pc(),
+        }
+    }
+
+    /// The instruction at the program counter, disassembled
+    #[wasm_bindgen(js_name = nextInstruction)]
+    pub fn next_instruction(&self) -> String {
+        let pc = self.z80.registers.get_pc();
+        match self.z80.parse_opcode(usize::from(pc)) {
+            Some((op, _)) => format!("{:?}", op),
+            None => String::new(),
+        }
+    }
+
+    /// `len` bytes of memory from `start`, wrapping around from the top of memory to the bottom
+    pub fn memory(&self, start: u16, len: u16) -> Vec<u8> {
+        (0..len)
+            .map(|i| self.z80.memory()[usize::from(start.wrapping_add(i))])
+            .collect()
+    }
+
+    /// Everything written to port 0 since the program was loaded
+    pub fn output(&self) -> Vec<u8> {
+        self.output.result()
+    }
+
+    /// The output as text
+    #[wasm_bindgen(js_name = outputText)]
+    pub fn output_text(&self) -> String {
+        String::from_utf8_lossy(&self.output.result()).into_owned()
+    }
+}
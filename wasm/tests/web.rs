@@ -0,0 +1,71 @@
+//! Run with `wasm-pack test --node`
+use wasm_bindgen_test::*;
+
+use zeerust_wasm::{example_assembly, example_binary, examples, Machine};
+
+fn load_example(name: &str) -> Machine {
+    let mut machine = Machine::new();
+    machine.load(&example_binary(name).unwrap());
+    machine
+}
+
+#[wasm_bindgen_test]
+fn lists_examples() {
+    assert!(examples().contains(&"fizzbuzz".to_string()));
+    assert!(example_assembly("countdown").unwrap().contains("halt"));
+    assert_eq!(None, example_binary("missing"));
+}
+
+#[wasm_bindgen_test]
+fn runs_countdown() {
+    let mut machine = load_example("countdown");
+    assert!(machine.run(10_000));
+    assert_eq!("9\n8\n7\n6\n5\n4\n3\n2\n1\n", machine.output_text());
+}
+
+#[wasm_bindgen_test]
+fn run_stops_at_limit() {
+    let mut machine = load_example("fizzbuzz");
+    assert!(!machine.run(10));
+    assert_eq!(10.0, machine.instructions());
+    assert!(machine.run(100_000));
+    assert!(machine
+        .output_text()
+        .starts_with("01\n02\nFizz\n04\nBuzz\n"));
+}
+
+#[wasm_bindgen_test]
+fn steps() {
+    // LD A, 0x2A; OUT (0), A; HALT
+    let mut machine = Machine::new();
+    machine.load(&[0x3E, 0x2A, 0xD3, 0x00, 0x76]);
+    assert_eq!("LD8(Reg(A), Immediate(42))", machine.next_instruction());
+
+    machine.step();
+    let regs = machine.registers();
+    assert_eq!(0x2A, regs.a);
+    assert_eq!(0x0002, regs.pc);
+
+    machine.step();
+    assert_eq!(vec![0x2A], machine.output());
+    machine.step();
+    assert!(machine.halted());
+}
+
+#[wasm_bindgen_test]
+fn reads_memory() {
+    let mut machine = Machine::new();
+    machine.load(&[0x01, 0x02, 0x03]);
+    assert_eq!(vec![0x01, 0x02, 0x03, 0x00], machine.memory(0x0000, 4));
+    assert_eq!(vec![0x00, 0x01], machine.memory(0xFFFF, 2));
+}
+
+#[wasm_bindgen_test]
+fn load_resets() {
+    let mut machine = load_example("countdown");
+    machine.run(10_000);
+    machine.load(&[0x76]);
+    assert!(!machine.halted());
+    assert_eq!(0.0, machine.instructions());
+    assert!(machine.output().is_empty());
+}
@@ -0,0 +1,58 @@
+// Built with `wasm-pack build --target web --out-dir www/pkg`
+import init, { Machine, examples, exampleAssembly, exampleBinary } from "./pkg/zeerust_wasm.js";
+
+// How many instructions Run executes before giving up on a program that doesn't halt
+const RUN_LIMIT = 1000000;
+
+const REGISTERS = ["a", "f", "b", "c", "d", "e", "h", "l", "ix", "iy", "sp", "pc"];
+
+const $ = (id) => document.getElementById(id);
+
+function hex(value, digits) {
+  return value.toString(16).toUpperCase().padStart(digits, "0");
+}
+
+function show(machine) {
+  const regs = machine.registers();
+  $("registers").innerHTML = REGISTERS.map((r) => {
+    const digits = r.length === 1 ? 2 : 4;
+    return `<tr><td>${r.toUpperCase()}</td><td>${hex(regs[r], digits)}</td></tr>`;
+  }).join("");
+  regs.free();
+  $("next").textContent = machine.halted() ? "(halted)" : machine.nextInstruction();
+  $("status").textContent = `${machine.instructions()} instructions executed`;
+  $("output").textContent = machine.outputText();
+}
+
+async function main() {
+  await init();
+  const machine = new Machine();
+
+  const load = () => {
+    const name = $("example").value;
+    $("assembly").textContent = exampleAssembly(name);
+    machine.load(exampleBinary(name));
+    show(machine);
+  };
+
+  for (const name of examples()) {
+    const option = document.createElement("option");
+    option.value = option.textContent = name;
+    $("example").appendChild(option);
+  }
+  $("example").addEventListener("change", load);
+  $("reset").addEventListener("click", load);
+  $("step").addEventListener("click", () => {
+    if (!machine.halted()) {
+      machine.step();
+    }
+    show(machine);
+  });
+  $("run").addEventListener("click", () => {
+    machine.run(RUN_LIMIT);
+    show(machine);
+  });
+  load();
+}
+
+main();